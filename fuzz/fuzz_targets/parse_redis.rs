@@ -0,0 +1,45 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+#![no_main]
+#![feature(proc_macro_hygiene)]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate futures;
+
+// synchrotron is a binary crate, so we pull in the modules the parser depends on directly.
+#[path = "../../src/common.rs"]
+mod common;
+#[path = "../../src/protocol/mod.rs"]
+mod protocol;
+#[path = "../../src/util/mod.rs"]
+mod util;
+
+fuzz_target!(|data: &[u8]| {
+    // Malformed input is fine, and expected, but it should only ever produce an error: never a
+    // panic, and never a hang.
+    let _ = protocol::redis::parse_messages(data, Some(1024));
+    let _ = protocol::redis::parse_messages(data, None);
+});
@@ -0,0 +1,151 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{BackendDescriptor, Distributor};
+use crypto::{digest::Digest, md5::Md5};
+
+/// Provides a consistent hashing distribution of requests, compatible with libketama.
+///
+/// Each backend is placed on a ring of 32-bit points at `vnodes` spots per unit of weight, based on
+/// its identifier, and a point is served by the first backend at or after it on the ring.  Adding
+/// or removing a backend only moves the points that land on its spots, rather than reshuffling
+/// nearly everything like modulo distribution does.
+pub struct KetamaDistributor {
+    vnodes: usize,
+    ring: Vec<(u32, usize)>,
+}
+
+impl KetamaDistributor {
+    pub fn new(vnodes: usize) -> KetamaDistributor { KetamaDistributor { vnodes, ring: Vec::new() } }
+}
+
+impl Distributor for KetamaDistributor {
+    fn update(&mut self, backends: Vec<BackendDescriptor>) {
+        let mut ring = Vec::new();
+        for backend in backends {
+            // Every MD5 digest gives us four points, so we hash a quarter as many times as we have
+            // points, just like libketama.
+            let points = self.vnodes * backend.weight;
+            for i in 0..(points + 3) / 4 {
+                let mut hasher = Md5::new();
+                hasher.input(format!("{}-{}", backend.identifier, i).as_bytes());
+
+                let mut digest = [0; 16];
+                hasher.result(&mut digest);
+
+                for chunk in digest.chunks(4).take(points - i * 4) {
+                    let point = (u32::from(chunk[3]) << 24)
+                        | (u32::from(chunk[2]) << 16)
+                        | (u32::from(chunk[1]) << 8)
+                        | u32::from(chunk[0]);
+                    ring.push((point, backend.idx));
+                }
+            }
+        }
+
+        ring.sort();
+        self.ring = ring;
+    }
+
+    fn choose(&self, point: u64) -> Option<usize> {
+        if self.ring.is_empty() {
+            return None;
+        }
+
+        // The ring is 32-bit, so fold wider hashes down to fit it.
+        let point = (point ^ (point >> 32)) as u32;
+        let idx = match self.ring.binary_search_by(|(p, _)| p.cmp(&point)) {
+            Ok(idx) => idx,
+            Err(idx) if idx == self.ring.len() => 0,
+            Err(idx) => idx,
+        };
+
+        Some(self.ring[idx].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::hasher::{Fnv64aHasher, KeyHasher};
+
+    fn descriptor(idx: usize, weight: usize) -> BackendDescriptor {
+        BackendDescriptor {
+            idx,
+            identifier: format!("10.0.0.{}:6379", idx),
+            healthy: true,
+            weight,
+        }
+    }
+
+    fn choose_keys(distributor: &KetamaDistributor) -> Vec<usize> {
+        let hasher = Fnv64aHasher::new();
+        (0..10000)
+            .map(|i| {
+                let point = hasher.hash(format!("key{}", i).as_bytes());
+                distributor.choose(point).expect("no backend chosen")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_removing_a_backend() {
+        let mut distributor = KetamaDistributor::new(160);
+        distributor.update((0..8).map(|idx| descriptor(idx, 1)).collect());
+        let before = choose_keys(&distributor);
+
+        // Every backend should get a reasonable share of the keys.
+        for idx in 0..8 {
+            let count = before.iter().filter(|chosen| **chosen == idx).count();
+            assert!(count > 800 && count < 1800, "backend {} got {} keys", idx, count);
+        }
+
+        distributor.update((0..8).filter(|idx| *idx != 3).map(|idx| descriptor(idx, 1)).collect());
+        let after = choose_keys(&distributor);
+
+        // Only the keys on the removed backend should move: roughly an eighth of them, and none of
+        // the keys that lived anywhere else.
+        let moved = before.iter().zip(&after).filter(|(a, b)| a != b).collect::<Vec<_>>();
+        assert!(moved.iter().all(|(a, _)| **a == 3));
+        assert!(moved.len() < 1000, "{} keys moved", moved.len());
+    }
+
+    #[test]
+    fn test_weighted_distribution() {
+        let mut distributor = KetamaDistributor::new(160);
+        distributor.update(vec![descriptor(0, 1), descriptor(1, 3), descriptor(2, 0)]);
+        assert_eq!(distributor.ring.len(), 640);
+
+        let chosen = choose_keys(&distributor);
+        let counts = (0..3)
+            .map(|idx| chosen.iter().filter(|c| **c == idx).count())
+            .collect::<Vec<_>>();
+        assert_eq!(counts[2], 0);
+        assert!(counts[1] > counts[0] * 2, "counts were {:?}", counts);
+    }
+
+    #[test]
+    fn test_no_backends() {
+        let mut distributor = KetamaDistributor::new(160);
+        assert_eq!(distributor.choose(42), None);
+
+        distributor.update(vec![descriptor(0, 0)]);
+        assert_eq!(distributor.choose(42), None);
+    }
+}
@@ -17,9 +17,13 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+mod ketama;
 mod modulo;
 mod random;
-pub use self::{modulo::ModuloDistributor, random::RandomDistributor};
+mod roundrobin;
+pub use self::{
+    ketama::KetamaDistributor, modulo::ModuloDistributor, random::RandomDistributor, roundrobin::RoundRobinDistributor,
+};
 use crate::errors::CreationError;
 
 /// A placeholder for backends.  This lets us avoid holding references to the actual backends.
@@ -27,20 +31,42 @@ pub struct BackendDescriptor {
     pub idx: usize,
     pub identifier: String,
     pub healthy: bool,
+    pub weight: usize,
+}
+
+/// Expands the given backends by their weight, so that a backend with a weight of N appears N
+/// times.  Backends with a weight of 0 don't appear at all.
+pub fn expand_weighted(backends: Vec<BackendDescriptor>) -> Vec<usize> {
+    backends
+        .into_iter()
+        .flat_map(|backend| std::iter::repeat(backend.idx).take(backend.weight))
+        .collect()
 }
 
 /// Distributes items amongst a set of backends.
+///
+/// Backends are weighted: a backend with a weight of N is treated as N backends, and so gets N
+/// times the share of items that a backend with a weight of 1 would.
 pub trait Distributor {
     fn update(&mut self, backends: Vec<BackendDescriptor>);
 
     /// Chooses a backend based on the given point.
-    fn choose(&self, point: u64) -> usize;
+    ///
+    /// Returns `None` if there are no backends to choose from.
+    fn choose(&self, point: u64) -> Option<usize>;
+
+    /// Whether or not the same point always maps to the same backend.
+    ///
+    /// Multi-key requests can only be checked for colocation when this is true.
+    fn is_key_affine(&self) -> bool { true }
 }
 
-pub fn configure_distributor(dist_type: &str) -> Result<Box<Distributor + Send + Sync>, CreationError> {
+pub fn configure_distributor(dist_type: &str, vnodes: usize) -> Result<Box<Distributor + Send + Sync>, CreationError> {
     match dist_type {
         "random" => Ok(Box::new(RandomDistributor::new())),
         "modulo" => Ok(Box::new(ModuloDistributor::new())),
+        "ketama" => Ok(Box::new(KetamaDistributor::new(vnodes))),
+        "roundrobin" => Ok(Box::new(RoundRobinDistributor::new())),
         s => {
             Err(CreationError::InvalidResource(format!(
                 "unknown distributor type {}",
@@ -17,12 +17,12 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use super::{BackendDescriptor, Distributor};
+use super::{expand_weighted, BackendDescriptor, Distributor};
 
 /// Provides a modulo'd distribution of requests.
 pub struct ModuloDistributor {
     backend_count: usize,
-    backends: Vec<BackendDescriptor>,
+    backends: Vec<usize>,
 }
 
 impl ModuloDistributor {
@@ -36,12 +36,51 @@ impl ModuloDistributor {
 
 impl Distributor for ModuloDistributor {
     fn update(&mut self, backends: Vec<BackendDescriptor>) {
-        self.backends = backends;
+        self.backends = expand_weighted(backends);
         self.backend_count = self.backends.len();
     }
 
-    fn choose(&self, point: u64) -> usize {
+    fn choose(&self, point: u64) -> Option<usize> {
+        if self.backend_count == 0 {
+            return None;
+        }
+
         let idx = point as usize % self.backend_count;
-        self.backends[idx].idx
+        Some(self.backends[idx])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(idx: usize, weight: usize) -> BackendDescriptor {
+        BackendDescriptor {
+            idx,
+            identifier: idx.to_string(),
+            healthy: true,
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_weighted_distribution() {
+        let mut distributor = ModuloDistributor::new();
+        distributor.update(vec![descriptor(0, 1), descriptor(1, 3), descriptor(2, 0)]);
+
+        let mut counts = [0; 3];
+        for point in 0..4000 {
+            let idx = distributor.choose(point).expect("no backend chosen");
+            counts[idx] += 1;
+        }
+
+        assert_eq!(counts, [1000, 3000, 0]);
+    }
+
+    #[test]
+    fn test_no_weighted_backends() {
+        let mut distributor = ModuloDistributor::new();
+        distributor.update(vec![descriptor(0, 0)]);
+        assert_eq!(distributor.choose(42), None);
     }
 }
@@ -17,13 +17,13 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use super::{BackendDescriptor, Distributor};
+use super::{expand_weighted, BackendDescriptor, Distributor};
 use rand::{thread_rng, Rng};
 
 /// Provides a randomized distribution of requests.
 pub struct RandomDistributor {
     backend_count: usize,
-    backends: Vec<BackendDescriptor>,
+    backends: Vec<usize>,
 }
 
 impl RandomDistributor {
@@ -37,13 +37,53 @@ impl RandomDistributor {
 
 impl Distributor for RandomDistributor {
     fn update(&mut self, backends: Vec<BackendDescriptor>) {
-        self.backends = backends;
+        self.backends = expand_weighted(backends);
         self.backend_count = self.backends.len();
     }
 
-    fn choose(&self, _point: u64) -> usize {
+    fn choose(&self, _point: u64) -> Option<usize> {
+        if self.backend_count == 0 {
+            return None;
+        }
+
         let mut rng = thread_rng();
         let idx = rng.gen_range(0, self.backend_count);
-        self.backends[idx].idx
+        Some(self.backends[idx])
+    }
+
+    fn is_key_affine(&self) -> bool { false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_distribution() {
+        let mut distributor = RandomDistributor::new();
+        distributor.update(vec![
+            BackendDescriptor {
+                idx: 0,
+                identifier: "a".to_owned(),
+                healthy: true,
+                weight: 1,
+            },
+            BackendDescriptor {
+                idx: 1,
+                identifier: "b".to_owned(),
+                healthy: true,
+                weight: 3,
+            },
+        ]);
+
+        let mut counts = [0; 2];
+        for _ in 0..10000 {
+            let idx = distributor.choose(0).expect("no backend chosen");
+            counts[idx] += 1;
+        }
+
+        // We should be somewhere close to a 1:3 split.
+        assert!(counts[0] > 2000 && counts[0] < 3000);
+        assert!(counts[1] > 7000 && counts[1] < 8000);
     }
 }
@@ -0,0 +1,107 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{expand_weighted, BackendDescriptor, Distributor};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Provides a round-robin distribution of requests.
+///
+/// The point is ignored entirely: every request simply goes to the next backend in the rotation.
+/// This only makes sense for backends that all hold the same data.
+pub struct RoundRobinDistributor {
+    backend_count: usize,
+    backends: Vec<usize>,
+    counter: AtomicUsize,
+}
+
+impl RoundRobinDistributor {
+    pub fn new() -> RoundRobinDistributor {
+        RoundRobinDistributor {
+            backend_count: 0,
+            backends: Vec::new(),
+            counter: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Distributor for RoundRobinDistributor {
+    fn update(&mut self, backends: Vec<BackendDescriptor>) {
+        // Unhealthy backends are left out of the rotation entirely, rather than being skipped over
+        // when their turn comes up, so the remaining backends split their share evenly.
+        let backends = backends.into_iter().filter(|backend| backend.healthy).collect();
+        self.backends = expand_weighted(backends);
+        self.backend_count = self.backends.len();
+    }
+
+    fn choose(&self, _point: u64) -> Option<usize> {
+        if self.backend_count == 0 {
+            return None;
+        }
+
+        let idx = self.counter.fetch_add(1, Ordering::Relaxed) % self.backend_count;
+        Some(self.backends[idx])
+    }
+
+    fn is_key_affine(&self) -> bool { false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(idx: usize, healthy: bool, weight: usize) -> BackendDescriptor {
+        BackendDescriptor {
+            idx,
+            identifier: idx.to_string(),
+            healthy,
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let mut distributor = RoundRobinDistributor::new();
+        distributor.update(vec![descriptor(0, true, 1), descriptor(1, true, 1), descriptor(2, true, 1)]);
+
+        // The point doesn't matter: we just go around in order.
+        let chosen = (0..6).map(|_| distributor.choose(42)).collect::<Vec<_>>();
+        assert_eq!(chosen, vec![Some(0), Some(1), Some(2), Some(0), Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn test_unhealthy_backends_are_skipped() {
+        let mut distributor = RoundRobinDistributor::new();
+        distributor.update(vec![descriptor(0, true, 1), descriptor(1, false, 1), descriptor(2, true, 2)]);
+
+        let mut counts = [0; 3];
+        for _ in 0..3000 {
+            let idx = distributor.choose(0).expect("no backend chosen");
+            counts[idx] += 1;
+        }
+
+        assert_eq!(counts, [1000, 0, 2000]);
+    }
+
+    #[test]
+    fn test_no_backends() {
+        let mut distributor = RoundRobinDistributor::new();
+        distributor.update(vec![descriptor(0, false, 1)]);
+        assert_eq!(distributor.choose(0), None);
+    }
+}
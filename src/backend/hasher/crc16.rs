@@ -0,0 +1,58 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::KeyHasher;
+
+// Redis Cluster spreads keys over this many slots.
+const CLUSTER_SLOTS: u64 = 16384;
+
+// How far to shift a slot so that the slots cover the whole 32-bit ketama ring.
+const SLOT_SPREAD_SHIFT: u64 = 18;
+
+/// The CRC16 (XMODEM) hash used by Redis Cluster, reduced to a slot number.
+///
+/// Keys land in the same slots they would in a Redis Cluster, although which backend a slot maps
+/// to is still up to the distributor.  Slots only span 14 bits, which would put nearly every key
+/// in front of the first point on a 32-bit ketama ring, so the slot is repeated in the upper bits
+/// to spread slots evenly across the ring.  The low bits are still the slot itself.
+pub struct Crc16Hasher;
+
+impl Crc16Hasher {
+    pub fn new() -> Crc16Hasher { Crc16Hasher {} }
+
+    /// Gets the Redis Cluster slot for the given key.
+    pub fn slot(&self, buf: &[u8]) -> u64 {
+        let mut crc = 0u16;
+        for b in buf {
+            crc ^= u16::from(*b) << 8;
+            for _ in 0..8 {
+                crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            }
+        }
+
+        u64::from(crc) % CLUSTER_SLOTS
+    }
+}
+
+impl KeyHasher for Crc16Hasher {
+    fn hash(&self, buf: &[u8]) -> u64 {
+        let slot = self.slot(buf);
+        (slot << SLOT_SPREAD_SHIFT) | slot
+    }
+}
@@ -17,9 +17,11 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+mod crc16;
 mod fnv64a;
 mod md5;
-pub use self::{fnv64a::Fnv64aHasher, md5::MD5Hasher};
+mod murmur3;
+pub use self::{crc16::Crc16Hasher, fnv64a::Fnv64aHasher, md5::MD5Hasher, murmur3::Murmur3Hasher};
 use crate::errors::CreationError;
 
 /// Basic hashing capabilities.
@@ -33,7 +35,84 @@ pub trait KeyHasher {
 pub fn configure_hasher(hash_type: &str) -> Result<Box<KeyHasher + Send + Sync>, CreationError> {
     match hash_type {
         "md5" => Ok(Box::new(MD5Hasher::new())),
-        "fnv1a_64" => Ok(Box::new(Fnv64aHasher::new())),
+        "fnv1a" | "fnv1a_64" => Ok(Box::new(Fnv64aHasher::new())),
+        "murmur3" => Ok(Box::new(Murmur3Hasher::new())),
+        "crc16" => Ok(Box::new(Crc16Hasher::new())),
         s => Err(CreationError::InvalidResource(format!("unknown hash type {}", s))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::distributor::{BackendDescriptor, Distributor, KetamaDistributor};
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(Murmur3Hasher::new().hash(b""), 0);
+        assert_eq!(Murmur3Hasher::new().hash(b"hello"), 0x248b_fa47);
+
+        // This is the check value for CRC16/XMODEM, and the slot Redis Cluster gives `foo`.
+        assert_eq!(Crc16Hasher::new().slot(b"123456789"), 0x31c3);
+        assert_eq!(Crc16Hasher::new().slot(b"foo"), 12182);
+        assert_eq!(Crc16Hasher::new().hash(b"foo"), (12182 << 18) | 12182);
+    }
+
+    #[test]
+    fn test_distribution() {
+        for hash_type in &["fnv1a", "murmur3", "crc16", "md5"] {
+            let hasher = configure_hasher(hash_type).expect("failed to configure hasher");
+
+            let mut counts = [0usize; 16];
+            for i in 0..10000 {
+                let key = format!("key-{}", i);
+                counts[(hasher.hash(key.as_bytes()) % 16) as usize] += 1;
+            }
+
+            let max = *counts.iter().max().unwrap() as f64;
+            let min = *counts.iter().min().unwrap() as f64;
+            assert!(
+                max / min < 1.5,
+                "{} distributed unevenly: {:?}",
+                hash_type,
+                counts
+            );
+        }
+    }
+
+    #[test]
+    fn test_ketama_distribution() {
+        let mut distributor = KetamaDistributor::new(160);
+        distributor.update(
+            (0..8)
+                .map(|idx| {
+                    BackendDescriptor {
+                        idx,
+                        identifier: format!("10.0.0.{}:6379", idx),
+                        healthy: true,
+                        weight: 1,
+                    }
+                })
+                .collect(),
+        );
+
+        for hash_type in &["fnv1a", "murmur3", "crc16", "md5"] {
+            let hasher = configure_hasher(hash_type).expect("failed to configure hasher");
+
+            let mut counts = [0usize; 8];
+            for i in 0..10000 {
+                let key = format!("key-{}", i);
+                let idx = distributor.choose(hasher.hash(key.as_bytes())).expect("no backend chosen");
+                counts[idx] += 1;
+            }
+
+            // Every backend on the ring should get somewhere between half and double its fair share.
+            assert!(
+                counts.iter().all(|count| *count > 625 && *count < 2500),
+                "{} distributed unevenly: {:?}",
+                hash_type,
+                counts
+            );
+        }
+    }
+}
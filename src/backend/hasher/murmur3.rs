@@ -0,0 +1,67 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::KeyHasher;
+
+const C1: u32 = 0xcc9e_2d51;
+const C2: u32 = 0x1b87_3593;
+
+/// The 32-bit x86 variant of MurmurHash3, with a seed of 0.
+pub struct Murmur3Hasher;
+
+impl Murmur3Hasher {
+    pub fn new() -> Murmur3Hasher { Murmur3Hasher {} }
+}
+
+fn mix_block(k: u32) -> u32 { k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2) }
+
+impl KeyHasher for Murmur3Hasher {
+    fn hash(&self, buf: &[u8]) -> u64 {
+        let mut h = 0u32;
+
+        let mut blocks = buf.chunks_exact(4);
+        for block in &mut blocks {
+            let k = u32::from(block[0])
+                | (u32::from(block[1]) << 8)
+                | (u32::from(block[2]) << 16)
+                | (u32::from(block[3]) << 24);
+            h ^= mix_block(k);
+            h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+        }
+
+        let tail = blocks.remainder();
+        if !tail.is_empty() {
+            let k = tail
+                .iter()
+                .enumerate()
+                .fold(0u32, |k, (i, b)| k | (u32::from(*b) << (8 * i)));
+            h ^= mix_block(k);
+        }
+
+        // Finalize, forcing all of the bits to avalanche.
+        h ^= buf.len() as u32;
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x85eb_ca6b);
+        h ^= h >> 13;
+        h = h.wrapping_mul(0xc2b2_ae35);
+        h ^= h >> 16;
+
+        u64::from(h)
+    }
+}
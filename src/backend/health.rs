@@ -19,17 +19,27 @@
 // SOFTWARE.
 use crate::util::FutureExt;
 use futures::{future::ok, task, Future};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::timer::Delay;
 
 pub struct BackendHealth {
     cooloff_enabled: bool,
     cooloff_period_ms: u64,
+    cooloff_max_ms: u64,
+    consecutive_trips: u32,
     error_limit: usize,
     error_count: usize,
     in_cooloff: bool,
+    half_open: bool,
+    probing: bool,
     epoch: u64,
     cooloff_done_at: Instant,
+    grace_done_at: Instant,
+    grace_retry_ms: u64,
+    healthy_since: SystemTime,
+    last_error_at: Option<SystemTime>,
+    last_cooloff_start_at: Option<SystemTime>,
+    last_cooloff_end_at: Option<SystemTime>,
 }
 
 impl BackendHealth {
@@ -39,26 +49,70 @@ impl BackendHealth {
             cooloff_enabled, cooloff_period_ms, error_limit
         );
 
+        let now = Instant::now();
         BackendHealth {
             cooloff_enabled,
             cooloff_period_ms,
+            cooloff_max_ms: cooloff_period_ms,
+            consecutive_trips: 0,
             error_limit,
             error_count: 0,
             in_cooloff: false,
+            half_open: false,
+            probing: false,
             epoch: 0,
-            cooloff_done_at: Instant::now(),
+            cooloff_done_at: now,
+            grace_done_at: now,
+            grace_retry_ms: cooloff_period_ms,
+            healthy_since: SystemTime::now(),
+            last_error_at: None,
+            last_cooloff_start_at: None,
+            last_cooloff_end_at: None,
         }
     }
 
+    /// Sets a grace period, starting now, during which the backend is given time to come up.
+    ///
+    /// While in the grace period, errors don't count toward the error limit.  Instead, each one
+    /// puts the backend into cooloff for just `retry_ms`, so that backends which are still starting
+    /// up are retried quickly instead of being knocked out for a long time.  None of this counts
+    /// toward backing off consecutive cooloffs, either, so the first cooloff once the grace period
+    /// is over is the base cooloff period.
+    pub fn set_grace_period(&mut self, grace_period_ms: u64, retry_ms: u64) {
+        debug!(
+            "[backend health] startup grace period (ms): {}, grace retry period (ms): {}",
+            grace_period_ms, retry_ms
+        );
+
+        self.grace_done_at = Instant::now() + Duration::from_millis(grace_period_ms);
+        self.grace_retry_ms = retry_ms;
+    }
+
+    /// Sets the longest cooloff period.
+    ///
+    /// Each consecutive time the backend goes into cooloff, without serving a request successfully
+    /// in between, the cooloff period doubles, up to this limit.  This keeps us from hammering a
+    /// backend that's flapping.
+    pub fn set_cooloff_max(&mut self, cooloff_max_ms: u64) {
+        debug!("[backend health] max cooloff period (ms): {}", cooloff_max_ms);
+        self.cooloff_max_ms = cooloff_max_ms.max(self.cooloff_period_ms);
+    }
+
+    fn in_grace_period(&self) -> bool { Instant::now() < self.grace_done_at }
+
     pub fn is_healthy(&mut self) -> bool {
         if !self.cooloff_enabled || !self.in_cooloff {
             return true;
         }
 
         if self.cooloff_done_at < Instant::now() {
+            // Rather than going straight back to being fully healthy, we're half-open until a
+            // probe request tells us whether or not the backend has actually recovered.
             self.error_count = 0;
             self.in_cooloff = false;
+            self.half_open = true;
             self.epoch += 1;
+            self.last_cooloff_end_at = Some(SystemTime::now());
 
             return true;
         }
@@ -66,29 +120,132 @@ impl BackendHealth {
         false
     }
 
+    /// Whether or not the backend just came out of cooloff, and hasn't yet been probed to see if
+    /// it has actually recovered.
+    ///
+    /// Only the probe request should be sent to a half-open backend.
+    pub fn is_half_open(&self) -> bool { self.half_open }
+
+    /// Whether or not a probe request is currently in flight.
+    pub fn is_probing(&self) -> bool { self.probing }
+
+    /// Tries to acquire the right to send a probe request to a half-open backend.
+    ///
+    /// Only one probe can be in flight at a time, so this returns `false` if the backend isn't
+    /// half-open, or if a probe has already been sent.
+    pub fn try_acquire_probe(&mut self) -> bool {
+        if !self.half_open || self.probing {
+            return false;
+        }
+
+        self.probing = true;
+        true
+    }
+
+    /// Records the result of the probe request to a half-open backend.
+    ///
+    /// If the probe succeeded, the backend is healthy again.  Otherwise, it goes right back into
+    /// cooloff.
+    pub fn record_probe_result(&mut self, success: bool) {
+        if !self.probing {
+            return;
+        }
+
+        self.probing = false;
+        self.half_open = false;
+
+        if success {
+            debug!("[health] probe succeeded, backend recovered");
+            self.healthy_since = SystemTime::now();
+            self.record_success();
+        } else {
+            debug!("[health] probe failed, setting cooloff");
+            self.last_error_at = Some(SystemTime::now());
+            self.start_cooloff();
+        }
+    }
+
     pub fn epoch(&self) -> u64 { self.epoch }
 
+    /// When the backend last became healthy: either when it was created, or when it last passed a
+    /// probe after coming out of cooloff.
+    pub fn healthy_since(&self) -> SystemTime { self.healthy_since }
+
+    /// When the backend last had an error, if ever.
+    pub fn last_error_at(&self) -> Option<SystemTime> { self.last_error_at }
+
+    /// When the backend last went into cooloff, if ever.
+    pub fn last_cooloff_start_at(&self) -> Option<SystemTime> { self.last_cooloff_start_at }
+
+    /// When the backend last came out of cooloff, if ever.
+    pub fn last_cooloff_end_at(&self) -> Option<SystemTime> { self.last_cooloff_end_at }
+
+    /// Marks that the backend successfully served a request.
+    ///
+    /// Once the backend is serving requests again after a cooloff, the next cooloff starts back at
+    /// the base cooloff period.
+    pub fn record_success(&mut self) {
+        if !self.in_cooloff {
+            self.consecutive_trips = 0;
+        }
+    }
+
     pub fn increment_error(&mut self) {
+        self.last_error_at = Some(SystemTime::now());
         if !self.cooloff_enabled {
             return;
         }
 
+        // While the backend is still starting up, we just try it again shortly, without holding the
+        // error against it.  If we're half-open, the probe gets to decide what happens next.
+        if self.in_grace_period() {
+            if !self.in_cooloff && !self.half_open {
+                debug!("[health] error during startup grace period, setting short cooloff");
+                self.start_cooloff();
+            }
+            return;
+        }
+
         self.error_count += 1;
 
         // If we're over the error threshold, put ourselves into cooloff.
         if self.error_count >= self.error_limit && !self.in_cooloff {
             debug!("[health] error count over limit, setting cooloff");
-            self.in_cooloff = true;
-            self.epoch += 1;
-            self.fire_cooloff_check();
+            self.start_cooloff();
         }
     }
 
+    /// Puts the backend into cooloff immediately, regardless of the error limit.
+    ///
+    /// This is for failures that tell us outright that the backend is unusable, like a failed
+    /// health check, rather than errors that might just be a blip.
+    pub fn trip(&mut self) {
+        self.last_error_at = Some(SystemTime::now());
+        if !self.cooloff_enabled || self.in_cooloff {
+            return;
+        }
+
+        debug!("[health] backend tripped, setting cooloff");
+        self.start_cooloff();
+    }
+
+    fn start_cooloff(&mut self) {
+        if !self.in_grace_period() {
+            self.consecutive_trips = self.consecutive_trips.saturating_add(1);
+        }
+        self.in_cooloff = true;
+        self.half_open = false;
+        self.probing = false;
+        self.epoch += 1;
+        self.last_cooloff_start_at = Some(SystemTime::now());
+        self.fire_cooloff_check();
+    }
+
     fn fire_cooloff_check(&mut self) {
         // Mark when our cooloff period should be lifted, and trigger a task notification to fire
         // once that deadline has passed: our health will be checked, and thus we can reenable
         // ourselves.
-        let deadline = Instant::now() + Duration::from_millis(self.cooloff_period_ms);
+        let deadline = Instant::now() + Duration::from_millis(self.get_cooloff_period_ms());
         self.cooloff_done_at = deadline;
 
         let current_task = task::current();
@@ -102,4 +259,240 @@ impl BackendHealth {
 
         tokio::spawn(task);
     }
+
+    fn get_cooloff_period_ms(&self) -> u64 {
+        if self.in_grace_period() {
+            return self.grace_retry_ms;
+        }
+
+        let factor = 1u64
+            .checked_shl(self.consecutive_trips.saturating_sub(1))
+            .unwrap_or_else(u64::max_value);
+        self.cooloff_period_ms.saturating_mul(factor).min(self.cooloff_max_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_timestamps() {
+        let mut health = BackendHealth::new(false, 1000, 1);
+        let created = health.healthy_since();
+        assert_eq!(health.last_error_at(), None);
+
+        // Errors are tracked even when cooloff is disabled, but we never go into cooloff.
+        health.increment_error();
+        let last_error = health.last_error_at().expect("error should have been tracked");
+        assert!(last_error >= created);
+        assert!(health.is_healthy());
+        assert_eq!(health.last_cooloff_start_at(), None);
+        assert_eq!(health.last_cooloff_end_at(), None);
+        assert_eq!(health.healthy_since(), created);
+    }
+
+    #[test]
+    fn test_cooloff_timestamps() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(futures::future::lazy(move || {
+            let mut health = BackendHealth::new(true, 5, 1);
+            let created = health.healthy_since();
+
+            // Going into cooloff marks when it started, but it hasn't ended yet.
+            health.trip();
+            let started = health.last_cooloff_start_at();
+            let ended_early = health.last_cooloff_end_at();
+
+            Delay::new(Instant::now() + Duration::from_millis(20)).then(move |_| {
+                // Coming out of it marks when it ended, but we're only healthy again once the probe
+                // says so.
+                let half_open = health.is_healthy() && health.is_half_open();
+                let ended = health.last_cooloff_end_at();
+                let unchanged = health.healthy_since() == created;
+
+                health.try_acquire_probe();
+                health.record_probe_result(true);
+                let recovered = health.healthy_since();
+
+                let _ = tx.send((created, started, ended_early, half_open, ended, unchanged, recovered));
+                Ok(())
+            })
+        }));
+
+        let (created, started, ended_early, half_open, ended, unchanged, recovered) =
+            rx.recv().expect("cooloff timestamp test never finished");
+        let started = started.expect("cooloff start should have been tracked");
+        let ended = ended.expect("cooloff end should have been tracked");
+        assert!(started >= created);
+        assert_eq!(ended_early, None);
+        assert!(half_open);
+        assert!(ended >= started + Duration::from_millis(5));
+        assert!(unchanged);
+        assert!(recovered >= ended);
+    }
+
+    #[test]
+    fn test_trip_without_cooloff() {
+        let mut health = BackendHealth::new(false, 1000, 5);
+        let epoch = health.epoch();
+
+        // Tripping always counts as an error, but can't put us into cooloff if it's disabled.
+        health.trip();
+        assert!(health.last_error_at().is_some());
+        assert!(health.is_healthy());
+        assert_eq!(health.epoch(), epoch);
+        assert_eq!(health.last_cooloff_start_at(), None);
+    }
+
+    #[test]
+    fn test_cooloff_backoff() {
+        let mut health = BackendHealth::new(true, 10000, 1);
+        health.set_cooloff_max(60000);
+
+        // Each consecutive trip doubles the cooloff period, up to the limit.
+        let mut periods = Vec::new();
+        for _ in 0..5 {
+            health.consecutive_trips += 1;
+            periods.push(health.get_cooloff_period_ms());
+        }
+        assert_eq!(periods, vec![10000, 20000, 40000, 60000, 60000]);
+
+        // Succeeding while still in cooloff doesn't count, but once we're out of it, we start over.
+        health.in_cooloff = true;
+        health.record_success();
+        assert_eq!(health.get_cooloff_period_ms(), 60000);
+
+        health.in_cooloff = false;
+        health.record_success();
+        health.consecutive_trips += 1;
+        assert_eq!(health.get_cooloff_period_ms(), 10000);
+
+        // Flapping for long enough can't overflow the period.
+        health.consecutive_trips = u32::max_value();
+        assert_eq!(health.get_cooloff_period_ms(), 60000);
+
+        // The startup grace period always uses its own, fixed, retry period.
+        health.set_grace_period(60000, 500);
+        assert_eq!(health.get_cooloff_period_ms(), 500);
+    }
+
+    #[test]
+    fn test_probe_gating() {
+        let mut health = BackendHealth::new(true, 10000, 1);
+        health.half_open = true;
+        assert!(health.is_healthy());
+
+        // Only a single probe can be in flight at a time.
+        assert!(health.try_acquire_probe());
+        assert!(health.is_probing());
+        assert!(!health.try_acquire_probe());
+
+        // A successful probe brings the backend fully back.
+        health.consecutive_trips = 3;
+        health.record_probe_result(true);
+        assert!(!health.is_half_open());
+        assert!(!health.is_probing());
+        assert!(health.is_healthy());
+        assert_eq!(health.consecutive_trips, 0);
+
+        // Once healthy, there's nothing to probe.
+        assert!(!health.try_acquire_probe());
+    }
+
+    #[test]
+    fn test_failed_probe_stays_in_cooloff() {
+        // Going into cooloff needs a task to schedule the end of it, so we run on a real runtime and
+        // ship the results back out, rather than asserting where a panic could be swallowed.
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(futures::future::lazy(move || {
+            let mut health = BackendHealth::new(true, 5, 1);
+            health.increment_error();
+            let tripped = !health.is_healthy();
+
+            Delay::new(Instant::now() + Duration::from_millis(20)).then(move |_| {
+                let half_open = health.is_healthy() && health.is_half_open();
+                let acquired = health.try_acquire_probe();
+                let blocked = !health.try_acquire_probe();
+
+                health.record_probe_result(false);
+                let closed = !health.is_healthy() && !health.is_half_open();
+                let _ = tx.send((tripped, half_open, acquired, blocked, closed));
+                Ok(())
+            })
+        }));
+
+        let (tripped, half_open, acquired, blocked, closed) = rx.recv().expect("probe test never finished");
+        assert!(tripped);
+        assert!(half_open);
+        assert!(acquired);
+        assert!(blocked);
+        assert!(closed);
+    }
+
+    #[test]
+    fn test_startup_grace_period() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(futures::future::lazy(move || {
+            let mut health = BackendHealth::new(true, 60000, 3);
+            health.set_grace_period(60000, 5);
+
+            // Errors while the backend is starting up aren't counted against it, but each one means
+            // we wait a little before trying it again, rather than the full cooloff period.
+            health.increment_error();
+            let tripped = !health.is_healthy();
+            health.increment_error();
+            let uncounted = health.error_count == 0 && health.consecutive_trips == 0;
+            let retry_in = health.cooloff_done_at.duration_since(Instant::now());
+
+            Delay::new(Instant::now() + Duration::from_millis(20)).then(move |_| {
+                let half_open = health.is_healthy() && health.is_half_open();
+
+                // A failed probe sends us right back into a short cooloff, too.
+                let acquired = health.try_acquire_probe();
+                health.record_probe_result(false);
+                let retrying = !health.is_healthy() && health.consecutive_trips == 0;
+                let next_retry_in = health.cooloff_done_at.duration_since(Instant::now());
+
+                let _ = tx.send((tripped, uncounted, retry_in, half_open, acquired, retrying, next_retry_in));
+                Ok(())
+            })
+        }));
+
+        let (tripped, uncounted, retry_in, half_open, acquired, retrying, next_retry_in) =
+            rx.recv().expect("grace period test never finished");
+        assert!(tripped);
+        assert!(uncounted);
+        assert!(retry_in <= Duration::from_millis(5));
+        assert!(half_open);
+        assert!(acquired);
+        assert!(retrying);
+        assert!(next_retry_in <= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_errors_count_after_grace_period() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(futures::future::lazy(move || {
+            let mut health = BackendHealth::new(true, 200, 3);
+            health.set_grace_period(0, 5);
+
+            // Once the grace period is over, errors count toward the limit as usual, and hitting it
+            // means sitting out the full cooloff period.
+            health.increment_error();
+            health.increment_error();
+            let counted = health.is_healthy() && health.error_count == 2;
+            health.increment_error();
+            let tripped = !health.is_healthy() && health.consecutive_trips == 1;
+            let retry_in = health.cooloff_done_at.duration_since(Instant::now());
+
+            let _ = tx.send((counted, tripped, retry_in));
+            Ok::<_, ()>(())
+        }));
+
+        let (counted, tripped, retry_in) = rx.recv().expect("grace period test never finished");
+        assert!(counted);
+        assert!(tripped);
+        assert!(retry_in > Duration::from_millis(100));
+    }
 }
@@ -0,0 +1,107 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const HEDGE_SAMPLE_LIMIT: usize = 1024;
+const HEDGE_RECALCULATE_INTERVAL: usize = 64;
+
+struct HedgeSamples {
+    latencies: VecDeque<Duration>,
+    since_recalculated: usize,
+    delay: Duration,
+}
+
+/// Tracks how long reads to a pool take, and how long to wait on one before hedging it.
+///
+/// The delay is the configured percentile of the most recent read latencies, and is never lower
+/// than the configured minimum.  Recalculating the delay means sorting every sample, so we only do
+/// it periodically rather than for every read.
+#[derive(Clone)]
+pub struct HedgeDelay {
+    percentile: f64,
+    min_delay: Duration,
+    samples: Arc<Mutex<HedgeSamples>>,
+}
+
+impl HedgeDelay {
+    pub fn new(percentile: f64, min_delay: Duration) -> HedgeDelay {
+        HedgeDelay {
+            percentile,
+            min_delay,
+            samples: Arc::new(Mutex::new(HedgeSamples {
+                latencies: VecDeque::with_capacity(HEDGE_SAMPLE_LIMIT),
+                since_recalculated: 0,
+                delay: min_delay,
+            })),
+        }
+    }
+
+    /// Gets how long to wait on a read before hedging it.
+    pub fn get(&self) -> Duration { self.samples.lock().unwrap().delay }
+
+    /// Records how long a read took.
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.latencies.len() == HEDGE_SAMPLE_LIMIT {
+            samples.latencies.pop_front();
+        }
+        samples.latencies.push_back(latency);
+
+        samples.since_recalculated += 1;
+        if samples.since_recalculated < HEDGE_RECALCULATE_INTERVAL {
+            return;
+        }
+        samples.since_recalculated = 0;
+
+        let mut sorted = samples.latencies.iter().cloned().collect::<Vec<_>>();
+        sorted.sort();
+        let idx = ((sorted.len() - 1) as f64 * self.percentile / 100.0).round() as usize;
+        samples.delay = sorted[idx].max(self.min_delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hedge_delay() {
+        let delay = HedgeDelay::new(90.0, Duration::from_millis(2));
+
+        // Until we have enough samples, we use the minimum delay.
+        assert_eq!(delay.get(), Duration::from_millis(2));
+
+        for i in 1..=HEDGE_RECALCULATE_INTERVAL as u64 {
+            delay.record(Duration::from_millis(i));
+        }
+        assert_eq!(delay.get(), Duration::from_millis(58));
+
+        // The delay never drops below the minimum, no matter how fast reads are.
+        let delay = HedgeDelay::new(90.0, Duration::from_millis(2));
+        for _ in 0..HEDGE_RECALCULATE_INTERVAL {
+            delay.record(Duration::from_micros(100));
+        }
+        assert_eq!(delay.get(), Duration::from_millis(2));
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::{
+    backend::{
+        message_queue::MessageState,
+        processor::{ClientState, IoTimeouts, Processor, ProcessorError, TcpStreamFuture},
+    },
+    common::{EnqueuedRequests, Message},
+    protocol::memcached::{self, is_retrieval_command, MemcachedMessage, MemcachedTransport},
+    util::{Connector, MaybeTlsStream, ProcessFuture},
+};
+use bytes::BytesMut;
+use futures::{future::ok, prelude::*};
+use std::{error::Error, net::SocketAddr};
+
+const MEMCACHED_END: &[u8] = b"END\r\n";
+
+#[derive(Clone)]
+pub struct MemcachedProcessor;
+
+impl MemcachedProcessor {
+    pub fn new() -> MemcachedProcessor { MemcachedProcessor }
+}
+
+impl Processor for MemcachedProcessor {
+    type Message = MemcachedMessage;
+    type Transport = MemcachedTransport<MaybeTlsStream>;
+
+    fn fragment_messages(
+        &self, msgs: Vec<Self::Message>,
+    ) -> Result<Vec<(MessageState, Self::Message)>, ProcessorError> {
+        memcached_fragment_messages(msgs)
+    }
+
+    fn defragment_messages(&self, msgs: Vec<(MessageState, Self::Message)>) -> Result<Self::Message, ProcessorError> {
+        memcached_defragment_messages(msgs)
+    }
+
+    fn transform_message(&self, _cmd: &[u8], msg: Self::Message) -> Result<Self::Message, ProcessorError> { Ok(msg) }
+
+    // The text protocol has no authentication, so there's nothing to intercept.
+    fn authenticate(&self, _msg: &Self::Message, _state: &mut ClientState) -> Option<Self::Message> { None }
+
+    // There's only ever the one keyspace, and no way to iterate over it.
+    fn get_selected_database(&self, _msg: &Self::Message) -> Option<usize> { None }
+
+    fn split_scan(&self, _msg: &Self::Message, _count: usize) -> Option<Result<(usize, Self::Message), Self::Message>> {
+        None
+    }
+
+    fn merge_scan(&self, msg: Self::Message, _idx: usize, _count: usize) -> Self::Message { msg }
+
+    fn get_ok_message(&self) -> Self::Message { MemcachedMessage::from_response(b"OK\r\n") }
+
+    fn get_error_message(&self, e: Box<Error>) -> Self::Message { MemcachedMessage::from_error_str(e.description()) }
+
+    fn get_error_message_str(&self, e: &str) -> Self::Message { MemcachedMessage::from_error_str(e) }
+
+    fn get_ping_message(&self) -> Self::Message { MemcachedMessage::from_inline("version") }
+
+    fn is_ping_response(&self, msg: &Self::Message) -> bool { msg.is_version() }
+
+    fn get_transport(&self, client: MaybeTlsStream) -> Self::Transport { MemcachedTransport::new(client) }
+
+    fn preconnect(&self, addr: &SocketAddr, connector: &Connector, _noreply: bool) -> ProcessFuture {
+        // There's no way to turn off replies for a whole memcached connection, so noreply backends
+        // get a plain connection, same as everyone else.
+        connector.connect(addr)
+    }
+
+    fn process(
+        &self, req: EnqueuedRequests<Self::Message>, stream: TcpStreamFuture, io_timeouts: IoTimeouts,
+    ) -> ProcessFuture {
+        // Requests are consumed when they're written, so we have to figure out how to read their
+        // responses ahead of time.
+        let retrievals = req.iter().map(|msg| msg.request().is_read()).collect::<Vec<_>>();
+        let inner = stream
+            .and_then(move |server| memcached::write_messages(server, req, io_timeouts.write_timeout()))
+            .and_then(move |(server, msgs, _n)| {
+                memcached::read_messages(server, msgs, retrievals, io_timeouts.read_timeout())
+            })
+            .and_then(move |(server, _n)| ok(server));
+        ProcessFuture::new(inner)
+    }
+}
+
+fn memcached_fragment_messages(
+    msgs: Vec<MemcachedMessage>,
+) -> Result<Vec<(MessageState, MemcachedMessage)>, ProcessorError> {
+    let mut fragments = Vec::new();
+
+    for msg in msgs {
+        // Anything the transport already answered for us, like an unsupported command, goes right
+        // back to the client.
+        if msg.is_inline() {
+            fragments.push((MessageState::Inline, msg));
+            continue;
+        }
+
+        // Multi-key retrievals are split into a retrieval per key, so that each key can be sent to
+        // whichever backend owns it.
+        let cmd = match msg.get_command() {
+            Some(cmd) if is_retrieval_command(cmd) && msg.arg_count() > 2 => cmd.to_ascii_lowercase(),
+            _ => {
+                fragments.push((MessageState::Standalone, msg));
+                continue;
+            },
+        };
+
+        let cmd_type = BytesMut::from(&cmd[..]);
+        let total_fragments = msg.arg_count() - 1;
+        for i in 0..total_fragments {
+            let key = msg.get_arg(i + 1).expect("retrieval should have key for fragment");
+            let fragment = MemcachedMessage::from_args(&[&cmd[..], key]);
+            fragments.push((MessageState::Fragmented(cmd_type.clone(), i, total_fragments), fragment));
+        }
+    }
+
+    Ok(fragments)
+}
+
+fn memcached_defragment_messages(
+    fragments: Vec<(MessageState, MemcachedMessage)>,
+) -> Result<MemcachedMessage, ProcessorError> {
+    let cmd_type = match fragments.first() {
+        Some((MessageState::Fragmented(buf, _, _), _)) => buf.clone(),
+        _ => {
+            return Err(ProcessorError::DefragmentError(
+                "tried to defragment messages, but got non-fragmented message in list".to_owned(),
+            ));
+        },
+    };
+
+    if !is_retrieval_command(&cmd_type) {
+        return Err(ProcessorError::DefragmentError(format!(
+            "unknown command type '{:?}'",
+            cmd_type
+        )));
+    }
+
+    // Each fragment is a complete retrieval response of its own, so we stitch together all of the
+    // values and give the whole thing a single END.  If any key failed, though, the client can't
+    // tell which one from a memcached response, so the whole thing fails.
+    let mut buf = BytesMut::new();
+    for (_state, fragment) in fragments {
+        let fbuf = fragment.into_bytes();
+        if MemcachedMessage::is_error(&fbuf) {
+            return Ok(MemcachedMessage::Response(fbuf));
+        }
+
+        if !fbuf.ends_with(MEMCACHED_END) {
+            return Err(ProcessorError::DefragmentError(
+                "non-retrieval response for GET/GETS!".to_owned(),
+            ));
+        }
+
+        buf.extend_from_slice(&fbuf[..fbuf.len() - MEMCACHED_END.len()]);
+    }
+    buf.extend_from_slice(MEMCACHED_END);
+
+    Ok(MemcachedMessage::Response(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::message_queue::MessageQueue,
+        common::{EnqueuedRequest, MessageResponse},
+    };
+    use futures::future::Either;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    fn drain_queue(queue: &mut MessageQueue<MemcachedProcessor>) -> (BytesMut, u64) {
+        let mut buf = BytesMut::new();
+        let mut count = 0;
+        while let Some((sbuf, scount)) = queue.get_sendable_buf() {
+            buf.unsplit(sbuf);
+            count += scount;
+        }
+
+        (buf, count)
+    }
+
+    #[test]
+    fn test_multi_get_fragments() {
+        let mut queue = MessageQueue::new(MemcachedProcessor::new());
+        let get = MemcachedMessage::from_inline("get key_one key_two key_three");
+        let assigned = queue.enqueue(vec![get]).expect("failed to enqueue get");
+        assert_eq!(assigned.len(), 3);
+        assert_eq!(assigned[1].1, MemcachedMessage::from_inline("get key_two"));
+
+        // Only the first and last keys were found.
+        let responses = assigned
+            .into_iter()
+            .enumerate()
+            .map(|(i, (slot, _))| {
+                let resp = match i {
+                    1 => MemcachedMessage::from_response(b"END\r\n"),
+                    _ => MemcachedMessage::from_response(format!("VALUE k{} 0 1\r\n{}\r\nEND\r\n", i, i).as_bytes()),
+                };
+                (slot, MessageResponse::Complete(resp))
+            })
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(&buf[..], &b"VALUE k0 0 1\r\n0\r\nVALUE k2 0 1\r\n2\r\nEND\r\n"[..]);
+    }
+
+    #[test]
+    fn test_multi_get_error_fails_command() {
+        let mut queue = MessageQueue::new(MemcachedProcessor::new());
+        let get = MemcachedMessage::from_inline("gets key_one key_two");
+        let assigned = queue.enqueue(vec![get]).expect("failed to enqueue gets");
+
+        let responses = assigned
+            .into_iter()
+            .enumerate()
+            .map(|(i, (slot, _))| {
+                let resp = match i {
+                    0 => MemcachedMessage::from_response(b"VALUE key_one 0 1 7\r\na\r\nEND\r\n"),
+                    _ => MemcachedMessage::from_error_str("out of memory"),
+                };
+                (slot, MessageResponse::Complete(resp))
+            })
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(&buf[..], &b"SERVER_ERROR out of memory\r\n"[..]);
+    }
+
+    #[test]
+    fn test_single_commands_pass_through() {
+        let msgs = vec![
+            MemcachedMessage::from_inline("get key_one"),
+            MemcachedMessage::from_inline("delete key_one"),
+            MemcachedMessage::from_unknown_command(),
+        ];
+        let fragments = memcached_fragment_messages(msgs.clone()).expect("failed to fragment");
+        assert_eq!(fragments[0].0, MessageState::Standalone);
+        assert_eq!(fragments[1].0, MessageState::Standalone);
+        assert_eq!(fragments[2].0, MessageState::Inline);
+        assert_eq!(fragments.into_iter().map(|(_, msg)| msg).collect::<Vec<_>>(), msgs);
+    }
+
+    #[test]
+    fn test_backend_round_trip() {
+        let msgs = memcached::parse_requests(b"get key_one\r\nset key_one 0 0 3\r\nfoo\r\nversion\r\n")
+            .expect("failed to parse requests");
+        let request_len = msgs.iter().map(|msg| msg.clone().into_buf().len()).sum::<usize>();
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind fake backend");
+        let address = listener.local_addr().expect("failed to get fake backend address");
+        let backend = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().expect("failed to accept connection");
+            let mut buf = vec![0; request_len];
+            conn.read_exact(&mut buf).expect("failed to read batch");
+            conn.write_all(b"VALUE key_one 0 3\r\nEND\r\nEND\r\nSTORED\r\nVERSION 1.6.9\r\n")
+                .expect("failed to write replies");
+        });
+
+        let mut rxs = Vec::new();
+        let requests = msgs
+            .into_iter()
+            .enumerate()
+            .map(|(i, msg)| {
+                let mut request = EnqueuedRequest::new(i, msg);
+                rxs.push(request.get_response_rx().expect("request should have a response"));
+                request
+            })
+            .collect::<Vec<_>>();
+
+        let processor = MemcachedProcessor::new();
+        let stream = Either::B(processor.preconnect(&address, &Connector::new(), false));
+        let result = processor.process(requests, stream, IoTimeouts::default()).wait();
+        backend.join().expect("fake backend panicked");
+        assert!(result.is_ok());
+
+        let responses = rxs
+            .into_iter()
+            .map(|rx| {
+                match rx.wait().expect("request was never answered") {
+                    (_, MessageResponse::Complete(msg)) => msg,
+                    (_, MessageResponse::Failed) => panic!("request should have a response"),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // A value whose data happens to be `END` must not be mistaken for the end of the response.
+        assert_eq!(
+            responses[0],
+            MemcachedMessage::from_response(b"VALUE key_one 0 3\r\nEND\r\nEND\r\n")
+        );
+        assert_eq!(responses[1], MemcachedMessage::from_response(b"STORED\r\n"));
+        assert!(processor.is_ping_response(&responses[2]));
+    }
+}
@@ -0,0 +1,611 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::{
+    backend::{
+        message_queue::MessageState,
+        processor::{ClientState, IoTimeouts, Processor, ProcessorError, TcpStreamFuture},
+        redis::{redis_get_data_buffer, redis_new_data_buffer, RedisProcessor},
+    },
+    common::EnqueuedRequests,
+    protocol::{
+        errors::ProtocolError,
+        redis::{RedisMessage, RedisTransport},
+    },
+    util::{Connector, MaybeTlsStream, ProcessFuture},
+};
+use futures::{future::result, prelude::*};
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+};
+
+type Store = HashMap<Vec<u8>, Vec<u8>>;
+
+/// An in-memory stand-in for a Redis backend, for tests.
+///
+/// `MemoryProcessor` fragments and defragments exactly like `RedisProcessor`, but instead of
+/// writing requests to the backend, it answers them from a per-backend map of keys to values.
+/// Backends still need an address that accepts TCP connections, since the backend connection
+/// lifecycle is built around real sockets, so each backend gets a local listener that simply
+/// never reads or writes anything.
+#[derive(Clone)]
+pub struct MemoryProcessor {
+    inner: RedisProcessor,
+    listeners: Arc<Mutex<Vec<TcpListener>>>,
+    stores: Arc<Mutex<HashMap<SocketAddr, Store>>>,
+}
+
+impl MemoryProcessor {
+    pub fn new() -> MemoryProcessor {
+        MemoryProcessor {
+            inner: RedisProcessor::new(),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+            stores: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Creates a new, empty backend, returning the address to configure it with.
+    pub fn add_backend(&self) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind memory backend listener");
+        let address = listener.local_addr().expect("failed to get memory backend address");
+
+        self.listeners.lock().unwrap().push(listener);
+        self.stores.lock().unwrap().insert(address, HashMap::new());
+        address
+    }
+
+    /// Stops the given backend, so that any further connections to it are refused.
+    pub fn stop_backend(&self, address: &SocketAddr) {
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| listener.local_addr().ok().as_ref() != Some(address));
+    }
+
+    /// Gets the value of the given key from the given backend.
+    pub fn get(&self, address: &SocketAddr, key: &[u8]) -> Option<Vec<u8>> {
+        self.stores
+            .lock()
+            .unwrap()
+            .get(address)
+            .and_then(|store| store.get(key))
+            .cloned()
+    }
+
+    /// Gets the number of keys held by the given backend.
+    pub fn key_count(&self, address: &SocketAddr) -> usize {
+        self.stores.lock().unwrap().get(address).map(|store| store.len()).unwrap_or(0)
+    }
+}
+
+impl Processor for MemoryProcessor {
+    type Message = RedisMessage;
+    type Transport = RedisTransport<MaybeTlsStream>;
+
+    fn fragment_messages(
+        &self, msgs: Vec<Self::Message>,
+    ) -> Result<Vec<(MessageState, Self::Message)>, ProcessorError> {
+        self.inner.fragment_messages(msgs)
+    }
+
+    fn defragment_messages(&self, msgs: Vec<(MessageState, Self::Message)>) -> Result<Self::Message, ProcessorError> {
+        self.inner.defragment_messages(msgs)
+    }
+
+    fn transform_message(&self, cmd: &[u8], msg: Self::Message) -> Result<Self::Message, ProcessorError> {
+        self.inner.transform_message(cmd, msg)
+    }
+
+    fn authenticate(&self, msg: &Self::Message, state: &mut ClientState) -> Option<Self::Message> {
+        self.inner.authenticate(msg, state)
+    }
+
+    fn get_selected_database(&self, msg: &Self::Message) -> Option<usize> { self.inner.get_selected_database(msg) }
+
+    fn get_ok_message(&self) -> Self::Message { self.inner.get_ok_message() }
+
+    fn split_scan(&self, msg: &Self::Message, count: usize) -> Option<Result<(usize, Self::Message), Self::Message>> {
+        self.inner.split_scan(msg, count)
+    }
+
+    fn merge_scan(&self, msg: Self::Message, idx: usize, count: usize) -> Self::Message {
+        self.inner.merge_scan(msg, idx, count)
+    }
+
+    fn get_error_message(&self, e: Box<Error>) -> Self::Message { self.inner.get_error_message(e) }
+
+    fn get_error_message_str(&self, e: &str) -> Self::Message { self.inner.get_error_message_str(e) }
+
+    fn get_ping_message(&self) -> Self::Message { self.inner.get_ping_message() }
+
+    fn is_ping_response(&self, msg: &Self::Message) -> bool { self.inner.is_ping_response(msg) }
+
+    fn get_transport(&self, client: MaybeTlsStream) -> Self::Transport { self.inner.get_transport(client) }
+
+    fn preconnect(&self, addr: &SocketAddr, connector: &Connector, _noreply: bool) -> ProcessFuture {
+        connector.connect(addr)
+    }
+
+    fn process(&self, req: EnqueuedRequests<Self::Message>, stream: TcpStreamFuture, _: IoTimeouts) -> ProcessFuture {
+        let stores = self.stores.clone();
+        let inner = stream.and_then(move |server| {
+            result(server.peer_addr().map_err(ProtocolError::IoError)).map(move |address| {
+                let mut stores = stores.lock().unwrap();
+                let store = stores.entry(address).or_insert_with(HashMap::new);
+                for mut msg in req {
+                    let response = memory_execute(store, msg.request());
+                    msg.fulfill(response);
+                }
+
+                server
+            })
+        });
+        ProcessFuture::new(inner)
+    }
+}
+
+fn memory_execute(store: &mut Store, msg: &RedisMessage) -> RedisMessage {
+    let args = match msg {
+        RedisMessage::Bulk(_, args) => args.iter().filter_map(redis_get_data_buffer).collect::<Vec<_>>(),
+        _ => return RedisMessage::from_error_str("unsupported message"),
+    };
+
+    match args.split_first() {
+        Some((cmd, [key])) if cmd.eq_ignore_ascii_case(b"get") => {
+            match store.get(*key) {
+                Some(value) => redis_new_data_buffer(value),
+                None => RedisMessage::Null,
+            }
+        },
+        Some((cmd, [key, value])) if cmd.eq_ignore_ascii_case(b"set") => {
+            store.insert(key.to_vec(), value.to_vec());
+            RedisMessage::OK
+        },
+        Some((cmd, keys)) if is_any_command(cmd, &[b"del", b"unlink"]) && !keys.is_empty() => {
+            let deleted = keys.iter().filter(|key| store.remove(**key).is_some()).count();
+            RedisMessage::from_integer(deleted as i64)
+        },
+        Some((cmd, keys)) if is_any_command(cmd, &[b"exists", b"touch"]) && !keys.is_empty() => {
+            let existing = keys.iter().filter(|key| store.contains_key(**key)).count();
+            RedisMessage::from_integer(existing as i64)
+        },
+        Some((cmd, args)) if cmd.eq_ignore_ascii_case(b"scan") && !args.is_empty() => memory_scan(store, args),
+        Some((cmd, [pattern])) if cmd.eq_ignore_ascii_case(b"keys") => {
+            let mut keys = store.keys().filter(|key| glob_matches(pattern, key)).collect::<Vec<_>>();
+            keys.sort();
+            RedisMessage::from_array(keys.into_iter().map(|key| RedisMessage::from_data(key)).collect())
+        },
+        Some((cmd, [])) if cmd.eq_ignore_ascii_case(b"dbsize") => RedisMessage::from_integer(store.len() as i64),
+        _ => RedisMessage::from_error_str("unsupported command"),
+    }
+}
+
+/// Scans a store in key order, using the position in the ordering as the cursor.
+fn memory_scan(store: &Store, args: &[&[u8]]) -> RedisMessage {
+    let cursor = match std::str::from_utf8(args[0]).ok().and_then(|cursor| cursor.parse::<usize>().ok()) {
+        Some(cursor) => cursor,
+        None => return RedisMessage::from_error_str("invalid cursor"),
+    };
+
+    let mut count = 10;
+    let mut pattern = None;
+    for option in args[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"count") => {
+                count = match std::str::from_utf8(value).ok().and_then(|count| count.parse::<usize>().ok()) {
+                    Some(count) if count > 0 => count,
+                    _ => return RedisMessage::from_error_str("value is not an integer or out of range"),
+                };
+            },
+            [name, value] if name.eq_ignore_ascii_case(b"match") => pattern = Some(*value),
+            _ => return RedisMessage::from_error_str("syntax error"),
+        }
+    }
+
+    let mut keys = store.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    let next = if cursor + count >= keys.len() { 0 } else { cursor + count };
+    let page = keys
+        .into_iter()
+        .skip(cursor)
+        .take(count)
+        .filter(|key| pattern.map_or(true, |pattern| glob_matches(pattern, key)))
+        .map(|key| RedisMessage::from_data(key))
+        .collect();
+    RedisMessage::from_array(vec![RedisMessage::from_data(next.to_string().as_bytes()), RedisMessage::from_array(page)])
+}
+
+/// Matches a key against a glob pattern, supporting `*` and `?`.
+fn glob_matches(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|i| glob_matches(rest, &key[i..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_matches(rest, &key[1..]),
+        Some((c, rest)) => key.first() == Some(c) && glob_matches(rest, &key[1..]),
+    }
+}
+
+fn is_any_command(cmd: &[u8], names: &[&[u8]]) -> bool { names.iter().any(|name| cmd.eq_ignore_ascii_case(name)) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{
+            message_queue::MessageQueue,
+            pool::{BackendPool, BackendPoolBuilder},
+        },
+        common::EnqueuedRequest,
+        conf::{BackendAddress, PoolConfiguration},
+        protocol::redis::parse_messages,
+    };
+    use futures::future::{lazy, poll_fn};
+    use metrics_runtime::{Receiver, Sink as MetricSink};
+    use tower_direct_service::DirectService;
+
+    fn run_commands(
+        pool: &mut BackendPool<MemoryProcessor>, queue: &mut MessageQueue<MemoryProcessor>, cmds: &[&str],
+    ) -> Vec<u8> {
+        let msgs = cmds.iter().map(|cmd| RedisMessage::from_inline(cmd)).collect();
+        let requests = queue
+            .enqueue(msgs)
+            .expect("failed to enqueue commands")
+            .into_iter()
+            .map(|(slot, msg)| EnqueuedRequest::new(slot, msg))
+            .collect();
+
+        poll_fn(|| pool.poll_ready()).wait().expect("pool never became ready");
+        let mut fut = pool.call(requests);
+        let responses = poll_fn(|| {
+            pool.poll_service()?;
+            fut.poll()
+        })
+        .wait()
+        .expect("failed to run commands");
+        queue.fulfill(responses);
+
+        let mut output = Vec::new();
+        while let Some((buf, _)) = queue.get_sendable_buf() {
+            output.extend_from_slice(&buf);
+        }
+        output
+    }
+
+    /// Runs commands against the pool on a real runtime, one at a time, and returns every reply.
+    ///
+    /// Broadcasts and scans hand their responses off to a task to be put together, which needs a
+    /// runtime to run on, so the replies are shipped back out to be checked.  The next command to
+    /// run is picked based on the last reply, until there isn't one.
+    fn run_on_runtime<F>(
+        mut pool: BackendPool<MemoryProcessor>, mut queue: MessageQueue<MemoryProcessor>, mut next_command: F,
+    ) -> Vec<RedisMessage>
+    where
+        F: FnMut(Option<&RedisMessage>) -> Option<String> + Send + 'static,
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            let mut replies = Vec::new();
+            let mut next = None;
+            let mut pending = None;
+            poll_fn(move || {
+                loop {
+                    if pending.is_none() {
+                        if next.is_none() {
+                            next = next_command(replies.last());
+                        }
+                        if next.is_none() {
+                            let _ = tx.send(replies.clone());
+                            return Ok(Async::Ready(()));
+                        }
+                        try_ready!(pool.poll_ready().map_err(|_| ()));
+
+                        let cmd = next.take().unwrap_or_default();
+                        let requests = queue
+                            .enqueue(vec![RedisMessage::from_inline(&cmd)])
+                            .map_err(|_| ())?
+                            .into_iter()
+                            .map(|(slot, msg)| EnqueuedRequest::new(slot, msg))
+                            .collect();
+                        pending = Some(pool.call(requests));
+                    }
+
+                    pool.poll_service().map_err(|_| ())?;
+                    let responses = match pending.as_mut() {
+                        Some(fut) => try_ready!(fut.poll().map_err(|_| ())),
+                        None => return Err(()),
+                    };
+                    pending = None;
+                    queue.fulfill(responses);
+
+                    while let Some((buf, _)) = queue.get_sendable_buf() {
+                        let (msgs, _) = parse_messages(&buf, None).map_err(|_| ())?;
+                        replies.extend(msgs);
+                    }
+                }
+            })
+        }));
+
+        rx.recv().expect("commands never finished")
+    }
+
+    /// Scans the whole pool, page by page, and returns every page of the scan.
+    fn scan_pool(
+        pool: BackendPool<MemoryProcessor>, queue: MessageQueue<MemoryProcessor>, options: &str,
+    ) -> Vec<RedisMessage> {
+        let options = options.to_owned();
+        run_on_runtime(pool, queue, move |last| {
+            // Keep going until a backend errors out or the cursor comes back around to 0.
+            let cursor = match last {
+                None => b"0".to_vec(),
+                Some(RedisMessage::Bulk(_, args)) => args.get(0).and_then(redis_get_data_buffer)?.to_vec(),
+                Some(_) => return None,
+            };
+            match &cursor[..] {
+                b"0" if last.is_some() => None,
+                cursor => Some(format!("scan {} {}", String::from_utf8_lossy(cursor), options)),
+            }
+        })
+    }
+
+    fn get_scan_keys(page: &RedisMessage) -> Vec<String> {
+        match page {
+            RedisMessage::Bulk(_, args) if args.len() == 2 => {
+                match &args[1] {
+                    RedisMessage::Bulk(_, keys) => {
+                        keys.iter()
+                            .filter_map(redis_get_data_buffer)
+                            .map(|key| String::from_utf8_lossy(key).into_owned())
+                            .collect()
+                    },
+                    _ => panic!("SCAN keys weren't an array"),
+                }
+            },
+            _ => panic!("SCAN reply wasn't a cursor and keys"),
+        }
+    }
+
+    fn build_pool(
+        processor: &MemoryProcessor, backends: usize, sink: MetricSink,
+    ) -> (BackendPool<MemoryProcessor>, Vec<SocketAddr>) {
+        let addresses = (0..backends).map(|_| processor.add_backend()).collect::<Vec<_>>();
+        let pool = build_pool_with_backends(processor, &addresses, sink);
+        (pool, addresses)
+    }
+
+    fn build_pool_with_backends(
+        processor: &MemoryProcessor, addresses: &[SocketAddr], sink: MetricSink,
+    ) -> BackendPool<MemoryProcessor> {
+        let mut config = PoolConfiguration::default();
+        config.addresses = addresses
+            .iter()
+            .map(|address| {
+                BackendAddress {
+                    address: *address,
+                    identifier: address.to_string(),
+                }
+            })
+            .collect();
+
+        // The backends answer immediately, so there's no need for a timer to enforce timeouts.
+        let mut options = HashMap::new();
+        options.insert("timeout_ms".to_owned(), "0".to_owned());
+        config.options = Some(options);
+
+        BackendPoolBuilder::new("memory".to_owned(), processor.clone(), config, sink)
+            .build()
+            .expect("failed to build pool")
+    }
+
+    #[test]
+    fn test_memory_backend_routing() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let (mut pool, addresses) = build_pool(&processor, 3, receiver.get_sink());
+        let mut queue = MessageQueue::new(processor.clone());
+
+        let output = run_commands(&mut pool, &mut queue, &["mset a 1 b 2 c 3 d 4", "mget a b c d e"]);
+        assert_eq!(
+            &output[..],
+            &b"+OK\r\n*5\r\n$1\r\n1\r\n$1\r\n2\r\n$1\r\n3\r\n$1\r\n4\r\n$-1\r\n"[..]
+        );
+
+        // Every key should live on exactly the backend the pool routes it to, and nowhere else.
+        for key in &[&b"a"[..], b"b", b"c", b"d"] {
+            let idx = pool.get_backend_index(key).expect("no backend for key");
+            for (i, address) in addresses.iter().enumerate() {
+                assert_eq!(processor.get(address, key).is_some(), i == idx);
+            }
+        }
+        let total = addresses.iter().map(|address| processor.key_count(address)).sum::<usize>();
+        assert_eq!(total, 4);
+
+        let output = run_commands(&mut pool, &mut queue, &["del a b e", "get a", "get c"]);
+        assert_eq!(&output[..], &b":2\r\n$-1\r\n$1\r\n3\r\n"[..]);
+    }
+
+    #[test]
+    fn test_multi_key_integer_commands() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let (mut pool, addresses) = build_pool(&processor, 2, receiver.get_sink());
+        let mut queue = MessageQueue::new(processor.clone());
+
+        // Pick three keys that span both backends: two on the first, and one on the second.
+        let keys = (0..100).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        let pool_ref = &pool;
+        let keys_for = |idx| {
+            keys.iter()
+                .filter(move |key| pool_ref.get_backend_index(key.as_bytes()) == Some(idx))
+        };
+        let mut chosen = keys_for(0).take(2).cloned().collect::<Vec<_>>();
+        chosen.extend(keys_for(1).take(1).cloned());
+        let keys = chosen.join(" ");
+
+        let set = format!("mset {}", chosen.iter().map(|key| format!("{} 1", key)).collect::<Vec<_>>().join(" "));
+        let output = run_commands(&mut pool, &mut queue, &[&set]);
+        assert_eq!(&output[..], &b"+OK\r\n"[..]);
+        assert_eq!(processor.key_count(&addresses[0]), 2);
+        assert_eq!(processor.key_count(&addresses[1]), 1);
+
+        // Each backend answers for its own keys, and the client sees the sum.
+        let exists = format!("exists {} missing", keys);
+        let touch = format!("touch {}", keys);
+        let del = format!("del {}", keys);
+        let output = run_commands(&mut pool, &mut queue, &[&exists, &touch, &del, &del]);
+        assert_eq!(&output[..], &b":3\r\n:3\r\n:3\r\n:0\r\n"[..]);
+
+        let output = run_commands(&mut pool, &mut queue, &[&set, &format!("unlink {}", keys)]);
+        assert_eq!(&output[..], &b"+OK\r\n:3\r\n"[..]);
+    }
+
+    #[test]
+    fn test_scan_across_backends() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let (mut pool, addresses) = build_pool(&processor, 3, receiver.get_sink());
+        let mut queue = MessageQueue::new(processor.clone());
+
+        let keys = (0..50).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        let pairs = keys.iter().map(|key| format!("{} 1", key)).collect::<Vec<_>>();
+        let output = run_commands(&mut pool, &mut queue, &[&format!("mset {} other 1", pairs.join(" "))]);
+        assert_eq!(&output[..], &b"+OK\r\n"[..]);
+        assert!(addresses.iter().all(|address| processor.key_count(address) > 0));
+
+        // Every key turns up exactly once, even though each page only comes from one backend.
+        let pages = scan_pool(pool, queue, "count 7");
+        assert!(pages.len() >= 3);
+        let mut seen = pages.iter().flat_map(get_scan_keys).collect::<Vec<_>>();
+        seen.sort();
+        let mut expected = keys.clone();
+        expected.push("other".to_owned());
+        expected.sort();
+        assert_eq!(seen, expected);
+
+        // MATCH is passed along to each backend, and the cursor still only hits 0 at the very end.
+        let pool = build_pool_with_backends(&processor, &addresses, receiver.get_sink());
+        let queue = MessageQueue::new(processor.clone());
+        let pages = scan_pool(pool, queue, "match key1? count 100");
+        assert_eq!(pages.len(), 3);
+        let mut seen = pages.iter().flat_map(get_scan_keys).collect::<Vec<_>>();
+        seen.sort();
+        let expected = (10..20).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn test_fanout_keys_and_dbsize() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let (mut pool, addresses) = build_pool(&processor, 3, receiver.get_sink());
+        let mut queue = MessageQueue::new(processor.clone());
+
+        let pairs = (0..30).map(|i| format!("key{} 1", i)).collect::<Vec<_>>();
+        let output = run_commands(&mut pool, &mut queue, &[&format!("mset {} other 1", pairs.join(" "))]);
+        assert_eq!(&output[..], &b"+OK\r\n"[..]);
+        let total = addresses.iter().map(|address| processor.key_count(address)).sum::<usize>();
+        assert!(addresses.iter().all(|address| processor.key_count(address) > 0));
+
+        // KEYS is off unless the pool allows it, but DBSIZE is always fine.
+        let replies = run_on_runtime(pool, queue, {
+            let mut cmds = vec!["keys *", "dbsize"].into_iter();
+            move |_| cmds.next().map(str::to_owned)
+        });
+        let output = replies.into_iter().flat_map(|msg| msg.into_resp()).collect::<Vec<_>>();
+        assert_eq!(output, format!("-ERR KEYS disabled on proxy\r\n:{}\r\n", total).into_bytes());
+
+        // With KEYS allowed, the keys from every backend come back as one flat list.
+        let pool = build_pool_with_backends(&processor, &addresses, receiver.get_sink()).set_allow_fanout_keys(true);
+        let queue = MessageQueue::new(processor.clone());
+        let replies = run_on_runtime(pool, queue, {
+            let mut cmds = vec!["keys *", "keys key?"].into_iter();
+            move |_| cmds.next().map(str::to_owned)
+        });
+        assert_eq!(replies.len(), 2);
+        match &replies[0] {
+            RedisMessage::Bulk(_, keys) => assert_eq!(keys.len(), total),
+            _ => panic!("KEYS reply wasn't an array"),
+        }
+        match &replies[1] {
+            RedisMessage::Bulk(_, keys) => assert_eq!(keys.len(), 10),
+            _ => panic!("KEYS reply wasn't an array"),
+        }
+    }
+
+    #[test]
+    fn test_mset_on_one_backend() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let (mut pool, addresses) = build_pool(&processor, 2, receiver.get_sink());
+        let mut queue = MessageQueue::new(processor.clone());
+
+        // Every pair lands on the same backend, so the other one never hears about the MSET.
+        let keys = (0..100)
+            .map(|i| format!("key{}", i))
+            .filter(|key| pool.get_backend_index(key.as_bytes()) == Some(0))
+            .take(3)
+            .collect::<Vec<_>>();
+        let pairs = keys.iter().map(|key| format!("{} 1", key)).collect::<Vec<_>>();
+        let output = run_commands(&mut pool, &mut queue, &[&format!("mset {}", pairs.join(" "))]);
+        assert_eq!(&output[..], &b"+OK\r\n"[..]);
+        assert_eq!(processor.key_count(&addresses[0]), 3);
+        assert_eq!(processor.key_count(&addresses[1]), 0);
+
+        // A trailing key without a value is rejected outright, without writing anything.
+        let output = run_commands(&mut pool, &mut queue, &["mset x 1 y", "get x"]);
+        assert_eq!(
+            &output[..],
+            &b"-ERR wrong number of arguments for 'mset' command\r\n$-1\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_mset_with_backend_down() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let (mut pool, addresses) = build_pool(&processor, 2, receiver.get_sink());
+        let mut queue = MessageQueue::new(processor.clone());
+
+        // Find a key for each backend, and then take the second backend down.
+        let keys = (0..100).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        let key_for = |idx| {
+            keys.iter()
+                .find(|key| pool.get_backend_index(key.as_bytes()) == Some(idx))
+                .expect("no key found for backend")
+                .clone()
+        };
+        let (up_key, down_key) = (key_for(0), key_for(1));
+        processor.stop_backend(&addresses[1]);
+
+        // MSET only succeeds if every backend does, but it isn't atomic: the backend that was up
+        // keeps its write.
+        let cmd = format!("mset {} 1 {} 2", up_key, down_key);
+        let output = run_commands(&mut pool, &mut queue, &[&cmd]);
+        assert_eq!(&output[..], &b"-ERR failed to receive response for 1 of 2 fragments\r\n"[..]);
+        assert_eq!(processor.get(&addresses[0], up_key.as_bytes()), Some(b"1".to_vec()));
+        assert_eq!(processor.get(&addresses[1], down_key.as_bytes()), None);
+
+        let cmd = format!("mset {} 3", up_key);
+        let output = run_commands(&mut pool, &mut queue, &[&cmd]);
+        assert_eq!(&output[..], &b"+OK\r\n"[..]);
+    }
+}
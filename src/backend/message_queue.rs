@@ -18,12 +18,15 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 use crate::{
-    backend::processor::{Processor, ProcessorError},
-    common::{AssignedRequests, AssignedResponse, Message, MessageResponse},
+    backend::processor::{ClientState, Processor, ProcessorError},
+    common::{AssignedRequests, AssignedResponse, Message, MessageResponse, RouteLog},
+    util::MemoryBudget,
 };
 use bytes::BytesMut;
+use fnv::{FnvHashMap, FnvHashSet};
+use metrics_runtime::data::Counter;
 use slab::Slab;
-use std::collections::VecDeque;
+use std::{collections::VecDeque, sync::Arc};
 
 /// Message state of queued messages.
 #[derive(Debug, PartialEq)]
@@ -65,6 +68,12 @@ pub enum MessageState {
     /// client for a given input message.  For example, if the client sent in a multi-get that
     /// asked for 10 keys, the 10th streaming fragment would be the "end" of the response.
     StreamingFragmented(Option<BytesMut>, bool),
+
+    /// An unfragmented, standalone message whose response must be transformed.
+    ///
+    /// The buffer identifies the command the message was for, which is passed back to the processor
+    /// so that it can rewrite the response before it's sent to the client.
+    Transformed(BytesMut),
 }
 
 pub struct MessageQueue<P>
@@ -77,6 +86,39 @@ where
     // Holds all message slots, and stores the slot IDs in order of the messages tied to them.
     slot_order: VecDeque<(usize, MessageState)>,
     slots: Slab<Option<P::Message>>,
+
+    // Slots whose response failed, and were filled in with an error message instead.
+    failed_slots: FnvHashSet<usize>,
+
+    // Whether or not identical reads within a batch are deduplicated, and, for deduplicated reads,
+    // the slots that get a copy of the response when the slot of the read we actually sent is
+    // fulfilled.
+    dedupe_reads: bool,
+    followers: FnvHashMap<usize, Vec<usize>>,
+
+    // Whether or not identical, consecutive idempotent writes within a batch are coalesced, and,
+    // for coalesced writes, the write we actually sent and the slots that get the response a
+    // repeat of it would have gotten.
+    coalesce_writes: bool,
+    coalesced: FnvHashMap<usize, (P::Message, Vec<usize>)>,
+
+    // The maximum number of slots we allow before we're considered full.
+    max_pending: Option<usize>,
+
+    // How many of the requests we've handed out are still waiting on a response, and where to
+    // count them if we go away before they come back.
+    in_flight: usize,
+    orphaned_responses: Option<Counter>,
+
+    // The budget for bytes buffered across all clients, if any, and how many bytes each slot has
+    // counted against it: the request while it's pending, and then the response until it's sent.
+    budget: Option<MemoryBudget>,
+    charges: FnvHashMap<usize, usize>,
+
+    // Where backends record which of them each slot was sent to, if anyone is keeping track, and
+    // the backends of the slots that have been responded to since they were last taken.
+    route_log: Option<RouteLog>,
+    routes: Vec<Arc<String>>,
 }
 
 impl<P> MessageQueue<P>
@@ -89,6 +131,94 @@ where
             processor,
             slot_order: VecDeque::new(),
             slots: Slab::new(),
+            failed_slots: FnvHashSet::default(),
+            dedupe_reads: false,
+            followers: FnvHashMap::default(),
+            coalesce_writes: false,
+            coalesced: FnvHashMap::default(),
+            max_pending: None,
+            in_flight: 0,
+            orphaned_responses: None,
+            budget: None,
+            charges: FnvHashMap::default(),
+            route_log: None,
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn set_dedupe_reads(mut self, dedupe_reads: bool) -> Self {
+        self.dedupe_reads = dedupe_reads;
+        self
+    }
+
+    pub fn set_coalesce_writes(mut self, coalesce_writes: bool) -> Self {
+        self.coalesce_writes = coalesce_writes;
+        self
+    }
+
+    pub fn set_max_pending(mut self, max_pending: Option<usize>) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    pub fn set_orphaned_responses(mut self, orphaned_responses: Counter) -> Self {
+        self.orphaned_responses = Some(orphaned_responses);
+        self
+    }
+
+    pub fn set_memory_budget(mut self, budget: Option<MemoryBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn set_route_log(mut self, route_log: Option<RouteLog>) -> Self {
+        self.route_log = route_log;
+        self
+    }
+
+    /// Takes the backends that the responses handed out since the last call were served by.
+    ///
+    /// A backend serving more than one fragment of the same response is only listed once.
+    pub fn take_routes(&mut self) -> Vec<Arc<String>> { std::mem::replace(&mut self.routes, Vec::new()) }
+
+    fn take_route(&mut self, slot_id: usize) {
+        if let Some(backend) = self.route_log.as_ref().and_then(|route_log| route_log.take(slot_id)) {
+            if !self.routes.contains(&backend) {
+                self.routes.push(backend);
+            }
+        }
+    }
+
+    /// Whether or not the queue is holding any messages at all.
+    pub fn is_empty(&self) -> bool { self.slot_order.is_empty() }
+
+    /// Gets the number of requests handed out by `enqueue` that haven't been fulfilled yet.
+    pub fn in_flight(&self) -> usize { self.in_flight }
+
+    /// Whether or not all clients together are buffering more bytes than they're allowed to.
+    pub fn is_over_budget(&self) -> bool { self.budget.as_ref().map_or(false, |budget| budget.is_exhausted()) }
+
+    fn charge(&mut self, slot_id: usize, n: usize) {
+        if let Some(budget) = self.budget.as_ref() {
+            budget.acquire(n);
+            *self.charges.entry(slot_id).or_insert(0) += n;
+        }
+    }
+
+    fn discharge(&mut self, slot_id: usize) {
+        if let (Some(budget), Some(n)) = (self.budget.as_ref(), self.charges.remove(&slot_id)) {
+            budget.release(n);
+        }
+    }
+
+    /// Whether or not the queue is holding as many messages as it's allowed to.
+    ///
+    /// The queue never refuses messages, so callers are responsible for not enqueueing more when
+    /// the queue is full.
+    pub fn is_full(&self) -> bool {
+        match self.max_pending {
+            Some(max_pending) => self.slot_order.len() >= max_pending,
+            None => false,
         }
     }
 
@@ -115,6 +245,7 @@ where
                         match state {
                             MessageState::Standalone
                             | MessageState::Inline
+                            | MessageState::Transformed(_)
                             | MessageState::StreamingFragmented(_, _) => true,
                             MessageState::Fragmented(_, _, _) => false,
                         }
@@ -127,9 +258,13 @@ where
         if has_immediate {
             let (slot_id, state) = self.slot_order.pop_front().expect("failed to pop slot order");
             let slot = self.slots.remove(slot_id).expect("failed to remove slot");
+            self.failed_slots.remove(&slot_id);
+            self.discharge(slot_id);
+            self.take_route(slot_id);
 
             let (buf, count) = match state {
                 MessageState::Standalone | MessageState::Inline => (slot.into_buf(), 1),
+                MessageState::Transformed(cmd) => (self.processor.transform_message(&cmd, slot)?.into_buf(), 1),
                 MessageState::StreamingFragmented(header, is_last) => {
                     let count = if is_last { 1 } else { 0 };
                     match header {
@@ -167,31 +302,136 @@ where
 
         // We have all the slots filled and ready to coalesce.  Pull out the fragments!
         let mut fragments = Vec::new();
+        let mut failed = 0;
         for _ in 0..fragment_count {
             let (slot_id, state) = self.slot_order.pop_front().expect("failed to pop fragment slot order");
             let msg = self.slots.remove(slot_id).expect("failed to remove fragment slot");
+            self.discharge(slot_id);
+            self.take_route(slot_id);
+            if self.failed_slots.remove(&slot_id) {
+                failed += 1;
+            }
             fragments.push((state, msg));
         }
 
+        // Unlike streaming fragments, where each fragment stands on its own and a failure only
+        // affects its own position in the response, these fragments are aggregated into a single
+        // response.  If any of them failed, the aggregate can't be trusted, so the whole command
+        // fails.
+        if failed > 0 {
+            let err = self.processor.get_error_message_str(&format!(
+                "failed to receive response for {} of {} fragments",
+                failed, fragment_count
+            ));
+            return Ok(Some((err.into_buf(), 1)));
+        }
+
         let msg = self.processor.defragment_messages(fragments)?;
         Ok(Some((msg.into_buf(), 1)))
     }
 
     pub fn enqueue(&mut self, msgs: Vec<P::Message>) -> Result<AssignedRequests<P::Message>, ProcessorError> {
         let fmsgs = self.processor.fragment_messages(msgs)?;
+        self.enqueue_fragments(fmsgs)
+    }
+
+    /// Enqueues messages from a client, letting the processor handle authentication first.
+    ///
+    /// Messages are checked in order, so a client that authenticates partway through a batch can
+    /// use the rest of it.  Anything the processor answers itself is queued inline, in place.
+    pub fn enqueue_authenticated(
+        &mut self, msgs: Vec<P::Message>, state: &mut ClientState,
+    ) -> Result<AssignedRequests<P::Message>, ProcessorError> {
+        let mut fmsgs = Vec::new();
+        let mut pending = Vec::new();
+        for msg in msgs {
+            match self.processor.authenticate(&msg, state) {
+                Some(reply) => {
+                    if !pending.is_empty() {
+                        fmsgs.extend(self.processor.fragment_messages(pending.split_off(0))?);
+                    }
+                    fmsgs.push((MessageState::Inline, reply));
+                },
+                None => pending.push(msg),
+            }
+        }
 
+        if !pending.is_empty() {
+            fmsgs.extend(self.processor.fragment_messages(pending)?);
+        }
+
+        self.enqueue_fragments(fmsgs)
+    }
+
+    fn enqueue_fragments(
+        &mut self, fmsgs: Vec<(MessageState, P::Message)>,
+    ) -> Result<AssignedRequests<P::Message>, ProcessorError> {
         let mut amsgs = Vec::new();
+        let mut reads = FnvHashMap::default();
+        let mut last_write: Option<(BytesMut, usize)> = None;
         for (msg_state, msg) in fmsgs {
             if msg_state == MessageState::Inline {
+                let size = msg.size();
                 let slot_id = self.slots.insert(Some(msg));
                 self.slot_order.push_back((slot_id, msg_state));
+                self.charge(slot_id, size);
+                last_write = None;
             } else {
+                let is_standalone = msg_state == MessageState::Standalone;
+                let is_whole = match msg_state {
+                    MessageState::Standalone | MessageState::Fragmented(_, 0, 1) => true,
+                    _ => false,
+                };
                 let slot_id = self.slots.insert(None);
                 self.slot_order.push_back((slot_id, msg_state));
+                self.charge(slot_id, msg.size());
+
+                if self.coalesce_writes {
+                    // Only a write that directly follows a byte-for-byte identical write gets
+                    // coalesced: anything at all in between could depend on, or change, what the
+                    // first write did.
+                    let buf = if is_whole && msg.is_idempotent_write() {
+                        Some(msg.clone().into_buf())
+                    } else {
+                        None
+                    };
+
+                    let leader = match last_write {
+                        Some((ref last_buf, leader)) if Some(last_buf) == buf.as_ref() => Some(leader),
+                        _ => None,
+                    };
+                    if let Some(leader) = leader {
+                        self.coalesced
+                            .entry(leader)
+                            .or_insert_with(|| (msg, Vec::new()))
+                            .1
+                            .push(slot_id);
+                        continue;
+                    }
+
+                    last_write = buf.map(|buf| (buf, slot_id));
+                }
+
+                if self.dedupe_reads {
+                    if !msg.is_read() {
+                        // A write could change what any of the reads before it would see, so we
+                        // can't deduplicate across it.
+                        reads.clear();
+                    } else if is_standalone {
+                        let buf = msg.clone().into_buf();
+                        if let Some(leader) = reads.get(&buf) {
+                            self.followers.entry(*leader).or_insert_with(Vec::new).push(slot_id);
+                            continue;
+                        }
+                        reads.insert(buf, slot_id);
+                    }
+                }
+
                 amsgs.push((slot_id, msg));
             }
         }
 
+        self.in_flight += amsgs.len();
         Ok(amsgs)
     }
 
@@ -199,17 +439,63 @@ where
     where
         I: IntoIterator<Item = AssignedResponse<P::Message>>,
     {
-        for (slot, response) in batch.into_iter() {
-            let slot = self.slots.get_mut(slot).unwrap();
-            match response {
-                MessageResponse::Complete(msg) => {
-                    slot.replace(msg);
-                },
-                MessageResponse::Failed => {
-                    let err = self.processor.get_error_message_str("failed to receive response");
-                    slot.replace(err);
-                },
+        self.fulfill_with_reason(batch, "failed to receive response");
+    }
+
+    /// Fails the given slots, responding to them with an error explaining why.
+    pub fn fail<I>(&mut self, slot_ids: I, reason: &str)
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let batch = slot_ids.into_iter().map(|slot_id| (slot_id, MessageResponse::Failed));
+        self.fulfill_with_reason(batch, reason);
+    }
+
+    fn fulfill_with_reason<I>(&mut self, batch: I, reason: &str)
+    where
+        I: IntoIterator<Item = AssignedResponse<P::Message>>,
+    {
+        for (slot_id, response) in batch.into_iter() {
+            self.in_flight = self.in_flight.saturating_sub(1);
+            let (msg, failed) = match response {
+                MessageResponse::Complete(msg) => (msg, false),
+                MessageResponse::Failed => (self.processor.get_error_message_str(reason), true),
+            };
+
+            // Followers were never sent anywhere themselves, so they were served by whichever
+            // backend served the slot they're following.
+            if let Some(followers) = self.followers.remove(&slot_id) {
+                for follower_id in followers {
+                    self.copy_route(slot_id, follower_id);
+                    self.fill_slot(follower_id, msg.clone(), failed);
+                }
+            }
+
+            if let Some((request, followers)) = self.coalesced.remove(&slot_id) {
+                let repeated = if failed { msg.clone() } else { request.get_repeated_response(&msg) };
+                for follower_id in followers {
+                    self.copy_route(slot_id, follower_id);
+                    self.fill_slot(follower_id, repeated.clone(), failed);
+                }
             }
+
+            self.fill_slot(slot_id, msg, failed);
+        }
+    }
+
+    fn copy_route(&self, from: usize, to: usize) {
+        if let Some(route_log) = self.route_log.as_ref() {
+            route_log.copy(from, to);
+        }
+    }
+
+    fn fill_slot(&mut self, slot_id: usize, msg: P::Message, failed: bool) {
+        self.charge(slot_id, msg.size());
+        let slot = self.slots.get_mut(slot_id).unwrap();
+        slot.replace(msg);
+
+        if failed {
+            self.failed_slots.insert(slot_id);
         }
     }
 
@@ -225,3 +511,25 @@ where
         }
     }
 }
+
+impl<P> Drop for MessageQueue<P>
+where
+    P: Processor,
+{
+    fn drop(&mut self) {
+        // Whatever we're still holding on to when the client goes away is never going to be sent.
+        if let Some(budget) = self.budget.as_ref() {
+            budget.release(self.charges.values().sum());
+        }
+
+        // Likewise for responses we're still waiting on.  The requests themselves still run to
+        // completion, since backend connections always process a batch in its entirety no matter
+        // who's waiting on it, so the connections stay in sync, and the responses are simply
+        // discarded as they come back.
+        if let Some(orphaned_responses) = self.orphaned_responses.as_ref() {
+            if self.in_flight > 0 {
+                orphaned_responses.record(self.in_flight as u64);
+            }
+        }
+    }
+}
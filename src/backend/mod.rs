@@ -21,40 +21,75 @@ pub mod distributor;
 mod errors;
 pub mod hasher;
 mod health;
+mod hedge;
+pub mod memcached;
+#[cfg(test)]
+pub mod memory;
 pub mod message_queue;
 pub mod pool;
 pub mod processor;
 pub mod redis;
 
-pub use self::errors::{BackendError, PoolError};
+pub use self::{
+    errors::{BackendError, PoolError},
+    hedge::HedgeDelay,
+};
 
 use crate::{
-    backend::{distributor::BackendDescriptor, health::BackendHealth, processor::Processor},
-    common::{AssignedResponses, EnqueuedRequests, Message, PendingResponses},
+    backend::{
+        distributor::BackendDescriptor,
+        hasher::{Fnv64aHasher, KeyHasher},
+        health::BackendHealth,
+        processor::{IoTimeouts, Processor},
+    },
+    common::{
+        AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, MessageResponse, PendingResponses, ResponseRelay,
+    },
     errors::CreationError,
-    util::ProcessFuture,
+    util::{Connector, IntegerMappedVec, MaybeTlsStream, ProcessFuture},
 };
 use futures::{
     future::{join_all, ok, Either, JoinAll},
     prelude::*,
     Poll,
 };
-use metrics_runtime::{data::Counter, Sink as MetricSink};
+use metrics_runtime::{
+    data::{Counter, Histogram},
+    Sink as MetricSink,
+};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     marker::PhantomData,
+    mem,
     net::SocketAddr,
     str::FromStr,
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 use tokio::{
-    net::tcp::TcpStream,
     sync::oneshot,
-    timer::{timeout::Error as TimeoutError, Timeout},
+    timer::{timeout::Error as TimeoutError, Delay, Timeout},
 };
 use tower_direct_service::DirectService;
 
 type MaybeTimeout<F> = Either<NotTimeout<F>, Timeout<F>>;
+type HealthCheck = Timeout<Box<Future<Item = bool, Error = ()> + Send>>;
+
+// How long a connection waits to try preconnecting again after failing to, doubling with every
+// failure, up to the maximum.
+const PRECONNECT_RETRY_MIN_MS: u64 = 100;
+const PRECONNECT_RETRY_MAX_MS: u64 = 5000;
+
+// How long a preconnecting backend holds off on taking requests while its connections are still
+// being established.
+const PRECONNECT_WARMUP_MS: u64 = 5000;
+
+// The most requests held back while a half-open backend is being probed.  Past this, requests are
+// failed right away, rather than piling up behind a probe that might take a while.
+const MAX_HELD_REQUESTS: usize = 1024;
 
 pub struct NotTimeout<F>
 where
@@ -73,6 +108,96 @@ where
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> { self.inner.poll().map_err(TimeoutError::inner) }
 }
 
+/// Drives a request on its own dedicated connection, giving up on it if the client goes away.
+///
+/// Resolves to `None` if the request was abandoned before it finished.
+struct Dedicated<F, T> {
+    inner: F,
+    relay: Option<ResponseRelay<T>>,
+}
+
+impl<F, T> Future for Dedicated<F, T>
+where
+    F: Future,
+{
+    type Error = F::Error;
+    type Item = Option<F::Item>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(relay) = self.relay.as_mut() {
+            if relay.poll_abandoned() {
+                return Ok(Async::Ready(None));
+            }
+        }
+
+        let result = try_ready!(self.inner.poll());
+
+        // By now, the request has either been fulfilled or dropped, so its response is waiting to
+        // be passed along.
+        if let Some(mut relay) = self.relay.take() {
+            relay.poll_abandoned();
+        }
+
+        Ok(Async::Ready(Some(result)))
+    }
+}
+
+/// Timeouts for requests sent to a backend, with optional per-command overrides.
+///
+/// A timeout of 0 means no timeout at all.
+#[derive(Clone, Default)]
+pub struct RequestTimeouts {
+    default_ms: u64,
+    overrides: Arc<HashMap<Vec<u8>, u64>>,
+}
+
+impl RequestTimeouts {
+    pub fn new(default_ms: u64, overrides: HashMap<String, u64>) -> RequestTimeouts {
+        let overrides = overrides
+            .into_iter()
+            .map(|(cmd, timeout_ms)| (cmd.to_ascii_uppercase().into_bytes(), timeout_ms))
+            .collect();
+
+        RequestTimeouts {
+            default_ms,
+            overrides: Arc::new(overrides),
+        }
+    }
+
+    /// Gets the timeout for the given message.
+    ///
+    /// Blocking commands can legitimately take forever, so they have no timeout unless one is
+    /// explicitly configured.
+    pub fn get<M: Message>(&self, msg: &M) -> u64 {
+        if let Some(cmd) = msg.command() {
+            if !self.overrides.is_empty() {
+                if let Some(timeout_ms) = self.overrides.get(&cmd.to_ascii_uppercase()) {
+                    return *timeout_ms;
+                }
+            }
+
+            if msg.is_blocking() {
+                return 0;
+            }
+        }
+
+        self.default_ms
+    }
+
+    /// Gets the timeout for the given batch, which is the longest timeout of any of its messages.
+    pub fn get_batch<M: Message + Clone>(&self, batch: &EnqueuedRequests<M>) -> u64 {
+        let mut batch_ms = 0;
+        for req in batch {
+            match self.get(req.request()) {
+                0 => return 0,
+                timeout_ms => batch_ms = batch_ms.max(timeout_ms),
+            }
+        }
+
+        batch_ms
+    }
+}
+
 /// A backend connection.
 ///
 /// This represents a one-to-one mapping with a TCP connection to the given backend server.  This
@@ -88,15 +213,36 @@ where
 {
     processor: P,
     address: SocketAddr,
-    timeout_ms: u64,
+    conn_id: usize,
+    timeouts: RequestTimeouts,
+    io_timeouts: IoTimeouts,
     noreply: bool,
+    idle_ping_ms: u64,
+    connector: Connector,
+    preconnect: bool,
 
-    stream: Option<TcpStream>,
+    stream: Option<MaybeTlsStream>,
+    connecting: Option<ProcessFuture>,
+    preconnect_retry: Option<Delay>,
+    preconnect_backoff_ms: u64,
     current: Option<MaybeTimeout<ProcessFuture>>,
     pending: VecDeque<EnqueuedRequests<P::Message>>,
     pending_len: usize,
+    current_len: u64,
+    stream_requests: u64,
+    idle_deadline: Option<Delay>,
+    pinging: bool,
+    outcome: Option<bool>,
+    probe_position: Option<usize>,
+    probe_epoch: u64,
+    probing: bool,
+    probe_outcome: Option<(u64, bool)>,
 
     connects: Counter,
+    disconnects: Counter,
+    idle_pings: Counter,
+    cancelled_requests: Counter,
+    requests_per_conn: Histogram,
 }
 
 impl<P> BackendConnection<P>
@@ -105,25 +251,194 @@ where
     P::Message: Message + Clone + Send + 'static,
 {
     pub fn new(
-        address: SocketAddr, processor: P, timeout_ms: u64, noreply: bool, mut sink: MetricSink,
+        address: SocketAddr, conn_id: usize, processor: P, timeouts: RequestTimeouts, io_timeouts: IoTimeouts,
+        noreply: bool, mut sink: MetricSink,
     ) -> BackendConnection<P> {
         BackendConnection {
             processor,
             address,
-            timeout_ms,
+            conn_id,
+            timeouts,
+            io_timeouts,
             noreply,
+            idle_ping_ms: 0,
+            connector: Connector::new(),
+            preconnect: false,
             stream: None,
+            connecting: None,
+            preconnect_retry: None,
+            preconnect_backoff_ms: PRECONNECT_RETRY_MIN_MS,
             current: None,
             pending: VecDeque::new(),
             pending_len: 0,
+            current_len: 0,
+            stream_requests: 0,
+            idle_deadline: None,
+            pinging: false,
+            outcome: None,
+            probe_position: None,
+            probe_epoch: 0,
+            probing: false,
+            probe_outcome: None,
             connects: sink.counter("connects"),
+            disconnects: sink.counter("disconnects"),
+            idle_pings: sink.counter("idle_pings"),
+            cancelled_requests: sink.counter("cancelled_requests"),
+            requests_per_conn: sink.histogram("requests_per_conn"),
         }
     }
 
+    /// Sets how long the connection can sit idle before we ping the backend over it.
+    ///
+    /// Connections that sit idle for long enough can be silently dropped by stateful firewalls or
+    /// NAT gateways along the way, and we'd otherwise only find out when the next request failed.
+    /// Pinging idle connections finds them before real traffic does, and keeps them from looking
+    /// idle in the first place.  Pings are never sent over `noreply` connections, since they'd
+    /// never get a response.  A value of 0 disables idle pings.
+    pub fn set_idle_ping_ms(mut self, idle_ping_ms: u64) -> Self {
+        self.idle_ping_ms = idle_ping_ms;
+        self
+    }
+
+    /// Sets the connector used to establish the connection, which decides whether or not we speak
+    /// TLS to the backend.
+    pub fn set_connector(mut self, connector: Connector) -> Self {
+        self.connector = connector;
+        self
+    }
+
+    /// Sets whether or not the connection is established as soon as possible, rather than when the
+    /// first request shows up for it.
+    ///
+    /// On a cold start, connecting lazily means the first requests to each connection wait on the
+    /// connection being established.  Preconnecting gets that out of the way before any requests
+    /// show up.
+    pub fn set_preconnect(mut self, preconnect: bool) -> Self {
+        self.preconnect = preconnect;
+        self
+    }
+
     pub fn enqueue(&mut self, batch: EnqueuedRequests<P::Message>) {
         self.pending_len += batch.len();
         self.pending.push_back(batch);
     }
+
+    /// Enqueues the probe request for a half-open backend, sent during the given health epoch.
+    ///
+    /// Other batches may still be queued, or in flight, ahead of it, so we keep track of which one
+    /// is the probe, and only its outcome is reported by `take_probe_outcome`.
+    pub fn enqueue_probe(&mut self, batch: EnqueuedRequests<P::Message>, epoch: u64) {
+        self.probe_position = Some(self.pending.len());
+        self.probe_epoch = epoch;
+        self.enqueue(batch);
+    }
+
+    /// Pings the backend if the connection has been idle for long enough.
+    ///
+    /// Returns `true` if a ping was started.
+    fn poll_idle(&mut self) -> bool {
+        if self.idle_ping_ms == 0 || self.noreply || self.stream.is_none() {
+            self.idle_deadline = None;
+            return false;
+        }
+
+        let idle_at = Instant::now() + Duration::from_millis(self.idle_ping_ms);
+        let deadline = self.idle_deadline.get_or_insert_with(|| Delay::new(idle_at));
+        match deadline.poll() {
+            Ok(Async::Ready(())) => {},
+            _ => return false,
+        }
+
+        self.idle_deadline = None;
+        self.pinging = true;
+        self.current_len = 0;
+        self.idle_pings.record(1);
+        trace!("[backend] [{}#{}] pinging idle connection", self.address, self.conn_id);
+
+        let ping = EnqueuedRequest::without_response(self.processor.get_ping_message());
+        let timeout_ms = self.timeouts.get(ping.request());
+        let stream = Either::A(ok(self.stream.take().expect("idle connection has no stream")));
+        let inner = self.processor.process(vec![ping], stream, self.io_timeouts);
+        let work = if timeout_ms == 0 {
+            Either::A(NotTimeout { inner })
+        } else {
+            Either::B(Timeout::new(inner, Duration::from_millis(timeout_ms)))
+        };
+
+        self.current = Some(work);
+        true
+    }
+
+    /// Establishes the connection ahead of time, if we're preconnecting and haven't yet.
+    ///
+    /// Failing to connect here doesn't count against the backend's health, since no requests are
+    /// waiting on the connection, so we just try again after backing off.  Once a request needs the
+    /// connection, it picks up any connection attempt still in flight, and otherwise connects the
+    /// same as it would without preconnecting.
+    fn poll_preconnect(&mut self) {
+        if !self.preconnect || self.stream.is_some() {
+            return;
+        }
+
+        loop {
+            if let Some(retry) = self.preconnect_retry.as_mut() {
+                match retry.poll() {
+                    Ok(Async::NotReady) => return,
+                    _ => self.preconnect_retry = None,
+                }
+            }
+
+            if self.connecting.is_none() {
+                debug!("[backend] [{}#{}] preconnecting", self.address, self.conn_id);
+                self.connects.record(1);
+                self.connecting = Some(self.processor.preconnect(&self.address, &self.connector, self.noreply));
+            }
+
+            let result = self.connecting.as_mut().expect("no connection attempt in flight").poll();
+            match result {
+                Ok(Async::NotReady) => return,
+                Ok(Async::Ready(stream)) => {
+                    self.connecting = None;
+                    self.stream = Some(stream);
+                    self.preconnect = false;
+                    return;
+                },
+                Err(e) => {
+                    self.connecting = None;
+                    debug!(
+                        "[backend] [{}#{}] failed to preconnect, retrying in {}ms: {}",
+                        self.address, self.conn_id, self.preconnect_backoff_ms, e
+                    );
+
+                    let retry_at = Instant::now() + Duration::from_millis(self.preconnect_backoff_ms);
+                    self.preconnect_retry = Some(Delay::new(retry_at));
+                    self.preconnect_backoff_ms = (self.preconnect_backoff_ms * 2).min(PRECONNECT_RETRY_MAX_MS);
+                },
+            }
+        }
+    }
+
+    /// Whether or not the connection has nothing in flight, and nothing waiting to be sent.
+    fn is_idle(&self) -> bool { self.current.is_none() && self.pending.is_empty() }
+
+    /// Whether the last batch of requests to finish, since this was last called, succeeded or
+    /// failed, if any finished at all.
+    ///
+    /// Idle pings don't count, since they aren't real requests.
+    fn take_outcome(&mut self) -> Option<bool> { self.outcome.take() }
+
+    /// Whether the probe request succeeded or failed, if it has finished since this was last called,
+    /// along with the health epoch it was sent during.
+    fn take_probe_outcome(&mut self) -> Option<(u64, bool)> { self.probe_outcome.take() }
+
+    fn reset_stream(&mut self) {
+        // Track how many requests the connection successfully served over its lifetime, which tells
+        // us how well we're actually multiplexing requests over our backend connections.
+        self.stream = None;
+        self.disconnects.record(1);
+        self.requests_per_conn.record_value(self.stream_requests);
+        self.stream_requests = 0;
+    }
 }
 
 impl<P> DirectService<EnqueuedRequests<P::Message>> for BackendConnection<P>
@@ -148,6 +463,15 @@ where
                         // The operation finished, and gave us the connection back.
                         self.stream = Some(stream);
                         self.current = None;
+                        if !self.pinging {
+                            self.outcome = Some(true);
+                        }
+                        if self.probing {
+                            self.probe_outcome = Some((self.probe_epoch, true));
+                        }
+                        self.pinging = false;
+                        self.probing = false;
+                        self.stream_requests += self.current_len;
                     },
                     Ok(Async::NotReady) => return Ok(Async::NotReady),
                     Err(e) => {
@@ -158,13 +482,33 @@ where
                         // fulfilled yet, so that we can at least hand back an error saying that
                         // something broke internally.
                         self.current = None;
+                        let was_ping = self.pinging;
+                        self.pinging = false;
+                        if !was_ping {
+                            self.outcome = Some(false);
+                        }
+                        if self.probing {
+                            self.probe_outcome = Some((self.probe_epoch, false));
+                        }
+                        self.probing = false;
+                        debug!(
+                            "[backend] [{}#{}] batch of {} request(s) failed: {}",
+                            self.address, self.conn_id, self.current_len, e
+                        );
+
+                        // Either way, the connection went down with the operation, so we'll be
+                        // establishing a new one when we go to process our next batch.  The failed
+                        // batch wasn't served, so it doesn't count towards the connection's requests.
+                        self.reset_stream();
 
-                        // If this is specifically an inner error, and not a timeout, then the
-                        // connection to the backend is also likely compromised, so we'll drop that
-                        // as well, giving us a new connection when we go to process our next
-                        // batch.
-                        if e.is_inner() {
-                            self.stream = None;
+                        // If this is specifically an inner error, and not a request timeout, then
+                        // the connection to the backend is also likely compromised, so we bubble
+                        // that up to be counted against the backend's health.  This includes
+                        // socket read/write timeouts, since those mean the backend has stalled.
+                        // A failed idle ping, though, most likely just found a connection that
+                        // was dropped somewhere along the way while idle, which is exactly what
+                        // it was meant to do, so we simply reconnect when we next need to.
+                        if e.is_inner() && !was_ping {
                             return Err(e.into_inner().unwrap().into());
                         }
                     },
@@ -183,6 +527,25 @@ where
 
                 match self.pending.pop_front() {
                     Some(batch2) => {
+                        // Requests that nobody is waiting on anymore, like hedged reads that lost
+                        // their race, aren't worth sending if they can be cancelled.
+                        self.pending_len -= batch2.len();
+                        let queued = batch2.len();
+                        let batch2 = batch2
+                            .into_iter()
+                            .filter_map(|mut req| if req.is_abandoned() { None } else { Some(req) })
+                            .collect::<Vec<_>>();
+                        self.cancelled_requests.record((queued - batch2.len()) as u64);
+
+                        // Whatever batch the probe gets folded into decides the probe's outcome.
+                        self.probe_position = match self.probe_position {
+                            Some(0) => {
+                                self.probing = true;
+                                None
+                            },
+                            position => position.map(|position| position - 1),
+                        };
+
                         if let Some(batch3) = batch.as_mut() {
                             batch3.extend(batch2);
                         } else {
@@ -194,31 +557,53 @@ where
             }
 
             match batch {
+                // Everything we picked up was cancelled, so there's nothing to send after all.
+                Some(ref batch) if batch.is_empty() => continue,
                 Some(batch) => {
-                    self.pending_len -= batch.len();
+                    self.idle_deadline = None;
+                    self.current_len = batch.len() as u64;
+                    let timeout_ms = self.timeouts.get_batch(&batch);
+                    trace!(
+                        "[backend] [{}#{}] processing batch of {} request(s)",
+                        self.address, self.conn_id, self.current_len
+                    );
+
+                    // Once there's real work to do, we're done preconnecting, and the batch picks up any
+                    // connection attempt that's still in flight.
+                    self.preconnect = false;
+                    self.preconnect_retry = None;
 
                     // Get our stream, which we either already have or we'll just get a future for.
-                    let stream = match self.stream.take() {
-                        Some(stream) => Either::A(ok(stream)),
-                        None => {
+                    let stream = match (self.stream.take(), self.connecting.take()) {
+                        (Some(stream), _) => Either::A(ok(stream)),
+                        (None, Some(connecting)) => Either::B(connecting),
+                        (None, None) => {
+                            debug!("[backend] [{}#{}] establishing connection", self.address, self.conn_id);
                             self.connects.record(1);
-                            Either::B(self.processor.preconnect(&self.address, self.noreply))
+                            Either::B(self.processor.preconnect(&self.address, &self.connector, self.noreply))
                         },
                     };
 
                     // Get the response future from the processor.
-                    let inner = self.processor.process(batch, stream);
+                    let inner = self.processor.process(batch, stream, self.io_timeouts);
 
                     // Wrap it up to handle any configured timeouts.
-                    let work = if self.timeout_ms == 0 {
+                    let work = if timeout_ms == 0 {
                         Either::A(NotTimeout { inner })
                     } else {
-                        Either::B(Timeout::new(inner, Duration::from_millis(self.timeout_ms)))
+                        Either::B(Timeout::new(inner, Duration::from_millis(timeout_ms)))
                     };
 
                     self.current = Some(work);
                 },
-                None => return Ok(Async::Ready(())),
+                None => {
+                    if self.poll_idle() {
+                        continue;
+                    }
+
+                    self.poll_preconnect();
+                    return Ok(Async::Ready(()));
+                },
             }
         }
     }
@@ -272,9 +657,31 @@ where
     P::Message: Message + Clone + Send + 'static,
 {
     identifier: String,
+    route: Arc<String>,
+    address: SocketAddr,
+    processor: P,
+    connector: Connector,
+    noreply: bool,
+    timeouts: RequestTimeouts,
+    io_timeouts: IoTimeouts,
     health: BackendHealth,
+    health_check_interval_ms: u64,
+    health_check_timeout_ms: u64,
+    health_check_deadline: Option<Delay>,
+    health_check: Option<HealthCheck>,
+    held: Vec<EnqueuedRequests<P::Message>>,
+    held_len: usize,
+    warming_up: bool,
+    warmup_ms: u64,
+    warmup_deadline: Option<Delay>,
     conns: Vec<BackendConnection<P>>,
     conns_index: usize,
+    preserve_order: bool,
+    conn_hasher: Fnv64aHasher,
+    blocking_limit: usize,
+    blocking_in_flight: Arc<AtomicUsize>,
+    weight: usize,
+    serialized: HashSet<Vec<u8>>,
     sink: MetricSink,
 }
 
@@ -284,8 +691,8 @@ where
     P::Message: Message + Clone + Send + 'static,
 {
     pub fn new(
-        address: SocketAddr, identifier: String, processor: P, mut options: HashMap<String, String>, noreply: bool,
-        sink: MetricSink,
+        address: SocketAddr, identifier: String, processor: P, mut options: HashMap<String, String>,
+        command_timeouts: HashMap<String, u64>, noreply: bool, preserve_order: bool, sink: MetricSink,
     ) -> Result<Backend<P>, CreationError>
     where
         P: Processor + Clone + Send + 'static,
@@ -298,6 +705,38 @@ where
             .map_err(|_| CreationError::InvalidParameter("options.conns".to_string()))?;
         debug!("[listener] using connection limit of '{}'", conn_limit);
 
+        let timeout_ms_raw = options.entry("timeout_ms".to_owned()).or_insert_with(|| "500".to_owned());
+        let timeout_ms = u64::from_str(timeout_ms_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.timeout_ms".to_string()))?;
+        let timeouts = RequestTimeouts::new(timeout_ms, command_timeouts);
+
+        let read_timeout_ms_raw = options
+            .entry("backend_read_timeout_ms".to_owned())
+            .or_insert_with(|| "0".to_owned());
+        let read_timeout_ms = u64::from_str(read_timeout_ms_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.backend_read_timeout_ms".to_string()))?;
+
+        let write_timeout_ms_raw = options
+            .entry("backend_write_timeout_ms".to_owned())
+            .or_insert_with(|| "0".to_owned());
+        let write_timeout_ms = u64::from_str(write_timeout_ms_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.backend_write_timeout_ms".to_string()))?;
+
+        let io_timeouts = IoTimeouts {
+            read_ms: read_timeout_ms,
+            write_ms: write_timeout_ms,
+        };
+
+        let idle_ping_ms_raw = options.entry("idle_ping_ms".to_owned()).or_insert_with(|| "0".to_owned());
+        let idle_ping_ms = u64::from_str(idle_ping_ms_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.idle_ping_ms".to_string()))?;
+
+        let blocking_limit_raw = options
+            .entry("blocking_conns".to_owned())
+            .or_insert_with(|| "16".to_owned());
+        let blocking_limit = usize::from_str(blocking_limit_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.blocking_conns".to_string()))?;
+
         let cooloff_enabled_raw = options
             .entry("cooloff_enabled".to_owned())
             .or_insert_with(|| "true".to_owned());
@@ -316,29 +755,403 @@ where
         let cooloff_error_limit = usize::from_str(cooloff_error_limit_raw.as_str())
             .map_err(|_| CreationError::InvalidParameter("options.cooloff_error_limit".to_string()))?;
 
-        let health = BackendHealth::new(cooloff_enabled, cooloff_timeout_ms, cooloff_error_limit);
+        // Consecutive cooloffs can back off exponentially, up to this limit, but by default every
+        // cooloff is the base cooloff period, same as it's always been.
+        let cooloff_max_ms_raw = options
+            .entry("cooloff_max_ms".to_owned())
+            .or_insert_with(|| cooloff_timeout_ms.to_string());
+        let cooloff_max_ms = u64::from_str(cooloff_max_ms_raw.as_str())
+            .ok()
+            .filter(|max_ms| *max_ms >= cooloff_timeout_ms)
+            .ok_or_else(|| CreationError::InvalidParameter("options.cooloff_max_ms".to_string()))?;
+
+        let grace_period_ms_raw = options
+            .entry("startup_grace_period_ms".to_owned())
+            .or_insert_with(|| "0".to_owned());
+        let grace_period_ms = u64::from_str(grace_period_ms_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.startup_grace_period_ms".to_string()))?;
+
+        let grace_retry_ms_raw = options
+            .entry("startup_grace_retry_ms".to_owned())
+            .or_insert_with(|| "500".to_owned());
+        let grace_retry_ms = u64::from_str(grace_retry_ms_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.startup_grace_retry_ms".to_string()))?;
+
+        let health_check_interval_ms_raw = options
+            .entry("health_check_interval_ms".to_owned())
+            .or_insert_with(|| "0".to_owned());
+        let health_check_interval_ms = u64::from_str(health_check_interval_ms_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.health_check_interval_ms".to_string()))?;
+
+        let preconnect_raw = options.entry("preconnect".to_owned()).or_insert_with(|| "false".to_owned());
+        let preconnect = bool::from_str(preconnect_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.preconnect".to_string()))?;
+
+        let health_check_timeout_ms_raw = options
+            .entry("health_check_timeout_ms".to_owned())
+            .or_insert_with(|| "1000".to_owned());
+        let health_check_timeout_ms = u64::from_str(health_check_timeout_ms_raw.as_str())
+            .ok()
+            .filter(|timeout_ms| *timeout_ms > 0)
+            .ok_or_else(|| CreationError::InvalidParameter("options.health_check_timeout_ms".to_string()))?;
+
+        let connector = Connector::from_options(&mut options, &identifier)?;
+
+        let mut health = BackendHealth::new(cooloff_enabled, cooloff_timeout_ms, cooloff_error_limit);
+        health.set_cooloff_max(cooloff_max_ms);
+        if grace_period_ms > 0 {
+            health.set_grace_period(grace_period_ms, grace_retry_ms);
+        }
 
-        // TODO: where the hell did the actual backend timeout value go? can't hard-code this
         let conns = (0..conn_limit)
-            .map(|_| BackendConnection::new(address, processor.clone(), 500, noreply, sink.clone()))
+            .map(|conn_id| {
+                BackendConnection::new(
+                    address,
+                    conn_id,
+                    processor.clone(),
+                    timeouts.clone(),
+                    io_timeouts,
+                    noreply,
+                    sink.clone(),
+                )
+                .set_idle_ping_ms(idle_ping_ms)
+                .set_connector(connector.clone())
+                .set_preconnect(preconnect)
+            })
             .collect();
 
-        Ok(Backend {
+        let mut backend = Backend {
+            route: Arc::new(identifier.clone()),
             identifier,
+            address,
+            processor,
+            connector,
+            noreply,
+            timeouts,
+            io_timeouts,
             health,
+            health_check_interval_ms,
+            health_check_timeout_ms,
+            health_check_deadline: None,
+            health_check: None,
+            held: Vec::new(),
+            held_len: 0,
+            warming_up: preconnect,
+            warmup_ms: PRECONNECT_WARMUP_MS,
+            warmup_deadline: None,
             conns,
             conns_index: 0,
+            preserve_order,
+            conn_hasher: Fnv64aHasher::new(),
+            blocking_limit,
+            blocking_in_flight: Arc::new(AtomicUsize::new(0)),
+            weight: 1,
+            serialized: HashSet::new(),
             sink,
-        })
+        };
+        backend.record_health_times();
+
+        Ok(backend)
+    }
+
+    /// Sets the weight of this backend, relative to the other backends in its pool.
+    pub fn set_weight(mut self, weight: usize) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Sets the commands that are serialized through a single connection of this backend.
+    ///
+    /// That's the connection their key maps to, or the first connection if we're preserving order.
+    pub fn set_serialized_commands(mut self, commands: Vec<String>) -> Self {
+        self.serialized = commands
+            .into_iter()
+            .map(|cmd| cmd.to_ascii_uppercase().into_bytes())
+            .collect();
+        self
+    }
+
+    fn is_serialized(&self, msg: &P::Message) -> bool {
+        match msg.command() {
+            Some(cmd) => self.serialized.contains(&cmd.to_ascii_uppercase()),
+            None => false,
+        }
+    }
+
+    /// Runs a blocking request on its own, short-lived connection.
+    ///
+    /// Blocking requests can hold their connection for an arbitrary amount of time, so rather than
+    /// stalling one of our shared connections, we open a dedicated connection for the request and
+    /// close it once the request finishes or times out.  The number of these connections open at
+    /// any given time is bounded by the `blocking_conns` option.
+    fn call_dedicated(&mut self, mut req: EnqueuedRequest<P::Message>) {
+        let in_flight = self.blocking_in_flight.clone();
+        if in_flight.fetch_add(1, Ordering::SeqCst) >= self.blocking_limit {
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            req.fulfill(self.processor.get_error_message_str("too many blocking commands in flight"));
+            return;
+        }
+
+        self.sink.record_counter("blocking_connects", 1);
+
+        // A blocking request sits idle on the socket until it's served, which is exactly what the
+        // read timeout is meant to catch, so only the write timeout applies here.
+        let io_timeouts = IoTimeouts {
+            read_ms: 0,
+            ..self.io_timeouts
+        };
+
+        // If the client goes away while the request is blocked, there's nobody left to hand the
+        // response to, so we drop the connection rather than holding a blocking slot until the
+        // backend times the request out and popping an element that would only get thrown away.
+        let relay = req.relay_response();
+
+        let timeout_ms = self.timeouts.get(req.request());
+        let stream = self.processor.preconnect(&self.address, &self.connector, self.noreply);
+        let inner = self.processor.process(vec![req], Either::B(stream), io_timeouts);
+        let inner = if timeout_ms == 0 {
+            Either::A(NotTimeout { inner })
+        } else {
+            Either::B(Timeout::new(inner, Duration::from_millis(timeout_ms)))
+        };
+        let work = Dedicated { inner, relay };
+
+        // Whether or not the request succeeds, the connection is dropped when we're done with it,
+        // and any request that didn't get a response is failed by its drop guard.
+        let address = self.address;
+        let task = work.then(move |result| {
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            match result {
+                Ok(Some(_)) => {},
+                Ok(None) => debug!("[backend] [{}] client went away during blocking request", address),
+                Err(e) => debug!("[backend] [{}] blocking request failed: {}", address, e),
+            }
+            ok(())
+        });
+        tokio::spawn(task);
+    }
+
+    /// Enqueues the given requests on our connections.
+    fn dispatch(&mut self, req: EnqueuedRequests<P::Message>) {
+        // Serialized requests always go through the same connection, so that they execute in
+        // exactly the order we got them in, no matter which client sent them.  If we're preserving
+        // order, the rest of the batch has to come along with them, so the whole batch goes through
+        // our first connection.  Otherwise, requests are spread over connections by key anyway, so
+        // every serialized request for a given key already lands on the same connection.
+        let has_serialized = !self.serialized.is_empty() && req.iter().any(|x| self.is_serialized(x.request()));
+        if has_serialized && self.preserve_order {
+            self.conns[0].enqueue(req);
+            return;
+        }
+
+        if self.preserve_order || self.conns.len() == 1 {
+            self.conns[self.conns_index].enqueue(req);
+
+            self.conns_index += 1;
+            self.conns_index %= self.conns.len();
+
+            return;
+        }
+
+        // We're allowed to reorder, so spread the batch over all of our connections.  We split by
+        // key so that requests touching the same key stay on the same connection, and thus stay in
+        // the order the client sent them in, while unrelated requests get pipelined in parallel.
+        let conn_count = self.conns.len();
+        let mut batches = IntegerMappedVec::new();
+        for msg in req {
+            let conn_idx = get_conn_point(self.conn_hasher.hash(msg.key())) as usize % conn_count;
+            batches.push(conn_idx, msg);
+        }
+
+        for (conn_idx, batch) in batches {
+            self.conns[conn_idx].enqueue(batch);
+        }
+    }
+
+    /// Enqueues the probe request for a half-open backend on the connection it would normally go to.
+    fn dispatch_probe(&mut self, probe: EnqueuedRequest<P::Message>) {
+        let conn_idx = if self.preserve_order && self.is_serialized(probe.request()) {
+            0
+        } else if self.preserve_order || self.conns.len() == 1 {
+            let conn_idx = self.conns_index;
+            self.conns_index = (self.conns_index + 1) % self.conns.len();
+            conn_idx
+        } else {
+            get_conn_point(self.conn_hasher.hash(probe.key())) as usize % self.conns.len()
+        };
+
+        let epoch = self.health.epoch();
+        self.conns[conn_idx].enqueue_probe(vec![probe], epoch);
+    }
+
+    /// Releases any requests held while we were half-open, once the probe has finished.
+    ///
+    /// If the backend recovered, they're sent along.  Otherwise, they're dropped, which fails them,
+    /// since they can't be served by this backend and were never meant for any other.
+    fn release_held(&mut self) {
+        if self.held.is_empty() || self.health.is_half_open() {
+            return;
+        }
+
+        let held = mem::replace(&mut self.held, Vec::new());
+        self.held_len = 0;
+        if self.is_healthy() && !self.health.is_half_open() {
+            for req in held {
+                self.dispatch(req);
+            }
+
+            // Our connections have already been driven this time around, so make sure we get
+            // polled again to actually send these.
+            futures::task::current().notify();
+        } else {
+            debug!("[backend] [{}] failing {} held batch(es) after failed probe", self.address, held.len());
+        }
     }
 
     pub fn health(&self) -> &BackendHealth { &self.health }
 
+    /// Drives our active health check, pinging the backend every `health_check_interval_ms`.
+    ///
+    /// Passive health tracking only notices a half-dead backend once client requests start failing
+    /// against it.  Pinging it on a schedule lets us put it into cooloff before that happens.  The
+    /// ping goes out over whichever connection is sitting idle, so the check never queues behind
+    /// real traffic or opens connections of its own, and if none are idle, real traffic is already
+    /// telling us how the backend is doing, so we skip that round.  A ping that errors, times out, or
+    /// gets anything other than the expected reply trips the backend.  Checks are never run against
+    /// `noreply` backends, since they'd never get a reply.
+    fn poll_health_check(&mut self) {
+        if self.health_check_interval_ms == 0 || self.noreply {
+            return;
+        }
+
+        loop {
+            if let Some(check) = self.health_check.as_mut() {
+                let passed = match check.poll() {
+                    Ok(Async::NotReady) => return,
+                    Ok(Async::Ready(passed)) => passed,
+                    Err(_) => false,
+                };
+                self.health_check = None;
+
+                let labels = [("backend", self.identifier.clone())];
+                if passed {
+                    self.sink.record_counter_with_labels("health_check_successes", 1, &labels);
+                } else {
+                    debug!("[backend] [{}] health check failed", self.address);
+                    self.sink.record_counter_with_labels("health_check_failures", 1, &labels);
+                    self.health.trip();
+                    self.record_health_times();
+                }
+            }
+
+            let next_at = Instant::now() + Duration::from_millis(self.health_check_interval_ms);
+            let deadline = self.health_check_deadline.get_or_insert_with(|| Delay::new(next_at));
+            match deadline.poll() {
+                Ok(Async::Ready(())) => {},
+                _ => return,
+            }
+
+            self.health_check_deadline = None;
+            self.health_check = self.start_health_check();
+        }
+    }
+
+    fn start_health_check(&mut self) -> Option<HealthCheck> {
+        let conn = match self.conns.iter_mut().find(|conn| conn.is_idle()) {
+            Some(conn) => conn,
+            None => {
+                trace!("[backend] [{}] no idle connections, skipping health check", self.address);
+                return None;
+            },
+        };
+        trace!("[backend] [{}#{}] running health check", self.address, conn.conn_id);
+
+        let mut ping = EnqueuedRequest::new(0, self.processor.get_ping_message());
+        let rx = ping.get_response_rx().expect("health check ping has no response channel");
+        conn.enqueue(vec![ping]);
+
+        let processor = self.processor.clone();
+        let inner = rx.map_err(|_| ()).map(move |(_, response)| {
+            match response {
+                MessageResponse::Complete(msg) => processor.is_ping_response(&msg),
+                MessageResponse::Failed => false,
+            }
+        });
+        let inner: Box<Future<Item = bool, Error = ()> + Send> = Box::new(inner);
+
+        Some(Timeout::new(inner, Duration::from_millis(self.health_check_timeout_ms)))
+    }
+
+    pub fn is_healthy(&mut self) -> bool {
+        // Checking our health is what brings us out of cooloff, so this is where we find out about it.
+        let epoch = self.health.epoch();
+        let healthy = self.health.is_healthy();
+        if self.health.epoch() != epoch {
+            self.record_health_times();
+        }
+        healthy
+    }
+
+    /// Whether or not we're still establishing our connections ahead of time, and so aren't
+    /// taking requests yet.
+    pub fn is_warming_up(&self) -> bool { self.warming_up }
+
+    /// Checks whether we're done warming up.
+    ///
+    /// When preconnecting, we hold off on taking requests until every connection is established,
+    /// so that the first requests never wait on a connection.  We only wait so long, though: a
+    /// backend we still can't connect to by then takes requests like any other, and its failures
+    /// count against its health as usual.
+    fn poll_warmup(&mut self) {
+        if !self.warming_up {
+            return;
+        }
+
+        let warmup_at = Instant::now() + Duration::from_millis(self.warmup_ms);
+        let deadline = self.warmup_deadline.get_or_insert_with(|| Delay::new(warmup_at));
+        let timed_out = match deadline.poll() {
+            Ok(Async::NotReady) => false,
+            _ => true,
+        };
+
+        if timed_out || self.conns.iter().all(|conn| !conn.preconnect) {
+            if timed_out {
+                debug!("[backend] [{}] gave up waiting on preconnecting", self.address);
+            }
+            self.warming_up = false;
+            self.warmup_deadline = None;
+        }
+    }
+
+    /// Gets what requests sent to this backend record as their route.
+    pub fn get_route(&self) -> &Arc<String> { &self.route }
+
     pub fn get_descriptor(&mut self) -> BackendDescriptor {
         BackendDescriptor {
             idx: 0,
             identifier: self.identifier.clone(),
-            healthy: self.health.is_healthy(),
+            healthy: self.is_healthy(),
+            weight: self.weight,
+        }
+    }
+
+    /// Records when this backend last became healthy, last errored, and last entered and exited
+    /// cooloff, as Unix timestamps in seconds, so that dashboards can tell how long it's been
+    /// stable without digging through logs.  Timestamps that have never been set aren't recorded.
+    fn record_health_times(&mut self) {
+        let times = [
+            ("healthy_since", Some(self.health.healthy_since())),
+            ("last_error_time", self.health.last_error_at()),
+            ("last_cooloff_start_time", self.health.last_cooloff_start_at()),
+            ("last_cooloff_end_time", self.health.last_cooloff_end_at()),
+        ];
+
+        for (name, time) in &times {
+            let secs = match time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+                Some(since_epoch) => since_epoch.as_secs() as i64,
+                None => continue,
+            };
+            self.sink.record_gauge_with_labels(*name, secs, &[("backend", self.identifier.clone())]);
         }
     }
 }
@@ -353,7 +1166,12 @@ where
     type Response = AssignedResponses<P::Message>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        if self.health.is_healthy() {
+        self.poll_warmup();
+        if self.warming_up {
+            return Ok(Async::NotReady);
+        }
+
+        if self.is_healthy() {
             Ok(Async::Ready(()))
         } else {
             Ok(Async::NotReady)
@@ -361,27 +1179,115 @@ where
     }
 
     fn poll_service(&mut self) -> Poll<(), Self::Error> {
+        self.poll_health_check();
+
+        let mut errored = false;
+        let mut probe_result = None;
         for conn in &mut self.conns {
             if conn.poll_service().is_err() {
                 self.health.increment_error();
+                errored = true;
             }
+
+            if let Some(true) = conn.take_outcome() {
+                self.health.record_success();
+            }
+
+            // Batches sent before we went into cooloff can still be finishing up, so we only go by
+            // the batch that the probe itself went out in.
+            // If something else tripped the backend while a probe was out, it went right back into
+            // cooloff, so the probe has nothing left to decide, even if a later one is out now.
+            match conn.take_probe_outcome() {
+                Some((epoch, success)) if epoch == self.health.epoch() && self.health.is_probing() => {
+                    probe_result = Some(success);
+                },
+                _ => {},
+            }
+        }
+
+        if let Some(success) = probe_result {
+            self.health.record_probe_result(success);
         }
 
+        if errored || probe_result.is_some() {
+            self.record_health_times();
+        }
+
+        self.release_held();
+
         Ok(Async::Ready(()))
     }
 
     fn poll_close(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
 
-    fn call(&mut self, req: EnqueuedRequests<P::Message>) -> Self::Future {
-        let result = self.conns[self.conns_index].call(req);
+    fn call(&mut self, mut req: EnqueuedRequests<P::Message>) -> Self::Future {
+        let response = req
+            .as_mut_slice()
+            .iter_mut()
+            .filter_map(|x| x.get_response_rx())
+            .collect::<Vec<_>>();
+
+        for msg in &req {
+            msg.record_route(&self.route);
+        }
 
-        self.conns_index += 1;
-        self.conns_index %= self.conns.len();
+        // Blocking requests never touch our shared connections.  This means they may complete out
+        // of order with respect to the rest of the batch.
+        let (blocking, mut req): (Vec<_>, Vec<_>) = req.into_iter().partition(|x| x.request().is_blocking());
+        for msg in blocking {
+            self.call_dedicated(msg);
+        }
+
+        if req.is_empty() {
+            return ResponseFuture::new(response);
+        }
+
+        // If we just came out of cooloff, we don't know yet if the backend has actually recovered,
+        // so we send it a single request to find out, and hold everything else until we do.
+        if self.health.is_half_open() {
+            if self.health.try_acquire_probe() {
+                debug!("[backend] [{}] sending probe request", self.address);
+                let probe = req.remove(0);
+                self.dispatch_probe(probe);
+            }
 
-        result
+            // Dropping requests fails them, so anything we've no room to hold is failed outright.
+            if self.held_len + req.len() > MAX_HELD_REQUESTS {
+                debug!(
+                    "[backend] [{}] failing {} request(s) with too many already held for probe",
+                    self.address,
+                    req.len()
+                );
+                self.sink.record_counter("held_requests_dropped", req.len() as u64);
+            } else if !req.is_empty() {
+                self.held_len += req.len();
+                self.held.push(req);
+            }
+
+            return ResponseFuture::new(response);
+        }
+
+        self.dispatch(req);
+        ResponseFuture::new(response)
     }
 }
 
+/// Scrambles a key hash before it's used to pick a connection.
+///
+/// The pool picks a backend with the same hash, so every key that reaches a backend shares
+/// something about its hash: with modulo distribution, the same low bits.  Picking a connection
+/// from those same low bits would pile every key onto a handful of connections, so we mix the
+/// hash first, using the MurmurHash3 finalizer, to make it independent of the pool's choice.
+fn get_conn_point(hash: u64) -> u64 {
+    let mut point = hash;
+    point ^= point >> 33;
+    point = point.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    point ^= point >> 33;
+    point = point.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    point ^= point >> 33;
+    point
+}
+
 pub struct ResponseFuture<P, E>
 where
     P: Processor + Send + 'static,
@@ -419,3 +1325,609 @@ where
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> { self.responses.poll().map_err(|e| e.into()) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{memory::MemoryProcessor, redis::RedisProcessor},
+        common::EnqueuedRequest,
+        protocol::redis::RedisMessage,
+        util::metrics::get_counter,
+    };
+    use futures::future::{lazy, poll_fn};
+    use metrics_runtime::Receiver;
+
+    #[test]
+    fn test_request_timeouts() {
+        let mut overrides = HashMap::new();
+        overrides.insert("eval".to_owned(), 5000);
+        overrides.insert("BRPOP".to_owned(), 30000);
+        let timeouts = RequestTimeouts::new(500, overrides);
+
+        assert_eq!(timeouts.get(&RedisMessage::from_inline("GET foo")), 500);
+        assert_eq!(timeouts.get(&RedisMessage::from_inline("EVAL script 0")), 5000);
+        assert_eq!(timeouts.get(&RedisMessage::from_inline("brpop queue 0")), 30000);
+        assert_eq!(timeouts.get(&RedisMessage::from_inline("BLPOP queue 0")), 0);
+
+        // Batches wait on their slowest command, and any command without a timeout means the
+        // batch has no timeout either.
+        let batch = vec![
+            EnqueuedRequest::without_response(RedisMessage::from_inline("GET foo")),
+            EnqueuedRequest::without_response(RedisMessage::from_inline("EVAL script 0")),
+        ];
+        assert_eq!(timeouts.get_batch(&batch), 5000);
+
+        let batch = vec![
+            EnqueuedRequest::without_response(RedisMessage::from_inline("GET foo")),
+            EnqueuedRequest::without_response(RedisMessage::from_inline("BLPOP queue 0")),
+        ];
+        assert_eq!(timeouts.get_batch(&batch), 0);
+    }
+
+    #[test]
+    fn test_serialized_commands() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let address = "127.0.0.1:6379".parse().unwrap();
+        let mut options = HashMap::new();
+        options.insert("conns".to_owned(), "4".to_owned());
+
+        let build = |preserve_order| {
+            Backend::new(
+                address,
+                "backend".to_owned(),
+                RedisProcessor::new(),
+                options.clone(),
+                HashMap::new(),
+                false,
+                preserve_order,
+                receiver.get_sink(),
+            )
+            .expect("failed to build backend")
+            .set_serialized_commands(vec!["incr".to_owned()])
+        };
+        let batch = |cmds: &[&str]| {
+            cmds.iter()
+                .map(|cmd| EnqueuedRequest::without_response(RedisMessage::from_inline(cmd)))
+                .collect::<Vec<_>>()
+        };
+        let pending = |backend: &Backend<RedisProcessor>| {
+            backend.conns.iter().map(|conn| conn.pending_len).collect::<Vec<_>>()
+        };
+
+        // Serialized commands from every batch land on the first connection, even though batches
+        // are otherwise spread over all of our connections.
+        let mut backend = build(true);
+        backend.call(batch(&["GET a", "GET b"]));
+        backend.call(batch(&["GET c"]));
+        backend.call(batch(&["INCR counter"]));
+        backend.call(batch(&["GET d", "incr counter"]));
+        backend.call(batch(&["GET e"]));
+        assert_eq!(pending(&backend), vec![5, 1, 1, 0]);
+
+        // When we're free to reorder, serialized commands go wherever their key does, so they stay
+        // in order without all piling onto the first connection.
+        let mut backend = build(false);
+        let hasher = Fnv64aHasher::new();
+        let conn_idx = |key: &str| get_conn_point(hasher.hash(key.as_bytes())) as usize % 4;
+        let key = (0..)
+            .map(|i| format!("counter{}", i))
+            .find(|key| conn_idx(key) != 0)
+            .expect("no key maps past the first connection");
+        let incr = format!("INCR {}", key);
+        backend.call(batch(&[incr.as_str(), incr.as_str()]));
+        backend.call(batch(&[incr.as_str()]));
+        assert_eq!(pending(&backend)[conn_idx(&key)], 3);
+        assert_eq!(pending(&backend).iter().sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn test_keys_spread_over_connections() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let mut options = HashMap::new();
+        options.insert("conns".to_owned(), "4".to_owned());
+
+        let mut backend = Backend::new(
+            "127.0.0.1:6379".parse().unwrap(),
+            "backend".to_owned(),
+            RedisProcessor::new(),
+            options,
+            HashMap::new(),
+            false,
+            false,
+            receiver.get_sink(),
+        )
+        .expect("failed to build backend");
+
+        // These are the keys that a modulo pool of four backends would route to its first backend,
+        // which all share the same low bits of their hash.  They should still use every connection.
+        let hasher = Fnv64aHasher::new();
+        let batch = (0..1000)
+            .map(|i| format!("key{}", i))
+            .filter(|key| hasher.hash(key.as_bytes()) % 4 == 0)
+            .map(|key| EnqueuedRequest::without_response(RedisMessage::from_inline(&format!("GET {}", key))))
+            .collect::<Vec<_>>();
+        let count = batch.len();
+        backend.call(batch);
+
+        let pending = backend.conns.iter().map(|conn| conn.pending_len).collect::<Vec<_>>();
+        assert_eq!(pending.iter().sum::<usize>(), count);
+        assert!(
+            pending.iter().all(|len| *len > count / 8),
+            "keys piled onto a subset of connections: {:?}",
+            pending
+        );
+    }
+
+    #[test]
+    fn test_abandoned_blocking_request_releases_connection() {
+        // The memory backend's listener accepts connections but never answers, so a blocking
+        // request sent to it can only ever finish by being abandoned.
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let mut backend = Backend::new(
+            address,
+            "backend".to_owned(),
+            RedisProcessor::new(),
+            HashMap::new(),
+            HashMap::new(),
+            false,
+            true,
+            receiver.get_sink(),
+        )
+        .expect("failed to build backend");
+        let in_flight = backend.blocking_in_flight.clone();
+
+        // The runtime only returns once the dedicated connection is done with, so run it on its
+        // own thread, where it can hang without taking the test with it.
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio_io_pool::run(lazy(move || {
+                let request = EnqueuedRequest::new(0, RedisMessage::from_inline("BLPOP queue 0"));
+                let response = backend.call(vec![request]);
+                assert_eq!(backend.blocking_in_flight.load(Ordering::SeqCst), 1);
+
+                // The client goes away before the backend ever answers.
+                drop(response);
+                ok(())
+            }));
+            let _ = tx.send(());
+        });
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(()));
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    fn build_memory_backend(
+        processor: &MemoryProcessor, address: SocketAddr, preconnect: bool, sink: MetricSink,
+    ) -> Backend<MemoryProcessor> {
+        let mut options = HashMap::new();
+        options.insert("conns".to_owned(), "2".to_owned());
+        options.insert("preconnect".to_owned(), preconnect.to_string());
+        options.insert("cooloff_error_limit".to_owned(), "1".to_owned());
+
+        Backend::new(
+            address,
+            "backend".to_owned(),
+            processor.clone(),
+            options,
+            HashMap::new(),
+            false,
+            true,
+            sink,
+        )
+        .expect("failed to build backend")
+    }
+
+    /// Trips the backend, waits for it to come back out of cooloff, half-open, runs `setup`, and
+    /// then sends it the given commands, the first of which goes out as the probe.
+    ///
+    /// Returns whether each command got a response, and whether the backend ended up healthy.
+    fn run_half_open<F>(mut backend: Backend<MemoryProcessor>, setup: F, cmds: Vec<String>) -> (Vec<bool>, bool)
+    where
+        F: FnOnce(&mut Backend<MemoryProcessor>) + Send + 'static,
+    {
+        // A short cooloff, and enough room for errors that only the probe decides where we end up.
+        backend.health = BackendHealth::new(true, 50, 5);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut setup = Some(setup);
+        let mut tripped = false;
+        let mut response = None;
+        tokio_io_pool::run(lazy(move || {
+            poll_fn(move || {
+                if !tripped {
+                    backend.health.trip();
+                    tripped = true;
+                }
+
+                backend.poll_service().map_err(|_| ())?;
+                if response.is_none() {
+                    // Checking our health is what brings us back out of cooloff once it's over.
+                    if !backend.is_healthy() {
+                        return Ok(Async::NotReady);
+                    }
+
+                    let setup = setup.take().expect("setup already run");
+                    setup(&mut backend);
+
+                    let batch = cmds
+                        .iter()
+                        .enumerate()
+                        .map(|(i, cmd)| EnqueuedRequest::new(i, RedisMessage::from_inline(cmd)))
+                        .collect::<Vec<_>>();
+                    response = Some(backend.call(batch));
+                    futures::task::current().notify();
+                    return Ok(Async::NotReady);
+                }
+
+                let responses = try_ready!(response.as_mut().unwrap().poll().map_err(|_| ()));
+                let completed = responses
+                    .into_iter()
+                    .map(|(_, response)| {
+                        match response {
+                            MessageResponse::Complete(_) => true,
+                            MessageResponse::Failed => false,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let healthy = backend.is_healthy() && !backend.health.is_half_open();
+                let _ = tx.send((completed, healthy));
+                Ok(Async::Ready(()))
+            })
+        }));
+
+        rx.recv().expect("half-open backend never finished")
+    }
+
+    fn cmds(first: &str, rest: usize) -> Vec<String> {
+        let mut cmds = vec![first.to_owned()];
+        cmds.extend((0..rest).map(|i| format!("GET key{}", i)));
+        cmds
+    }
+
+    #[test]
+    fn test_successful_probe_releases_held_requests() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        let backend = build_memory_backend(&processor, address, false, receiver.get_sink());
+
+        let (completed, healthy) = run_half_open(backend, |_| {}, cmds("SET foo bar", MAX_HELD_REQUESTS));
+        assert!(completed.iter().all(|completed| *completed));
+        assert!(healthy);
+        assert_eq!(processor.get(&address, b"foo"), Some(b"bar".to_vec()));
+    }
+
+    #[test]
+    fn test_failed_probe_fails_held_requests() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        let backend = build_memory_backend(&processor, address, false, receiver.get_sink());
+
+        let processor2 = processor.clone();
+        let setup = move |_: &mut Backend<MemoryProcessor>| processor2.stop_backend(&address);
+        let (completed, healthy) = run_half_open(backend, setup, cmds("SET foo bar", 2));
+        assert_eq!(completed, vec![false, false, false]);
+        assert!(!healthy);
+    }
+
+    #[test]
+    fn test_probe_outcome_ignores_other_batches() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        let backend = build_memory_backend(&processor, address, false, receiver.get_sink());
+
+        // A batch from before the cooloff is still queued on the second connection, and fails,
+        // while the probe goes out on the first connection, and succeeds.
+        let dead = processor.add_backend();
+        processor.stop_backend(&dead);
+        let setup = move |backend: &mut Backend<MemoryProcessor>| {
+            backend.conns[1].address = dead;
+            backend.conns[1].enqueue(vec![EnqueuedRequest::without_response(RedisMessage::from_inline("GET foo"))]);
+        };
+        let (completed, healthy) = run_half_open(backend, setup, cmds("SET foo bar", 0));
+        assert_eq!(completed, vec![true]);
+        assert!(healthy);
+    }
+
+    #[test]
+    fn test_held_requests_are_capped() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        let backend = build_memory_backend(&processor, address, false, receiver.get_sink());
+
+        // Anything that doesn't fit is failed right away, without waiting on the probe.
+        let (completed, healthy) = run_half_open(backend, |_| {}, cmds("SET foo bar", MAX_HELD_REQUESTS + 1));
+        assert!(completed[0]);
+        assert!(completed[1..].iter().all(|completed| !*completed));
+        assert!(healthy);
+    }
+
+    #[test]
+    fn test_startup_grace_period_retries_quickly() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        processor.stop_backend(&address);
+
+        // The backend isn't up yet, and a full cooloff would keep us away from it for a minute.
+        let mut options = HashMap::new();
+        options.insert("cooloff_timeout_ms".to_owned(), "60000".to_owned());
+        options.insert("startup_grace_period_ms".to_owned(), "60000".to_owned());
+        options.insert("startup_grace_retry_ms".to_owned(), "10".to_owned());
+        let mut backend = Backend::new(
+            address,
+            "backend".to_owned(),
+            processor.clone(),
+            options,
+            HashMap::new(),
+            false,
+            true,
+            receiver.get_sink(),
+        )
+        .expect("failed to build backend");
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut response = None;
+        let mut failed_at = None;
+        tokio_io_pool::run(lazy(move || {
+            poll_fn(move || {
+                backend.poll_service().map_err(|_| ())?;
+                if response.is_none() {
+                    let request = EnqueuedRequest::new(0, RedisMessage::from_inline("GET foo"));
+                    response = Some(backend.call(vec![request]));
+                    futures::task::current().notify();
+                    return Ok(Async::NotReady);
+                }
+
+                // The failed connection puts the backend into cooloff, but only for a moment.
+                if failed_at.is_none() {
+                    try_ready!(response.as_mut().unwrap().poll().map_err(|_| ()));
+                    if backend.is_healthy() {
+                        let _ = tx.send(None);
+                        return Ok(Async::Ready(()));
+                    }
+                    failed_at = Some(Instant::now());
+                }
+
+                if !backend.is_healthy() {
+                    return Ok(Async::NotReady);
+                }
+
+                let _ = tx.send(failed_at.map(|failed_at| failed_at.elapsed()));
+                Ok(Async::Ready(()))
+            })
+        }));
+
+        let retried_after = rx.recv().expect("backend never came back out of cooloff");
+        assert!(retried_after.expect("backend never went into cooloff") < Duration::from_secs(5));
+    }
+
+    /// Drives the backend until a health check trips it, and returns whether each of its
+    /// connections still had something in flight at that point.
+    fn run_until_health_check_trips<P>(mut backend: Backend<P>) -> Vec<bool>
+    where
+        P: Processor + Clone + Send + 'static,
+        P::Message: Message + Clone + Send + 'static,
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            poll_fn(move || {
+                backend.poll_service().map_err(|_| ())?;
+                if backend.health.last_cooloff_start_at().is_none() {
+                    return Ok(Async::NotReady);
+                }
+
+                let _ = tx.send(backend.conns.iter().map(|conn| conn.current.is_some()).collect());
+                Ok(Async::Ready(()))
+            })
+        }));
+
+        rx.recv().expect("health check never tripped the backend")
+    }
+
+    fn get_health_check_options(preconnect: bool) -> HashMap<String, String> {
+        let mut options = HashMap::new();
+        options.insert("preconnect".to_owned(), preconnect.to_string());
+        options.insert("cooloff_timeout_ms".to_owned(), "50".to_owned());
+        options.insert("health_check_interval_ms".to_owned(), "10".to_owned());
+        options.insert("health_check_timeout_ms".to_owned(), "50".to_owned());
+        options
+    }
+
+    #[test]
+    fn test_failed_health_check_trips_backend() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        processor.stop_backend(&address);
+
+        // The ping can't even connect, and the failed connection goes down along with it.
+        let backend = Backend::new(
+            address,
+            "backend".to_owned(),
+            processor,
+            get_health_check_options(false),
+            HashMap::new(),
+            false,
+            true,
+            receiver.get_sink(),
+        )
+        .expect("failed to build backend");
+        assert_eq!(run_until_health_check_trips(backend), vec![false]);
+    }
+
+    #[test]
+    fn test_timed_out_health_check_trips_backend() {
+        // The memory backend's listener accepts connections but never answers, so a real ping sent
+        // to it can only ever time out.
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+
+        // The ping goes out over the connection that was already sitting idle, where it's still
+        // waiting on a reply when the check gives up on it.
+        let backend = Backend::new(
+            address,
+            "backend".to_owned(),
+            RedisProcessor::new(),
+            get_health_check_options(true),
+            HashMap::new(),
+            false,
+            true,
+            receiver.get_sink(),
+        )
+        .expect("failed to build backend");
+        assert_eq!(run_until_health_check_trips(backend), vec![true]);
+    }
+
+    #[test]
+    fn test_preconnect() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+
+        // Without preconnecting, nothing is connected until requests show up.
+        let mut backend = build_memory_backend(&processor, address, false, receiver.get_sink());
+        assert!(backend.poll_service().is_ok());
+        assert!(backend.conns.iter().all(|conn| conn.stream.is_none()));
+
+        // With it, every connection is established before any requests show up.
+        let mut backend = build_memory_backend(&processor, address, true, receiver.get_sink());
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            poll_fn(move || {
+                backend.poll_service().map_err(|_| ())?;
+                if backend.conns.iter().any(|conn| conn.stream.is_none()) {
+                    return Ok(Async::NotReady);
+                }
+
+                // Since the connection is already there, the first request is done as soon as it's
+                // sent, rather than first having to wait for us to connect.
+                let request = EnqueuedRequest::new(0, RedisMessage::from_inline("SET foo bar"));
+                let mut response = backend.call(vec![request]);
+                backend.poll_service().map_err(|_| ())?;
+                let _ = tx.send(response.poll().map(|result| result.is_ready()).unwrap_or(false));
+                Ok(Async::Ready(()))
+            })
+        }));
+
+        assert_eq!(rx.recv(), Ok(true));
+        assert_eq!(processor.get(&address, b"foo"), Some(b"bar".to_vec()));
+    }
+
+    /// Drives a preconnecting backend until it's ready to take requests, and returns whether every
+    /// one of its connections had been established by then.
+    fn run_until_warmed_up(mut backend: Backend<MemoryProcessor>) -> bool {
+        assert!(backend.is_warming_up());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            poll_fn(move || {
+                backend.poll_service().map_err(|_| ())?;
+                try_ready!(backend.poll_ready().map_err(|_| ()));
+
+                let _ = tx.send(backend.conns.iter().all(|conn| conn.stream.is_some()));
+                Ok(Async::Ready(()))
+            })
+        }));
+
+        rx.recv().expect("backend never warmed up")
+    }
+
+    #[test]
+    fn test_preconnect_warmup() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        let dead = processor.add_backend();
+        processor.stop_backend(&dead);
+
+        // Without preconnecting, there's nothing to wait on.
+        let backend = build_memory_backend(&processor, address, false, receiver.get_sink());
+        assert!(!backend.is_warming_up());
+
+        // With it, requests wait until every connection is up...
+        let backend = build_memory_backend(&processor, address, true, receiver.get_sink());
+        assert!(run_until_warmed_up(backend));
+
+        // ...but not forever, when the backend can't be reached yet.
+        let mut backend = build_memory_backend(&processor, dead, true, receiver.get_sink());
+        backend.warmup_ms = 50;
+        assert!(!run_until_warmed_up(backend));
+    }
+
+    #[test]
+    fn test_idle_connections_are_pinged() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+
+        let mut options = HashMap::new();
+        options.insert("preconnect".to_owned(), "true".to_owned());
+        options.insert("idle_ping_ms".to_owned(), "20".to_owned());
+        let mut backend = Backend::new(
+            address,
+            "backend".to_owned(),
+            processor.clone(),
+            options,
+            HashMap::new(),
+            false,
+            true,
+            receiver.get_sink(),
+        )
+        .expect("failed to build backend");
+
+        // With nothing else going on, the connection gets pinged every time it's been idle for long
+        // enough, so it should have been pinged a few times over.
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            let mut done = Delay::new(Instant::now() + Duration::from_millis(100));
+            poll_fn(move || {
+                backend.poll_service().map_err(|_| ())?;
+                try_ready!(done.poll().map_err(|_| ()));
+
+                let conn = &backend.conns[0];
+                let _ = tx.send((conn.stream.is_some(), conn.stream_requests));
+                Ok(Async::Ready(()))
+            })
+        }));
+
+        // Pings don't count as requests served, and the connection was kept, not torn down, for them.
+        assert_eq!(rx.recv(), Ok((true, 0)));
+        assert!(get_counter(&receiver, "backend.idle_pings") >= 2);
+        assert_eq!(get_counter(&receiver, "backend.connects"), 1);
+        assert_eq!(get_counter(&receiver, "backend.disconnects"), 0);
+    }
+
+    #[test]
+    fn test_failed_preconnect_does_not_trip_cooloff() {
+        let processor = MemoryProcessor::new();
+        let address = processor.add_backend();
+        processor.stop_backend(&address);
+
+        // Every attempt is refused, but since no requests are waiting on them, the backend stays
+        // healthy and keeps trying, backing off a little more every time.
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let mut backend = build_memory_backend(&processor, address, true, receiver.get_sink());
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            poll_fn(move || {
+                backend.poll_service().map_err(|_| ())?;
+                if backend.conns.iter().all(|conn| conn.preconnect_backoff_ms >= 4 * PRECONNECT_RETRY_MIN_MS) {
+                    let _ = tx.send((backend.is_healthy(), backend.conns.iter().any(|conn| conn.preconnect)));
+                    return Ok(Async::Ready(()));
+                }
+
+                Ok(Async::NotReady)
+            })
+        }));
+
+        assert_eq!(rx.recv(), Ok((true, true)));
+    }
+}
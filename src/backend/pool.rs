@@ -22,32 +22,115 @@ use super::{
     hasher::{configure_hasher, KeyHasher},
 };
 use crate::{
-    backend::{processor::Processor, Backend, BackendError, PoolError, ResponseFuture},
-    common::{AssignedResponses, EnqueuedRequests, Message},
+    backend::{
+        message_queue::MessageState, processor::Processor, Backend, BackendError, HedgeDelay, PoolError,
+        ResponseFuture,
+    },
+    common::{AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, MessageResponse},
     conf::PoolConfiguration,
     errors::CreationError,
-    util::IntegerMappedVec,
+    util::{FutureExt, IntegerMappedVec},
 };
+use bytes::BytesMut;
 use futures::{
-    future::{join_all, JoinAll},
+    future::{join_all, ok, poll_fn, Either, JoinAll},
     prelude::*,
 };
 use metrics_runtime::Sink as MetricSink;
-use std::{collections::HashMap, marker::PhantomData};
+use rand::{thread_rng, Rng};
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    timer::Delay,
+};
 use tower_direct_service::DirectService;
 
 type DistributorFutureSafe = Box<Distributor + Send + 'static>;
 type KeyHasherFutureSafe = Box<KeyHasher + Send + 'static>;
 
+// How many keys we sample to estimate how much of the keyspace moves when the distribution changes.
+const REMAP_SAMPLE_KEYS: usize = 1024;
+
+lazy_static! {
+    // What requests sent to every backend in the pool record as their route.
+    static ref EVERY_BACKEND: Arc<String> = Arc::new("*".to_owned());
+}
+
+/// Explicit key to backend mappings that take precedence over the distributor.
+#[derive(Default)]
+pub struct KeyOverrides {
+    exact: HashMap<Vec<u8>, usize>,
+    prefixes: Vec<(Vec<u8>, usize)>,
+}
+
+impl KeyOverrides {
+    pub fn new() -> KeyOverrides { KeyOverrides::default() }
+
+    pub fn add(&mut self, key: &str, backend_idx: usize) {
+        if key.ends_with('*') {
+            let prefix = key[..key.len() - 1].as_bytes().to_vec();
+            self.prefixes.push((prefix, backend_idx));
+
+            // Keep the longest prefixes first so that the most specific override wins.
+            self.prefixes.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        } else {
+            self.exact.insert(key.as_bytes().to_vec(), backend_idx);
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<usize> {
+        if self.exact.is_empty() && self.prefixes.is_empty() {
+            return None;
+        }
+
+        self.exact.get(key).cloned().or_else(|| {
+            self.prefixes
+                .iter()
+                .find(|(prefix, _)| key.starts_with(prefix))
+                .map(|(_, idx)| *idx)
+        })
+    }
+}
+
+/// A read that's waiting too long on its backend, to be sent to another backend.
+struct HedgeRequest<M> {
+    request: M,
+    exclude: usize,
+    tx: oneshot::Sender<M>,
+}
+
+/// A pool of backends, with requests spread across them by key.
+///
+/// The pool is ready as long as any of its backends are.  When none of them are, typically because
+/// they're all in cooloff, the pool still reports itself as ready, rather than leaving callers to
+/// wait on it: it opens its circuit instead, and fails every request it's given with an error, right
+/// away, until a backend is available again.  The one exception is when backends are still warming
+/// up, which is over soon enough that the pool waits on them instead.
 pub struct BackendPool<P>
 where
     P: Processor + Clone + Send + 'static,
     P::Message: Message + Send + 'static,
 {
+    processor: P,
     distributor: DistributorFutureSafe,
     key_hasher: KeyHasherFutureSafe,
+    key_overrides: KeyOverrides,
+    hash_tags: bool,
+    allow_fanout_keys: bool,
     backends: Vec<Backend<P>>,
     noreply: bool,
+    verify_rate: f64,
+    hedge_delay: Option<HedgeDelay>,
+    hedges_tx: mpsc::UnboundedSender<HedgeRequest<P::Message>>,
+    hedges_rx: mpsc::UnboundedReceiver<HedgeRequest<P::Message>>,
+    members: Option<Vec<usize>>,
+    circuit_open: bool,
     epoch: u64,
     sink: MetricSink,
 }
@@ -58,14 +141,25 @@ where
     P::Message: Message + Send + 'static,
 {
     pub fn new(
-        backends: Vec<Backend<P>>, distributor: DistributorFutureSafe, key_hasher: KeyHasherFutureSafe, noreply: bool,
-        sink: MetricSink,
+        processor: P, backends: Vec<Backend<P>>, distributor: DistributorFutureSafe, key_hasher: KeyHasherFutureSafe,
+        key_overrides: KeyOverrides, noreply: bool, verify_rate: f64, sink: MetricSink,
     ) -> BackendPool<P> {
+        let (hedges_tx, hedges_rx) = mpsc::unbounded_channel();
         let mut pool = BackendPool {
+            processor,
             distributor,
             key_hasher,
+            key_overrides,
+            hash_tags: false,
+            allow_fanout_keys: false,
             backends,
             noreply,
+            verify_rate,
+            hedge_delay: None,
+            hedges_tx,
+            hedges_rx,
+            members: None,
+            circuit_open: false,
             epoch: 0,
             sink,
         };
@@ -73,6 +167,27 @@ where
         pool
     }
 
+    /// Sets how long to wait on a read before hedging it, if at all.
+    pub fn set_hedge_delay(mut self, hedge_delay: Option<HedgeDelay>) -> Self {
+        self.hedge_delay = hedge_delay;
+        self
+    }
+
+    /// Sets whether only the hash tag of a key, if it has one, is used to distribute it.
+    pub fn set_hash_tags(mut self, hash_tags: bool) -> Self {
+        self.hash_tags = hash_tags;
+        self
+    }
+
+    /// Sets whether requests that list keys, like `KEYS`, are allowed to go to every backend.
+    ///
+    /// Only sharded pools send them to every backend: other pools send them to a single backend,
+    /// like any other request, whether or not this is set.
+    pub fn set_allow_fanout_keys(mut self, allow_fanout_keys: bool) -> Self {
+        self.allow_fanout_keys = allow_fanout_keys;
+        self
+    }
+
     pub fn regenerate_distribution(&mut self) {
         let descriptors = self
             .backends
@@ -84,9 +199,349 @@ where
                 descriptor
             })
             .filter(|backend| backend.healthy)
-            .collect();
+            .collect::<Vec<_>>();
+
+        // If the set of backends in the distribution is actually changing, keys are about to move
+        // between backends, so sample where keys go now to compare against where they go after.
+        let members = descriptors.iter().map(|backend| backend.idx).collect::<Vec<_>>();
+        let previous = match self.members.replace(members.clone()) {
+            Some(ref previous) if previous == &members => None,
+            previous => previous,
+        };
+        let before = match previous {
+            Some(_) if self.distributor.is_key_affine() => {
+                Some(sample_distribution(&*self.distributor, &*self.key_hasher))
+            },
+            _ => None,
+        };
+
         self.distributor.update(descriptors);
         self.sink.record_counter("distribution_updated", 1);
+
+        if let Some(previous) = previous {
+            let added = members.iter().filter(|idx| !previous.contains(idx)).count();
+            let removed = previous.iter().filter(|idx| !members.contains(idx)).count();
+            self.sink.record_counter("remap_events", 1);
+
+            match before {
+                Some(before) => {
+                    let after = sample_distribution(&*self.distributor, &*self.key_hasher);
+                    info!(
+                        "[pool] backends changed ({} added, {} removed): ~{:.1}% of keys remapped",
+                        added,
+                        removed,
+                        get_remapped_fraction(&before, &after) * 100.0
+                    );
+                },
+                None => info!("[pool] backends changed ({} added, {} removed)", added, removed),
+            }
+        }
+    }
+
+    pub fn get_backend_index(&self, key: &[u8]) -> Option<usize> {
+        match self.key_overrides.get(key) {
+            Some(idx) => Some(idx),
+            None => {
+                let hashed = if self.hash_tags { get_hash_tag(key) } else { key };
+                self.distributor.choose(self.key_hasher.hash(hashed))
+            },
+        }
+    }
+
+    fn respond_with_error(
+        &self, msg: &mut EnqueuedRequest<P::Message>, error: &str,
+    ) -> Option<ResponseFuture<P, BackendError>> {
+        msg.get_response_rx().map(|rx| {
+            msg.fulfill(self.processor.get_error_message_str(error));
+            ResponseFuture::new(vec![rx])
+        })
+    }
+
+    /// Sends the given request to every backend in the pool, merging their responses into one.
+    ///
+    /// Backends that are out of the pool still get the request: a command like `FLUSHALL` that
+    /// quietly skipped a backend would leave it out of sync with the rest of the pool, so it's
+    /// better for the whole command to fail.
+    ///
+    /// This is only for sharded pools.  Where every backend holds every key, the backends are
+    /// replicas of each other, and a single one of them has the full picture.
+    fn broadcast(&mut self, mut msg: EnqueuedRequest<P::Message>) -> Option<ResponseFuture<P, BackendError>> {
+        let rx = msg.get_response_rx()?;
+        msg.record_route(&EVERY_BACKEND);
+
+        let responses = self
+            .backends
+            .iter_mut()
+            .map(|backend| backend.call(vec![EnqueuedRequest::new(0, msg.request().clone())]))
+            .collect::<Vec<_>>();
+        let count = responses.len();
+        let cmd = BytesMut::from(msg.request().command().unwrap_or_default());
+
+        let processor = self.processor.clone();
+        let task = join_all(responses)
+            .then(move |result| {
+                let merged = result.ok().and_then(|results| {
+                    let fragments = results
+                        .into_iter()
+                        .flatten()
+                        .enumerate()
+                        .map(|(i, (_, response))| {
+                            match response {
+                                MessageResponse::Complete(fragment) => {
+                                    Some((MessageState::Fragmented(cmd.clone(), i, count), fragment))
+                                },
+                                MessageResponse::Failed => None,
+                            }
+                        })
+                        .collect::<Option<Vec<_>>>()?;
+
+                    processor.defragment_messages(fragments).ok()
+                });
+
+                let response = merged.unwrap_or_else(|| {
+                    processor.get_error_message_str("failed to receive response from every backend")
+                });
+                msg.fulfill(response);
+                Ok::<(), ()>(())
+            })
+            .untyped();
+
+        tokio::spawn(task);
+        Some(ResponseFuture::new(vec![rx]))
+    }
+
+    /// Sends one page of a pool-wide keyspace scan to the backend the client's cursor is on.
+    fn scan(
+        &mut self, mut msg: EnqueuedRequest<P::Message>, backend_idx: usize, request: P::Message,
+    ) -> Option<ResponseFuture<P, BackendError>> {
+        let rx = msg.get_response_rx()?;
+        msg.record_route(self.backends[backend_idx].get_route());
+
+        let count = self.backends.len();
+        let response = self.backends[backend_idx].call(vec![EnqueuedRequest::new(0, request)]);
+
+        let processor = self.processor.clone();
+        let task = response
+            .then(move |result| {
+                let response = match result.ok().and_then(|results| results.into_iter().next()) {
+                    Some((_, MessageResponse::Complete(response))) => {
+                        processor.merge_scan(response, backend_idx, count)
+                    },
+                    _ => processor.get_error_message_str("failed to receive response from backend"),
+                };
+                msg.fulfill(response);
+                Ok::<(), ()>(())
+            })
+            .untyped();
+
+        tokio::spawn(task);
+        Some(ResponseFuture::new(vec![rx]))
+    }
+
+    fn keys_colocated(&self, msg: &EnqueuedRequest<P::Message>) -> bool {
+        if !self.distributor.is_key_affine() {
+            return true;
+        }
+
+        match msg.request().colocated_keys() {
+            None => true,
+            Some(keys) => {
+                let mut backend_idxs = keys.into_iter().map(|key| self.get_backend_index(key));
+                match backend_idxs.next() {
+                    None => true,
+                    Some(first) => backend_idxs.all(|idx| idx == first),
+                }
+            },
+        }
+    }
+
+    /// Gets the hedge delay for the given request, if it should be hedged.
+    ///
+    /// Only reads are hedged, since a write sent to two backends would be applied twice, and reads
+    /// for keys pinned to a specific backend are left alone, since no other backend can serve them.
+    /// For the same reason, nothing is hedged when the distributor shards keys across backends: any
+    /// other backend would quickly answer with a miss, and that could beat the real response.
+    fn get_hedge_delay(&self, msg: &EnqueuedRequest<P::Message>) -> Option<HedgeDelay> {
+        if self.noreply || self.backends.len() < 2 || !msg.request().is_read() {
+            return None;
+        }
+
+        if self.distributor.is_key_affine() {
+            return None;
+        }
+
+        if self.key_overrides.get(msg.key()).is_some() {
+            return None;
+        }
+
+        self.hedge_delay.clone()
+    }
+
+    /// Sends the given read to the given backend, hedging it if the backend is slow to respond.
+    ///
+    /// If the backend hasn't responded by the time the hedge delay is up, the read is also sent to
+    /// another backend, and the client gets whichever response comes back first.  The losing
+    /// request is cancelled: if it's still queued up behind other requests, it's never sent at
+    /// all, and otherwise its response is simply dropped when it arrives.
+    fn hedge(
+        &mut self, mut msg: EnqueuedRequest<P::Message>, backend_idx: usize, hedge_delay: HedgeDelay,
+    ) -> Option<ResponseFuture<P, BackendError>> {
+        let rx = msg.get_response_rx()?;
+
+        // Whichever backend answers first, the request was routed to the first one.
+        msg.record_route(self.backends[backend_idx].get_route());
+
+        let start = Instant::now();
+        let original = EnqueuedRequest::new(0, msg.request().clone()).set_cancellable(true);
+        let primary = self.backends[backend_idx]
+            .call(vec![original])
+            .then(|result| ok::<_, ()>(get_first_response(result)));
+        let deadline = Delay::new(start + hedge_delay.get());
+
+        let request = msg.request().clone();
+        let mut hedges = self.hedges_tx.clone();
+        let task = primary
+            .select2(deadline)
+            .then(move |result| {
+                match result {
+                    Ok(Either::A((response, _))) => Either::A(ok(response)),
+                    Err(Either::A((_, _))) => Either::A(ok(None)),
+                    Ok(Either::B((_, primary))) | Err(Either::B((_, primary))) => {
+                        // The pool owns the backends, so it has to send the hedged request for us.
+                        // If it can't, we'll just keep waiting on the primary.
+                        let (tx, rx) = oneshot::channel();
+                        let _ = hedges.try_send(HedgeRequest {
+                            request,
+                            exclude: backend_idx,
+                            tx,
+                        });
+                        let hedged = rx.then(|result| ok::<_, ()>(result.ok()));
+
+                        // Take the first actual response we get, if either request fails.
+                        let race = primary.select2(hedged).then(|result| {
+                            match result {
+                                Ok(Either::A((Some(response), _))) | Ok(Either::B((Some(response), _))) => {
+                                    Either::A(ok(Some(response)))
+                                },
+                                Ok(Either::A((None, hedged))) => Either::B(Either::A(hedged)),
+                                Ok(Either::B((None, primary))) => Either::B(Either::B(primary)),
+                                Err(_) => Either::A(ok(None)),
+                            }
+                        });
+                        Either::B(race)
+                    },
+                }
+            })
+            .then(move |result| {
+                // Any request that we don't get a response for is failed by its drop guard.
+                if let Ok(Some(response)) = result {
+                    hedge_delay.record(start.elapsed());
+                    msg.fulfill(response);
+                }
+                ok::<(), ()>(())
+            });
+
+        tokio::spawn(task);
+        Some(ResponseFuture::new(vec![rx]))
+    }
+
+    /// Sends a hedged read to the next healthy backend after the one it was originally sent to.
+    fn send_hedge(&mut self, hedge: HedgeRequest<P::Message>) {
+        let backend_idx = match self.get_other_backend(hedge.exclude) {
+            Some(idx) => idx,
+            None => return,
+        };
+
+        self.sink.record_counter("hedged_requests", 1);
+
+        let request = EnqueuedRequest::new(0, hedge.request).set_cancellable(true);
+        let mut response = self.backends[backend_idx].call(vec![request]);
+        let mut tx = Some(hedge.tx);
+        let task = poll_fn(move || {
+            // If the original request won the race, we give up on ours, which cancels it.
+            if let Some(Ok(Async::Ready(()))) = tx.as_mut().map(|tx| tx.poll_close()) {
+                return Ok(Async::Ready(()));
+            }
+
+            let result = match response.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(responses)) => Ok(responses),
+                Err(e) => Err(e),
+            };
+            if let (Some(tx), Some(response)) = (tx.take(), get_first_response(result)) {
+                let _ = tx.send(response);
+            }
+            Ok(Async::Ready(()))
+        });
+        tokio::spawn(task);
+    }
+
+    fn should_verify(&self, msg: &EnqueuedRequest<P::Message>) -> bool {
+        self.verify_rate > 0.0
+            && !self.distributor.is_key_affine()
+            && self.backends.len() > 1
+            && msg.request().is_read()
+            && thread_rng().gen_bool(self.verify_rate)
+    }
+
+    /// Sends the given read to the given backend, and a copy of it to another replica, and compares
+    /// the responses.
+    ///
+    /// This is purely for detecting replicas that have silently diverged: the client gets the
+    /// response from the backend the read was routed to, as soon as it arrives, and the copy's
+    /// response is discarded after comparison.  A mismatch between the two is tracked by the
+    /// `replica_divergence` counter, while either read erroring or failing is tracked by
+    /// `replica_verify_failures`.
+    fn verify(
+        &mut self, mut msg: EnqueuedRequest<P::Message>, backend_idx: usize, replica_idx: usize,
+    ) -> Option<ResponseFuture<P, BackendError>> {
+        let rx = msg.get_response_rx()?;
+        msg.record_route(self.backends[backend_idx].get_route());
+
+        // The client's response is passed along for comparison on its way back to the client.
+        let request = msg.request().clone();
+        let (tx, routed) = oneshot::channel();
+        let primary = self.backends[backend_idx]
+            .call(vec![EnqueuedRequest::new(0, request.clone())])
+            .then(move |result| {
+                // Any request that we don't get a response for is failed by its drop guard.
+                if let Some(response) = get_first_response(result) {
+                    let _ = tx.send(response.clone());
+                    msg.fulfill(response);
+                }
+                ok::<(), ()>(())
+            });
+        tokio::spawn(primary);
+
+        let routed = routed.then(|result| ok::<_, ()>(result.ok()));
+        let replica = self.backends[replica_idx]
+            .call(vec![EnqueuedRequest::new(0, request)])
+            .then(|result| ok::<_, ()>(get_first_response(result)));
+
+        let mut sink = self.sink.clone();
+        let task = routed.join(replica).map(move |results| match results {
+            (Some(routed), Some(replica)) => {
+                if routed.into_buf() != replica.into_buf() {
+                    sink.record_counter("replica_divergence", 1);
+                }
+            },
+            (routed, replica) => {
+                let failures = routed.is_none() as u64 + replica.is_none() as u64;
+                sink.record_counter("replica_verify_failures", failures);
+            },
+        });
+        tokio::spawn(task);
+
+        Some(ResponseFuture::new(vec![rx]))
+    }
+
+    /// Gets the next healthy backend after the given one, to send a copy of a read routed to it.
+    fn get_other_backend(&mut self, backend_idx: usize) -> Option<usize> {
+        let count = self.backends.len();
+        let backends = &mut self.backends;
+        (1..count)
+            .map(|i| (backend_idx + i) % count)
+            .find(|idx| backends[*idx].is_healthy())
     }
 }
 
@@ -116,10 +571,30 @@ where
             epoch += backend.health().epoch();
         }
 
-        if !any_ready {
+        // Backends that are still warming up will be taking requests shortly, so if that's all
+        // that's holding us up, we wait on them rather than failing requests.
+        if !any_ready && self.backends.iter().any(|backend| backend.is_warming_up()) {
             return Ok(Async::NotReady);
         }
 
+        // If every backend is out of the pool, there's nowhere to send anything.  Rather than
+        // making clients wait until a backend comes back, we open the circuit: we stay ready, and
+        // fail requests as they come in without going anywhere near the backends.  Callers can't
+        // tell an open circuit apart from a healthy pool here, so they must not take being ready to
+        // mean that any backend is.
+        if !any_ready {
+            if !self.circuit_open {
+                warn!("[pool] no backends available, failing all requests until one comes back");
+                self.circuit_open = true;
+            }
+            return Ok(Async::Ready(()));
+        }
+
+        if self.circuit_open {
+            info!("[pool] backends available again, resuming requests");
+            self.circuit_open = false;
+        }
+
         if self.epoch != epoch {
             debug!("regenerating distribution");
             self.regenerate_distribution();
@@ -130,6 +605,11 @@ where
     }
 
     fn poll_service(&mut self) -> Poll<(), Self::Error> {
+        // Send out any hedged reads before driving the backends, so they go out right away.
+        while let Ok(Async::Ready(Some(hedge))) = self.hedges_rx.poll() {
+            self.send_hedge(hedge);
+        }
+
         for backend in &mut self.backends {
             // not clear if it actually makes sense to pre-emptively return notready without
             // driving all services.. poll_ready should cover the "am i knocked out of the pool
@@ -150,13 +630,86 @@ where
     }
 
     fn call(&mut self, req: EnqueuedRequests<P::Message>) -> Self::Future {
+        if self.circuit_open {
+            self.sink.record_counter("circuit_open", req.len() as u64);
+            let futs = req
+                .into_iter()
+                .filter_map(|mut msg| self.respond_with_error(&mut msg, "no backends available"))
+                .collect();
+            return PoolResponse::new(futs);
+        }
+
         let mut futs = Vec::new();
         let mut batches = IntegerMappedVec::new();
 
-        for msg in req {
-            let msg_key = msg.key();
-            let msg_hashed = self.key_hasher.hash(msg_key);
-            let backend_idx = self.distributor.choose(msg_hashed);
+        // When every backend holds every key, any one of them can answer for the whole keyspace,
+        // so requests that operate on the whole keyspace are routed like any other request.
+        let sharded = self.distributor.is_key_affine();
+
+        for mut msg in req {
+            // Keyspace scans walk the backends one at a time, so each page goes to a single backend.
+            let scan = if sharded {
+                self.processor.split_scan(msg.request(), self.backends.len())
+            } else {
+                None
+            };
+            match scan {
+                Some(Ok((backend_idx, request))) => {
+                    futs.extend(self.scan(msg, backend_idx, request));
+                    continue;
+                },
+                Some(Err(response)) => {
+                    futs.extend(msg.get_response_rx().map(|rx| {
+                        msg.fulfill(response);
+                        ResponseFuture::new(vec![rx])
+                    }));
+                    continue;
+                },
+                None => {},
+            }
+
+            // Requests that operate on the whole keyspace have to go to every backend.  Listing keys
+            // walks the entire keyspace of every backend, though, so that has to be turned on.
+            if sharded && msg.request().is_broadcast() {
+                if msg.request().is_key_listing() && !self.allow_fanout_keys {
+                    futs.extend(self.respond_with_error(&mut msg, "KEYS disabled on proxy"));
+                    continue;
+                }
+
+                futs.extend(self.broadcast(msg));
+                continue;
+            }
+
+            // Multi-key requests need all of their keys to live on the same backend, otherwise we
+            // can't serve them, so we respond with an error directly.
+            if !self.keys_colocated(&msg) {
+                let err = "CROSSSLOT keys in request don't hash to the same backend";
+                futs.extend(self.respond_with_error(&mut msg, err));
+                continue;
+            }
+
+            // If every backend is out of the pool, there's nowhere to send the request.
+            let backend_idx = match self.get_backend_index(msg.key()) {
+                Some(idx) => idx,
+                None => {
+                    futs.extend(self.respond_with_error(&mut msg, "no backends available"));
+                    continue;
+                },
+            };
+
+            // Reads picked for verification are compared against another replica, rather than
+            // hedged, since the replica is already being asked for them.
+            if self.should_verify(&msg) {
+                if let Some(replica_idx) = self.get_other_backend(backend_idx) {
+                    futs.extend(self.verify(msg, backend_idx, replica_idx));
+                    continue;
+                }
+            }
+
+            if let Some(hedge_delay) = self.get_hedge_delay(&msg) {
+                futs.extend(self.hedge(msg, backend_idx, hedge_delay));
+                continue;
+            }
 
             batches.push(backend_idx, msg);
         }
@@ -179,6 +732,7 @@ where
     processor: P,
     config: PoolConfiguration,
     noreply: bool,
+    preserve_order: bool,
     sink: MetricSink,
 }
 
@@ -194,6 +748,7 @@ where
             processor,
             config,
             noreply: false,
+            preserve_order: true,
             sink,
         }
     }
@@ -203,17 +758,33 @@ where
         self
     }
 
+    pub fn set_preserve_order(mut self, preserve_order: bool) -> Self {
+        self.preserve_order = preserve_order;
+        self
+    }
+
     pub fn build(self) -> Result<BackendPool<P>, CreationError>
     where
         P: Processor + Clone + Send + 'static,
         P::Message: Message + Send + 'static,
     {
+        if self.config.addresses.is_empty() {
+            return Err(CreationError::InvalidParameter("addresses".to_string()));
+        }
+
         let mut options = self.config.options.unwrap_or_else(HashMap::new);
         let dist_type = options
             .entry("distribution".to_owned())
             .or_insert_with(|| "modulo".to_owned())
             .to_lowercase();
-        let distributor = configure_distributor(&dist_type)?;
+        let vnodes_raw = options
+            .entry("vnodes".to_owned())
+            .or_insert_with(|| "160".to_owned());
+        let vnodes = usize::from_str(vnodes_raw.as_str())
+            .ok()
+            .filter(|vnodes| *vnodes > 0)
+            .ok_or_else(|| CreationError::InvalidParameter("options.vnodes".to_string()))?;
+        let distributor = configure_distributor(&dist_type, vnodes)?;
         debug!("[listener] using distributor '{}'", dist_type);
 
         let hash_type = options
@@ -223,21 +794,150 @@ where
         let hasher = configure_hasher(&hash_type)?;
         debug!("[listener] using hasher '{}'", hash_type);
 
+        let verify_rate_raw = options
+            .entry("verify_replicas_rate".to_owned())
+            .or_insert_with(|| "0".to_owned());
+        let verify_rate = f64::from_str(verify_rate_raw.as_str())
+            .ok()
+            .filter(|rate| *rate >= 0.0 && *rate <= 1.0)
+            .ok_or_else(|| CreationError::InvalidParameter("options.verify_replicas_rate".to_string()))?;
+
+        // Reads are hedged once they've taken longer than this percentile of recent reads.  A
+        // percentile of 0 disables hedging entirely.
+        let hedge_percentile_raw = options
+            .entry("hedge_percentile".to_owned())
+            .or_insert_with(|| "0".to_owned());
+        let hedge_percentile = f64::from_str(hedge_percentile_raw.as_str())
+            .ok()
+            .filter(|percentile| *percentile >= 0.0 && *percentile < 100.0)
+            .ok_or_else(|| CreationError::InvalidParameter("options.hedge_percentile".to_string()))?;
+
+        let hedge_min_delay_ms_raw = options
+            .entry("hedge_min_delay_ms".to_owned())
+            .or_insert_with(|| "1".to_owned());
+        let hedge_min_delay_ms = u64::from_str(hedge_min_delay_ms_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.hedge_min_delay_ms".to_string()))?;
+
+        // Verifying replicas only makes sense if every backend holds every key.  With a sharded pool,
+        // the backends that don't own the key would always look divergent.
+        if verify_rate > 0.0 && distributor.is_key_affine() {
+            return Err(CreationError::InvalidParameter("options.verify_replicas_rate".to_string()));
+        }
+
+        // Hedging only makes sense if any backend can serve any key.  With a sharded pool, the hedge
+        // would go to a backend that doesn't own the key.
+        if hedge_percentile > 0.0 && distributor.is_key_affine() {
+            return Err(CreationError::InvalidParameter("options.hedge_percentile".to_string()));
+        }
+
+        let hedge_delay = if hedge_percentile > 0.0 {
+            Some(HedgeDelay::new(hedge_percentile, Duration::from_millis(hedge_min_delay_ms)))
+        } else {
+            None
+        };
+
+        let hash_tags_raw = options
+            .entry("hash_tags".to_owned())
+            .or_insert_with(|| "false".to_owned());
+        let hash_tags = bool::from_str(hash_tags_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.hash_tags".to_string()))?;
+
+        let allow_fanout_keys_raw = options
+            .entry("allow_fanout_keys".to_owned())
+            .or_insert_with(|| "false".to_owned());
+        let allow_fanout_keys = bool::from_str(allow_fanout_keys_raw.as_str())
+            .map_err(|_| CreationError::InvalidParameter("options.allow_fanout_keys".to_string()))?;
+
+        // Resolve any key overrides to the backends they point at.
+        let mut key_overrides = KeyOverrides::new();
+        for (key, identifier) in self.config.key_overrides.iter().flatten() {
+            let backend_idx = self
+                .config
+                .addresses
+                .iter()
+                .position(|address| &address.identifier == identifier)
+                .ok_or_else(|| CreationError::InvalidParameter(format!("key_overrides.{}", key)))?;
+            key_overrides.add(key, backend_idx);
+        }
+
+        // Make sure any weights point at backends that actually exist.
+        let weights = self.config.weights.clone().unwrap_or_default();
+        for identifier in weights.keys() {
+            if !self.config.addresses.iter().any(|address| &address.identifier == identifier) {
+                return Err(CreationError::InvalidParameter(format!("weights.{}", identifier)));
+            }
+        }
+
         // Build all of our backends for this pool.
         let mut backends = Vec::new();
         for address in &self.config.addresses {
+            let weight = weights.get(&address.identifier).cloned().unwrap_or(1);
             let backend = Backend::new(
                 address.address,
                 address.identifier.clone(),
                 self.processor.clone(),
                 options.clone(),
+                self.config.command_timeouts.clone().unwrap_or_default(),
                 self.noreply,
+                self.preserve_order,
                 self.sink.clone(),
-            )?;
+            )?
+            .set_weight(weight)
+            .set_serialized_commands(self.config.serialized_commands.clone().unwrap_or_default());
             backends.push(backend);
         }
 
-        Ok(BackendPool::new(backends, distributor, hasher, self.noreply, self.sink))
+        Ok(BackendPool::new(
+            self.processor,
+            backends,
+            distributor,
+            hasher,
+            key_overrides,
+            self.noreply,
+            verify_rate,
+            self.sink,
+        )
+        .set_hedge_delay(hedge_delay)
+        .set_hash_tags(hash_tags)
+        .set_allow_fanout_keys(allow_fanout_keys))
+    }
+}
+
+/// Gets the portion of a key that should be hashed when hash tags are enabled.
+///
+/// Like Redis Cluster, if the key contains a `{` followed later by a `}` with at least one byte
+/// between them, only the bytes between the first `{` and the first `}` after it are hashed, so
+/// `user:{123}:profile` and `user:{123}:email` land on the same backend.  Otherwise, the whole key
+/// is hashed.
+fn get_hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(start) = key.iter().position(|b| *b == b'{') {
+        if let Some(len) = key[start + 1..].iter().position(|b| *b == b'}') {
+            if len > 0 {
+                return &key[start + 1..start + 1 + len];
+            }
+        }
+    }
+
+    key
+}
+
+/// Samples which backend a fixed set of keys is distributed to.
+fn sample_distribution(distributor: &Distributor, key_hasher: &KeyHasher) -> Vec<Option<usize>> {
+    (0..REMAP_SAMPLE_KEYS)
+        .map(|i| distributor.choose(key_hasher.hash(i.to_string().as_bytes())))
+        .collect()
+}
+
+/// Gets the fraction of sampled keys that were distributed to a different backend.
+fn get_remapped_fraction(before: &[Option<usize>], after: &[Option<usize>]) -> f64 {
+    let remapped = before.iter().zip(after).filter(|(a, b)| a != b).count();
+    remapped as f64 / before.len().max(1) as f64
+}
+
+fn get_first_response<M>(result: Result<AssignedResponses<M>, BackendError>) -> Option<M> {
+    match result.ok()?.into_iter().next() {
+        Some((_, MessageResponse::Complete(response))) => Some(response),
+        _ => None,
     }
 }
 
@@ -277,3 +977,512 @@ where
         Ok(Async::Ready(flattened))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{
+            distributor::{
+                BackendDescriptor, KetamaDistributor, ModuloDistributor, RandomDistributor, RoundRobinDistributor,
+            },
+            hasher::Fnv64aHasher,
+            memory::MemoryProcessor,
+            redis::RedisProcessor,
+            NotTimeout,
+        },
+        conf::BackendAddress,
+        protocol::redis::RedisMessage,
+        util::{metrics::get_counter, ProcessFuture},
+    };
+    use futures::future::{empty, lazy};
+    use metrics_runtime::Receiver;
+
+    #[test]
+    fn test_key_overrides() {
+        let mut overrides = KeyOverrides::new();
+        assert_eq!(overrides.get(b"hot"), None);
+
+        overrides.add("hot", 2);
+        overrides.add("user:*", 1);
+        overrides.add("user:admin:*", 3);
+
+        assert_eq!(overrides.get(b"hot"), Some(2));
+        assert_eq!(overrides.get(b"hotter"), None);
+        assert_eq!(overrides.get(b"user:42"), Some(1));
+        assert_eq!(overrides.get(b"user:admin:1"), Some(3));
+        assert_eq!(overrides.get(b"users"), None);
+    }
+
+    #[test]
+    fn test_hash_tags() {
+        assert_eq!(get_hash_tag(b"user:{123}:profile"), b"123");
+        assert_eq!(get_hash_tag(b"user:{123}:email"), b"123");
+        assert_eq!(get_hash_tag(b"{123}:profile"), b"123");
+        assert_eq!(get_hash_tag(b"user:{123}"), b"123");
+        assert_eq!(get_hash_tag(b"{123}"), b"123");
+
+        // Empty tags and unterminated tags mean the whole key is used.
+        assert_eq!(get_hash_tag(b"user:{}:profile"), b"user:{}:profile");
+        assert_eq!(get_hash_tag(b"user:{123"), b"user:{123");
+        assert_eq!(get_hash_tag(b"user:}123{"), b"user:}123{");
+        assert_eq!(get_hash_tag(b"user"), b"user");
+
+        // Only the first opening brace and the first closing brace after it count.
+        assert_eq!(get_hash_tag(b"{{123}}"), b"{123");
+        assert_eq!(get_hash_tag(b"{a}{b}"), b"a");
+        assert_eq!(get_hash_tag(b"{}{b}"), b"{}{b}");
+    }
+
+    #[test]
+    fn test_remapped_fraction() {
+        let descriptor = |idx| {
+            BackendDescriptor {
+                idx,
+                identifier: idx.to_string(),
+                healthy: true,
+                weight: 1,
+            }
+        };
+        let hasher = Fnv64aHasher::new();
+
+        let mut distributor = ModuloDistributor::new();
+        distributor.update(vec![descriptor(0), descriptor(1), descriptor(2)]);
+        let before = sample_distribution(&distributor, &hasher);
+        assert_eq!(get_remapped_fraction(&before, &before), 0.0);
+
+        // Going from three backends to two with modulo distribution keeps only the keys that were
+        // on the first two backends, and happen to land on the same one again: a third of them.
+        distributor.update(vec![descriptor(0), descriptor(1)]);
+        let after = sample_distribution(&distributor, &hasher);
+        let remapped = get_remapped_fraction(&before, &after);
+        assert!(remapped > 0.6 && remapped < 0.73, "remapped fraction was {}", remapped);
+    }
+
+    #[test]
+    fn test_empty_pool() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+
+        // Pools without backends are rejected up front...
+        let builder = BackendPoolBuilder::new(
+            "empty".to_owned(),
+            RedisProcessor::new(),
+            PoolConfiguration::default(),
+            receiver.get_sink(),
+        );
+        assert!(builder.build().is_err());
+
+        // ...but if we still somehow end up with nowhere to send a request, we open the circuit and
+        // respond with an error instead of panicking or waiting forever.
+        let mut pool = BackendPool::new(
+            RedisProcessor::new(),
+            Vec::new(),
+            Box::new(ModuloDistributor::new()),
+            Box::new(Fnv64aHasher::new()),
+            KeyOverrides::new(),
+            false,
+            0.0,
+            receiver.get_sink(),
+        );
+
+        assert!(!pool.circuit_open);
+        assert_eq!(pool.poll_ready().expect("pool should never error"), Async::Ready(()));
+        assert!(pool.circuit_open);
+
+        let request = EnqueuedRequest::new(0, RedisMessage::from_inline("get foo"));
+        let responses = pool.call(vec![request]).wait().expect("failed to get responses");
+        assert_eq!(responses.len(), 1);
+
+        match responses.into_iter().next() {
+            Some((0, MessageResponse::Complete(msg))) => {
+                assert_eq!(&msg.into_buf()[..], &b"-ERR no backends available\r\n"[..]);
+            },
+            _ => panic!("expected an error response"),
+        }
+    }
+
+    #[test]
+    fn test_circuit_opens_while_every_backend_is_in_cooloff() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let sink = receiver.get_sink();
+
+        // Backends can only go into cooloff, and come back out of it, on a running executor.
+        tokio_io_pool::run(lazy(move || {
+            let backends = (0..2)
+                .map(|i| {
+                    let address = format!("127.0.0.1:{}", 6379 + i).parse().unwrap();
+                    let mut options = HashMap::new();
+                    options.insert("cooloff_timeout_ms".to_owned(), "50".to_owned());
+                    Backend::new(
+                        address,
+                        i.to_string(),
+                        RedisProcessor::new(),
+                        options,
+                        HashMap::new(),
+                        false,
+                        true,
+                        sink.clone(),
+                    )
+                    .expect("failed to build backend")
+                })
+                .collect();
+            let mut pool = BackendPool::new(
+                RedisProcessor::new(),
+                backends,
+                Box::new(ModuloDistributor::new()),
+                Box::new(Fnv64aHasher::new()),
+                KeyOverrides::new(),
+                false,
+                0.0,
+                sink,
+            );
+
+            assert_eq!(pool.poll_ready().expect("pool should never error"), Async::Ready(()));
+            assert!(!pool.circuit_open);
+
+            // With every backend in cooloff, the pool is still ready, but fails requests on the spot
+            // instead of handing them to any backend.
+            for backend in &mut pool.backends {
+                backend.health.trip();
+            }
+            assert_eq!(pool.poll_ready().expect("pool should never error"), Async::Ready(()));
+            assert!(pool.circuit_open);
+
+            let request = EnqueuedRequest::new(0, RedisMessage::from_inline("get foo"));
+            let responses = pool.call(vec![request]).wait().expect("failed to get responses");
+            match responses.into_iter().next() {
+                Some((0, MessageResponse::Complete(msg))) => {
+                    assert_eq!(&msg.into_buf()[..], &b"-ERR no backends available\r\n"[..]);
+                },
+                _ => panic!("expected an error response"),
+            }
+            for backend in &pool.backends {
+                assert!(backend.conns.iter().all(|conn| conn.pending_len == 0));
+            }
+
+            // Once the backends are out of cooloff, the circuit closes again.
+            Delay::new(Instant::now() + Duration::from_millis(100)).then(move |_| {
+                assert_eq!(pool.poll_ready().expect("pool should never error"), Async::Ready(()));
+                assert!(!pool.circuit_open);
+                ok::<(), ()>(())
+            })
+        }));
+
+        assert_eq!(get_counter(&receiver, "circuit_open"), 1);
+    }
+
+    fn build_hedged_pool(distributor: DistributorFutureSafe, sink: MetricSink) -> BackendPool<RedisProcessor> {
+        let backends = (0..3)
+            .map(|i| {
+                let address = format!("127.0.0.1:{}", 6379 + i).parse().unwrap();
+                Backend::new(
+                    address,
+                    i.to_string(),
+                    RedisProcessor::new(),
+                    HashMap::new(),
+                    HashMap::new(),
+                    false,
+                    true,
+                    sink.clone(),
+                )
+                .expect("failed to build backend")
+            })
+            .collect();
+
+        BackendPool::new(
+            RedisProcessor::new(),
+            backends,
+            distributor,
+            Box::new(Fnv64aHasher::new()),
+            KeyOverrides::new(),
+            false,
+            0.0,
+            sink,
+        )
+        .set_hedge_delay(Some(HedgeDelay::new(90.0, Duration::from_millis(1))))
+    }
+
+    #[test]
+    fn test_sharded_pools_never_hedge() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let read = EnqueuedRequest::new(0, RedisMessage::from_inline("get foo"));
+        let write = EnqueuedRequest::new(0, RedisMessage::from_inline("set foo bar"));
+
+        // Only the backend a key is sharded to can answer for it, so sharded pools never hedge,
+        // even if they were somehow given a hedge delay.
+        let pool = build_hedged_pool(Box::new(ModuloDistributor::new()), receiver.get_sink());
+        assert!(pool.get_hedge_delay(&read).is_none());
+        let pool = build_hedged_pool(Box::new(KetamaDistributor::new(160)), receiver.get_sink());
+        assert!(pool.get_hedge_delay(&read).is_none());
+
+        // Pools where any backend can serve any key hedge their reads, but never their writes.
+        let pool = build_hedged_pool(Box::new(RoundRobinDistributor::new()), receiver.get_sink());
+        assert!(pool.get_hedge_delay(&read).is_some());
+        assert!(pool.get_hedge_delay(&write).is_none());
+
+        // Asking a sharded pool to hedge is rejected outright.
+        let build = |distribution: &str| {
+            let mut config = PoolConfiguration::default();
+            config.addresses = vec![BackendAddress {
+                address: "127.0.0.1:6379".parse().unwrap(),
+                identifier: "backend".to_owned(),
+            }];
+            let mut options = HashMap::new();
+            options.insert("distribution".to_owned(), distribution.to_owned());
+            options.insert("hedge_percentile".to_owned(), "95".to_owned());
+            config.options = Some(options);
+
+            BackendPoolBuilder::new("hedged".to_owned(), RedisProcessor::new(), config, receiver.get_sink()).build()
+        };
+        assert!(build("modulo").is_err());
+        assert!(build("ketama").is_err());
+        assert!(build("random").is_ok());
+        assert!(build("roundrobin").is_ok());
+    }
+
+    #[test]
+    fn test_keyspace_requests_on_replicated_pools() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let mut pool = build_hedged_pool(Box::new(RandomDistributor::new()), receiver.get_sink())
+            .set_hedge_delay(None)
+            .set_allow_fanout_keys(false);
+
+        let batch = ["dbsize", "scan 0 match foo* count 5", "keys foo*", "flushall"]
+            .iter()
+            .enumerate()
+            .map(|(i, cmd)| EnqueuedRequest::new(i, RedisMessage::from_inline(cmd)))
+            .collect::<Vec<_>>();
+        let _responses = pool.call(batch);
+
+        // Every backend of a replicated pool holds the whole keyspace, so each request goes to
+        // exactly one of them, as is: no composite cursors, no merging, and no refusing KEYS.
+        let pending = pool
+            .backends
+            .iter()
+            .map(|backend| backend.conns.iter().map(|conn| conn.pending_len).sum::<usize>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pending.iter().sum::<usize>(),
+            4,
+            "requests were fanned out: {:?}",
+            pending
+        );
+
+        // Sharded pools, on the other hand, would have to ask every backend, which isn't allowed.
+        let mut pool = build_hedged_pool(Box::new(ModuloDistributor::new()), receiver.get_sink());
+        let request = EnqueuedRequest::new(0, RedisMessage::from_inline("keys foo*"));
+        let responses = pool.call(vec![request]).wait().expect("failed to get responses");
+        match responses.into_iter().next() {
+            Some((0, MessageResponse::Complete(msg))) => {
+                assert_eq!(&msg.into_buf()[..], &b"-ERR KEYS disabled on proxy\r\n"[..]);
+            },
+            _ => panic!("expected an error response"),
+        }
+    }
+
+    #[test]
+    fn test_sharded_pools_never_verify() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let read = EnqueuedRequest::new(0, RedisMessage::from_inline("get foo"));
+        let write = EnqueuedRequest::new(0, RedisMessage::from_inline("set foo bar"));
+
+        let build = |distribution: &str| {
+            let mut config = PoolConfiguration::default();
+            config.addresses = (0..3)
+                .map(|i| {
+                    BackendAddress {
+                        address: format!("127.0.0.1:{}", 6379 + i).parse().unwrap(),
+                        identifier: i.to_string(),
+                    }
+                })
+                .collect();
+            let mut options = HashMap::new();
+            options.insert("distribution".to_owned(), distribution.to_owned());
+            options.insert("verify_replicas_rate".to_owned(), "1".to_owned());
+            config.options = Some(options);
+
+            BackendPoolBuilder::new("verified".to_owned(), RedisProcessor::new(), config, receiver.get_sink()).build()
+        };
+
+        // Asking a sharded pool to verify its replicas is rejected outright, since the backends
+        // that don't own a key would always look like they'd diverged.
+        assert!(build("modulo").is_err());
+        assert!(build("ketama").is_err());
+
+        // Pools where every backend holds every key verify their reads, but never their writes.
+        let pool = build("roundrobin").expect("failed to build pool");
+        assert!(pool.should_verify(&read));
+        assert!(!pool.should_verify(&write));
+        let pool = build("random").expect("failed to build pool");
+        assert!(pool.should_verify(&read));
+
+        // ...and even if a sharded pool somehow ends up with a verification rate, it never verifies.
+        let mut pool = build_hedged_pool(Box::new(ModuloDistributor::new()), receiver.get_sink());
+        pool.verify_rate = 1.0;
+        assert!(!pool.should_verify(&read));
+    }
+
+    #[test]
+    fn test_verified_reads_go_to_another_replica() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let sink = receiver.get_sink();
+        let processor = MemoryProcessor::new();
+        let addresses = vec![processor.add_backend(), processor.add_backend()];
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            let backends = addresses
+                .iter()
+                .enumerate()
+                .map(|(i, address)| {
+                    Backend::new(
+                        *address,
+                        i.to_string(),
+                        processor.clone(),
+                        HashMap::new(),
+                        HashMap::new(),
+                        false,
+                        true,
+                        sink.clone(),
+                    )
+                    .expect("failed to build backend")
+                })
+                .collect();
+            let mut pool = BackendPool::new(
+                processor.clone(),
+                backends,
+                Box::new(RoundRobinDistributor::new()),
+                Box::new(Fnv64aHasher::new()),
+                KeyOverrides::new(),
+                false,
+                1.0,
+                sink,
+            );
+
+            // Only one of the replicas has the key, so whichever one the read is routed to, the
+            // other one disagrees with it.
+            let set = EnqueuedRequest::new(0, RedisMessage::from_inline("set foo bar"));
+            let mut set = Some(pool.backends[0].call(vec![set]));
+            let mut get = None;
+            let mut answered = false;
+            poll_fn(move || {
+                loop {
+                    pool.poll_service().map_err(|_| ())?;
+
+                    if let Some(response) = set.as_mut() {
+                        try_ready!(response.poll().map_err(|_| ()));
+                        set = None;
+                        let request = EnqueuedRequest::new(0, RedisMessage::from_inline("get foo"));
+                        get = Some(pool.call(vec![request]));
+                        continue;
+                    }
+
+                    if !answered {
+                        let response = get.as_mut().expect("read was never sent");
+                        try_ready!(response.poll().map_err(|_| ()));
+                        answered = true;
+                    }
+
+                    // Wait for the copy of the read to be served, too.
+                    let served = pool
+                        .backends
+                        .iter()
+                        .map(|backend| backend.conns.iter().map(|conn| conn.stream_requests).sum::<u64>())
+                        .collect::<Vec<_>>();
+                    if served.iter().sum::<u64>() < 3 {
+                        return Ok(Async::NotReady);
+                    }
+
+                    let _ = tx.send(served);
+                    return Ok(Async::Ready(()));
+                }
+            })
+        }));
+
+        // Each replica was asked for the key exactly once: the read wasn't sent twice to the
+        // backend it was routed to.
+        assert_eq!(rx.recv(), Ok(vec![2, 1]));
+        assert_eq!(get_counter(&receiver, "replica_divergence"), 1);
+        assert_eq!(get_counter(&receiver, "replica_verify_failures"), 0);
+    }
+
+    #[test]
+    fn test_hedged_read_cancels_the_slow_primary() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let sink = receiver.get_sink();
+        let processor = MemoryProcessor::new();
+        let addresses = vec![processor.add_backend(), processor.add_backend()];
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            let backends = addresses
+                .iter()
+                .enumerate()
+                .map(|(i, address)| {
+                    let mut options = HashMap::new();
+                    options.insert("conns".to_owned(), "1".to_owned());
+                    Backend::new(
+                        *address,
+                        i.to_string(),
+                        processor.clone(),
+                        options,
+                        HashMap::new(),
+                        false,
+                        true,
+                        sink.clone(),
+                    )
+                    .expect("failed to build backend")
+                })
+                .collect();
+            let mut pool = BackendPool::new(
+                processor.clone(),
+                backends,
+                Box::new(RoundRobinDistributor::new()),
+                Box::new(Fnv64aHasher::new()),
+                KeyOverrides::new(),
+                false,
+                0.0,
+                sink,
+            )
+            .set_hedge_delay(Some(HedgeDelay::new(90.0, Duration::from_millis(1))));
+
+            // The primary's only connection is stuck on an earlier batch that never finishes, so the
+            // read queues up behind it, and the hedge sent to the other backend wins.
+            let request = EnqueuedRequest::new(0, RedisMessage::from_inline("get foo"));
+            let mut response = pool.call(vec![request]);
+            let primary = pool
+                .backends
+                .iter()
+                .position(|backend| backend.conns[0].pending_len == 1)
+                .expect("read wasn't sent to any backend");
+            pool.backends[primary].conns[0].current = Some(Either::A(NotTimeout {
+                inner: ProcessFuture::new(empty()),
+            }));
+
+            poll_fn(move || {
+                pool.poll_service().map_err(|_| ())?;
+                let answered = match response.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(responses)) => match responses.into_iter().next() {
+                        Some((0, MessageResponse::Complete(msg))) => &msg.into_buf()[..] == b"$-1\r\n",
+                        _ => false,
+                    },
+                    Err(_) => false,
+                };
+
+                // Once the primary gets unstuck, the read it had queued up is dropped instead of sent.
+                let conn = &mut pool.backends[primary].conns[0];
+                conn.current = None;
+                let _ = conn.poll_service();
+                let _ = tx.send((answered, conn.pending_len, conn.current.is_none()));
+                Ok(Async::Ready(()))
+            })
+        }));
+
+        assert_eq!(rx.recv(), Ok((true, 0, true)));
+        assert_eq!(get_counter(&receiver, "hedged_requests"), 1);
+
+        // Connections record their metrics under the backend's scope.
+        assert_eq!(get_counter(&receiver, "backend.cancelled_requests"), 1);
+    }
+}
@@ -24,14 +24,48 @@ use crate::{
     backend::message_queue::MessageState,
     common::{EnqueuedRequests, Message},
     protocol::errors::ProtocolError,
-    util::ProcessFuture,
+    util::{Connector, MaybeTlsStream, ProcessFuture},
 };
 use futures::future::{Either, FutureResult};
-use std::{error::Error, net::SocketAddr};
-use tokio::net::tcp::TcpStream;
+use std::{error::Error, net::SocketAddr, time::Duration};
 
-/// An existing or pending TcpStream.
-pub type TcpStreamFuture = Either<FutureResult<TcpStream, ProtocolError>, ProcessFuture>;
+/// What a processor knows about a single client, kept for as long as the client is connected.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientState {
+    /// Whether or not the client has authenticated.
+    pub authenticated: bool,
+
+    /// The protocol version the client has negotiated, if it has negotiated one.
+    pub protocol_version: Option<usize>,
+}
+
+/// An existing or pending backend stream, which may or may not be using TLS.
+pub type TcpStreamFuture = Either<FutureResult<MaybeTlsStream, ProtocolError>, ProcessFuture>;
+
+/// Socket-level timeouts for reading from, and writing to, a backend.
+///
+/// Request timeouts bound the total time a batch can take, no matter how much progress it's
+/// making.  These instead bound how long we'll wait on a socket that isn't making any progress at
+/// all, which catches a backend that has stalled at the TCP level much sooner.  A timeout of 0
+/// disables it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IoTimeouts {
+    pub read_ms: u64,
+    pub write_ms: u64,
+}
+
+impl IoTimeouts {
+    pub fn read_timeout(&self) -> Option<Duration> { to_duration(self.read_ms) }
+
+    pub fn write_timeout(&self) -> Option<Duration> { to_duration(self.write_ms) }
+}
+
+fn to_duration(ms: u64) -> Option<Duration> {
+    match ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
 
 /// Cache-specific logic for processing requests and interacting with backends.
 pub trait Processor
@@ -53,22 +87,64 @@ where
     /// `fragment_messages` -- back into a cohesive response that the client will understand.
     fn defragment_messages(&self, _: Vec<(MessageState, Self::Message)>) -> Result<Self::Message, ProcessorError>;
 
+    /// Transforms the response to a client's request before it is sent back.
+    ///
+    /// The buffer identifies the original command, and is the same buffer that was given in
+    /// `MessageState::Transformed` when the message was fragmented.
+    fn transform_message(&self, _: &[u8], _: Self::Message) -> Result<Self::Message, ProcessorError>;
+
+    /// Intercepts any message that a client uses to authenticate, or to negotiate how it's spoken
+    /// to, along with any message that it can't send until it has authenticated.
+    ///
+    /// `state` is the client's state, which the processor updates as the client authenticates and
+    /// negotiates.  Returns the reply to send back to the client if the message was handled here,
+    /// or `None` if it should be processed as usual.
+    fn authenticate(&self, _: &Self::Message, _: &mut ClientState) -> Option<Self::Message>;
+
+    /// Gets the database that the given message switches the client to, if it's a request to
+    /// switch databases.
+    fn get_selected_database(&self, _: &Self::Message) -> Option<usize>;
+
+    /// Splits a request to iterate over the keyspace of a pool with the given number of backends.
+    ///
+    /// A pool's keyspace is iterated over one backend at a time, so the cursor a client holds has
+    /// to say which backend it's on, as well as where it is on that backend.  Returns the index of
+    /// the backend to send the request to, along with the request to send it, or, if the request is
+    /// malformed, the error to send back to the client.  Returns `None` for any other request.
+    fn split_scan(&self, _: &Self::Message, _: usize) -> Option<Result<(usize, Self::Message), Self::Message>>;
+
+    /// Rewrites a backend's response to a request from `split_scan` so that its cursor covers the
+    /// whole pool, given the index of the backend and the number of backends in the pool.
+    fn merge_scan(&self, _: Self::Message, _: usize, _: usize) -> Self::Message;
+
+    /// Gets the message sent to the client when a request succeeds with nothing else to say.
+    fn get_ok_message(&self) -> Self::Message;
+
     /// Converts the given error into a corresponding format that can be sent to the client.
     fn get_error_message(&self, _: Box<Error>) -> Self::Message;
 
     /// Converts the given error string into a corresponding format the can be sent to the client.
     fn get_error_message_str(&self, _: &str) -> Self::Message;
 
-    /// Wraps the given TCP stream with a protocol-specific transport layer, allowing the caller to
-    /// extract protocol-specific messages, as well as send them, via the `Stream` and `Sink`
+    /// Gets a no-op request that can be sent to a backend to check that the connection is alive.
+    fn get_ping_message(&self) -> Self::Message;
+
+    /// Whether or not the given message is the response a healthy backend gives to a ping.
+    fn is_ping_response(&self, _: &Self::Message) -> bool;
+
+    /// Wraps the given client stream with a protocol-specific transport layer, allowing the caller
+    /// to extract protocol-specific messages, as well as send them, via the `Stream` and `Sink`
     /// implementations.
-    fn get_transport(&self, _: TcpStream) -> Self::Transport;
+    fn get_transport(&self, _: MaybeTlsStream) -> Self::Transport;
 
-    /// Connects to the given address via TCP and performs any necessary processor-specific
-    /// initialization.
-    fn preconnect(&self, _: &SocketAddr, _: bool) -> ProcessFuture;
+    /// Connects to the given address with the given connector, and performs any necessary
+    /// processor-specific initialization.
+    fn preconnect(&self, _: &SocketAddr, _: &Connector, _: bool) -> ProcessFuture;
 
     /// Processes a batch of requests, running the necessary operations against the given TCP
     /// stream.
-    fn process(&self, _: EnqueuedRequests<Self::Message>, _: TcpStreamFuture) -> ProcessFuture;
+    ///
+    /// Reads from, and writes to, the stream must respect the given I/O timeouts, failing the
+    /// batch if they're exceeded.
+    fn process(&self, _: EnqueuedRequests<Self::Message>, _: TcpStreamFuture, _: IoTimeouts) -> ProcessFuture;
 }
@@ -20,87 +20,331 @@
 use crate::{
     backend::{
         message_queue::MessageState,
-        processor::{Processor, ProcessorError, TcpStreamFuture},
+        processor::{ClientState, IoTimeouts, Processor, ProcessorError, TcpStreamFuture},
     },
     common::{EnqueuedRequests, Message},
-    protocol::{
-        errors::ProtocolError,
-        redis::{self, RedisMessage, RedisTransport},
-    },
-    util::ProcessFuture,
+    conf::ReplyTransformConfiguration,
+    errors::CreationError,
+    protocol::redis::{self, KeyArity, RedisMessage, RedisTransport},
+    util::{Connector, MaybeTlsStream, ProcessFuture, Sizable},
 };
 use bytes::BytesMut;
+use crypto::{digest::Digest, sha1::Sha1, util::fixed_time_eq};
 use futures::{
     future::{ok, Either},
     prelude::*,
 };
 use itoa;
-use std::{borrow::Borrow, error::Error, net::SocketAddr};
-use tokio::net::TcpStream;
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 const REDIS_DEL: &[u8] = b"del";
+const REDIS_UNLINK: &[u8] = b"unlink";
+const REDIS_EXISTS: &[u8] = b"exists";
+const REDIS_TOUCH: &[u8] = b"touch";
 const REDIS_SET: &[u8] = b"set";
+const REDIS_DBSIZE: &[u8] = b"dbsize";
+const REDIS_KEYS: &[u8] = b"keys";
+const REDIS_FLUSHALL: &[u8] = b"flushall";
+const REDIS_FLUSHDB: &[u8] = b"flushdb";
+const REDIS_SCRIPT: &[u8] = b"script";
+const REDIS_CLUSTER: &[u8] = b"cluster";
+const REDIS_ASKING: &[u8] = b"asking";
+const REDIS_TIME: &[u8] = b"time";
+const REDIS_HELLO: &[u8] = b"hello";
+const REDIS_AUTH: &[u8] = b"auth";
+const REDIS_SETNAME: &[u8] = b"setname";
+const REDIS_QUIT: &[u8] = b"quit";
+const REDIS_RESET: &[u8] = b"reset";
+const REDIS_DEFAULT_USER: &[u8] = b"default";
+const REDIS_SELECT: &[u8] = b"select";
+const REDIS_SCAN: &[u8] = b"scan";
+
+// The low bits of a composite SCAN cursor hold the index of the backend being scanned, and the rest
+// hold that backend's own cursor.
+const REDIS_SCAN_BACKEND_BITS: u64 = 10;
+const REDIS_SCAN_MAX_BACKENDS: u64 = 1 << REDIS_SCAN_BACKEND_BITS;
+
+// The largest reply we'll assemble for a KEYS sent to every backend.  Anything bigger is refused,
+// since SCAN can walk the same keys a page at a time.
+const REDIS_KEYS_MAX_REPLY_BYTES: usize = 64 * 1024 * 1024;
+
+/// A transformation applied to the reply of a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplyTransform {
+    /// Replaces a null reply with an empty string.
+    NullToEmpty,
+
+    /// Replaces an error reply with a null reply.
+    ErrorToNull,
+
+    /// Replaces an OK reply with the integer 1.
+    OkToInteger,
+}
+
+/// A rule for transforming the replies of a specific command.
+#[derive(Clone, Debug)]
+pub struct ReplyRule {
+    command: Vec<u8>,
+    transform: ReplyTransform,
+}
+
+impl ReplyRule {
+    pub fn from_config(config: &ReplyTransformConfiguration) -> Result<ReplyRule, CreationError> {
+        let transform = match config.transform.to_lowercase().as_str() {
+            "null_to_empty" => ReplyTransform::NullToEmpty,
+            "error_to_null" => ReplyTransform::ErrorToNull,
+            "ok_to_integer" => ReplyTransform::OkToInteger,
+            s => return Err(CreationError::InvalidResource(format!("unknown reply transform {}", s))),
+        };
+
+        Ok(ReplyRule {
+            command: config.command.to_lowercase().into_bytes(),
+            transform,
+        })
+    }
+
+    fn matches(&self, cmd: &[u8]) -> bool { self.command.as_slice().eq_ignore_ascii_case(cmd) }
+
+    fn apply(&self, msg: RedisMessage) -> RedisMessage {
+        match (&self.transform, msg) {
+            (ReplyTransform::NullToEmpty, RedisMessage::Null) => redis_new_data_buffer(b""),
+            (ReplyTransform::ErrorToNull, RedisMessage::Error(_, _)) => RedisMessage::Null,
+            (ReplyTransform::OkToInteger, RedisMessage::OK) => RedisMessage::from_integer(1),
+            (_, msg) => msg,
+        }
+    }
+}
 
 #[derive(Clone)]
-pub struct RedisProcessor;
+pub struct RedisProcessor {
+    allow_debug: bool,
+    allow_blocking: bool,
+    allow_select: bool,
+    max_args: Option<usize>,
+    announce_address: Option<SocketAddr>,
+    reply_rules: Arc<Vec<ReplyRule>>,
+    requirepass: Option<String>,
+}
 
 impl RedisProcessor {
-    pub fn new() -> RedisProcessor { RedisProcessor {} }
+    pub fn new() -> RedisProcessor {
+        RedisProcessor {
+            allow_debug: false,
+            allow_blocking: false,
+            allow_select: false,
+            max_args: None,
+            announce_address: None,
+            reply_rules: Arc::new(Vec::new()),
+            requirepass: None,
+        }
+    }
+
+    pub fn set_allow_debug(mut self, allow_debug: bool) -> Self {
+        self.allow_debug = allow_debug;
+        self
+    }
+
+    pub fn set_allow_blocking(mut self, allow_blocking: bool) -> Self {
+        self.allow_blocking = allow_blocking;
+        self
+    }
+
+    /// Sets whether or not clients can switch databases with `SELECT`.
+    ///
+    /// Backend connections are shared between clients, so `SELECT` can never be passed on to a
+    /// backend.  When enabled, it's left for the router to handle, which must be able to, since
+    /// valid requests to switch databases are passed on to it as-is.
+    pub fn set_allow_select(mut self, allow_select: bool) -> Self {
+        self.allow_select = allow_select;
+        self
+    }
+
+    pub fn set_max_args(mut self, max_args: Option<usize>) -> Self {
+        self.max_args = max_args;
+        self
+    }
+
+    /// Sets the address that clients are told to connect to us on.
+    ///
+    /// When set, cluster topology commands are answered directly, claiming that this address owns
+    /// every slot, so that cluster-aware clients can treat the proxy as a single-node cluster.
+    /// Otherwise, every `CLUSTER` command is answered with an error.
+    pub fn set_announce_address(mut self, announce_address: SocketAddr) -> Self {
+        self.announce_address = Some(announce_address);
+        self
+    }
+
+    pub fn set_reply_rules(mut self, reply_rules: Vec<ReplyRule>) -> Self {
+        self.reply_rules = Arc::new(reply_rules);
+        self
+    }
+
+    /// Sets the password clients must authenticate with before sending any commands.
+    ///
+    /// Like Redis itself, the password belongs to the `default` user, which is the user clients
+    /// authenticate as when they don't give one.
+    pub fn set_requirepass(mut self, requirepass: Option<String>) -> Self {
+        self.requirepass = requirepass;
+        self
+    }
 }
 
 impl Processor for RedisProcessor {
     type Message = RedisMessage;
-    type Transport = RedisTransport<TcpStream>;
+    type Transport = RedisTransport<MaybeTlsStream>;
 
     fn fragment_messages(
         &self, msgs: Vec<Self::Message>,
     ) -> Result<Vec<(MessageState, Self::Message)>, ProcessorError> {
-        redis_fragment_messages(msgs)
+        redis_fragment_messages(
+            msgs,
+            self.allow_blocking,
+            self.allow_select,
+            self.announce_address,
+            &self.reply_rules,
+        )
     }
 
     fn defragment_messages(&self, msgs: Vec<(MessageState, Self::Message)>) -> Result<Self::Message, ProcessorError> {
         redis_defragment_messages(msgs)
     }
 
+    fn transform_message(&self, cmd: &[u8], msg: Self::Message) -> Result<Self::Message, ProcessorError> {
+        Ok(self
+            .reply_rules
+            .iter()
+            .filter(|rule| rule.matches(cmd))
+            .fold(msg, |msg, rule| rule.apply(msg)))
+    }
+
+    fn authenticate(&self, msg: &Self::Message, state: &mut ClientState) -> Option<Self::Message> {
+        redis_authenticate(msg, self.requirepass.as_ref().map(|s| s.as_bytes()), state)
+    }
+
+    fn get_selected_database(&self, msg: &Self::Message) -> Option<usize> { redis_get_selected_database(msg) }
+
+    fn get_ok_message(&self) -> Self::Message { RedisMessage::OK }
+
+    fn split_scan(&self, msg: &Self::Message, count: usize) -> Option<Result<(usize, Self::Message), Self::Message>> {
+        redis_split_scan(msg, count)
+    }
+
+    fn merge_scan(&self, msg: Self::Message, idx: usize, count: usize) -> Self::Message {
+        redis_merge_scan(msg, idx, count)
+    }
+
     fn get_error_message(&self, e: Box<Error>) -> Self::Message { RedisMessage::from_error(e) }
 
     fn get_error_message_str(&self, e: &str) -> Self::Message { RedisMessage::from_error_str(e) }
 
-    fn get_transport(&self, client: TcpStream) -> Self::Transport { RedisTransport::new(client) }
+    fn get_ping_message(&self) -> Self::Message { RedisMessage::from_inline("PING") }
 
-    fn preconnect(&self, addr: &SocketAddr, noreply: bool) -> ProcessFuture {
-        let inner = TcpStream::connect(addr)
-            .map_err(ProtocolError::IoError)
-            .and_then(move |conn| {
-                if noreply {
-                    let noreply_req = RedisMessage::from_inline("CLIENT REPLY OFF");
-                    Either::A(redis::write_raw_message(conn, noreply_req).map(|(server, _n)| server))
-                } else {
-                    Either::B(ok(conn))
-                }
-            });
+    fn is_ping_response(&self, msg: &Self::Message) -> bool { msg.is_pong() }
+
+    fn get_transport(&self, client: MaybeTlsStream) -> Self::Transport {
+        RedisTransport::new(client)
+            .set_allow_debug(self.allow_debug)
+            .set_max_args(self.max_args)
+    }
+
+    fn preconnect(&self, addr: &SocketAddr, connector: &Connector, noreply: bool) -> ProcessFuture {
+        let inner = connector.connect(addr).and_then(move |conn| {
+            if noreply {
+                let noreply_req = RedisMessage::from_inline("CLIENT REPLY OFF");
+                Either::A(redis::write_raw_message(conn, noreply_req).map(|(server, _n)| server))
+            } else {
+                Either::B(ok(conn))
+            }
+        });
         ProcessFuture::new(inner)
     }
 
-    fn process(&self, req: EnqueuedRequests<Self::Message>, stream: TcpStreamFuture) -> ProcessFuture {
+    fn process(
+        &self, req: EnqueuedRequests<Self::Message>, stream: TcpStreamFuture, io_timeouts: IoTimeouts,
+    ) -> ProcessFuture {
         let inner = stream
-            .and_then(move |server| redis::write_messages(server, req))
-            .and_then(move |(server, msgs, _n)| redis::read_messages(server, msgs))
+            .and_then(move |server| redis::write_messages(server, req, io_timeouts.write_timeout()))
+            .and_then(move |(server, msgs, _n)| redis::read_messages(server, msgs, io_timeouts.read_timeout()))
             .and_then(move |(server, _n)| ok(server));
         ProcessFuture::new(inner)
     }
 }
 
-fn redis_fragment_messages(msgs: Vec<RedisMessage>) -> Result<Vec<(MessageState, RedisMessage)>, ProcessorError> {
+fn redis_fragment_messages(
+    msgs: Vec<RedisMessage>, allow_blocking: bool, allow_select: bool, announce_address: Option<SocketAddr>,
+    reply_rules: &[ReplyRule],
+) -> Result<Vec<(MessageState, RedisMessage)>, ProcessorError> {
     let mut fragments = Vec::new();
 
     for msg in msgs {
+        // Clients can send us anything that parses as RESP, including replies like `+OK`, which
+        // aren't commands at all.  Reject them in place rather than echoing them back.
+        if !redis_is_command_message(&msg) {
+            fragments.push((MessageState::Inline, RedisMessage::from_error_str("unknown command")));
+            continue;
+        }
+
+        // CLUSTER commands never go to a backend, since any of them could be talking to a node that
+        // belongs to an actual cluster.  We answer the topology ones ourselves, as long as we know
+        // what address to give out, and refuse the rest.
+        if redis_is_cluster_message(&msg) {
+            let response = match announce_address.as_ref() {
+                Some(address) => redis_cluster_response(redis_get_cluster_subcommand(&msg), address),
+                None => RedisMessage::from_error_str("cluster_announce_address not configured"),
+            };
+            fragments.push((MessageState::Inline, response));
+            continue;
+        }
+
+        // Blocking commands hold their backend connection until they return, stalling every other
+        // request queued on that connection, so we don't send them unless explicitly allowed.
+        if !allow_blocking && msg.is_blocking() {
+            let err = RedisMessage::from_error_str("blocking commands are not supported through this proxy");
+            fragments.push((MessageState::Inline, err));
+            continue;
+        }
+
+        // Cluster-aware clients send ASKING ahead of commands after being redirected.  We never
+        // redirect, so there's nothing to do but acknowledge it.
+        if let Some(cmd) = msg.get_command() {
+            if cmd.eq_ignore_ascii_case(REDIS_ASKING) {
+                fragments.push((MessageState::Inline, RedisMessage::OK));
+                continue;
+            }
+
+            // Which backend's clock TIME should read is anyone's guess, so we answer with ours.
+            if cmd.eq_ignore_ascii_case(REDIS_TIME) {
+                fragments.push((MessageState::Inline, redis_time_response()));
+                continue;
+            }
+
+            // SELECT can never reach a backend, since backend connections are shared, so anything
+            // we don't reject here is left for the router to switch databases with.
+            if cmd.eq_ignore_ascii_case(REDIS_SELECT) {
+                if let Some(err) = redis_check_select(&msg, allow_select) {
+                    fragments.push((MessageState::Inline, err));
+                    continue;
+                }
+            }
+        }
+
         if !redis_is_multi_message(&msg) {
-            // This message isn't fragmentable, so it passes through untouched.
+            // This message isn't fragmentable, so it passes through untouched, although we may
+            // still need to rewrite its reply if any of the reply rules match it.
             let state = if msg.is_inline() {
                 MessageState::Inline
             } else {
-                MessageState::Standalone
+                match msg.get_command() {
+                    Some(cmd) if reply_rules.iter().any(|rule| rule.matches(cmd)) => {
+                        MessageState::Transformed(BytesMut::from(cmd))
+                    },
+                    _ => MessageState::Standalone,
+                }
             };
             fragments.push((state, msg));
         } else {
@@ -110,12 +354,15 @@ fn redis_fragment_messages(msgs: Vec<RedisMessage>) -> Result<Vec<(MessageState,
                     // will be for our fragments.
                     let cmd = args.remove(0);
                     let cmd_buf = redis_get_data_buffer(&cmd);
-                    let new_cmd_buf = match cmd_buf {
+                    let new_cmd_buf: &[u8] = match cmd_buf {
                         Some(buf) => {
-                            match buf {
+                            match &buf.to_ascii_lowercase()[..] {
                                 b"mget" => b"get",
-                                b"del" => b"del",
                                 b"mset" => b"set",
+                                REDIS_DEL => REDIS_DEL,
+                                REDIS_UNLINK => REDIS_UNLINK,
+                                REDIS_EXISTS => REDIS_EXISTS,
+                                REDIS_TOUCH => REDIS_TOUCH,
                                 x => {
                                     return Err(ProcessorError::FragmentError(format!(
                                         "tried to fragment command '{:?}' but command is not fragmentable!",
@@ -131,27 +378,30 @@ fn redis_fragment_messages(msgs: Vec<RedisMessage>) -> Result<Vec<(MessageState,
                         },
                     };
 
+                    // Make sure we have at least one key, and that we won't be left with extra
+                    // arguments, i.e. a key without a value for MSET.  This is the client's mistake,
+                    // so we tell them about it, same as Redis would, rather than dropping them.
+                    let arg_take_cnt = if new_cmd_buf == b"set" { 2 } else { 1 };
+                    if args.is_empty() || args.len() % arg_take_cnt != 0 {
+                        let err = format!(
+                            "wrong number of arguments for '{}' command",
+                            String::from_utf8_lossy(cmd_buf.unwrap_or_default()).to_lowercase()
+                        );
+                        fragments.push((MessageState::Inline, RedisMessage::from_error_str(&err)));
+                        continue;
+                    }
+
                     // Now we'll do the actual splitting.  We take the new command string (get for
-                    // mget, set for mset, and del for del) and build a buffer for it.  We extract
-                    // N arguments at a time from our original message, where N is either 1 or 2
-                    // depending on if this is a set operation.  With each N arguments, we build a
-                    // new message using the new command string and the arguments we extract.
+                    // mget, set for mset, and the command itself otherwise) and build a buffer for
+                    // it.  We extract N arguments at a time from our original message, where N is
+                    // either 1 or 2 depending on if this is a set operation.  With each N
+                    // arguments, we build a new message using the new command string and the
+                    // arguments we extract.
                     let cmd_arg = redis_new_data_buffer(&new_cmd_buf[..]);
                     let mut cmd_type = BytesMut::with_capacity(new_cmd_buf.len());
                     cmd_type.extend_from_slice(&new_cmd_buf[..]);
 
-                    let arg_take_cnt = if new_cmd_buf == b"set" { 2 } else { 1 };
-                    let total_fragments = args.len();
-
-                    // Make sure we won't be left with extra arguments.
-                    if total_fragments % arg_take_cnt != 0 {
-                        return Err(ProcessorError::FragmentError(format!(
-                            "incorrect multiple of argument count! (multiple: {}, arg count: {}, cmd type: {:?})",
-                            arg_take_cnt,
-                            args.len(),
-                            &cmd_type
-                        )));
-                    }
+                    let total_fragments = args.len() / arg_take_cnt;
 
                     // For get requests, we can stream back the fragments so long as they're in
                     // order.  We also need to make sure we provide the proper header (aka the data
@@ -197,6 +447,12 @@ fn redis_fragment_messages(msgs: Vec<RedisMessage>) -> Result<Vec<(MessageState,
     Ok(fragments)
 }
 
+/// Puts the responses to the fragments of a command back together into a single response.
+///
+/// Multi-key reads like MGET never get here: their fragments are streamed to the client one at a
+/// time, as soon as they're ready.  Almost everything that does get here collapses into a single
+/// integer or status reply, however many fragments there are.  The exception is a KEYS sent to
+/// every backend, whose reply grows with the keyspace, and so is capped.
 fn redis_defragment_messages(fragments: Vec<(MessageState, RedisMessage)>) -> Result<RedisMessage, ProcessorError> {
     // This shouldn't happen but it's a simple invariant that lets me write slightly cleaner code.
     if fragments.is_empty() {
@@ -215,26 +471,30 @@ fn redis_defragment_messages(fragments: Vec<(MessageState, RedisMessage)>) -> Re
         },
     };
 
-    // We have the command type, so let's actually defragment now.
-    match cmd_type.borrow() {
-        // DEL returns the number of keys it deleted, so we have to tally up the integer responses.
-        REDIS_DEL => {
-            let mut keys_deleted = 0;
+    // We have the command type, so let's actually defragment now.  Broadcast commands are
+    // fragmented by the pool rather than by us, so their command type is as the client sent it.
+    let cmd_type = cmd_type.to_ascii_lowercase();
+    match &cmd_type[..] {
+        // DEL and UNLINK return the number of keys they deleted, EXISTS and TOUCH the number of keys
+        // that existed, and DBSIZE the number of keys a backend holds, so we have to tally up the
+        // integer responses.
+        REDIS_DEL | REDIS_UNLINK | REDIS_EXISTS | REDIS_TOUCH | REDIS_DBSIZE => {
+            let mut total = 0;
             for (_state, fragment) in fragments {
                 match fragment {
-                    RedisMessage::Integer(_, value) => keys_deleted += value,
+                    RedisMessage::Integer(_, value) => total += value,
                     RedisMessage::Error(_, _) => return Ok(fragment),
                     _ => {
                         return Err(ProcessorError::DefragmentError(
-                            "non-integer response for DEL!".to_owned(),
+                            "non-integer response for DEL/UNLINK/EXISTS/TOUCH/DBSIZE!".to_owned(),
                         ));
                     },
                 }
             }
 
-            Ok(RedisMessage::from_integer(keys_deleted))
+            Ok(RedisMessage::from_integer(total))
         },
-        REDIS_SET => {
+        REDIS_SET | REDIS_FLUSHALL | REDIS_FLUSHDB => {
             // MSET is funny because it says it can't fail, but really, the command has no failure
             // mode _except_ for, like, you know, the server running out of memory.  However, MSET
             // also promises to be atomic.
@@ -243,6 +503,11 @@ fn redis_defragment_messages(fragments: Vec<(MessageState, RedisMessage)>) -> Re
             // completed, but we'll send back the first error we iterate over so we can at least
             // inform the caller that _something_ bad happened.  If we see no errors, we assume
             // everything went well, and send back the "normal" OK message.
+            //
+            // Once split across backends, MSET simply isn't atomic: an error means that some of
+            // the keys may have been written while others weren't, and nothing is rolled back.
+            // Fragments that never got a response at all, like when a backend is down, fail the
+            // command before we ever get here.
             for (_state, fragment) in fragments {
                 if let RedisMessage::Error(_, _) = fragment {
                     return Ok(fragment);
@@ -251,6 +516,29 @@ fn redis_defragment_messages(fragments: Vec<(MessageState, RedisMessage)>) -> Re
 
             Ok(RedisMessage::OK)
         },
+        // Every backend holds a different slice of the keyspace, so the keys they list are simply
+        // put together into one list.
+        REDIS_KEYS => redis_merge_keys(fragments, REDIS_KEYS_MAX_REPLY_BYTES),
+        // SCRIPT subcommands should get the same response from every backend, i.e. the same SHA1
+        // for SCRIPT LOAD.  If they don't, the backends are out of sync, and we can't pick one.
+        REDIS_SCRIPT => {
+            let responses = fragments.into_iter().map(|(_state, fragment)| fragment).collect::<Vec<_>>();
+            let err = responses.iter().find(|fragment| {
+                match fragment {
+                    RedisMessage::Error(_, _) => true,
+                    _ => false,
+                }
+            });
+            if let Some(err) = err {
+                return Ok(err.clone());
+            }
+
+            if responses.iter().any(|fragment| fragment != &responses[0]) {
+                return Ok(RedisMessage::from_error_str("backends returned different responses"));
+            }
+
+            Ok(responses.into_iter().next().unwrap())
+        },
         x => {
             Err(ProcessorError::DefragmentError(format!(
                 "unknown command type '{:?}'",
@@ -260,43 +548,419 @@ fn redis_defragment_messages(fragments: Vec<(MessageState, RedisMessage)>) -> Re
     }
 }
 
-fn redis_get_data_buffer(msg: &RedisMessage) -> Option<&[u8]> {
+/// Puts the keys listed by every backend for a `KEYS` into a single reply.
+///
+/// Unlike every other fragmented response, this one grows with the data, and the merged reply is a
+/// copy of every backend's reply on top of the replies themselves, so it's replaced with an error
+/// once the backends' replies add up to more than `max_bytes`.
+fn redis_merge_keys(
+    fragments: Vec<(MessageState, RedisMessage)>, max_bytes: usize,
+) -> Result<RedisMessage, ProcessorError> {
+    let mut keys = Vec::new();
+    let mut size = 0;
+    for (_state, fragment) in fragments {
+        let fragment_size = fragment.size();
+        match fragment {
+            RedisMessage::Bulk(_, args) => {
+                size += fragment_size;
+                if size > max_bytes {
+                    return Ok(RedisMessage::from_error_str("KEYS reply too large, use SCAN instead"));
+                }
+                keys.extend(args);
+            },
+            RedisMessage::Error(_, _) => return Ok(fragment),
+            _ => {
+                return Err(ProcessorError::DefragmentError("non-array response for KEYS!".to_owned()));
+            },
+        }
+    }
+
+    Ok(redis_new_bulk_from_args(keys))
+}
+
+pub fn redis_get_data_buffer(msg: &RedisMessage) -> Option<&[u8]> {
     match msg {
         RedisMessage::Data(buf, offset) => Some(redis_clean_data(buf, *offset)),
         _ => None,
     }
 }
 
-fn redis_is_multi_message(msg: &RedisMessage) -> bool {
+fn redis_is_cluster_message(msg: &RedisMessage) -> bool {
+    msg.get_command().map_or(false, |cmd| cmd.eq_ignore_ascii_case(REDIS_CLUSTER))
+}
+
+fn redis_get_cluster_subcommand(msg: &RedisMessage) -> Option<&[u8]> {
+    match msg {
+        RedisMessage::Bulk(_, args) if args.len() >= 2 => {
+            match redis_get_data_buffer(&args[0]) {
+                Some(cmd) if cmd.eq_ignore_ascii_case(REDIS_CLUSTER) => redis_get_data_buffer(&args[1]),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+fn redis_cluster_node_id(address: &SocketAddr) -> String {
+    // Node IDs are 40 hex characters, which happens to be the size of a SHA-1 digest, so we derive
+    // a stable ID from our address.
+    let mut hasher = Sha1::new();
+    hasher.input_str(&address.to_string());
+    hasher.result_str()
+}
+
+fn redis_cluster_response(subcmd: Option<&[u8]>, address: &SocketAddr) -> RedisMessage {
+    // We present ourselves as a single-node cluster that owns all 16384 slots, so that clients
+    // send everything to us and never try to follow a redirect.
+    let ip = address.ip().to_string();
+    let port = i64::from(address.port());
+    let node_id = redis_cluster_node_id(address);
+    let subcmd = subcmd.unwrap_or_default();
+
+    if subcmd.eq_ignore_ascii_case(b"slots") {
+        let node = redis_new_bulk_from_args(vec![
+            redis_new_data_buffer(ip.as_bytes()),
+            RedisMessage::from_integer(port),
+            redis_new_data_buffer(node_id.as_bytes()),
+        ]);
+        let slots = redis_new_bulk_from_args(vec![
+            RedisMessage::from_integer(0),
+            RedisMessage::from_integer(16383),
+            node,
+        ]);
+        redis_new_bulk_from_args(vec![slots])
+    } else if subcmd.eq_ignore_ascii_case(b"shards") {
+        let node = redis_new_bulk_from_args(vec![
+            redis_new_data_buffer(b"id"),
+            redis_new_data_buffer(node_id.as_bytes()),
+            redis_new_data_buffer(b"port"),
+            RedisMessage::from_integer(port),
+            redis_new_data_buffer(b"ip"),
+            redis_new_data_buffer(ip.as_bytes()),
+            redis_new_data_buffer(b"endpoint"),
+            redis_new_data_buffer(ip.as_bytes()),
+            redis_new_data_buffer(b"role"),
+            redis_new_data_buffer(b"master"),
+            redis_new_data_buffer(b"replication-offset"),
+            RedisMessage::from_integer(0),
+            redis_new_data_buffer(b"health"),
+            redis_new_data_buffer(b"online"),
+        ]);
+        let shard = redis_new_bulk_from_args(vec![
+            redis_new_data_buffer(b"slots"),
+            redis_new_bulk_from_args(vec![RedisMessage::from_integer(0), RedisMessage::from_integer(16383)]),
+            redis_new_data_buffer(b"nodes"),
+            redis_new_bulk_from_args(vec![node]),
+        ]);
+        redis_new_bulk_from_args(vec![shard])
+    } else if subcmd.eq_ignore_ascii_case(b"nodes") {
+        let node = format!(
+            "{} {}:{}@{} myself,master - 0 0 1 connected 0-16383\n",
+            node_id,
+            ip,
+            port,
+            port + 10000
+        );
+        redis_new_data_buffer(node.as_bytes())
+    } else {
+        RedisMessage::from_error_str("only CLUSTER SLOTS, SHARDS and NODES are supported")
+    }
+}
+
+fn redis_time_response() -> RedisMessage {
+    // The clock being set before the epoch is the only way this fails, and that's not worth
+    // failing the command over.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    redis_new_bulk_from_args(vec![
+        redis_new_data_buffer(now.as_secs().to_string().as_bytes()),
+        redis_new_data_buffer(now.subsec_micros().to_string().as_bytes()),
+    ])
+}
+
+fn redis_check_select(msg: &RedisMessage, allow_select: bool) -> Option<RedisMessage> {
+    if !allow_select {
+        return Some(RedisMessage::from_error_str("SELECT is not supported by this proxy: databases are not supported"));
+    }
+
     match msg {
+        RedisMessage::Bulk(_, args) if args.len() == 2 => {
+            match redis_get_selected_database(msg) {
+                Some(_) => None,
+                None => Some(RedisMessage::from_error_str("value is not an integer or out of range")),
+            }
+        },
+        _ => Some(RedisMessage::from_error_str("wrong number of arguments for 'select' command")),
+    }
+}
+
+fn redis_get_selected_database(msg: &RedisMessage) -> Option<usize> {
+    match msg {
+        RedisMessage::Bulk(_, args) if args.len() == 2 => {
+            match redis_get_data_buffer(&args[0]) {
+                Some(cmd) if cmd.eq_ignore_ascii_case(REDIS_SELECT) => {
+                    redis_get_data_buffer(&args[1])
+                        .and_then(|buf| std::str::from_utf8(buf).ok())
+                        .and_then(|buf| buf.parse::<usize>().ok())
+                },
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Splits a SCAN over the whole pool into a SCAN of the backend that the client's cursor is on.
+///
+/// Backends are scanned in order, each to completion before moving on to the next, so at any point,
+/// every backend before the current one has already returned cursor 0, and every backend after it
+/// is still at cursor 0.  That leaves the current backend's index and cursor as the only state,
+/// which we pack into a single integer, since clients expect cursors to be integers.  The rest of
+/// the arguments, like MATCH and COUNT, are passed along untouched.
+fn redis_split_scan(msg: &RedisMessage, backend_count: usize) -> Option<Result<(usize, RedisMessage), RedisMessage>> {
+    let args = match msg {
+        RedisMessage::Bulk(_, args) => args,
+        _ => return None,
+    };
+
+    match msg.get_command() {
+        Some(cmd) if cmd.eq_ignore_ascii_case(REDIS_SCAN) => {},
+        _ => return None,
+    }
+
+    if args.len() < 2 {
+        return Some(Err(RedisMessage::from_error_str("wrong number of arguments for 'scan' command")));
+    }
+
+    if backend_count as u64 > REDIS_SCAN_MAX_BACKENDS {
+        return Some(Err(RedisMessage::from_error_str("too many backends to SCAN")));
+    }
+
+    let composite = match redis_get_data_buffer(&args[1])
+        .and_then(|buf| std::str::from_utf8(buf).ok())
+        .and_then(|buf| buf.parse::<u64>().ok())
+    {
+        Some(composite) => composite,
+        None => return Some(Err(RedisMessage::from_error_str("invalid cursor"))),
+    };
+
+    let backend_idx = (composite & (REDIS_SCAN_MAX_BACKENDS - 1)) as usize;
+    let cursor = composite >> REDIS_SCAN_BACKEND_BITS;
+    if backend_idx >= backend_count {
+        return Some(Err(RedisMessage::from_error_str("invalid cursor")));
+    }
+
+    let mut new_args = args.clone();
+    new_args[1] = redis_new_data_buffer(cursor.to_string().as_bytes());
+    Some(Ok((backend_idx, redis_new_bulk_from_args(new_args))))
+}
+
+/// Rewrites a backend's page of SCAN results to carry a composite cursor for the whole pool.
+///
+/// Once a backend is done, the cursor moves on to the start of the next backend, and the cursor is
+/// only 0 once the last backend is done.
+fn redis_merge_scan(msg: RedisMessage, backend_idx: usize, backend_count: usize) -> RedisMessage {
+    let (cursor, keys) = match msg {
+        RedisMessage::Bulk(_, ref args) if args.len() == 2 => {
+            let cursor = redis_get_data_buffer(&args[0])
+                .and_then(|buf| std::str::from_utf8(buf).ok())
+                .and_then(|buf| buf.parse::<u64>().ok());
+            match cursor {
+                Some(cursor) => (cursor, args[1].clone()),
+                None => return RedisMessage::from_error_str("backend sent an invalid SCAN cursor"),
+            }
+        },
+        // Errors from the backend go back to the client as-is.
+        _ => return msg,
+    };
+
+    let composite = if cursor == 0 {
+        match backend_idx + 1 {
+            next if next >= backend_count => Some(0),
+            next => Some(next as u64),
+        }
+    } else {
+        cursor
+            .checked_mul(REDIS_SCAN_MAX_BACKENDS)
+            .map(|cursor| cursor | backend_idx as u64)
+    };
+
+    match composite {
+        Some(composite) => {
+            redis_new_bulk_from_args(vec![redis_new_data_buffer(composite.to_string().as_bytes()), keys])
+        },
+        None => RedisMessage::from_error_str("backend SCAN cursor is too large to pass along"),
+    }
+}
+
+fn redis_authenticate(
+    msg: &RedisMessage, requirepass: Option<&[u8]>, state: &mut ClientState,
+) -> Option<RedisMessage> {
+    let cmd = msg.command().map(|cmd| cmd.to_ascii_lowercase());
+    let cmd = cmd.as_ref().map(|cmd| &cmd[..]);
+    let args = match msg {
         RedisMessage::Bulk(_, args) => {
+            args.iter()
+                .map(redis_get_data_buffer)
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_default()
+        },
+        _ => Vec::new(),
+    };
+
+    // Credentials are never passed on to backends, which have their own, so we answer AUTH
+    // ourselves whether or not we have a password.  HELLO only changes how we talk to the client,
+    // so we answer it ourselves, too, once we know it's allowed.
+    let (version, credentials) = match cmd {
+        Some(REDIS_AUTH) => {
             match args.len() {
-                0 => false,
-                _ => {
-                    let arg = &args[0];
-                    match redis_get_data_buffer(arg) {
-                        Some(buf) => {
-                            match buf {
-                                b"mget" | b"mset" | b"del" => true,
-                                _ => false,
-                            }
-                        },
-                        None => false,
-                    }
-                },
+                2 => (None, Some((REDIS_DEFAULT_USER, args[1]))),
+                3 => (None, Some((args[1], args[2]))),
+                _ => return Some(RedisMessage::from_error_str("wrong number of arguments for 'auth' command")),
             }
         },
+        Some(REDIS_HELLO) => {
+            match redis_parse_hello(&args) {
+                Ok(hello) => hello,
+                Err(e) => return Some(e),
+            }
+        },
+        _ => (None, None),
+    };
+
+    match (credentials, requirepass) {
+        (Some(_), None) => {
+            return Some(RedisMessage::from_error_str(
+                "AUTH <password> called without any password configured for the default user",
+            ));
+        },
+        (Some((user, pass)), Some(requirepass)) => {
+            // How long it takes to turn away a wrong password shouldn't say how much of it was right.
+            if user != REDIS_DEFAULT_USER || !fixed_time_eq(pass, requirepass) {
+                return Some(RedisMessage::from_error_code(
+                    "WRONGPASS",
+                    "invalid username-password pair or user is disabled.",
+                ));
+            }
+            state.authenticated = true;
+        },
+        // Clients can always say goodbye, or start over, even if they never said hello.
+        (None, Some(_)) if !state.authenticated && cmd != Some(REDIS_QUIT) && cmd != Some(REDIS_RESET) => {
+            return Some(RedisMessage::from_error_code("NOAUTH", "Authentication required."));
+        },
+        _ => {},
+    }
+
+    match cmd {
+        Some(REDIS_AUTH) => Some(RedisMessage::OK),
+        Some(REDIS_HELLO) => {
+            // Only a HELLO that gets this far switches the protocol version.  One without a
+            // version just asks what we're already speaking.
+            if let Some(version) = version {
+                state.protocol_version = Some(version);
+            }
+            Some(redis_hello_response())
+        },
+        Some(REDIS_RESET) => {
+            // Starting over means authenticating again, and going back to the default protocol.
+            // This never reaches a backend, since the backend connection is shared with other
+            // clients.  The database picked with SELECT belongs to the router, so it's left as is.
+            state.authenticated = false;
+            state.protocol_version = None;
+            Some(RedisMessage::from_status("RESET"))
+        },
+        _ => None,
+    }
+}
+
+/// Parses the arguments of `HELLO [protover [AUTH username password] [SETNAME clientname]]`.
+///
+/// Returns the protocol version asked for, and the credentials given, if any, or the error to send
+/// back to the client.  Client names mean nothing to us, so they're accepted and ignored.
+fn redis_parse_hello<'a>(
+    args: &[&'a [u8]],
+) -> Result<(Option<usize>, Option<(&'a [u8], &'a [u8])>), RedisMessage> {
+    let version = match args.get(1) {
+        Some(arg) => {
+            std::str::from_utf8(arg)
+                .ok()
+                .and_then(|arg| arg.parse::<usize>().ok())
+                .ok_or_else(|| RedisMessage::from_error_str("Protocol version is not an integer or out of range"))?
+        },
+        None => return Ok((None, None)),
+    };
+
+    // We can parse RESP3, but replies from backends are passed along exactly as they were sent, in
+    // RESP2, so a client that asked for RESP3 would get replies in a protocol it didn't ask for.
+    // Until we can convert them, RESP3 is refused like any other version we don't speak.
+    if version != redis::REDIS_RESP2 {
+        return Err(RedisMessage::from_error_code(
+            "NOPROTO",
+            "sorry, this protocol version is not supported",
+        ));
+    }
+
+    let mut credentials = None;
+    let mut pos = 2;
+    while pos < args.len() {
+        let option = args[pos];
+        if option.eq_ignore_ascii_case(REDIS_AUTH) && pos + 2 < args.len() {
+            credentials = Some((args[pos + 1], args[pos + 2]));
+            pos += 3;
+        } else if option.eq_ignore_ascii_case(REDIS_SETNAME) && pos + 1 < args.len() {
+            pos += 2;
+        } else {
+            return Err(RedisMessage::from_error_str(&format!(
+                "Syntax error in HELLO option '{}'",
+                String::from_utf8_lossy(option)
+            )));
+        }
+    }
+
+    Ok((Some(version), credentials))
+}
+
+fn redis_hello_response() -> RedisMessage {
+    let entries = vec![
+        (redis_new_data_buffer(b"server"), redis_new_data_buffer(b"synchrotron")),
+        (
+            redis_new_data_buffer(b"version"),
+            redis_new_data_buffer(env!("CARGO_PKG_VERSION").as_bytes()),
+        ),
+        (
+            redis_new_data_buffer(b"proto"),
+            RedisMessage::from_integer(redis::REDIS_RESP2 as i64),
+        ),
+        (redis_new_data_buffer(b"mode"), redis_new_data_buffer(b"standalone")),
+        (redis_new_data_buffer(b"role"), redis_new_data_buffer(b"master")),
+        (redis_new_data_buffer(b"modules"), RedisMessage::from_array(Vec::new())),
+    ];
+
+    // RESP2 has no maps, so we send the same thing as a flat array of keys and values.
+    RedisMessage::from_array(entries.into_iter().flat_map(|(key, value)| vec![key, value]).collect())
+}
+
+fn redis_is_command_message(msg: &RedisMessage) -> bool {
+    match msg {
+        RedisMessage::Bulk(_, args) => !args.is_empty(),
+        RedisMessage::Data(_, _) | RedisMessage::Ping | RedisMessage::Quit => true,
         _ => false,
     }
 }
 
+fn redis_is_multi_message(msg: &RedisMessage) -> bool {
+    match msg.get_command() {
+        Some(cmd) => redis::get_key_arity(cmd) == KeyArity::Multi,
+        None => false,
+    }
+}
+
 fn redis_clean_data(buf: &BytesMut, offset: usize) -> &[u8] {
     assert!(buf.len() > 2);
     let val_len = buf.len() - 2;
     &buf[offset..val_len]
 }
 
-fn redis_new_data_buffer(buf: &[u8]) -> RedisMessage {
+pub fn redis_new_data_buffer(buf: &[u8]) -> RedisMessage {
     let mut new_buf = BytesMut::new();
     new_buf.extend_from_slice(b"$");
 
@@ -337,7 +1001,17 @@ fn redis_new_bulk_from_args(args: Vec<RedisMessage>) -> RedisMessage {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::{Error, ErrorKind};
+    use crate::{backend::message_queue::MessageQueue, common::MessageResponse};
+    use crate::common::EnqueuedRequest;
+    use crate::protocol::{errors::ProtocolError, redis::parse_messages};
+    use crate::util::MemoryBudget;
+    use std::{
+        io::{Error, ErrorKind, Read, Write},
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
 
     const STATUS_BUF: &str = "StAtUs_BuF";
     const DATA_BUF: &[u8; 8] = b"DaTa_BuF";
@@ -391,4 +1065,950 @@ mod tests {
         assert_eq!(dm_buf, Some(&DATA_BUF[..]));
         assert!(bm_buf.is_none());
     }
+
+    #[test]
+    fn test_reply_transforms() {
+        let config = |command: &str, transform: &str| {
+            ReplyTransformConfiguration {
+                command: command.to_owned(),
+                transform: transform.to_owned(),
+            }
+        };
+
+        let rules = vec![
+            ReplyRule::from_config(&config("GET", "null_to_empty")).unwrap(),
+            ReplyRule::from_config(&config("set", "ok_to_integer")).unwrap(),
+        ];
+        assert!(ReplyRule::from_config(&config("GET", "bogus")).is_err());
+
+        let processor = RedisProcessor::new().set_reply_rules(rules);
+        let fragments = processor
+            .fragment_messages(vec![
+                RedisMessage::from_inline("get foo"),
+                RedisMessage::from_inline("SET foo bar"),
+                RedisMessage::from_inline("INCR foo"),
+            ])
+            .unwrap();
+
+        assert_eq!(fragments[0].0, MessageState::Transformed(BytesMut::from(&b"get"[..])));
+        assert_eq!(fragments[1].0, MessageState::Transformed(BytesMut::from(&b"SET"[..])));
+        assert_eq!(fragments[2].0, MessageState::Standalone);
+
+        let empty = processor.transform_message(b"get", RedisMessage::Null).unwrap();
+        assert_eq!(empty.into_resp(), BytesMut::from(&b"$0\r\n\r\n"[..]));
+
+        let one = processor.transform_message(b"SET", RedisMessage::OK).unwrap();
+        assert_eq!(one, RedisMessage::from_integer(1));
+
+        let untouched = processor.transform_message(b"get", RedisMessage::OK).unwrap();
+        assert_eq!(untouched, RedisMessage::OK);
+    }
+
+    #[test]
+    fn test_mget_all_misses_returns_positional_nils() {
+        // Clients expect one nil per requested key for MGET, even if nothing matched, and never a
+        // single nil for the whole reply.
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let mget = RedisMessage::from_inline("mget key_one key_two key_three");
+        let assigned = queue.enqueue(vec![mget]).expect("failed to enqueue mget");
+        assert_eq!(assigned.len(), 3);
+
+        let responses = assigned
+            .into_iter()
+            .map(|(slot, _)| (slot, MessageResponse::Complete(RedisMessage::Null)))
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+
+        let mut buf = BytesMut::new();
+        let mut count = 0;
+        while let Some((sbuf, scount)) = queue.get_sendable_buf() {
+            buf.unsplit(sbuf);
+            count += scount;
+        }
+
+        assert_eq!(count, 1);
+        assert_eq!(&buf[..], &b"*3\r\n$-1\r\n$-1\r\n$-1\r\n"[..]);
+    }
+
+    fn drain_queue(queue: &mut MessageQueue<RedisProcessor>) -> (BytesMut, u64) {
+        let mut buf = BytesMut::new();
+        let mut count = 0;
+        while let Some((sbuf, scount)) = queue.get_sendable_buf() {
+            buf.unsplit(sbuf);
+            count += scount;
+        }
+
+        (buf, count)
+    }
+
+    #[test]
+    fn test_streaming_fragment_failure_is_positional() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let mget = RedisMessage::from_inline("mget key_one key_two key_three");
+        let assigned = queue.enqueue(vec![mget]).expect("failed to enqueue mget");
+
+        // Fail the middle fragment: only its position in the response should be an error.
+        let responses = assigned
+            .into_iter()
+            .enumerate()
+            .map(|(i, (slot, _))| {
+                if i == 1 {
+                    (slot, MessageResponse::Failed)
+                } else {
+                    (slot, MessageResponse::Complete(RedisMessage::Null))
+                }
+            })
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(
+            &buf[..],
+            &b"*3\r\n$-1\r\n-ERR failed to receive response\r\n$-1\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_aggregate_fragment_failure_fails_command() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let del = RedisMessage::from_inline("del key_one key_two key_three");
+        let assigned = queue.enqueue(vec![del]).expect("failed to enqueue del");
+
+        // Fail a single fragment: the summed response can't be trusted, so the whole command fails.
+        let responses = assigned
+            .into_iter()
+            .enumerate()
+            .map(|(i, (slot, _))| {
+                if i == 1 {
+                    (slot, MessageResponse::Failed)
+                } else {
+                    (slot, MessageResponse::Complete(RedisMessage::from_integer(1)))
+                }
+            })
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(
+            &buf[..],
+            &b"-ERR failed to receive response for 1 of 3 fragments\r\n"[..]
+        );
+
+        // Without any failures, we should get the sum.
+        let del = RedisMessage::from_inline("del key_one key_two key_three");
+        let assigned = queue.enqueue(vec![del]).expect("failed to enqueue del");
+        let responses = assigned
+            .into_iter()
+            .map(|(slot, _)| (slot, MessageResponse::Complete(RedisMessage::from_integer(1))))
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(&buf[..], &b":3\r\n"[..]);
+    }
+
+    #[test]
+    fn test_cluster_shim() {
+        let address = "127.0.0.1:7000".parse().unwrap();
+        let node_id = redis_cluster_node_id(&address);
+        assert_eq!(node_id.len(), 40);
+
+        let processor = RedisProcessor::new().set_announce_address(address);
+        let mut queue = MessageQueue::new(processor);
+        let msgs = vec![
+            RedisMessage::from_inline("cluster slots"),
+            RedisMessage::from_inline("CLUSTER NODES"),
+            RedisMessage::from_inline("cluster shards"),
+            RedisMessage::from_inline("cluster failover"),
+        ];
+
+        // None of these should ever need to go to a backend.
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue cluster commands");
+        assert!(assigned.is_empty());
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 4);
+
+        let nodes = format!("{} 127.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-16383\n", node_id);
+        let expected = format!(
+            "*1\r\n*3\r\n:0\r\n:16383\r\n*3\r\n$9\r\n127.0.0.1\r\n:7000\r\n$40\r\n{id}\r\n${}\r\n{}\r\n\
+             *1\r\n*4\r\n$5\r\nslots\r\n*2\r\n:0\r\n:16383\r\n$5\r\nnodes\r\n*1\r\n*14\r\n\
+             $2\r\nid\r\n$40\r\n{id}\r\n$4\r\nport\r\n:7000\r\n\
+             $2\r\nip\r\n$9\r\n127.0.0.1\r\n$8\r\nendpoint\r\n$9\r\n127.0.0.1\r\n\
+             $4\r\nrole\r\n$6\r\nmaster\r\n$18\r\nreplication-offset\r\n:0\r\n\
+             $6\r\nhealth\r\n$6\r\nonline\r\n\
+             -ERR only CLUSTER SLOTS, SHARDS and NODES are supported\r\n",
+            nodes.len(),
+            nodes,
+            id = node_id
+        );
+        assert_eq!(&buf[..], expected.as_bytes());
+    }
+
+    #[test]
+    fn test_cluster_without_announce_address() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let msgs = vec![
+            RedisMessage::from_inline("CLUSTER RESET"),
+            RedisMessage::from_inline("cluster slots"),
+            RedisMessage::from_inline("cluster"),
+        ];
+
+        // Without an address to announce, nothing about the cluster can be answered, but none of
+        // it can be allowed through to a backend either.
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue cluster commands");
+        assert!(assigned.is_empty());
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 3);
+        assert_eq!(
+            &buf[..],
+            &b"-ERR cluster_announce_address not configured\r\n\
+               -ERR cluster_announce_address not configured\r\n\
+               -ERR cluster_announce_address not configured\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_asking_is_noop() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let msgs = vec![RedisMessage::from_inline("ASKING"), RedisMessage::from_inline("get foo")];
+
+        // ASKING is acknowledged inline, and only the GET should go to a backend.
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 1);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(&buf[..], &b"+OK\r\n"[..]);
+    }
+
+    #[test]
+    fn test_time_is_answered_inline() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let msgs = vec![RedisMessage::from_inline("TIME")];
+
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert!(assigned.is_empty());
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+
+        let resp = String::from_utf8(buf.to_vec()).unwrap();
+        let parts = resp.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(parts[0], "*2");
+        assert_eq!(parts[1], format!("${}", parts[2].len()));
+        assert_eq!(parts[3], format!("${}", parts[4].len()));
+
+        let secs = parts[2].parse::<u64>().unwrap();
+        let micros = parts[4].parse::<u32>().unwrap();
+        assert!(secs >= before && secs <= before + 1);
+        assert!(micros < 1_000_000);
+    }
+
+    #[test]
+    fn test_hello_is_answered_inline() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let mut state = ClientState::default();
+        let msgs = vec![
+            RedisMessage::from_inline("HELLO 3"),
+            RedisMessage::from_inline("HELLO 2"),
+            RedisMessage::from_inline("HELLO 4"),
+            RedisMessage::from_inline("HELLO three"),
+        ];
+
+        let assigned = queue
+            .enqueue_authenticated(msgs, &mut state)
+            .expect("failed to enqueue messages");
+        assert!(assigned.is_empty());
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 4);
+
+        // Only RESP2 is spoken, since replies from backends are never converted to RESP3.
+        let noproto = "-NOPROTO sorry, this protocol version is not supported\r\n";
+        let resp = String::from_utf8(buf.to_vec()).unwrap();
+        assert!(resp.starts_with(noproto));
+        let resp2 = &resp[noproto.len()..];
+        assert!(resp2.starts_with("*12\r\n$6\r\nserver\r\n"));
+        assert!(resp2.contains("$5\r\nproto\r\n:2\r\n"));
+        assert!(resp.ends_with(&format!(
+            "{}-ERR Protocol version is not an integer or out of range\r\n",
+            noproto
+        )));
+    }
+
+    #[test]
+    fn test_hello_negotiates_protocol_version() {
+        let processor = RedisProcessor::new().set_requirepass(Some("hunter2".to_owned()));
+        let mut client_state = ClientState::default();
+        let mut hello = |cmd: &str| {
+            let reply = processor.authenticate(&RedisMessage::from_inline(cmd), &mut client_state);
+            let reply = reply.expect("HELLO should always be answered by the proxy").into_resp();
+            (String::from_utf8(reply.to_vec()).unwrap(), client_state.clone())
+        };
+
+        // Nothing changes until a HELLO actually succeeds.
+        let (reply, state) = hello("HELLO 2");
+        assert!(reply.starts_with("-NOAUTH"));
+        assert_eq!(state.protocol_version, None);
+
+        let (reply, state) = hello("HELLO 2 AUTH default hunter3");
+        assert!(reply.starts_with("-WRONGPASS"));
+        assert_eq!(state.protocol_version, None);
+        assert!(!state.authenticated);
+
+        // Options are read in order, so an option's value is never mistaken for an option.
+        let (reply, state) = hello("HELLO 2 SETNAME auth");
+        assert!(reply.starts_with("-NOAUTH"));
+        assert!(!state.authenticated);
+
+        let (reply, _) = hello("HELLO 2 AUTH default");
+        assert_eq!(reply, "-ERR Syntax error in HELLO option 'AUTH'\r\n");
+        let (reply, _) = hello("HELLO 2 FOO bar");
+        assert_eq!(reply, "-ERR Syntax error in HELLO option 'FOO'\r\n");
+
+        // RESP3 is refused before the credentials are even looked at.
+        let (reply, state) = hello("HELLO 3 AUTH default hunter2");
+        assert!(reply.starts_with("-NOPROTO"));
+        assert_eq!(state.protocol_version, None);
+        assert!(!state.authenticated);
+
+        let (reply, state) = hello("HELLO 2 SETNAME auth AUTH default hunter2");
+        assert!(reply.starts_with("*12\r\n"));
+        assert_eq!(state.protocol_version, Some(redis::REDIS_RESP2));
+        assert!(state.authenticated);
+
+        // Asking without a version gets the one we're already speaking, and asking for one we
+        // don't speak leaves things as they were.
+        let (reply, _) = hello("HELLO");
+        assert!(reply.starts_with("*12\r\n"));
+        let (reply, state) = hello("HELLO 3");
+        assert!(reply.starts_with("-NOPROTO"));
+        assert_eq!(state.protocol_version, Some(redis::REDIS_RESP2));
+    }
+
+    #[test]
+    fn test_select_is_left_for_the_router() {
+        // Without database routing, SELECT is rejected outright.
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let assigned = queue
+            .enqueue(vec![RedisMessage::from_inline("SELECT 2")])
+            .expect("failed to enqueue messages");
+        assert!(assigned.is_empty());
+
+        let (buf, _) = drain_queue(&mut queue);
+        assert_eq!(
+            &buf[..],
+            &b"-ERR SELECT is not supported by this proxy: databases are not supported\r\n"[..]
+        );
+
+        // With it, malformed SELECTs are still rejected, but valid ones make it to the router.
+        let mut queue = MessageQueue::new(RedisProcessor::new().set_allow_select(true));
+        let msgs = vec![
+            RedisMessage::from_inline("SELECT two"),
+            RedisMessage::from_inline("SELECT"),
+            RedisMessage::from_inline("SELECT 2"),
+        ];
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 1);
+        assert_eq!(RedisProcessor::new().get_selected_database(&assigned[0].1), Some(2));
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 2);
+        let expected = "-ERR value is not an integer or out of range\r\n\
+                        -ERR wrong number of arguments for 'select' command\r\n";
+        assert_eq!(&buf[..], expected.as_bytes());
+    }
+
+    #[test]
+    fn test_commands_before_auth_are_rejected() {
+        let processor = RedisProcessor::new().set_requirepass(Some("hunter2".to_owned()));
+        let mut queue = MessageQueue::new(processor);
+        let mut state = ClientState::default();
+
+        let msgs = vec![
+            RedisMessage::from_inline("GET foo"),
+            RedisMessage::from_inline("PING"),
+            RedisMessage::from_inline("HELLO 2"),
+        ];
+        let assigned = queue
+            .enqueue_authenticated(msgs, &mut state)
+            .expect("failed to enqueue messages");
+        assert!(assigned.is_empty());
+        assert!(!state.authenticated);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 3);
+        assert_eq!(&buf[..], "-NOAUTH Authentication required.\r\n".repeat(3).as_bytes());
+    }
+
+    #[test]
+    fn test_reset_before_and_after_auth() {
+        let processor = RedisProcessor::new().set_requirepass(Some("hunter2".to_owned()));
+        let mut queue = MessageQueue::new(processor);
+        let mut state = ClientState::default();
+
+        // RESET is allowed before authenticating, like in Redis, and afterwards it takes the
+        // authentication away again.
+        let msgs = vec![
+            RedisMessage::from_inline("RESET"),
+            RedisMessage::from_inline("AUTH hunter2"),
+            RedisMessage::from_inline("RESET"),
+            RedisMessage::from_inline("GET foo"),
+        ];
+        let assigned = queue
+            .enqueue_authenticated(msgs, &mut state)
+            .expect("failed to enqueue messages");
+        assert!(assigned.is_empty());
+        assert!(!state.authenticated);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 4);
+        let expected = "+RESET\r\n+OK\r\n+RESET\r\n-NOAUTH Authentication required.\r\n";
+        assert_eq!(&buf[..], expected.as_bytes());
+    }
+
+    #[test]
+    fn test_auth_with_wrong_password() {
+        let processor = RedisProcessor::new().set_requirepass(Some("hunter2".to_owned()));
+        let mut queue = MessageQueue::new(processor);
+        let mut state = ClientState::default();
+
+        let msgs = vec![
+            RedisMessage::from_inline("AUTH hunter3"),
+            RedisMessage::from_inline("AUTH someone hunter2"),
+            RedisMessage::from_inline("HELLO 2 AUTH default hunter3"),
+            RedisMessage::from_inline("GET foo"),
+        ];
+        let assigned = queue
+            .enqueue_authenticated(msgs, &mut state)
+            .expect("failed to enqueue messages");
+        assert!(assigned.is_empty());
+        assert!(!state.authenticated);
+
+        let wrongpass = "-WRONGPASS invalid username-password pair or user is disabled.\r\n".repeat(3);
+        let expected = wrongpass + "-NOAUTH Authentication required.\r\n";
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 4);
+        assert_eq!(&buf[..], expected.as_bytes());
+    }
+
+    #[test]
+    fn test_auth_with_correct_password() {
+        let processor = RedisProcessor::new().set_requirepass(Some("hunter2".to_owned()));
+        let mut queue = MessageQueue::new(processor.clone());
+        let mut state = ClientState::default();
+
+        // Once authenticated, the rest of the batch goes through as usual.
+        let msgs = vec![
+            RedisMessage::from_inline("AUTH hunter2"),
+            RedisMessage::from_inline("GET foo"),
+        ];
+        let assigned = queue
+            .enqueue_authenticated(msgs, &mut state)
+            .expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 1);
+        assert!(state.authenticated);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(&buf[..], &b"+OK\r\n"[..]);
+
+        // HELLO can authenticate too, in which case it gets its usual reply.
+        let mut queue = MessageQueue::new(processor);
+        let mut state = ClientState::default();
+
+        let msgs = vec![RedisMessage::from_inline("HELLO 2 AUTH default hunter2")];
+        let assigned = queue
+            .enqueue_authenticated(msgs, &mut state)
+            .expect("failed to enqueue messages");
+        assert!(assigned.is_empty());
+        assert!(state.authenticated);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert!(buf.starts_with(b"*12\r\n$6\r\nserver\r\n"));
+    }
+
+    #[test]
+    fn test_auth_without_requirepass() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let mut state = ClientState::default();
+
+        // Without a password, everyone gets in, but AUTH still never makes it to a backend.
+        let msgs = vec![
+            RedisMessage::from_inline("AUTH hunter2"),
+            RedisMessage::from_inline("GET foo"),
+        ];
+        let assigned = queue
+            .enqueue_authenticated(msgs, &mut state)
+            .expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 1);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert!(buf.starts_with(b"-ERR AUTH <password> called without any password configured"));
+    }
+
+    #[test]
+    fn test_replies_are_rejected() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let input = b"+OK\r\n:1\r\n*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n";
+        let (msgs, _) = parse_messages(&input[..], None).expect("failed to parse messages");
+        assert_eq!(msgs.len(), 3);
+
+        // Only the real command should make it to a backend, and the replies we were sent should
+        // get errors in their place.
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 1);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 2);
+        assert_eq!(&buf[..], &b"-ERR unknown command\r\n-ERR unknown command\r\n"[..]);
+    }
+
+    #[test]
+    fn test_uppercase_multi_commands_fragment() {
+        let processor = RedisProcessor::new();
+        let fragments = processor
+            .fragment_messages(vec![RedisMessage::from_inline("MGET foo bar")])
+            .expect("failed to fragment messages");
+
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0].1.get_command(), Some(&b"get"[..]));
+        assert_eq!(fragments[1].1.key(), &b"bar"[..]);
+    }
+
+    #[test]
+    fn test_mset_fragments_by_pair() {
+        let processor = RedisProcessor::new();
+        let fragments = processor
+            .fragment_messages(vec![RedisMessage::from_inline("MSET foo 1 bar 2")])
+            .expect("failed to fragment messages");
+
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0].0, MessageState::Fragmented(BytesMut::from(&b"set"[..]), 0, 2));
+        assert_eq!(fragments[0].1, RedisMessage::from_inline("set foo 1"));
+        assert_eq!(fragments[1].0, MessageState::Fragmented(BytesMut::from(&b"set"[..]), 1, 2));
+        assert_eq!(fragments[1].1, RedisMessage::from_inline("set bar 2"));
+    }
+
+    #[test]
+    fn test_multi_commands_with_wrong_arg_count_are_rejected() {
+        let processor = RedisProcessor::new();
+        let fragments = processor
+            .fragment_messages(vec![
+                RedisMessage::from_inline("MSET foo 1 bar"),
+                RedisMessage::from_inline("mget"),
+                RedisMessage::from_inline("get foo"),
+            ])
+            .expect("failed to fragment messages");
+
+        // The bad commands get errors in place, and everything else carries on as usual.
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments[0].0, MessageState::Inline);
+        assert_eq!(
+            fragments[0].1,
+            RedisMessage::from_error_str("wrong number of arguments for 'mset' command")
+        );
+        assert_eq!(fragments[1].0, MessageState::Inline);
+        assert_eq!(
+            fragments[1].1,
+            RedisMessage::from_error_str("wrong number of arguments for 'mget' command")
+        );
+        assert_eq!(fragments[2].0, MessageState::Standalone);
+    }
+
+    #[test]
+    fn test_broadcast_defragment() {
+        let processor = RedisProcessor::new();
+        let broadcast = |cmd: &str, responses: Vec<RedisMessage>| {
+            let count = responses.len();
+            let fragments = responses
+                .into_iter()
+                .enumerate()
+                .map(|(i, msg)| (MessageState::Fragmented(BytesMut::from(cmd), i, count), msg))
+                .collect();
+            processor.defragment_messages(fragments).expect("failed to defragment")
+        };
+
+        let dbsize = broadcast("DBSIZE", vec![RedisMessage::from_integer(3), RedisMessage::from_integer(4)]);
+        assert_eq!(dbsize, RedisMessage::from_integer(7));
+
+        let flushall = broadcast("flushall", vec![RedisMessage::OK, RedisMessage::OK]);
+        assert_eq!(flushall, RedisMessage::OK);
+
+        let exists = broadcast("EXISTS", vec![RedisMessage::from_integer(1), RedisMessage::from_integer(0)]);
+        assert_eq!(exists, RedisMessage::from_integer(1));
+
+        let failed_unlink = broadcast("unlink", vec![RedisMessage::from_integer(1), ERR_MSG.clone()]);
+        assert_eq!(failed_unlink, ERR_MSG.clone());
+
+        let failed_flushall = broadcast("FLUSHALL", vec![RedisMessage::OK, ERR_MSG.clone()]);
+        assert_eq!(failed_flushall, ERR_MSG.clone());
+
+        let load = broadcast("SCRIPT", vec![DATA_MSG.clone(), DATA_MSG.clone()]);
+        assert_eq!(load, DATA_MSG.clone());
+
+        let diverged = broadcast("SCRIPT", vec![DATA_MSG.clone(), DATA_MSG_2.clone()]);
+        assert_eq!(diverged, RedisMessage::from_error_str("backends returned different responses"));
+
+        let keys = broadcast(
+            "keys",
+            vec![
+                RedisMessage::from_array(vec![DATA_MSG.clone()]),
+                RedisMessage::from_array(vec![]),
+                RedisMessage::from_array(vec![DATA_MSG_2.clone(), DATA_MSG.clone()]),
+            ],
+        );
+        assert_eq!(keys, RedisMessage::from_array(vec![DATA_MSG.clone(), DATA_MSG_2.clone(), DATA_MSG.clone()]));
+
+        let failed_keys = broadcast("KEYS", vec![RedisMessage::from_array(vec![]), ERR_MSG.clone()]);
+        assert_eq!(failed_keys, ERR_MSG.clone());
+
+        // Lists of keys too big to put together are refused, rather than buffered in full.
+        let list = RedisMessage::from_array(vec![DATA_MSG.clone(), DATA_MSG_2.clone()]);
+        let max_bytes = list.size() * 2;
+        let fragments = |count: usize| {
+            (0..count)
+                .map(|i| (MessageState::Fragmented(BytesMut::from("keys"), i, count), list.clone()))
+                .collect::<Vec<_>>()
+        };
+        let keys = redis_merge_keys(fragments(2), max_bytes).expect("failed to merge keys");
+        let expected = vec![DATA_MSG.clone(), DATA_MSG_2.clone(), DATA_MSG.clone(), DATA_MSG_2.clone()];
+        assert_eq!(keys, RedisMessage::from_array(expected));
+        let too_many_keys = redis_merge_keys(fragments(3), max_bytes).expect("failed to merge keys");
+        assert_eq!(too_many_keys, RedisMessage::from_error_str("KEYS reply too large, use SCAN instead"));
+    }
+
+    #[test]
+    fn test_blocking_commands_rejected() {
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let msgs = vec![RedisMessage::from_inline("blpop queue 0"), RedisMessage::from_inline("lpop queue")];
+
+        // The blocking command is rejected inline, and only the non-blocking one goes to a backend.
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 1);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(
+            &buf[..],
+            &b"-ERR blocking commands are not supported through this proxy\r\n"[..]
+        );
+
+        // Once allowed, blocking commands go to the backend like anything else.
+        let mut queue = MessageQueue::new(RedisProcessor::new().set_allow_blocking(true));
+        let msgs = vec![RedisMessage::from_inline("blpop queue 0")];
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 1);
+    }
+
+    #[test]
+    fn test_command_options_pass_through() {
+        let msgs = vec![
+            RedisMessage::from_inline("SET key val KEEPTTL"),
+            RedisMessage::from_inline("EXPIRE key 100 GT"),
+        ];
+        let original = msgs.iter().map(|msg| msg.get_buf()).collect::<Vec<_>>();
+
+        // Neither of these should be fragmented or altered on their way to the backend.
+        let fragments = redis_fragment_messages(msgs, false, false, None, &[]).expect("failed to fragment messages");
+        assert_eq!(fragments.len(), 2);
+
+        for ((state, msg), buf) in fragments.into_iter().zip(original) {
+            assert_eq!(state, MessageState::Standalone);
+            assert_eq!(msg.key(), &b"key"[..]);
+            assert_eq!(msg.into_buf(), buf);
+        }
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mut queue = MessageQueue::new(RedisProcessor::new()).set_max_pending(Some(3));
+        assert!(!queue.is_full());
+
+        // Each fragment takes up its own slot.
+        let assigned = queue
+            .enqueue(vec![RedisMessage::from_inline("mget foo bar baz")])
+            .expect("failed to enqueue mget");
+        assert!(queue.is_full());
+
+        let responses = assigned
+            .into_iter()
+            .map(|(slot, _)| (slot, MessageResponse::Complete(RedisMessage::Null)))
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+
+        let (_, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert!(!queue.is_full());
+    }
+
+    #[test]
+    fn test_dedupe_reads() {
+        let mut queue = MessageQueue::new(RedisProcessor::new()).set_dedupe_reads(true);
+        let msgs = vec![
+            RedisMessage::from_inline("get foo"),
+            RedisMessage::from_inline("get bar"),
+            RedisMessage::from_inline("get foo"),
+            RedisMessage::from_inline("set foo baz"),
+            RedisMessage::from_inline("get foo"),
+        ];
+
+        // The second `get foo` is served by the first, but the write in between means the last
+        // `get foo` has to go to the backend.
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 4);
+
+        let responses = assigned
+            .into_iter()
+            .enumerate()
+            .map(|(i, (slot, _))| (slot, MessageResponse::Complete(RedisMessage::from_integer(i as i64))))
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 5);
+        assert_eq!(&buf[..], &b":0\r\n:1\r\n:0\r\n:2\r\n:3\r\n"[..]);
+
+        // Without deduplication, every read goes to the backend.
+        let mut queue = MessageQueue::new(RedisProcessor::new());
+        let msgs = vec![RedisMessage::from_inline("get foo"), RedisMessage::from_inline("get foo")];
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 2);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let used = Arc::new(AtomicUsize::new(0));
+        let budget = MemoryBudget::new(64, used.clone());
+        let mut queue = MessageQueue::new(RedisProcessor::new()).set_memory_budget(Some(budget));
+
+        let get = RedisMessage::from_inline("get foo");
+        let get_size = get.size();
+        let assigned = queue.enqueue(vec![get]).expect("failed to enqueue messages");
+        assert_eq!(used.load(Ordering::SeqCst), get_size);
+        assert!(!queue.is_over_budget());
+
+        // The response counts against the budget alongside the request until it's been sent.
+        let response = redis_new_data_buffer(&[b'a'; 64][..]);
+        let response_size = response.size();
+        let responses = assigned
+            .into_iter()
+            .map(|(slot, _)| (slot, MessageResponse::Complete(response.clone())))
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+        assert_eq!(used.load(Ordering::SeqCst), get_size + response_size);
+        assert!(queue.is_over_budget());
+
+        let (_, count) = drain_queue(&mut queue);
+        assert_eq!(count, 1);
+        assert_eq!(used.load(Ordering::SeqCst), 0);
+
+        // Anything still pending when the client goes away is released, too.
+        queue.enqueue(vec![RedisMessage::from_inline("get foo")]).expect("failed to enqueue messages");
+        assert_eq!(used.load(Ordering::SeqCst), get_size);
+        drop(queue);
+        assert_eq!(used.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_coalesce_writes() {
+        let mut queue = MessageQueue::new(RedisProcessor::new()).set_coalesce_writes(true);
+        let msgs = vec![
+            RedisMessage::from_inline("set foo bar"),
+            RedisMessage::from_inline("set foo bar"),
+            RedisMessage::from_inline("set foo bar"),
+            RedisMessage::from_inline("del foo"),
+            RedisMessage::from_inline("del foo"),
+            RedisMessage::from_inline("set foo baz"),
+            RedisMessage::from_inline("get foo"),
+            RedisMessage::from_inline("set foo baz"),
+            RedisMessage::from_inline("set foo baz nx"),
+            RedisMessage::from_inline("set foo baz nx"),
+        ];
+
+        // Only identical writes right next to each other are coalesced: the `get` in between the
+        // second pair of `set foo baz` means the last one has to go to the backend, and `SET` with
+        // options never gets coalesced.
+        let assigned = queue.enqueue(msgs).expect("failed to enqueue messages");
+        assert_eq!(assigned.len(), 7);
+        assert_eq!(queue.in_flight(), 7);
+
+        let responses = vec![
+            RedisMessage::OK,
+            RedisMessage::from_integer(1),
+            RedisMessage::OK,
+            RedisMessage::Null,
+            RedisMessage::OK,
+            RedisMessage::OK,
+            RedisMessage::Null,
+        ];
+        let responses = assigned
+            .into_iter()
+            .zip(responses)
+            .map(|((slot, _), response)| (slot, MessageResponse::Complete(response)))
+            .collect::<Vec<_>>();
+        queue.fulfill(responses);
+        assert_eq!(queue.in_flight(), 0);
+
+        // A repeated `DEL` deletes nothing, since the key is already gone.
+        let (buf, count) = drain_queue(&mut queue);
+        assert_eq!(count, 10);
+        assert_eq!(
+            &buf[..],
+            &b"+OK\r\n+OK\r\n+OK\r\n:1\r\n:0\r\n+OK\r\n$-1\r\n+OK\r\n+OK\r\n$-1\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_backend_replies_to_half_a_batch() {
+        let msgs = (0..4)
+            .map(|i| RedisMessage::from_inline(&format!("get key{}", i)))
+            .collect::<Vec<_>>();
+        let request_len = msgs.iter().map(|msg| msg.clone().into_buf().len()).sum::<usize>();
+
+        // Our fake backend reads the whole batch, but only answers the first half of it before
+        // going away, as if it crashed mid-reply.
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind fake backend");
+        let address = listener.local_addr().expect("failed to get fake backend address");
+        let backend = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().expect("failed to accept connection");
+            let mut buf = vec![0; request_len];
+            conn.read_exact(&mut buf).expect("failed to read batch");
+            conn.write_all(b"$3\r\nfoo\r\n$3\r\nbar\r\n").expect("failed to write replies");
+        });
+
+        let mut rxs = Vec::new();
+        let requests = msgs
+            .into_iter()
+            .enumerate()
+            .map(|(i, msg)| {
+                let mut request = EnqueuedRequest::new(i, msg);
+                rxs.push(request.get_response_rx().expect("request should have a response"));
+                request
+            })
+            .collect::<Vec<_>>();
+
+        let processor = RedisProcessor::new();
+        let stream = Either::B(processor.preconnect(&address, &Connector::new(), false));
+        let result = processor.process(requests, stream, IoTimeouts::default()).wait();
+        backend.join().expect("fake backend panicked");
+
+        // The connection is no good once the backend has gone away mid-batch, so the batch fails
+        // instead of waiting forever on the rest of the replies.
+        match result {
+            Err(ProtocolError::BackendClosedPrematurely) => {},
+            _ => panic!("batch should have failed"),
+        }
+
+        // The replies we did get belong to the first half of the batch, and the rest get errors.
+        let responses = rxs
+            .into_iter()
+            .map(|rx| {
+                match rx.wait().expect("request was never answered") {
+                    (_, MessageResponse::Complete(msg)) => msg,
+                    (_, MessageResponse::Failed) => panic!("request should have a response"),
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(responses[0], redis_new_data_buffer(b"foo"));
+        assert_eq!(responses[1], redis_new_data_buffer(b"bar"));
+        assert_eq!(responses[2], RedisMessage::from_error_str("backend closed prematurely"));
+        assert_eq!(responses[3], RedisMessage::from_error_str("backend closed prematurely"));
+    }
+
+    #[test]
+    fn test_backend_read_timeout() {
+        let msg = RedisMessage::from_inline("get key");
+        let request_len = msg.clone().into_buf().len();
+
+        // Our fake backend reads the request, and then stalls, holding the connection open without
+        // ever answering, until we're done with it.
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind fake backend");
+        let address = listener.local_addr().expect("failed to get fake backend address");
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let backend = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().expect("failed to accept connection");
+            let mut buf = vec![0; request_len];
+            conn.read_exact(&mut buf).expect("failed to read request");
+            let _ = done_rx.recv();
+        });
+
+        let mut request = EnqueuedRequest::new(0, msg);
+        let rx = request.get_response_rx().expect("request should have a response");
+
+        // The read timeout runs on a timer, so the batch has to run on a real runtime.
+        let (tx, result_rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(futures::future::lazy(move || {
+            let processor = RedisProcessor::new();
+            let stream = Either::B(processor.preconnect(&address, &Connector::new(), false));
+            let io_timeouts = IoTimeouts {
+                read_ms: 50,
+                write_ms: 0,
+            };
+            let start = Instant::now();
+            processor
+                .process(vec![request], stream, io_timeouts)
+                .then(move |result| {
+                    let timed_out = match result {
+                        Err(ProtocolError::IoError(ref e)) => e.kind() == ErrorKind::TimedOut,
+                        _ => false,
+                    };
+                    let _ = tx.send((timed_out, start.elapsed()));
+                    Ok(())
+                })
+        }));
+        let _ = done_tx.send(());
+        backend.join().expect("fake backend panicked");
+
+        // The batch fails once the backend has gone quiet for long enough, rather than waiting on
+        // it forever, and the request gets an error saying why.
+        let (timed_out, elapsed) = result_rx.recv().expect("batch never finished");
+        assert!(timed_out);
+        assert!(elapsed >= Duration::from_millis(50));
+        match rx.wait().expect("request was never answered") {
+            (_, MessageResponse::Complete(msg)) => assert_eq!(msg, RedisMessage::from_error_str("backend timed out")),
+            (_, MessageResponse::Failed) => panic!("request should have a response"),
+        }
+    }
+
+    #[test]
+    fn test_scan_composite_cursor() {
+        let page = |cursor: &str, keys: &[&str]| {
+            let keys = keys.iter().map(|key| redis_new_data_buffer(key.as_bytes())).collect();
+            redis_new_bulk_from_args(vec![redis_new_data_buffer(cursor.as_bytes()), RedisMessage::from_array(keys)])
+        };
+
+        // A fresh scan starts at the beginning of the first backend.
+        let scan = RedisMessage::from_inline("scan 0 match foo* count 5");
+        let (idx, request) = redis_split_scan(&scan, 3).expect("not a scan").expect("scan was rejected");
+        assert_eq!(idx, 0);
+        assert_eq!(request, RedisMessage::from_inline("scan 0 match foo* count 5"));
+
+        // Partway through a backend, its cursor carries the backend index along with it.
+        let merged = redis_merge_scan(page("17", &["foo1"]), 1, 3);
+        assert_eq!(merged, page(&((17 << REDIS_SCAN_BACKEND_BITS) | 1).to_string(), &["foo1"]));
+        let scan = RedisMessage::from_inline(&format!("scan {} count 5", (17 << REDIS_SCAN_BACKEND_BITS) | 1));
+        let (idx, request) = redis_split_scan(&scan, 3).expect("not a scan").expect("scan was rejected");
+        assert_eq!(idx, 1);
+        assert_eq!(request, RedisMessage::from_inline("scan 17 count 5"));
+
+        // Finishing a backend moves on to the next one, and finishing the last one ends the scan.
+        assert_eq!(redis_merge_scan(page("0", &["foo2"]), 0, 3), page("1", &["foo2"]));
+        assert_eq!(redis_merge_scan(page("0", &[]), 2, 3), page("0", &[]));
+
+        // Cursors that don't point at a backend in the pool are rejected.
+        let scan = RedisMessage::from_inline("scan 5");
+        assert_eq!(redis_split_scan(&scan, 3), Some(Err(RedisMessage::from_error_str("invalid cursor"))));
+        let scan = RedisMessage::from_inline("scan nope");
+        assert_eq!(redis_split_scan(&scan, 3), Some(Err(RedisMessage::from_error_str("invalid cursor"))));
+        assert_eq!(redis_split_scan(&RedisMessage::from_inline("get scan"), 3), None);
+    }
 }
@@ -19,16 +19,46 @@
 // SOFTWARE.
 use crate::util::Sizable;
 use bytes::BytesMut;
+use fnv::FnvHashMap;
+use futures::{Async, Future};
+use std::sync::{Arc, Mutex};
 use tokio::sync::oneshot::{channel, Receiver, Sender};
 
 pub trait Message: Sizable {
     fn key(&self) -> &[u8];
+    fn command(&self) -> Option<&[u8]>;
     fn is_inline(&self) -> bool;
+    fn is_error(buf: &[u8]) -> bool;
+    fn is_read(&self) -> bool;
+
+    /// Whether or not sending this write again, immediately after itself, is guaranteed to leave
+    /// the data exactly as it was after the first write.
+    fn is_idempotent_write(&self) -> bool;
+
+    /// Gets the response that sending this write again, immediately after itself, would get, given
+    /// the response to the first write.
+    fn get_repeated_response(&self, response: &Self) -> Self;
+
+    /// Whether or not this message can block on the backend until some condition is met, holding
+    /// its connection for an arbitrary amount of time.
+    fn is_blocking(&self) -> bool;
+
+    /// Whether or not this message operates on the entire keyspace, rather than specific keys, and
+    /// so must be sent to every backend.
+    fn is_broadcast(&self) -> bool;
+
+    /// Whether or not this message lists every key in the keyspace matching some pattern, which is
+    /// expensive enough for a backend that sending it to every backend is opt-in.
+    fn is_key_listing(&self) -> bool;
+
+    /// Gets all of the keys for this message, if it operates on multiple keys that must all be
+    /// served by the same backend.
+    fn colocated_keys(&self) -> Option<Vec<&[u8]>>;
     fn into_buf(self) -> BytesMut;
 }
 
 /// Message response types for a queued message.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum MessageResponse<T> {
     /// The message ultimately "failed".  This happens if a queued message is dropped before having
     /// a response sent for it, which may happen if an error occurs during the backend read, etc.
@@ -51,12 +81,45 @@ pub type PendingResponse<T> = Receiver<AssignedResponse<T>>;
 pub type PendingResponses<T> = Vec<PendingResponse<T>>;
 pub type EnqueuedRequests<T> = Vec<EnqueuedRequest<T>>;
 
+/// Which backend each of a client's requests was sent to, by request ID.
+///
+/// Routers hand this to the requests they create, and backends record themselves in it as the
+/// requests are handed to them, so that the access log can say where each request went.
+#[derive(Clone, Default)]
+pub struct RouteLog {
+    routes: Arc<Mutex<FnvHashMap<usize, Arc<String>>>>,
+}
+
+impl RouteLog {
+    fn record(&self, id: usize, backend: &Arc<String>) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.insert(id, backend.clone());
+        }
+    }
+
+    /// Records that a request was sent wherever another request was sent.
+    pub fn copy(&self, from: usize, to: usize) {
+        if let Ok(mut routes) = self.routes.lock() {
+            if let Some(backend) = routes.get(&from).cloned() {
+                routes.insert(to, backend);
+            }
+        }
+    }
+
+    /// Takes the backend the given request was sent to, if it was sent to one.
+    pub fn take(&self, id: usize) -> Option<Arc<String>> {
+        self.routes.lock().ok().and_then(|mut routes| routes.remove(&id))
+    }
+}
+
 pub struct EnqueuedRequest<T: Clone + Message> {
     id: usize,
     request: Option<T>,
     has_response: bool,
     done: bool,
+    cancellable: bool,
     tx: Option<Sender<AssignedResponse<T>>>,
+    route_log: Option<RouteLog>,
 }
 
 impl<T: Clone + Message> EnqueuedRequest<T> {
@@ -67,6 +130,8 @@ impl<T: Clone + Message> EnqueuedRequest<T> {
             tx: None,
             has_response: true,
             done: false,
+            cancellable: false,
+            route_log: None,
         }
     }
 
@@ -77,6 +142,46 @@ impl<T: Clone + Message> EnqueuedRequest<T> {
             tx: None,
             has_response: false,
             done: true,
+            cancellable: false,
+            route_log: None,
+        }
+    }
+
+    /// Sets where this request records which backend it was sent to.
+    pub fn set_route_log(mut self, route_log: Option<RouteLog>) -> Self {
+        self.route_log = route_log;
+        self
+    }
+
+    /// Sets whether or not this request can be dropped, rather than sent, once nobody is waiting on
+    /// its response anymore.
+    ///
+    /// Only requests that don't change anything should be cancellable, since a cancelled request
+    /// may never reach the backend at all.
+    pub fn set_cancellable(mut self, cancellable: bool) -> Self {
+        self.cancellable = cancellable;
+        self
+    }
+
+    /// Whether or not this request is cancellable and whoever was waiting on its response has gone
+    /// away without it.
+    ///
+    /// Registers the current task to be notified if they go away later.
+    pub fn is_abandoned(&mut self) -> bool {
+        if !self.cancellable {
+            return false;
+        }
+
+        match self.tx.as_mut().map(|tx| tx.poll_close()) {
+            Some(Ok(Async::NotReady)) | None => false,
+            _ => true,
+        }
+    }
+
+    /// Records that this request was sent to the given backend, if anyone is keeping track.
+    pub fn record_route(&self, backend: &Arc<String>) {
+        if let Some(route_log) = self.route_log.as_ref() {
+            route_log.record(self.id, backend);
         }
     }
 
@@ -87,6 +192,8 @@ impl<T: Clone + Message> EnqueuedRequest<T> {
         self.request.as_ref().expect("tried to get key for empty request").key()
     }
 
+    pub fn request(&self) -> &T { self.request.as_ref().expect("tried to get empty request") }
+
     pub fn consume(&mut self) -> T { self.request.take().unwrap() }
 
     pub fn fulfill(&mut self, response: T) {
@@ -114,6 +221,59 @@ impl<T: Clone + Message> EnqueuedRequest<T> {
 
         None
     }
+
+    /// Puts a relay between this request and whoever is waiting on its response.
+    ///
+    /// The relay can tell when they've gone away, so that work done on their behalf can be
+    /// abandoned, which isn't possible once the request itself has been handed off.
+    pub fn relay_response(&mut self) -> Option<ResponseRelay<T>> {
+        let client_tx = self.tx.take()?;
+        let (tx, rx) = channel();
+        self.tx = Some(tx);
+
+        Some(ResponseRelay {
+            client_tx: Some(client_tx),
+            rx,
+        })
+    }
+}
+
+/// Passes a response along from a request to whoever was originally waiting on it.
+pub struct ResponseRelay<T> {
+    client_tx: Option<Sender<AssignedResponse<T>>>,
+    rx: Receiver<AssignedResponse<T>>,
+}
+
+impl<T> ResponseRelay<T> {
+    /// Passes the response along if there is one yet, and checks whether or not whoever was waiting
+    /// on it has gone away without it.
+    ///
+    /// Registers the current task to be notified when either happens.
+    pub fn poll_abandoned(&mut self) -> bool {
+        let mut client_tx = match self.client_tx.take() {
+            Some(tx) => tx,
+            None => return false,
+        };
+
+        match self.rx.poll() {
+            Ok(Async::Ready(response)) => {
+                let _ = client_tx.send(response);
+                return false;
+            },
+            Ok(Async::NotReady) => {},
+            // The request's drop guard always sends something, so this can't really happen, but if
+            // it does, there's nothing left to pass along.
+            Err(_) => return false,
+        }
+
+        match client_tx.poll_close() {
+            Ok(Async::NotReady) => {
+                self.client_tx = Some(client_tx);
+                false
+            },
+            _ => true,
+        }
+    }
 }
 
 impl<T: Clone + Message> Drop for EnqueuedRequest<T> {
@@ -25,6 +25,16 @@ use std::{collections::HashMap, env};
 pub struct Configuration {
     pub stats_addr: String,
     pub logging: LoggingConfiguration,
+
+    /// The maximum number of bytes buffered for requests and responses, across all clients.
+    ///
+    /// Each request counts from when it's read until its response has been sent, and its response
+    /// from when it comes back from the backend until then, too.  Once the limit is reached, new
+    /// connections are turned away, and clients waiting on responses stop having their requests
+    /// read until enough has been sent back.  Clients with nothing outstanding can always send a
+    /// single batch, so the limit can be overshot by up to one batch per such client.  Unlimited
+    /// by default.
+    pub max_buffered_bytes: Option<usize>,
     pub listeners: HashMap<String, ListenerConfiguration>,
 }
 
@@ -36,16 +46,266 @@ pub struct LoggingConfiguration {
 #[derive(Deserialize, Default, Clone, Debug)]
 pub struct ListenerConfiguration {
     pub protocol: String,
+
+    /// The address to listen on, either as `<ip>:<port>`, or as `unix:<path>` for a Unix domain
+    /// socket.
     pub address: String,
+
+    /// How long, in milliseconds, clients are given to disconnect on their own when the listener is
+    /// closed during a reload or shutdown, before being forcibly disconnected.
+    ///
+    /// Defaults to 5000.
     pub reload_timeout_ms: Option<u64>,
+
+    /// Whether or not to preserve the submission order of requests sent to a backend.
+    ///
+    /// When enabled (the default), every batch destined for a backend is handed to a single backend
+    /// connection and executed in exactly the order the client sent it.  When disabled, a batch may be
+    /// split across all of a backend's connections, keyed by the request key, so that it can be
+    /// pipelined in parallel.  Requests for the same key always land on the same connection and keep
+    /// their relative order, but requests for different keys may execute in any order relative to one
+    /// another.  Only disable this if clients never rely on ordering between commands that touch
+    /// different keys, such as a `SET` of one key followed by a script that reads it.
+    pub preserve_order: Option<bool>,
+
+    /// Whether or not to allow `DEBUG` commands through to backends.
+    ///
+    /// Defaults to false, which rejects all `DEBUG` subcommands.  When enabled, only `DEBUG OBJECT`
+    /// is allowed, and is routed by its key to the backend that owns it.
+    pub allow_debug: Option<bool>,
+
+    /// The password clients must authenticate with, via `AUTH` or `HELLO`, before sending commands.
+    ///
+    /// Authentication happens at the proxy, and is never passed on to backends.  Only applies to
+    /// Redis listeners.  Unset by default, which lets every client in.
+    pub requirepass: Option<String>,
+
+    /// The address, as `<ip>:<port>`, that cluster-aware clients are told to connect to.
+    ///
+    /// `CLUSTER SLOTS`, `SHARDS` and `NODES` are answered directly, presenting the proxy as a
+    /// single-node cluster at this address.  Defaults to the listen address, unless it's a Unix
+    /// domain socket or a wildcard address like `0.0.0.0`, which clients can't connect to, in which
+    /// case every cluster command gets an error unless this is set.  Other `CLUSTER` commands are
+    /// always rejected, and never reach a backend.  Only applies to Redis listeners.
+    pub cluster_announce_address: Option<String>,
+
+    /// Whether or not to allow blocking commands, such as `BLPOP` or `BZMPOP`, through to backends.
+    ///
+    /// Defaults to false, which rejects them with an error.  When enabled, each blocking command is
+    /// run on its own short-lived backend connection, outside of the shared connections, so that
+    /// it can't stall other requests.  The number of these connections per backend is limited by
+    /// the `blocking_conns` pool option, and they're subject to `command_timeouts`.
+    pub allow_blocking: Option<bool>,
+
+    /// The maximum number of arguments, including the command itself, allowed in a single command.
+    ///
+    /// Commands over the limit are rejected with a protocol error, and the client is disconnected,
+    /// before they're fully buffered.  Unlimited by default.
+    pub max_args_per_command: Option<usize>,
+
+    /// Whether or not to log every client request.  Defaults to false.
+    ///
+    /// Each request is logged with when it was read, the client's address, the command, its first
+    /// key, the backends that served it, whether it succeeded, the size of its response, and how
+    /// long it took.  Entries go to the regular log unless `access_log_path` is set.
+    ///
+    /// Entries are written by a dedicated thread.  If it falls too far behind, new entries are
+    /// dropped, and counted in `access_log_dropped`, rather than holding up any clients.
+    pub access_log: Option<bool>,
+
+    /// The file to write the access log to, if enabled, instead of the regular log.
+    pub access_log_path: Option<String>,
+
+    /// The size, in bytes, that the access log file can grow to before it's rotated.
+    ///
+    /// When rotated, the file is moved aside to the same path with `.1` appended, replacing the
+    /// last rotated file, and a new file is started.  Defaults to 104857600 (100MB).
+    pub access_log_max_bytes: Option<u64>,
+
+    /// Whether or not to deduplicate identical read commands within a single pipelined batch.
+    ///
+    /// When enabled, duplicate reads are sent to the backend once, and the response is handed back
+    /// to every copy.  Any write in the batch ends deduplication for the reads before it, so reads
+    /// never observe a stale value.  Defaults to false.
+    pub dedupe_reads: Option<bool>,
+
+    /// Whether or not to coalesce identical, consecutive writes within a single pipelined batch.
+    ///
+    /// When enabled, a write that directly follows a byte-for-byte identical write from the same
+    /// client, in the same batch, isn't sent to the backend, and instead gets the response that the
+    /// repeated write would have gotten.  Only writes that provably have no further effect when
+    /// repeated are coalesced: a plain `SET <key> <value>`, without any options, and a single-key
+    /// `DEL <key>`.  Any other command in between, even a read, ends coalescing, and writes are
+    /// never reordered.  Another client's write to the same key can still land in between the two
+    /// writes on the backend, in which case the coalesced write would have overwritten it, so only
+    /// enable this if that can't happen or doesn't matter.  Defaults to false.
+    pub coalesce_writes: Option<bool>,
+
+    /// The maximum number of responses a single client can have pending.
+    ///
+    /// Responses are held until they can be sent back in order, and fragmented commands hold all of
+    /// their fragments until the whole response can be assembled.  Once a client hits this limit,
+    /// we stop reading new requests from it until some of its responses have been sent.  Each
+    /// fragment of a fragmented command counts separately.  Unlimited by default.
+    pub max_pending_responses: Option<usize>,
+
+    /// How long, in milliseconds, to wait on a response to a client's request.
+    ///
+    /// Each batch of requests read from a client gets its own deadline, and when it passes, the
+    /// requests in it are answered with an error, so that a stalled backend can't hold up the rest
+    /// of the client's pipelined requests indefinitely.  Batches with blocking commands, like
+    /// `BLPOP`, are never timed out.  Disabled by default.
+    pub request_timeout_ms: Option<u64>,
+
+    /// The maximum number of connections allowed from a single source IP.
+    ///
+    /// Connections over the limit are sent an error and closed as soon as they're accepted.  The
+    /// source IP is the address of the connecting peer, so clients behind a NAT share a limit, as
+    /// do clients behind a load balancer, unless `proxy_protocol` is enabled.  Unlimited by default.
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Source IPs allowed to connect, as addresses or CIDR blocks, such as `10.0.0.0/8`.
+    ///
+    /// When set, connections from any other address are closed as soon as they're accepted, before
+    /// anything is read from them.  The source IP is the address of the connecting peer, so clients
+    /// behind a NAT are seen as coming from it, as are clients behind a load balancer, unless
+    /// `proxy_protocol` is enabled.  Allows all addresses by default.
+    pub allowed_sources: Option<Vec<String>>,
+
+    /// Source IPs denied from connecting, as addresses or CIDR blocks.
+    ///
+    /// Checked after `allowed_sources`, so this can carve exceptions out of an allowed block.  Empty
+    /// by default.
+    pub denied_sources: Option<Vec<String>>,
+
+    /// An ordered list of rules for rewriting the replies to specific commands.
+    ///
+    /// This is meant for shimming clients that expect slightly different reply shapes, and is empty
+    /// by default.  All rules matching a command are applied, in order.
+    pub reply_transforms: Option<Vec<ReplyTransformConfiguration>>,
+
+    /// A mapping of database numbers to the pools that serve them, such as `{ "0" = "default" }`.
+    ///
+    /// When set, clients can switch databases with `SELECT`, and their commands go to the pool for
+    /// whichever database they last selected, starting from database 0, which must be mapped.
+    /// Selecting an unmapped database is an error.  Only supported by Redis listeners with fixed
+    /// routing.  Unset by default, which rejects `SELECT` entirely.
+    pub db_pools: Option<HashMap<String, String>>,
+
+    /// TLS settings for client connections.
+    ///
+    /// When set, every client has to connect over TLS, which is terminated by the proxy: clients
+    /// that don't complete a TLS handshake within 5 seconds are disconnected.  Unset by default,
+    /// which only accepts plain TCP connections.
+    pub tls: Option<TlsConfiguration>,
+
+    /// The file mode for the socket file of a Unix domain socket listener, in octal, such as `660`.
+    ///
+    /// Clients need write permission on the socket file to connect, so this controls who can talk
+    /// to the listener.  Any stale socket file left at the path is removed on startup, and the
+    /// socket file is removed again on shutdown.  Left as whatever the process umask gives by
+    /// default.  Unix domain socket listeners don't support TLS.
+    pub socket_mode: Option<String>,
+
+    /// Whether or not clients connect through a load balancer that sends a PROXY protocol header.
+    ///
+    /// When enabled, every connection has to start with a PROXY protocol header, in either the text
+    /// (version 1) or binary (version 2) format, which is read before anything else, including the
+    /// TLS handshake.  The source address from the header identifies the client, instead of the
+    /// address of the load balancer, both in logs and for source filtering and per-IP connection
+    /// limits, which are checked once the header has been read.  Connections with a missing or
+    /// malformed header, or whose header doesn't arrive within five seconds, are dropped.  Defaults
+    /// to false.
+    pub proxy_protocol: Option<bool>,
+
+    /// Whether or not to disable Nagle's algorithm on client sockets, so that small responses go
+    /// out right away.
+    ///
+    /// Defaults to true.
+    pub tcp_nodelay: Option<bool>,
+
+    /// How long, in seconds, a client connection can sit idle before TCP keepalive probes are sent
+    /// over it.
+    ///
+    /// Defaults to 0, which disables keepalives.
+    pub tcp_keepalive_secs: Option<u64>,
     pub pools: HashMap<String, PoolConfiguration>,
+
+    /// How requests are routed to pools.
+    ///
+    /// `type` is one of `fixed` (the default), `shadow`, or `readwrite`.  Pools can be grouped by
+    /// name: a group is the pool named after the group, such as `shadow`, along with every pool
+    /// named after the group followed by a number, such as `shadow2`, or by an underscore, such as
+    /// `shadow_east`, in order by name.
+    ///
+    /// Shadow routing sends requests to the `default` pool, mirrors them to every pool in the
+    /// `shadow` group, and also takes `shadow_sample_pct`, the percentage of requests, from 0 to 100, that are
+    /// mirrored to the shadow pools, picked at random.  Everything is mirrored by default.  Setting
+    /// `shadow_compare` to `true` compares each shadow response against the primary response,
+    /// counting them in the `shadow_match` and `shadow_mismatch` metrics, labeled by shadow pool.
+    ///
+    /// Read/write routing sends writes, and anything it doesn't know to be a read, to the `primary`
+    /// pool, and spreads reads over every pool in the `replica` group, taking turns.
     pub routing: HashMap<String, String>,
 }
 
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct TlsConfiguration {
+    /// Path to the PEM-encoded certificate chain presented to clients, leaf certificate first.
+    pub cert_path: String,
+
+    /// Path to the PEM-encoded private key for the certificate, in either PKCS#8 or RSA format.
+    pub key_path: String,
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct ReplyTransformConfiguration {
+    pub command: String,
+    pub transform: String,
+}
+
 #[derive(Deserialize, Default, Clone, Debug)]
 pub struct PoolConfiguration {
     pub addresses: Vec<BackendAddress>,
     pub options: Option<HashMap<String, String>>,
+
+    /// Explicit key to backend mappings, consulted before the distributor.
+    ///
+    /// Keys map to the identifier of a backend in this pool.  A key ending in `*` is treated as a
+    /// prefix, and the longest matching prefix wins if no exact key matches.  Pinned keys always go
+    /// to their backend, even if it's unhealthy.
+    pub key_overrides: Option<HashMap<String, String>>,
+
+    /// Per-command timeouts, in milliseconds, that override the pool's `timeout_ms` option.
+    ///
+    /// Requests are sent to a backend connection in batches, and a batch waits for its slowest
+    /// command, so a batch gets the longest timeout of any command in it.  A timeout of 0 disables
+    /// the timeout entirely.  Blocking commands, such as `BLPOP`, have no timeout unless one is
+    /// configured here: they hold their backend connection until they return, so everything else
+    /// queued on that connection waits behind them.
+    pub command_timeouts: Option<HashMap<String, u64>>,
+
+    /// Relative weights for the backends in this pool, keyed by backend identifier.
+    ///
+    /// Backends default to a weight of 1, and a backend with a weight of N gets roughly N times the
+    /// traffic of a backend with a weight of 1.  A weight of 0 sends a backend no traffic, other
+    /// than keys pinned to it with `key_overrides`.  With `modulo` distribution, a backend with a
+    /// weight of N takes up N slots, so changing any weight remaps keys just like adding or removing
+    /// backends would.  With `random` distribution, weights simply bias the random choice.
+    pub weights: Option<HashMap<String, usize>>,
+
+    /// Commands that are serialized through a single connection to each backend.
+    ///
+    /// Normally, requests from different clients are spread over all of a backend's connections, so
+    /// two clients racing to, say, `INCR` the same counter may see their commands executed in
+    /// either order.  Serialized commands for a given key all go through the same connection, and
+    /// so execute in exactly the order the proxy received them.  When `preserve_order` is disabled,
+    /// that's the connection the key is already sent to.  When it's enabled, batches can't be split
+    /// by key, so a batch containing a serialized command goes through the backend's first
+    /// connection in its entirety, and every serialized command shares that one connection, getting
+    /// none of the throughput of the others: keep this list short, and reserved for commands where
+    /// the ordering actually matters.
+    pub serialized_commands: Option<Vec<String>>,
 }
 
 impl Configuration {
@@ -20,7 +20,10 @@
 use slog::Level;
 
 mod config;
-pub use self::config::{Configuration, ListenerConfiguration, LoggingConfiguration, PoolConfiguration};
+pub use self::config::{
+    Configuration, ListenerConfiguration, LoggingConfiguration, PoolConfiguration, ReplyTransformConfiguration,
+    TlsConfiguration,
+};
 
 mod backend_addr;
 pub use self::backend_addr::BackendAddress;
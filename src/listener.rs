@@ -19,72 +19,482 @@
 // SOFTWARE.
 use crate::{
     backend::{
+        memcached::MemcachedProcessor,
         pool::{BackendPool, BackendPoolBuilder},
         processor::Processor,
-        redis::RedisProcessor,
+        redis::{RedisProcessor, ReplyRule},
     },
-    common::{AssignedRequests, AssignedResponse, EnqueuedRequests, Message},
+    common::{AssignedRequests, AssignedResponse, EnqueuedRequests, Message, RouteLog},
     conf::ListenerConfiguration,
     errors::CreationError,
-    protocol::errors::ProtocolError,
-    routing::{FixedRouter, ShadowRouter},
-    service::{Pipeline, PipelineError},
-    util::FutureExt,
+    protocol::{errors::ProtocolError, proxy::read_proxy_header},
+    routing::{DatabaseRouter, FixedRouter, ReadWriteRouter, RouteLogging, ShadowRouter},
+    service::{AccessLogWriter, Pipeline, PipelineError},
+    util::{get_tls_acceptor, ClientAddr, FutureExt, IpNetwork, MaybeTlsStream, MemoryBudget, SocketOptions},
 };
 use bytes::BytesMut;
 use futures::{
-    future::{lazy, ok, Shared},
+    future::{lazy, loop_fn, ok, Either, Loop, Shared},
     prelude::*,
 };
 use futures_turnstyle::Waiter;
 use metrics_runtime::Sink as MetricSink;
 use net2::TcpBuilder;
-use std::{collections::HashMap, fmt::Display, net::SocketAddr};
-use tokio::{io, net::TcpListener, reactor};
+#[cfg(unix)]
+use std::fs;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io,
+    net::TcpListener,
+    reactor,
+    timer::{Delay, Timeout},
+};
 use tokio_evacuate::{Evacuate, Warden};
 use tokio_executor::DefaultExecutor;
+use tokio_rustls::TlsAcceptor;
 use tower_buffer::{Buffer, DirectServiceRef};
 use tower_service::Service;
 
+// How often the number of clients still connected is logged while a listener is draining.
+const DRAIN_LOG_INTERVAL_MS: u64 = 500;
+
+// How often the number of clients still connected is recorded while a listener is draining.
+const DRAIN_GAUGE_INTERVAL_MS: u64 = 1000;
+
+// How long a load balancer has to send the PROXY protocol header for a new client.
+const PROXY_HEADER_TIMEOUT_MS: u64 = 5000;
+
+// How long a new client has to finish the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
 type GenericRuntimeFuture = Box<Future<Item = (), Error = ()> + Send + 'static>;
 type BufferedPool<T, M> = Buffer<DirectServiceRef<BackendPool<T>>, EnqueuedRequests<M>>;
 
+/// Settings applied to the pipeline of every client connected to a listener.
+#[derive(Clone)]
+struct ClientOptions {
+    access_log: Option<AccessLogWriter>,
+    dedupe_reads: bool,
+    coalesce_writes: bool,
+    max_pending_responses: Option<usize>,
+    request_timeout: Option<Duration>,
+    max_connections_per_ip: Option<usize>,
+    source_filter: SourceFilter,
+    memory_budget: Option<MemoryBudget>,
+    tls_acceptor: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+    socket_options: SocketOptions,
+}
+
+/// Ways that getting a newly-accepted client ready to send commands can fail.
+enum ClientSetupError {
+    ProxyHeader(ProtocolError),
+    Rejected,
+    TlsHandshake(io::Error),
+}
+
+/// Reasons a client can be turned away based on where it's connecting from.
+enum ClientRejection {
+    SourceNotAllowed,
+    TooManyConnections,
+}
+
+/// Decides which source IPs are allowed to connect to a listener.
+#[derive(Clone)]
+struct SourceFilter {
+    allowed: Option<Vec<IpNetwork>>,
+    denied: Vec<IpNetwork>,
+}
+
+impl SourceFilter {
+    fn from_config(config: &ListenerConfiguration) -> Result<SourceFilter, CreationError> {
+        let parse = |name: &str, sources: &[String]| {
+            sources
+                .iter()
+                .map(|source| {
+                    source
+                        .parse::<IpNetwork>()
+                        .map_err(|_| CreationError::InvalidParameter(format!("{} ({})", name, source)))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        let allowed = match config.allowed_sources.as_ref() {
+            Some(sources) => Some(parse("allowed_sources", sources)?),
+            None => None,
+        };
+        let denied = parse("denied_sources", config.denied_sources.as_ref().map_or(&[], |s| s.as_slice()))?;
+
+        Ok(SourceFilter { allowed, denied })
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        let allowed = match self.allowed.as_ref() {
+            Some(allowed) => allowed.iter().any(|net| net.contains(ip)),
+            None => true,
+        };
+        allowed && !self.denied.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Limits the number of clients that can be connected from a single source IP.
+#[derive(Clone)]
+struct ClientLimiter {
+    limit: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ClientLimiter {
+    fn new(limit: usize) -> ClientLimiter {
+        ClientLimiter {
+            limit,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registers a new client from the given address, unless that address is already at its limit.
+    ///
+    /// The client is counted until the returned guard is dropped.
+    fn acquire(&self, ip: IpAddr) -> Option<ClientGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.get(&ip).cloned().unwrap_or(0);
+        if count >= self.limit {
+            return None;
+        }
+
+        counts.insert(ip, count + 1);
+        Some(ClientGuard {
+            ip,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+struct ClientGuard {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        let remaining = match counts.get_mut(&self.ip) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            },
+            None => return,
+        };
+
+        if remaining == 0 {
+            counts.remove(&self.ip);
+        }
+    }
+}
+
+/// Decides whether a client is allowed to connect, based on its address.
+///
+/// Allowed clients are counted against the per-IP connection limit until the returned guard is
+/// dropped.  Source IPs don't apply to clients on a Unix domain socket, where the socket file's
+/// permissions decide who gets to connect.
+fn admit_client(
+    client_addr: &ClientAddr, source_filter: &SourceFilter, limiter: Option<&ClientLimiter>,
+) -> Result<Option<ClientGuard>, ClientRejection> {
+    let client_ip = match client_addr.ip() {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+
+    if !source_filter.allows(client_ip) {
+        return Err(ClientRejection::SourceNotAllowed);
+    }
+
+    match limiter {
+        Some(limiter) => limiter.acquire(client_ip).map(Some).ok_or(ClientRejection::TooManyConnections),
+        None => Ok(None),
+    }
+}
+
+/// Turns away a client that wasn't admitted, telling it why if it's worth telling.
+fn reject_client<P>(
+    client: MaybeTlsStream, client_addr: &ClientAddr, rejection: ClientRejection, processor: &P, sink: &mut MetricSink,
+) where
+    P: Processor,
+    P::Message: Message,
+{
+    match rejection {
+        ClientRejection::SourceNotAllowed => {
+            sink.record_counter("connections_rejected", 1);
+            debug!("[client] [{}] rejected: source address not allowed", client_addr);
+        },
+        ClientRejection::TooManyConnections => {
+            sink.record_counter("clients_rejected", 1);
+            warn!(
+                "[client] [{}] rejected: too many connections from this address",
+                client_addr
+            );
+
+            let err = processor.get_error_message_str("too many connections from your address");
+            tokio::spawn(io::write_all(client, err.into_buf()).then(|_| ok(())));
+        },
+    }
+}
+
+/// Counts the clients connected to a listener, alongside the warden that evacuation waits on, so
+/// that we know how many are left while the listener is draining.
+#[derive(Clone)]
+struct ClientWarden {
+    warden: Warden,
+    count: Arc<AtomicUsize>,
+}
+
+impl ClientWarden {
+    fn new(warden: Warden) -> ClientWarden {
+        ClientWarden {
+            warden,
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn increment(&self) {
+        self.warden.increment();
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn decrement(&self) {
+        self.warden.decrement();
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn count(&self) -> usize { self.count.load(Ordering::SeqCst) }
+}
+
+/// An address that a listener accepts client connections on.
+#[derive(Clone, Debug, PartialEq)]
+enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    /// Parses a listen address, which is either `<ip>:<port>` or `unix:<path>`.
+    fn parse(address: &str) -> Result<ListenAddress, CreationError> {
+        if address.starts_with("unix:") {
+            let path = &address["unix:".len()..];
+            if path.is_empty() {
+                return Err(CreationError::InvalidParameter("address".to_owned()));
+            }
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+
+        address
+            .parse()
+            .map(ListenAddress::Tcp)
+            .map_err(|_| CreationError::InvalidParameter("address".to_owned()))
+    }
+}
+
+/// Gets the address that cluster-aware clients should be told to connect to, if any.
+///
+/// Clients need an address they can reconnect to, which neither a Unix domain socket nor a
+/// wildcard address is, so those listeners only announce an address if one is configured.
+fn get_announce_address(
+    announce_address: Option<&str>, listen_address: &ListenAddress,
+) -> Result<Option<SocketAddr>, CreationError> {
+    match announce_address {
+        Some(address) => {
+            address
+                .parse::<SocketAddr>()
+                .ok()
+                .filter(|address| !address.ip().is_unspecified())
+                .map(Some)
+                .ok_or_else(|| CreationError::InvalidParameter("cluster_announce_address".to_owned()))
+        },
+        None => {
+            match listen_address {
+                ListenAddress::Tcp(address) if !address.ip().is_unspecified() => Ok(Some(*address)),
+                _ => Ok(None),
+            }
+        },
+    }
+}
+
+/// A bound socket that client connections are accepted from.
+///
+/// Yields each accepted client along with its address, which we may not be able to get if the
+/// client has already gone away.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, SocketFile),
+}
+
+impl Stream for Listener {
+    type Error = io::Error;
+    type Item = (MaybeTlsStream, io::Result<ClientAddr>);
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (client, _) = try_ready!(listener.poll_accept());
+                let client_addr = client.peer_addr().map(ClientAddr::Tcp);
+                Ok(Async::Ready(Some((MaybeTlsStream::Plain(client), client_addr))))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, socket_file) => {
+                // Clients on a Unix domain socket are almost always unnamed, so the best we can do
+                // to identify them is to say which socket they came in on.
+                let (client, _) = try_ready!(listener.poll_accept());
+                let client_addr = ClientAddr::Unix(socket_file.path.clone());
+                Ok(Async::Ready(Some((MaybeTlsStream::Unix(client), Ok(client_addr)))))
+            },
+        }
+    }
+}
+
+/// The socket file of a Unix domain socket listener, which is removed when dropped.
+#[cfg(unix)]
+struct SocketFile {
+    path: PathBuf,
+    inode: u64,
+}
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        use std::os::unix::fs::MetadataExt;
+
+        // After a reload, the new listener will have already replaced the socket file with its own,
+        // so only remove it if it's still the one we created.
+        match fs::symlink_metadata(&self.path) {
+            Ok(ref metadata) if metadata.ino() == self.inode => {
+                if let Err(e) = fs::remove_file(&self.path) {
+                    warn!(
+                        "[listener] [{}] failed to remove socket file: {}",
+                        self.path.display(),
+                        e
+                    );
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
 /// Creates a listener from the given configuration.
 ///
 /// The listener will spawn a socket for accepting client connections, and when a client connects,
 /// spawn a task to process all of the messages from that client until the client disconnects or
 /// there is an unrecoverable connection/protocol error.
 pub fn from_config(
-    version: usize, name: String, config: ListenerConfiguration, close: Shared<Waiter>, sink: MetricSink,
+    version: usize, name: String, config: ListenerConfiguration, memory_budget: Option<MemoryBudget>,
+    close: Shared<Waiter>, sink: MetricSink,
 ) -> Result<GenericRuntimeFuture, CreationError> {
     // Create the actual listener proper.
     let listen_address = config.address.clone();
-    let listener = get_listener(&listen_address).expect("failed to create the TCP listener");
+    let address = ListenAddress::parse(&listen_address)?;
+    if let ListenAddress::Unix(_) = address {
+        if config.tls.is_some() {
+            return Err(CreationError::InvalidParameter("tls".to_owned()));
+        }
+    }
+    let socket_mode = match config.socket_mode {
+        Some(ref mode) => {
+            let mode = u32::from_str_radix(mode, 8)
+                .ok()
+                .filter(|mode| *mode <= 0o777)
+                .ok_or_else(|| CreationError::InvalidParameter("socket_mode".to_owned()))?;
+            Some(mode)
+        },
+        None => None,
+    };
+    let listener = get_listener(&address, socket_mode).map_err(|e| {
+        CreationError::InvalidResource(format!("failed to create listener on '{}': {}", listen_address, e))
+    })?;
 
     // Now build our handler: this is what's actually going to do the real work.
     let protocol = config.protocol.to_lowercase();
     let handler = match protocol.as_str() {
-        "redis" => routing_from_config(name, config, listener, close.clone(), RedisProcessor::new(), sink),
+        "redis" => {
+            let reply_rules = config
+                .reply_transforms
+                .iter()
+                .flatten()
+                .map(ReplyRule::from_config)
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut processor = RedisProcessor::new()
+                .set_allow_debug(config.allow_debug.unwrap_or(false))
+                .set_allow_blocking(config.allow_blocking.unwrap_or(false))
+                .set_allow_select(config.db_pools.is_some())
+                .set_max_args(config.max_args_per_command)
+                .set_requirepass(config.requirepass.clone())
+                .set_reply_rules(reply_rules);
+
+            let announce_address = config.cluster_announce_address.as_ref().map(String::as_str);
+            if let Some(address) = get_announce_address(announce_address, &address)? {
+                processor = processor.set_announce_address(address);
+            }
+            routing_from_config(name, config, listener, memory_budget, close.clone(), processor, sink)
+        },
+        "memcached" => {
+            let processor = MemcachedProcessor::new();
+            routing_from_config(name, config, listener, memory_budget, close.clone(), processor, sink)
+        },
         s => Err(CreationError::InvalidResource(format!("unknown cache protocol: {}", s))),
     }?;
 
     // Make sure our handlers close out when told.
     let listen_address2 = listen_address.clone();
     let wrapped = lazy(move || {
-        info!("[listener] starting listener '{}' (v{})", listen_address, version);
+        info!("[listener] [{}] starting listener (v{})", listen_address, version);
         ok(())
     })
     .and_then(|_| handler)
     .select2(close)
     .then(move |_| {
-        info!("[listener] shutting down listener '{}' (v{})", listen_address2, version);
+        info!("[listener] [{}] shutting down listener (v{})", listen_address2, version);
         ok(())
     });
     Ok(Box::new(wrapped))
 }
 
+fn get_access_log_writer(
+    config: &ListenerConfiguration, sink: &mut MetricSink,
+) -> Result<Option<AccessLogWriter>, CreationError> {
+    if !config.access_log.unwrap_or(false) {
+        return Ok(None);
+    }
+
+    match config.access_log_path {
+        Some(ref path) => {
+            let max_bytes = config.access_log_max_bytes.unwrap_or(100 * 1024 * 1024);
+            AccessLogWriter::file(Path::new(path), max_bytes, sink)
+                .map(Some)
+                .map_err(|e| CreationError::InvalidResource(format!("failed to open access log '{}': {}", path, e)))
+        },
+        None => {
+            AccessLogWriter::logger(sink)
+                .map(Some)
+                .map_err(|e| CreationError::InvalidResource(format!("failed to start access log: {}", e)))
+        },
+    }
+}
+
 fn routing_from_config<P, C>(
-    name: String, config: ListenerConfiguration, listener: TcpListener, close: C, processor: P, sink: MetricSink,
+    name: String, config: ListenerConfiguration, listener: Listener, memory_budget: Option<MemoryBudget>, close: C,
+    processor: P, sink: MetricSink,
 ) -> Result<GenericRuntimeFuture, CreationError>
 where
     P: Processor + Clone + Send + 'static,
@@ -94,26 +504,61 @@ where
     C: Future + Clone + Send + 'static,
 {
     let reload_timeout_ms = config.reload_timeout_ms.unwrap_or_else(|| 5000);
-
-    // Build our evacuator and wrap it as shared.  This lets us soft close everything.
-    let (warden, evacuate) = Evacuate::new(close, reload_timeout_ms);
-    let closer = evacuate.shared();
+    let preserve_order = config.preserve_order.unwrap_or(true);
 
     // Get our scoped metric sink.
     let mut sink = sink.clone();
     sink.add_default_labels(&[("listener", name)]);
 
+    let client_options = ClientOptions {
+        access_log: get_access_log_writer(&config, &mut sink)?,
+        dedupe_reads: config.dedupe_reads.unwrap_or(false),
+        coalesce_writes: config.coalesce_writes.unwrap_or(false),
+        max_pending_responses: config.max_pending_responses,
+        request_timeout: config.request_timeout_ms.map(Duration::from_millis),
+        max_connections_per_ip: config.max_connections_per_ip,
+        source_filter: SourceFilter::from_config(&config)?,
+        memory_budget,
+        tls_acceptor: match config.tls {
+            Some(ref tls) => Some(get_tls_acceptor(tls)?),
+            None => None,
+        },
+        proxy_protocol: config.proxy_protocol.unwrap_or(false),
+        socket_options: SocketOptions::new(
+            config.tcp_nodelay.unwrap_or(true),
+            config.tcp_keepalive_secs.unwrap_or(0),
+        ),
+    };
+
+    // Build our evacuator and wrap it as shared.  This lets us soft close everything.
+    let (warden, evacuate) = Evacuate::new(close.clone(), reload_timeout_ms);
+    let warden = ClientWarden::new(warden);
+    let closer = evacuate.shared();
+
+    // Keep an eye on how draining goes once we're told to close.
+    let drain_timeout = Duration::from_millis(reload_timeout_ms);
+    let address = config.address.clone();
+    tokio::spawn(monitor_drain(
+        address,
+        close,
+        warden.clone(),
+        drain_timeout,
+        sink.clone(),
+    ));
+
     // Extract all the configured pools and build a backend pool for them.
     let mut pools = HashMap::new();
     let pool_configs = config.pools.clone();
     for (pool_name, pool_config) in pool_configs {
         debug!(
-            "[listener] configuring backend pool '{}' for address '{}'",
-            &pool_name,
-            config.address.clone()
+            "[listener] [{}] configuring backend pool '{}'",
+            config.address.clone(),
+            &pool_name
         );
 
-        let pool = BackendPoolBuilder::new(pool_name.clone(), processor.clone(), pool_config, sink.clone()).build()?;
+        let pool = BackendPoolBuilder::new(pool_name.clone(), processor.clone(), pool_config, sink.clone())
+            .set_preserve_order(preserve_order)
+            .build()?;
         let buffered_pool = Buffer::new_direct(pool, 32, &DefaultExecutor::current()).map_err(|_| {
             CreationError::InvalidResource(format!(
                 "error while building pool '{}': failed to spawn task",
@@ -129,16 +574,32 @@ where
         .entry("type".to_owned())
         .or_insert_with(|| "fixed".to_owned())
         .to_lowercase();
+    if let Some(db_pools) = config.db_pools {
+        if route_type != "fixed" {
+            return Err(CreationError::InvalidResource(format!(
+                "db_pools is not supported with route type '{}'",
+                route_type
+            )));
+        }
+
+        let db_pools = get_database_pools(&pools, db_pools)?;
+        return get_database_router(listener, db_pools, processor, warden, closer, client_options, sink);
+    }
+
     match route_type.as_str() {
-        "fixed" => get_fixed_router(listener, pools, processor, warden, closer, sink),
-        "shadow" => get_shadow_router(listener, pools, processor, warden, closer, sink),
+        "fixed" => get_fixed_router(listener, pools, processor, warden, closer, client_options, sink),
+        "shadow" => {
+            let router = get_shadow_router(&pools, &routing, processor.clone(), closer.clone(), sink.clone())?;
+            build_router_chain(listener, processor, router, warden, closer, client_options, sink)
+        },
+        "readwrite" => get_readwrite_router(listener, pools, processor, warden, closer, client_options, sink),
         x => Err(CreationError::InvalidResource(format!("unknown route type '{}'", x))),
     }
 }
 
 fn get_fixed_router<P, C>(
-    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P, warden: Warden, close: C,
-    sink: MetricSink,
+    listener: Listener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P, warden: ClientWarden,
+    close: C, client_options: ClientOptions, sink: MetricSink,
 ) -> Result<GenericRuntimeFuture, CreationError>
 where
     P: Processor + Clone + Send + 'static,
@@ -152,14 +613,39 @@ where
         .get("default")
         .ok_or_else(|| CreationError::InvalidResource("no default pool configured for fixed router".to_string()))?
         .clone();
-    let router = FixedRouter::new(processor.clone(), default_pool);
+    let router = FixedRouter::new(processor.clone(), default_pool, sink.clone());
 
-    build_router_chain(listener, processor, router, warden, close, sink)
+    build_router_chain(listener, processor, router, warden, close, client_options, sink)
 }
 
-fn get_shadow_router<P, C>(
-    listener: TcpListener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P, warden: Warden, close: C,
-    sink: MetricSink,
+fn get_database_pools<P>(
+    pools: &HashMap<String, BufferedPool<P, P::Message>>, db_pools: HashMap<String, String>,
+) -> Result<HashMap<usize, BufferedPool<P, P::Message>>, CreationError>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+{
+    let mut db_inners = HashMap::new();
+    for (db, pool_name) in db_pools {
+        let db_num = db
+            .parse::<usize>()
+            .map_err(|_| CreationError::InvalidParameter(format!("db_pools.{}", db)))?;
+        let pool = pools.get(&pool_name).ok_or_else(|| {
+            CreationError::InvalidResource(format!("no pool '{}' configured for database {}", pool_name, db_num))
+        })?;
+        db_inners.insert(db_num, pool.clone());
+    }
+
+    if !db_inners.contains_key(&0) {
+        return Err(CreationError::InvalidResource("no pool configured for database 0".to_string()));
+    }
+
+    Ok(db_inners)
+}
+
+fn get_database_router<P, C>(
+    listener: Listener, db_pools: HashMap<usize, BufferedPool<P, P::Message>>, processor: P, warden: ClientWarden,
+    close: C, client_options: ClientOptions, sink: MetricSink,
 ) -> Result<GenericRuntimeFuture, CreationError>
 where
     P: Processor + Clone + Send + 'static,
@@ -167,6 +653,44 @@ where
     P::Transport:
         Sink<SinkItem = BytesMut, SinkError = std::io::Error> + Stream<Item = P::Message, Error = ProtocolError> + Send,
     C: Future + Clone + Send + 'static,
+{
+    // Construct an instance of our router.
+    let router = DatabaseRouter::new(processor.clone(), db_pools, sink.clone());
+
+    build_router_chain(listener, processor, router, warden, close, client_options, sink)
+}
+
+/// Gets the name of every pool in the given group, along with the pool, sorted by name.
+///
+/// A group is made up of the pool named after the group itself, such as `replica`, along with every
+/// pool whose name is the group's followed by either a number, such as `replica2`, or an underscore,
+/// such as `replica_east`.
+fn get_pool_group<T: Clone>(pools: &HashMap<String, T>, group: &str) -> Vec<(String, T)> {
+    let mut names = pools
+        .keys()
+        .filter(|name| {
+            match name.get(group.len()..) {
+                Some(suffix) if name.starts_with(group) => {
+                    suffix.is_empty()
+                        || suffix.starts_with('_')
+                        || suffix.chars().all(|c| c.is_ascii_digit())
+                },
+                _ => false,
+            }
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names.into_iter().map(|name| (name.clone(), pools[name].clone())).collect()
+}
+
+fn get_shadow_router<P, C>(
+    pools: &HashMap<String, BufferedPool<P, P::Message>>, routing: &HashMap<String, String>, processor: P, close: C,
+    sink: MetricSink,
+) -> Result<ShadowRouter<P, BufferedPool<P, P::Message>>, CreationError>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+    C: Future + Clone + Send + 'static,
 {
     // Construct an instance of our router.
     let default_pool = pools
@@ -174,70 +698,290 @@ where
         .ok_or_else(|| CreationError::InvalidResource("no default pool configured for shadow router".to_string()))?
         .clone();
 
-    let shadow_pool = pools
-        .get("shadow")
-        .ok_or_else(|| CreationError::InvalidResource("no shadow pool configured for shadow router".to_string()))?
+    // Requests are mirrored to every pool in the `shadow` group, in order.
+    let shadow_pools = get_pool_group(pools, "shadow");
+    if shadow_pools.is_empty() {
+        return Err(CreationError::InvalidResource("no shadow pools configured for shadow router".to_string()));
+    }
+
+    // Only this percentage of requests, picked at random, are mirrored to the shadow pool.
+    let sample_pct = match routing.get("shadow_sample_pct") {
+        Some(pct) => {
+            pct.parse::<f64>()
+                .ok()
+                .filter(|pct| *pct >= 0.0 && *pct <= 100.0)
+                .ok_or_else(|| CreationError::InvalidParameter("routing.shadow_sample_pct".to_string()))?
+        },
+        None => 100.0,
+    };
+
+    // Whether or not shadow responses are compared against the primary responses.
+    let compare = match routing.get("shadow_compare") {
+        Some(compare) => {
+            compare
+                .parse::<bool>()
+                .map_err(|_| CreationError::InvalidParameter("routing.shadow_compare".to_string()))?
+        },
+        None => false,
+    };
+
+    Ok(ShadowRouter::new(processor, default_pool, shadow_pools, close, sink)
+        .set_sample_rate(sample_pct / 100.0)
+        .set_compare(compare))
+}
+
+fn get_readwrite_router<P, C>(
+    listener: Listener, pools: HashMap<String, BufferedPool<P, P::Message>>, processor: P, warden: ClientWarden,
+    close: C, client_options: ClientOptions, sink: MetricSink,
+) -> Result<GenericRuntimeFuture, CreationError>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+    P::Transport:
+        Sink<SinkItem = BytesMut, SinkError = std::io::Error> + Stream<Item = P::Message, Error = ProtocolError> + Send,
+    C: Future + Clone + Send + 'static,
+{
+    // Construct an instance of our router.
+    let primary_pool = pools
+        .get("primary")
+        .ok_or_else(|| CreationError::InvalidResource("no primary pool configured for readwrite router".to_string()))?
         .clone();
 
-    let router = ShadowRouter::new(processor.clone(), default_pool, shadow_pool);
+    // Reads can be spread over every pool in the `replica` group, taking turns in order.
+    let replica_pools = get_pool_group(&pools, "replica")
+        .into_iter()
+        .map(|(_, pool)| pool)
+        .collect::<Vec<_>>();
+    if replica_pools.is_empty() {
+        return Err(CreationError::InvalidResource(
+            "no replica pool configured for readwrite router".to_string(),
+        ));
+    }
+
+    let router = ReadWriteRouter::new(processor.clone(), primary_pool, replica_pools, sink.clone());
+
+    build_router_chain(listener, processor, router, warden, close, client_options, sink)
+}
 
-    build_router_chain(listener, processor, router, warden, close, sink)
+/// Applies the listener's socket options to a newly-accepted client.
+///
+/// Unix domain sockets have no TCP options to speak of.  Failing to set them doesn't stop the client
+/// from being served, though it may not perform as well.
+fn configure_client(client: &MaybeTlsStream, client_addr: &ClientAddr, socket_options: &SocketOptions) {
+    if let MaybeTlsStream::Plain(ref stream) = client {
+        if let Err(e) = socket_options.apply(stream) {
+            warn!("[client] [{}] failed to set socket options: {}", client_addr, e);
+        }
+    }
 }
 
 fn build_router_chain<P, R, C>(
-    listener: TcpListener, processor: P, router: R, warden: Warden, close: C, mut sink: MetricSink,
+    listener: Listener, processor: P, router: R, warden: ClientWarden, close: C, client_options: ClientOptions,
+    mut sink: MetricSink,
 ) -> Result<GenericRuntimeFuture, CreationError>
 where
     P: Processor + Clone + Send + 'static,
     P::Message: Message + Clone + Send + 'static,
     P::Transport:
         Sink<SinkItem = BytesMut, SinkError = std::io::Error> + Stream<Item = P::Message, Error = ProtocolError> + Send,
-    R: Service<AssignedRequests<P::Message>> + Clone + Send + 'static,
+    R: Service<AssignedRequests<P::Message>> + RouteLogging + Clone + Send + 'static,
     R::Error: Display + Send + Sync,
     R::Response: IntoIterator<Item = AssignedResponse<P::Message>> + Send,
     R::Future: Future + Send,
     C: Future + Clone + Send + 'static,
 {
     let close2 = close.clone();
+    let limiter = client_options.max_connections_per_ip.map(ClientLimiter::new);
     let task = listener
-        .incoming()
-        .for_each(move |client| {
+        .for_each(move |(client, client_addr)| {
+            // If the socket already errored out by the time we accepted it, there's no client to
+            // talk to, so just drop the connection instead of taking down the whole listener.
+            let client_addr = match client_addr {
+                Ok(addr) => addr,
+                Err(e) => {
+                    sink.record_counter("clients_dropped", 1);
+                    warn!("[client] failed to get peer address for accepted connection: {}", e);
+                    return ok(());
+                },
+            };
+
+            configure_client(&client, &client_addr, &client_options.socket_options);
+
+            // Turn away anyone not allowed to talk to us before we even look at what they send.
+            // Behind a load balancer, though, the peer is just the load balancer, so we can't tell
+            // who the client is until we've read the PROXY protocol header.
+            let guard = if client_options.proxy_protocol {
+                None
+            } else {
+                match admit_client(&client_addr, &client_options.source_filter, limiter.as_ref()) {
+                    Ok(guard) => guard,
+                    Err(rejection) => {
+                        reject_client(client, &client_addr, rejection, &processor, &mut sink);
+                        return ok(());
+                    },
+                }
+            };
+
+            // If we're already buffering as much as we're allowed to, a new client would only add
+            // to it, so turn it away until things calm down.
+            if client_options.memory_budget.as_ref().map_or(false, |budget| budget.is_exhausted()) {
+                sink.record_counter("clients_rejected", 1);
+                sink.record_counter("memory_pressure", 1);
+                warn!(
+                    "[client] [{}] rejected: too much buffered across all clients",
+                    client_addr
+                );
+
+                let err = processor.get_error_message_str("proxy is out of buffer space, try again later");
+                tokio::spawn(io::write_all(client, err.into_buf()).then(|_| ok(())));
+                return ok(());
+            }
+
             warden.increment();
             sink.record_counter("clients_connected", 1);
 
             let router = router.clone();
             let processor = processor.clone();
             let close = close.clone();
+            let client_options = client_options.clone();
             let warden2 = warden.clone();
             let mut sink2 = sink.clone();
-            let client_addr = client.peer_addr().unwrap();
-            debug!("[client] {} connected", client_addr);
+            let pipeline_sink = sink.clone();
+            debug!("[client] [{}] connected", client_addr);
 
-            let transport = processor.get_transport(client);
-            let task = Pipeline::new(transport, router, processor, sink.clone())
-                .then(move |result| {
-                    match result {
-                        Ok(_) => {
-                            debug!("[client] {} disconnected", client_addr);
+            // Load balancers send their PROXY protocol header before anything else, even the TLS
+            // handshake.  Both happen as part of the client's own task, so that a client that's
+            // slow to finish them, or never does, doesn't hold up accepting anyone else.
+            let header = if client_options.proxy_protocol {
+                let peer_addr = client_addr.clone();
+                let source_filter = client_options.source_filter.clone();
+                let limiter = limiter.clone();
+                let processor = processor.clone();
+                let mut sink = sink.clone();
+
+                let timeout = Duration::from_millis(PROXY_HEADER_TIMEOUT_MS);
+                let header = Timeout::new(read_proxy_header(client), timeout).map_err(|e| {
+                    ClientSetupError::ProxyHeader(e.into_inner().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for PROXY protocol header").into()
+                    }))
+                });
+
+                // Now that we know who the load balancer is proxying for, we can decide whether
+                // or not to let them in.
+                Either::A(header.and_then(move |(client, source_addr)| {
+                    let client_addr = match source_addr {
+                        Some(source_addr) => {
+                            debug!("[client] [{}] is proxying for {}", peer_addr, source_addr);
+                            ClientAddr::Tcp(source_addr)
+                        },
+                        None => peer_addr,
+                    };
+
+                    match admit_client(&client_addr, &source_filter, limiter.as_ref()) {
+                        Ok(guard) => Ok((client, client_addr, guard)),
+                        Err(rejection) => {
+                            reject_client(client, &client_addr, rejection, &processor, &mut sink);
+                            Err(ClientSetupError::Rejected)
                         },
+                    }
+                }))
+            } else {
+                Either::B(ok((client, client_addr.clone(), guard)))
+            };
+
+            let tls_acceptor = client_options.tls_acceptor.clone();
+            let stream = header.and_then(move |(client, client_addr, guard)| {
+                let stream = match (tls_acceptor, client) {
+                    (Some(acceptor), MaybeTlsStream::Plain(client)) => {
+                        // A client that never finishes the handshake would otherwise hold on to its
+                        // connection, and its slot against any connection limits, forever.
+                        let timeout = Duration::from_millis(TLS_HANDSHAKE_TIMEOUT_MS);
+                        let stream = Timeout::new(acceptor.accept(client), timeout)
+                            .map(|stream| MaybeTlsStream::Server(Box::new(stream)))
+                            .map_err(|e| {
+                                ClientSetupError::TlsHandshake(e.into_inner().unwrap_or_else(|| {
+                                    io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for TLS handshake")
+                                }))
+                            });
+                        Either::A(stream)
+                    },
+                    (_, client) => Either::B(ok(client)),
+                };
+                stream.map(move |stream| (stream, client_addr, guard))
+            });
+
+            let task = stream
+                .then(move |result| {
+                    let (stream, client_addr, guard) = match result {
+                        Ok(client) => client,
                         Err(e) => {
                             match e {
-                                // If we got a protocol error from a client, that's bad.  Otherwise,
-                                // clients closing their connection is a normal thing.
-                                PipelineError::TransportReceive(ie) => {
-                                    if !ie.client_closed() {
-                                        sink2.record_counter("client_errors", 1);
-                                        error!("[client] transport error from {}: {}", client_addr, ie);
-                                    }
+                                ClientSetupError::ProxyHeader(e) => {
+                                    sink2.record_counter("proxy_protocol_errors", 1);
+                                    warn!(
+                                        "[client] [{}] dropped: invalid PROXY protocol header: {}",
+                                        client_addr, e
+                                    );
+                                },
+                                // Already accounted for when the client was turned away.
+                                ClientSetupError::Rejected => {},
+                                ClientSetupError::TlsHandshake(e) => {
+                                    // Most likely a client that isn't speaking TLS at all.
+                                    sink2.record_counter("tls_handshake_failures", 1);
+                                    warn!("[client] [{}] dropped: TLS handshake failed: {}", client_addr, e);
                                 },
-                                e => error!("[client] error from {}: {}", client_addr, e),
                             }
+
+                            warden2.decrement();
+                            return Either::A(ok::<(), ()>(()));
                         },
+                    };
+
+                    // The router is this client's own clone, so it can record where this client's
+                    // requests went for the access log without mixing them up with anyone else's.
+                    let mut router = router;
+                    let access_log = client_options.access_log.clone().map(|writer| {
+                        let route_log = RouteLog::default();
+                        router.set_route_log(route_log.clone());
+                        (writer, route_log)
+                    });
+
+                    let transport = processor.get_transport(stream);
+                    let mut pipeline = Pipeline::new(transport, router, processor, pipeline_sink)
+                        .set_dedupe_reads(client_options.dedupe_reads)
+                        .set_coalesce_writes(client_options.coalesce_writes)
+                        .set_max_pending_responses(client_options.max_pending_responses)
+                        .set_request_timeout(client_options.request_timeout)
+                        .set_memory_budget(client_options.memory_budget.clone());
+                    if let Some((writer, route_log)) = access_log {
+                        pipeline = pipeline.set_access_log(client_addr.clone(), writer, route_log);
                     }
 
-                    warden2.decrement();
+                    Either::B(pipeline.then(move |result| {
+                        match result {
+                            Ok(_) => {
+                                debug!("[client] [{}] disconnected", client_addr);
+                            },
+                            Err(e) => {
+                                match e {
+                                    // If we got a protocol error from a client, that's bad.  Otherwise,
+                                    // clients closing their connection is a normal thing.
+                                    PipelineError::TransportReceive(ie) => {
+                                        if !ie.client_closed() {
+                                            sink2.record_counter("client_errors", 1);
+                                            error!("[client] [{}] transport error: {}", client_addr, ie);
+                                        }
+                                    },
+                                    e => error!("[client] [{}] error: {}", client_addr, e),
+                                }
+                            },
+                        }
+
+                        warden2.decrement();
+                        drop(guard);
 
-                    ok::<(), ()>(())
+                        ok::<(), ()>(())
+                    }))
                 })
                 .select2(close);
 
@@ -251,8 +995,72 @@ where
     Ok(Box::new(task.untyped()))
 }
 
-fn get_listener(addr_str: &str) -> io::Result<TcpListener> {
-    let addr = addr_str.parse().unwrap();
+/// Reports on the clients still connected to a listener while it drains.
+///
+/// Once `close` resolves, the number of clients left is recorded as the `clients_draining` gauge
+/// every second, and logged every 500ms, until they've all disconnected or `timeout` runs out.  If
+/// any are left at that point, they get disconnected, and `drain_timeout_forced` is incremented.
+fn monitor_drain<C>(
+    address: String, close: C, clients: ClientWarden, timeout: Duration, mut sink: MetricSink,
+) -> impl Future<Item = (), Error = ()>
+where
+    C: Future,
+{
+    close.then(move |_| {
+        let deadline = Instant::now() + timeout;
+        let gauge_interval = Duration::from_millis(DRAIN_GAUGE_INTERVAL_MS);
+
+        loop_fn(Instant::now(), move |next_gauge_at| {
+            let now = Instant::now();
+            let remaining = clients.count();
+
+            let next_gauge_at = if now >= next_gauge_at {
+                sink.record_gauge("clients_draining", remaining as i64);
+                now + gauge_interval
+            } else {
+                next_gauge_at
+            };
+
+            if remaining == 0 {
+                sink.record_gauge("clients_draining", 0);
+                debug!("[listener] [{}] all clients disconnected, done draining", address);
+                return Either::A(ok(Loop::Break(())));
+            }
+
+            if now >= deadline {
+                sink.record_gauge("clients_draining", 0);
+                sink.record_counter("drain_timeout_forced", 1);
+                warn!(
+                    "[listener] [{}] drain timed out, forcibly disconnecting {} client(s)",
+                    address, remaining
+                );
+                return Either::A(ok(Loop::Break(())));
+            }
+
+            info!(
+                "[listener] [{}] draining, {} client(s) still connected",
+                address, remaining
+            );
+
+            let next_log_at = now + Duration::from_millis(DRAIN_LOG_INTERVAL_MS);
+            let wake_at = if next_log_at < deadline { next_log_at } else { deadline };
+            Either::B(
+                Delay::new(wake_at)
+                    .map(move |_| Loop::Continue(next_gauge_at))
+                    .map_err(|_| ()),
+            )
+        })
+    })
+}
+
+fn get_listener(address: &ListenAddress, socket_mode: Option<u32>) -> io::Result<Listener> {
+    match address {
+        ListenAddress::Tcp(addr) => get_tcp_listener(addr).map(Listener::Tcp),
+        ListenAddress::Unix(path) => get_unix_listener(path, socket_mode),
+    }
+}
+
+fn get_tcp_listener(addr: &SocketAddr) -> io::Result<TcpListener> {
     let builder = match addr {
         SocketAddr::V4(_) => TcpBuilder::new_v4()?,
         SocketAddr::V6(_) => TcpBuilder::new_v6()?,
@@ -265,6 +1073,47 @@ fn get_listener(addr_str: &str) -> io::Result<TcpListener> {
         .and_then(|l| TcpListener::from_std(l, &reactor::Handle::default()))
 }
 
+#[cfg(unix)]
+fn get_unix_listener(path: &Path, socket_mode: Option<u32>) -> io::Result<Listener> {
+    use socket2::{Domain, SockAddr, Socket, Type};
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+    // Clear out any socket file left behind by a previous run, since we can't bind over it, but
+    // never anything that isn't a socket.
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+            }
+            fs::remove_file(path)?;
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+
+    // The socket file is created by binding to it, but nobody can connect until we start listening,
+    // so setting its mode in between means no client ever gets in under the default mode.  Doing it
+    // this way, rather than masking out the mode while we bind, leaves the process-wide umask alone.
+    let socket = Socket::new(Domain::unix(), Type::stream(), None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    if let Some(mode) = socket_mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    socket.listen(1024)?;
+    let listener = UnixListener::from_std(socket.into_unix_listener(), &reactor::Handle::default())?;
+    let socket_file = SocketFile {
+        path: path.to_path_buf(),
+        inode: fs::symlink_metadata(path)?.ino(),
+    };
+
+    Ok(Listener::Unix(listener, socket_file))
+}
+
+#[cfg(windows)]
+fn get_unix_listener(_path: &Path, _socket_mode: Option<u32>) -> io::Result<Listener> {
+    Err(io::Error::new(io::ErrorKind::Other, "Unix domain sockets are not supported on this platform"))
+}
+
 #[cfg(unix)]
 fn configure_builder(builder: &TcpBuilder) -> io::Result<()> {
     use net2::unix::*;
@@ -275,3 +1124,196 @@ fn configure_builder(builder: &TcpBuilder) -> io::Result<()> {
 
 #[cfg(windows)]
 fn configure_builder(_builder: &TcpBuilder) -> io::Result<()> { Ok(()) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::{env, os::unix::net::UnixListener as StdUnixListener, process};
+
+    fn ip(s: &str) -> IpAddr { s.parse().unwrap() }
+
+    #[test]
+    fn test_source_filter() {
+        let mut config = ListenerConfiguration::default();
+        let filter = SourceFilter::from_config(&config).unwrap();
+        assert!(filter.allows(ip("1.2.3.4")));
+
+        config.allowed_sources = Some(vec!["10.0.0.0/8".to_owned(), "fd00::/8".to_owned()]);
+        config.denied_sources = Some(vec!["10.0.0.13".to_owned()]);
+        let filter = SourceFilter::from_config(&config).unwrap();
+        assert!(filter.allows(ip("10.1.2.3")));
+        assert!(filter.allows(ip("fd00::1")));
+        assert!(!filter.allows(ip("10.0.0.13")));
+        assert!(!filter.allows(ip("192.168.1.1")));
+
+        config.allowed_sources = None;
+        let filter = SourceFilter::from_config(&config).unwrap();
+        assert!(filter.allows(ip("192.168.1.1")));
+        assert!(!filter.allows(ip("10.0.0.13")));
+
+        config.denied_sources = Some(vec!["10.0.0.300".to_owned()]);
+        assert!(SourceFilter::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_admit_client() {
+        let mut config = ListenerConfiguration::default();
+        config.denied_sources = Some(vec!["10.0.0.13".to_owned()]);
+        let filter = SourceFilter::from_config(&config).unwrap();
+        let limiter = ClientLimiter::new(1);
+
+        let tcp = |s: &str| ClientAddr::Tcp(s.parse().unwrap());
+        let admit = |addr: &ClientAddr| admit_client(addr, &filter, Some(&limiter));
+
+        match admit(&tcp("10.0.0.13:50000")) {
+            Err(ClientRejection::SourceNotAllowed) => {},
+            _ => panic!("expected denied source to be rejected"),
+        }
+
+        // Each address gets its own limit, and a slot frees up once its client goes away.
+        let guard = admit(&tcp("10.0.0.1:50000")).ok().unwrap();
+        assert!(guard.is_some());
+        match admit(&tcp("10.0.0.1:50001")) {
+            Err(ClientRejection::TooManyConnections) => {},
+            _ => panic!("expected client over the limit to be rejected"),
+        }
+        assert!(admit(&tcp("10.0.0.2:50000")).ok().unwrap().is_some());
+        drop(guard);
+        assert!(admit(&tcp("10.0.0.1:50001")).ok().unwrap().is_some());
+
+        // Unix domain socket clients have no address to go by.
+        let unix = ClientAddr::Unix(PathBuf::from("/var/run/synchrotron.sock"));
+        assert!(admit(&unix).ok().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_listen_address() {
+        assert_eq!(
+            ListenAddress::parse("127.0.0.1:6379").unwrap(),
+            ListenAddress::Tcp("127.0.0.1:6379".parse().unwrap())
+        );
+        assert_eq!(
+            ListenAddress::parse("unix:/var/run/synchrotron.sock").unwrap(),
+            ListenAddress::Unix(PathBuf::from("/var/run/synchrotron.sock"))
+        );
+        assert!(ListenAddress::parse("unix:").is_err());
+        assert!(ListenAddress::parse("localhost:6379").is_err());
+    }
+
+    #[test]
+    fn test_pool_group() {
+        let pools = vec![
+            "default", "shadow_b", "shadow", "shadow_a", "shadow2", "shadow10", "shadowy", "shadow2b", "replica_a",
+        ]
+        .into_iter()
+        .map(|name| (name.to_owned(), name))
+        .collect::<HashMap<_, _>>();
+        let group = |group| get_pool_group(&pools, group).into_iter().map(|(_, pool)| pool).collect::<Vec<_>>();
+
+        // Both numbered and underscored pools are part of a group, but other pools that just happen
+        // to start with the same name aren't.
+        assert_eq!(group("shadow"), vec!["shadow", "shadow10", "shadow2", "shadow_a", "shadow_b"]);
+        assert_eq!(group("replica"), vec!["replica_a"]);
+        assert!(group("primary").is_empty());
+    }
+
+    #[test]
+    fn test_announce_address() {
+        let tcp = ListenAddress::parse("10.0.0.1:6379").unwrap();
+        let wildcard = ListenAddress::parse("0.0.0.0:6379").unwrap();
+        let unix = ListenAddress::parse("unix:/var/run/synchrotron.sock").unwrap();
+        let announced = "10.0.0.2:7000".parse().unwrap();
+
+        // By default, we announce the address we're listening on, as long as clients can connect to it.
+        assert_eq!(get_announce_address(None, &tcp).unwrap(), Some("10.0.0.1:6379".parse().unwrap()));
+        assert_eq!(get_announce_address(None, &wildcard).unwrap(), None);
+        assert_eq!(get_announce_address(None, &unix).unwrap(), None);
+
+        // A configured address always wins, but has to be one that clients can connect to.
+        assert_eq!(get_announce_address(Some("10.0.0.2:7000"), &tcp).unwrap(), Some(announced));
+        assert_eq!(get_announce_address(Some("10.0.0.2:7000"), &wildcard).unwrap(), Some(announced));
+        assert_eq!(get_announce_address(Some("10.0.0.2:7000"), &unix).unwrap(), Some(announced));
+        assert!(get_announce_address(Some("0.0.0.0:7000"), &tcp).is_err());
+        assert!(get_announce_address(Some("localhost:7000"), &tcp).is_err());
+    }
+
+    /// Accepts a client from a local listener, sets it up with the given socket options the same way
+    /// a listener would, and gets the nodelay and keepalive settings of the resulting socket.
+    fn get_accepted_socket_options(socket_options: SocketOptions) -> Option<(bool, Option<Duration>)> {
+        let listener = get_tcp_listener(&"127.0.0.1:0".parse().unwrap()).expect("failed to bind listener");
+        let address = listener.local_addr().expect("failed to get listener address");
+        let listener = Listener::Tcp(listener);
+
+        // The connection just sits in the accept queue until the listener gets around to it.
+        let _client = std::net::TcpStream::connect(address).expect("failed to connect to listener");
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            listener.into_future().then(move |result| {
+                let settings = match result {
+                    Ok((Some((client, Ok(client_addr))), _)) => {
+                        configure_client(&client, &client_addr, &socket_options);
+                        match client {
+                            MaybeTlsStream::Plain(stream) => stream
+                                .nodelay()
+                                .and_then(|nodelay| stream.keepalive().map(|keepalive| (nodelay, keepalive)))
+                                .ok(),
+                            _ => None,
+                        }
+                    },
+                    _ => None,
+                };
+                let _ = tx.send(settings);
+                Ok::<(), ()>(())
+            })
+        }));
+
+        rx.recv().expect("listener never accepted a client")
+    }
+
+    #[test]
+    fn test_client_socket_options() {
+        assert_eq!(get_accepted_socket_options(SocketOptions::default()), Some((true, None)));
+        assert_eq!(
+            get_accepted_socket_options(SocketOptions::new(false, 30)),
+            Some((false, Some(Duration::from_secs(30))))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_listener_socket_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("synchrotron-listener-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+
+        // Anything at the path that isn't a socket is left alone.
+        fs::write(&path, "not a socket").unwrap();
+        assert!(get_unix_listener(&path, None).is_err());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+
+        // A stale socket file gets replaced, and the new one is created with the mode we asked for.
+        drop(StdUnixListener::bind(&path).unwrap());
+        let listener = get_unix_listener(&path, Some(0o600)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        drop(listener);
+        assert!(!path.exists());
+
+        // The mode is exactly what was asked for, even when it's looser than the umask would allow.
+        let listener = get_unix_listener(&path, Some(0o666)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o666);
+        drop(listener);
+
+        // A listener that's been replaced by a newer one leaves the newer socket file in place.
+        let old = get_unix_listener(&path, None).unwrap();
+        let new = get_unix_listener(&path, None).unwrap();
+        drop(old);
+        assert!(path.exists());
+        drop(new);
+        assert!(!path.exists());
+    }
+}
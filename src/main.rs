@@ -41,7 +41,10 @@ use crate::{
     libc::{SIGINT, SIGUSR1},
 };
 use futures::future::{lazy, ok};
-use std::thread;
+use std::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+};
 
 extern crate tokio;
 use tokio::{
@@ -74,7 +77,7 @@ mod util;
 use crate::{
     conf::{Configuration, LevelExt},
     errors::CreationError,
-    util::FutureExt,
+    util::{FutureExt, MemoryBudget},
 };
 use metrics_runtime::{
     exporters::HttpExporter, recorders::PrometheusRecorder, Controller, Receiver, Sink as MetricSink,
@@ -150,18 +153,19 @@ fn launch_supervisor(
     supervisor_rx: mpsc::UnboundedReceiver<SupervisorCommand>, shutdown_tx: oneshot::Sender<()>, sink: MetricSink,
 ) {
     let turnstyle = Turnstyle::new();
+    let buffered_bytes = Arc::new(AtomicUsize::new(0));
     let supervisor = supervisor_rx
         .map_err(|_| CreationError::ListenerSpawnFailed)
         .fold(turnstyle, move |ts, command| {
             match command {
                 SupervisorCommand::Launch => {
                     let (version, waiter) = ts.join();
-                    launch_listeners(version, waiter, sink.clone())?;
+                    launch_listeners(version, waiter, buffered_bytes.clone(), sink.clone())?;
                     counter!("supervisor.configuration_loads", 1);
                 },
                 SupervisorCommand::Reload => {
                     let (version, waiter) = ts.join();
-                    launch_listeners(version, waiter, sink.clone())?;
+                    launch_listeners(version, waiter, buffered_bytes.clone(), sink.clone())?;
                     ts.turn();
                     counter!("supervisor.configuration_loads", 1);
                 },
@@ -184,8 +188,13 @@ fn launch_supervisor(
     tokio::spawn(supervisor);
 }
 
-fn launch_listeners(version: usize, close: Waiter, sink: MetricSink) -> Result<(), CreationError> {
+fn launch_listeners(
+    version: usize, close: Waiter, buffered_bytes: Arc<AtomicUsize>, sink: MetricSink,
+) -> Result<(), CreationError> {
     let configuration = Configuration::new().expect("failed to parse configuration");
+    let budget = configuration
+        .max_buffered_bytes
+        .map(|limit| MemoryBudget::new(limit, buffered_bytes));
     let closer = close.shared();
     let listeners = configuration
         .listeners
@@ -193,7 +202,7 @@ fn launch_listeners(version: usize, close: Waiter, sink: MetricSink) -> Result<(
         .map(|(name, config)| {
             let close = closer.clone();
 
-            listener::from_config(version, name, config, close, sink.clone())
+            listener::from_config(version, name, config, budget.clone(), close, sink.clone())
         })
         .collect::<Vec<_>>();
 
@@ -24,6 +24,7 @@ use tokio::sync::oneshot;
 pub enum ProtocolError {
     IoError(io::Error),
     InvalidProtocol,
+    TooManyArguments,
     BackendClosedPrematurely,
 }
 
@@ -46,6 +47,7 @@ impl error::Error for ProtocolError {
         match *self {
             ProtocolError::IoError(ref e) => e.description(),
             ProtocolError::InvalidProtocol => "invalid protocol",
+            ProtocolError::TooManyArguments => "too many arguments",
             ProtocolError::BackendClosedPrematurely => "backend closed prematurely",
         }
     }
@@ -58,6 +60,7 @@ impl fmt::Display for ProtocolError {
         match *self {
             ProtocolError::IoError(ref ie) => fmt::Display::fmt(ie, f),
             ProtocolError::InvalidProtocol => write!(f, "invalid protocol"),
+            ProtocolError::TooManyArguments => write!(f, "too many arguments"),
             ProtocolError::BackendClosedPrematurely => write!(f, "backend closed prematurely"),
         }
     }
@@ -0,0 +1,788 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::{
+    common::{EnqueuedRequests, Message},
+    protocol::errors::ProtocolError,
+    util::Sizable,
+};
+use btoi::btoi;
+use bytes::BytesMut;
+use futures::{future::Either, prelude::*};
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{write_all, AsyncRead, AsyncWrite, Error, ErrorKind},
+    timer::{Delay, Timeout},
+};
+
+const MAX_OUTSTANDING_WBUF: usize = 8192;
+
+// Keys are limited to 250 bytes, so no valid command line comes anywhere close to this.  Anything
+// longer is garbage, and we'd rather not buffer it forever waiting for a line ending.
+const MAX_LINE_LEN: usize = 2048;
+
+// Like memcached itself, we don't take items over 1MB.  The length of a data block comes from the
+// client, so without a limit, a client could have us buffer as much as it cares to send.
+const MAX_ITEM_LEN: usize = 1024 * 1024;
+
+const MEMCACHED_CRLF: &[u8] = b"\r\n";
+const MEMCACHED_END: &[u8] = b"END\r\n";
+const MEMCACHED_VALUE: &[u8] = b"VALUE ";
+const MEMCACHED_NOREPLY: &[u8] = b"noreply";
+const MEMCACHED_BACKEND_CLOSED: &str = "backend closed prematurely";
+const MEMCACHED_BACKEND_TIMED_OUT: &str = "backend timed out";
+const MEMCACHED_BACKEND_READ_FAILED: &str = "failed to read from backend";
+
+// Commands that carry a data block after their command line.  We only support some of them, but
+// we have to know about all of them to stay in sync with the client when rejecting the rest.
+const STORAGE_COMMANDS: [&[u8]; 6] = [b"set", b"add", b"replace", b"append", b"prepend", b"cas"];
+
+pub struct MemcachedTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    transport: T,
+    rbuf: BytesMut,
+    wbuf: BytesMut,
+    closed: bool,
+}
+
+pub struct MemcachedMultipleMessages<T>
+where
+    T: AsyncRead,
+{
+    transport: Option<T>,
+    rbuf: BytesMut,
+    bytes_read: usize,
+    msgs: EnqueuedRequests<MemcachedMessage>,
+    retrievals: Vec<bool>,
+    read_timeout: Option<Duration>,
+    read_deadline: Option<Delay>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MemcachedMessage {
+    /// A request, along with where its command and arguments are in the buffer.
+    ///
+    /// The buffer holds the entire request, including the data block of storage commands.
+    Request(BytesMut, Vec<Range<usize>>),
+
+    /// A response, which we never need to look inside of beyond knowing where it ends.
+    Response(BytesMut),
+}
+
+impl MemcachedMessage {
+    pub fn from_args(args: &[&[u8]]) -> MemcachedMessage {
+        let mut buf = BytesMut::with_capacity(args.iter().map(|arg| arg.len() + 1).sum::<usize>() + 2);
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                buf.extend_from_slice(b" ");
+            }
+            buf.extend_from_slice(arg);
+        }
+
+        let ranges = split_args(&buf[..]);
+        buf.extend_from_slice(MEMCACHED_CRLF);
+        MemcachedMessage::Request(buf, ranges)
+    }
+
+    pub fn from_inline(cmd: &str) -> MemcachedMessage {
+        let args = cmd.split_whitespace().map(str::as_bytes).collect::<Vec<_>>();
+        MemcachedMessage::from_args(&args)
+    }
+
+    pub fn from_response(resp: &[u8]) -> MemcachedMessage { MemcachedMessage::Response(BytesMut::from(resp)) }
+
+    /// The reply to a command we don't recognize.
+    pub fn from_unknown_command() -> MemcachedMessage { MemcachedMessage::from_response(b"ERROR\r\n") }
+
+    pub fn from_client_error_str(error_str: &str) -> MemcachedMessage {
+        MemcachedMessage::Response(BytesMut::from(format!("CLIENT_ERROR {}\r\n", error_str)))
+    }
+
+    pub fn from_error_str(error_str: &str) -> MemcachedMessage {
+        MemcachedMessage::Response(BytesMut::from(format!("SERVER_ERROR {}\r\n", error_str)))
+    }
+
+    pub fn get_command(&self) -> Option<&[u8]> { self.get_arg(0) }
+
+    /// Gets the argument at the given position, where the command itself is at position 0.
+    pub fn get_arg(&self, idx: usize) -> Option<&[u8]> {
+        match self {
+            MemcachedMessage::Request(buf, args) => args.get(idx).map(|range| &buf[range.clone()]),
+            MemcachedMessage::Response(_) => None,
+        }
+    }
+
+    /// Gets the number of arguments, including the command itself.
+    pub fn arg_count(&self) -> usize {
+        match self {
+            MemcachedMessage::Request(_, args) => args.len(),
+            MemcachedMessage::Response(_) => 0,
+        }
+    }
+
+    /// Whether or not this message is the reply to a `version` command.
+    pub fn is_version(&self) -> bool {
+        match self {
+            MemcachedMessage::Response(buf) => buf.starts_with(b"VERSION "),
+            MemcachedMessage::Request(_, _) => false,
+        }
+    }
+
+    pub fn get_buf(&self) -> &[u8] {
+        match self {
+            MemcachedMessage::Request(buf, _) => &buf[..],
+            MemcachedMessage::Response(buf) => &buf[..],
+        }
+    }
+
+    pub fn into_bytes(self) -> BytesMut {
+        match self {
+            MemcachedMessage::Request(buf, _) => buf,
+            MemcachedMessage::Response(buf) => buf,
+        }
+    }
+}
+
+impl Sizable for MemcachedMessage {
+    fn size(&self) -> usize { self.get_buf().len() }
+}
+
+impl Message for MemcachedMessage {
+    fn key(&self) -> &[u8] {
+        // Commands without a key, like `version`, can go to any backend.
+        self.get_arg(1).unwrap_or(b"")
+    }
+
+    fn command(&self) -> Option<&[u8]> { self.get_command() }
+
+    fn is_inline(&self) -> bool {
+        match self {
+            MemcachedMessage::Request(_, _) => false,
+            MemcachedMessage::Response(_) => true,
+        }
+    }
+
+    fn is_error(buf: &[u8]) -> bool {
+        buf.starts_with(b"ERROR") || buf.starts_with(b"CLIENT_ERROR") || buf.starts_with(b"SERVER_ERROR")
+    }
+
+    fn is_read(&self) -> bool { self.get_command().map_or(false, is_retrieval_command) }
+
+    fn is_idempotent_write(&self) -> bool { false }
+
+    fn get_repeated_response(&self, response: &Self) -> Self { response.clone() }
+
+    fn is_blocking(&self) -> bool { false }
+
+    fn is_broadcast(&self) -> bool { false }
+
+    fn is_key_listing(&self) -> bool { false }
+
+    fn colocated_keys(&self) -> Option<Vec<&[u8]>> { None }
+
+    fn into_buf(self) -> BytesMut { self.into_bytes() }
+}
+
+/// Whether or not the given command retrieves values, getting a multi-line response back.
+pub fn is_retrieval_command(cmd: &[u8]) -> bool {
+    cmd.eq_ignore_ascii_case(b"get") || cmd.eq_ignore_ascii_case(b"gets")
+}
+
+fn is_storage_command(cmd: &[u8]) -> bool { STORAGE_COMMANDS.iter().any(|c| c.eq_ignore_ascii_case(cmd)) }
+
+/// Checks that the given request is one we support, and is well-formed.
+///
+/// If it isn't, the response to send back to the client is returned instead.
+fn check_request(msg: &MemcachedMessage) -> Option<MemcachedMessage> {
+    let cmd = msg.get_command()?.to_ascii_lowercase();
+    let (min_args, max_args) = match &cmd[..] {
+        b"get" | b"gets" => (2, usize::max_value()),
+        b"set" | b"add" | b"replace" => (5, 6),
+        b"delete" => (2, 3),
+        b"incr" | b"decr" => (3, 4),
+        b"version" => (1, 1),
+        b"append" | b"prepend" | b"cas" => {
+            let reason = format!("{} is not supported by this proxy", String::from_utf8_lossy(&cmd));
+            return Some(MemcachedMessage::from_client_error_str(&reason));
+        },
+        _ => return Some(MemcachedMessage::from_unknown_command()),
+    };
+
+    let arg_count = msg.arg_count();
+    if arg_count < min_args || arg_count > max_args {
+        return Some(MemcachedMessage::from_client_error_str("bad command line format"));
+    }
+
+    // Commands with `noreply` get no response from the backend, which would leave us waiting on it
+    // forever, so we don't allow them.
+    if !is_retrieval_command(&cmd) && msg.get_arg(arg_count - 1) == Some(MEMCACHED_NOREPLY) {
+        return Some(MemcachedMessage::from_client_error_str(
+            "noreply is not supported by this proxy",
+        ));
+    }
+
+    None
+}
+
+impl<T> MemcachedTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub fn new(transport: T) -> Self {
+        MemcachedTransport {
+            transport,
+            rbuf: BytesMut::new(),
+            wbuf: BytesMut::new(),
+            closed: false,
+        }
+    }
+
+    fn fill_read_buf(&mut self) -> Poll<(), ProtocolError> {
+        loop {
+            self.rbuf.reserve(8192);
+
+            let n = try_ready!(self.transport.read_buf(&mut self.rbuf));
+            if n == 0 {
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+}
+
+impl<T> Stream for MemcachedTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type Error = ProtocolError;
+    type Item = MemcachedMessage;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.closed {
+            return Ok(Async::Ready(None));
+        }
+
+        let socket_closed = self.fill_read_buf()?.is_ready();
+
+        match read_request(&mut self.rbuf) {
+            Ok(Async::Ready((bytes_read, msg))) => {
+                trace!("[protocol] got message from client! ({} bytes)", bytes_read);
+
+                // Quitting gets no response, so we just stop taking requests, and the connection
+                // is closed once everything before it has been answered.
+                if msg.get_command().map_or(false, |cmd| cmd.eq_ignore_ascii_case(b"quit")) {
+                    self.closed = true;
+                    return Ok(Async::Ready(None));
+                }
+
+                // Requests we can't serve are rejected inline.  Each request is self-contained, so
+                // we stay in sync with the client and can keep the transport open.
+                if let Some(emsg) = check_request(&msg) {
+                    return Ok(Async::Ready(Some(emsg)));
+                }
+
+                Ok(Async::Ready(Some(msg)))
+            },
+            Err(ProtocolError::InvalidProtocol) => {
+                // We can't tell where this request ends, so there's no way to resynchronize: hand
+                // back an error, inlined, and close the transport.
+                self.closed = true;
+                self.rbuf.clear();
+
+                let emsg = MemcachedMessage::from_client_error_str("bad command line format");
+                Ok(Async::Ready(Some(emsg)))
+            },
+            Err(e) => Err(e),
+            _ => {
+                if socket_closed {
+                    Ok(Async::Ready(None))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            },
+        }
+    }
+}
+
+impl<T> Sink for MemcachedTransport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type SinkError = Error;
+    type SinkItem = BytesMut;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.wbuf.len() >= MAX_OUTSTANDING_WBUF {
+            self.poll_complete()?;
+
+            if self.wbuf.len() >= MAX_OUTSTANDING_WBUF {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        self.wbuf.unsplit(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        while !self.wbuf.is_empty() {
+            let n = try_ready!(self.transport.poll_write(&self.wbuf));
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            let _ = self.wbuf.split_to(n);
+        }
+
+        try_ready!(self.transport.poll_flush());
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T> MemcachedMultipleMessages<T>
+where
+    T: AsyncRead,
+{
+    pub fn new(
+        transport: T, msgs: EnqueuedRequests<MemcachedMessage>, retrievals: Vec<bool>, read_timeout: Option<Duration>,
+    ) -> Self {
+        MemcachedMultipleMessages {
+            transport: Some(transport),
+            rbuf: BytesMut::new(),
+            bytes_read: 0,
+            msgs,
+            retrievals,
+            read_timeout,
+            read_deadline: None,
+        }
+    }
+
+    fn poll_read_deadline(&mut self) -> Result<(), ProtocolError> {
+        if let Some(deadline) = self.read_deadline.as_mut() {
+            let elapsed = deadline
+                .poll()
+                .map_err(|e| Error::new(ErrorKind::Other, e))?
+                .is_ready();
+            if elapsed {
+                return Err(Error::new(ErrorKind::TimedOut, "backend read timed out").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails all of the requests we haven't yet read a response for.
+    fn fail_pending(&mut self, reason: &str) {
+        let err = MemcachedMessage::from_error_str(reason);
+        self.retrievals.clear();
+        for mut qmsg in self.msgs.drain(..) {
+            qmsg.fulfill(err.clone())
+        }
+    }
+
+    fn fill_read_buf(&mut self) -> Poll<(), ProtocolError> {
+        loop {
+            self.rbuf.reserve(16384);
+
+            let n = try_ready!(self.transport.as_mut().unwrap().read_buf(&mut self.rbuf));
+            self.bytes_read += n;
+
+            if n == 0 {
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+}
+
+impl<T> Future for MemcachedMultipleMessages<T>
+where
+    T: AsyncRead,
+{
+    type Error = ProtocolError;
+    type Item = (T, usize);
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let bytes_read = self.bytes_read;
+        let socket_closed = match self.fill_read_buf() {
+            Ok(closed) => closed.is_ready(),
+            Err(e) => {
+                self.fail_pending(MEMCACHED_BACKEND_READ_FAILED);
+                return Err(e);
+            },
+        };
+
+        // The read timeout only covers time spent waiting on the socket, so every time we make
+        // some progress, the clock starts over.
+        if let Some(read_timeout) = self.read_timeout {
+            if self.read_deadline.is_none() || self.bytes_read != bytes_read {
+                self.read_deadline = Some(Delay::new(Instant::now() + read_timeout));
+            }
+        }
+
+        loop {
+            if self.msgs.is_empty() {
+                return Ok(Async::Ready((self.transport.take().unwrap(), self.bytes_read)));
+            }
+
+            match read_response(&mut self.rbuf, self.retrievals[0]) {
+                Ok(Async::Ready((bytes_read, msg))) => {
+                    trace!("[protocol] got message from server! ({} bytes)", bytes_read);
+
+                    self.retrievals.remove(0);
+                    let mut qmsg = self.msgs.remove(0);
+                    qmsg.fulfill(msg)
+                },
+                Err(e) => {
+                    self.fail_pending(MEMCACHED_BACKEND_READ_FAILED);
+                    return Err(e);
+                },
+                _ => {
+                    if socket_closed {
+                        self.fail_pending(MEMCACHED_BACKEND_CLOSED);
+                        return Err(ProtocolError::BackendClosedPrematurely);
+                    }
+
+                    if let Err(e) = self.poll_read_deadline() {
+                        self.fail_pending(MEMCACHED_BACKEND_TIMED_OUT);
+                        return Err(e);
+                    }
+
+                    return Ok(Async::NotReady);
+                },
+            }
+        }
+    }
+}
+
+/// Reads the responses to the given requests.
+///
+/// Whether or not each request is a retrieval has to be given separately, since the requests
+/// themselves have already been consumed by the time they were written.
+pub fn read_messages<T>(
+    rx: T, msgs: EnqueuedRequests<MemcachedMessage>, retrievals: Vec<bool>, read_timeout: Option<Duration>,
+) -> MemcachedMultipleMessages<T>
+where
+    T: AsyncRead,
+{
+    MemcachedMultipleMessages::new(rx, msgs, retrievals, read_timeout)
+}
+
+pub fn write_messages<T>(
+    transport: T, mut msgs: EnqueuedRequests<MemcachedMessage>, write_timeout: Option<Duration>,
+) -> impl Future<Item = (T, EnqueuedRequests<MemcachedMessage>, usize), Error = ProtocolError>
+where
+    T: AsyncWrite,
+{
+    let mut buf = BytesMut::new();
+    for msg in &mut msgs {
+        buf.unsplit(msg.consume().into_bytes());
+    }
+
+    let buf_len = buf.len();
+    let write = write_all(transport, buf)
+        .map(move |(transport, _buf)| (transport, msgs, buf_len))
+        .map_err(|e| e.into());
+
+    match write_timeout {
+        None => Either::A(write),
+        Some(write_timeout) => {
+            Either::B(Timeout::new(write, write_timeout).map_err(|e| {
+                e.into_inner()
+                    .unwrap_or_else(|| Error::new(ErrorKind::TimedOut, "backend write timed out").into())
+            }))
+        },
+    }
+}
+
+/// Parses as many complete requests as possible from the given buffer.
+#[cfg(test)]
+pub fn parse_requests(buf: &[u8]) -> Result<Vec<MemcachedMessage>, ProtocolError> {
+    let mut rd = BytesMut::from(buf);
+    let mut msgs = Vec::new();
+    while let Async::Ready((_, msg)) = read_request(&mut rd)? {
+        msgs.push(msg);
+    }
+
+    Ok(msgs)
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> { buf.windows(2).position(|bytes| bytes == MEMCACHED_CRLF) }
+
+fn split_args(line: &[u8]) -> Vec<Range<usize>> {
+    let mut args = Vec::new();
+    let mut start = None;
+    for (i, b) in line.iter().enumerate() {
+        match (*b == b' ', start) {
+            (true, Some(s)) => {
+                args.push(s..i);
+                start = None;
+            },
+            (false, None) => start = Some(i),
+            _ => {},
+        }
+    }
+
+    if let Some(s) = start {
+        args.push(s..line.len());
+    }
+
+    args
+}
+
+fn read_request(rd: &mut BytesMut) -> Poll<(usize, MemcachedMessage), ProtocolError> {
+    let line_end = match find_crlf(rd) {
+        Some(pos) => pos,
+        None if rd.len() > MAX_LINE_LEN => return Err(ProtocolError::InvalidProtocol),
+        None => return Ok(Async::NotReady),
+    };
+
+    let args = split_args(&rd[..line_end]);
+    if args.is_empty() {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
+    // Storage commands are followed by a data block of the length given in the command line.
+    let mut total = line_end + 2;
+    if is_storage_command(&rd[args[0].clone()]) {
+        let data_len = args
+            .get(4)
+            .and_then(|range| btoi::<usize>(&rd[range.clone()]).ok())
+            .ok_or(ProtocolError::InvalidProtocol)?;
+        if data_len > MAX_ITEM_LEN {
+            return Err(ProtocolError::InvalidProtocol);
+        }
+
+        total = data_len.checked_add(total + 2).ok_or(ProtocolError::InvalidProtocol)?;
+        if rd.len() < total {
+            return Ok(Async::NotReady);
+        }
+
+        if &rd[total - 2..total] != MEMCACHED_CRLF {
+            return Err(ProtocolError::InvalidProtocol);
+        }
+    }
+
+    let buf = rd.split_to(total);
+    Ok(Async::Ready((total, MemcachedMessage::Request(buf, args))))
+}
+
+fn read_response(rd: &mut BytesMut, retrieval: bool) -> Poll<(usize, MemcachedMessage), ProtocolError> {
+    // Retrievals get a value block for each key that was found, and then an `END` line, while
+    // everything else gets a single line.  Errors are always a single line.
+    let mut pos = 0;
+    loop {
+        let line_end = match find_crlf(&rd[pos..]) {
+            Some(n) => pos + n,
+            None => return Ok(Async::NotReady),
+        };
+        let line = &rd[pos..line_end];
+
+        if retrieval && line.starts_with(MEMCACHED_VALUE) {
+            let data_len = split_args(line)
+                .get(3)
+                .and_then(|range| btoi::<usize>(&line[range.clone()]).ok())
+                .ok_or(ProtocolError::InvalidProtocol)?;
+
+            let block_end = data_len
+                .checked_add(line_end + 4)
+                .ok_or(ProtocolError::InvalidProtocol)?;
+            if rd.len() < block_end {
+                return Ok(Async::NotReady);
+            }
+
+            if &rd[block_end - 2..block_end] != MEMCACHED_CRLF {
+                return Err(ProtocolError::InvalidProtocol);
+            }
+
+            pos = block_end;
+            continue;
+        }
+
+        let total = line_end + 2;
+        if retrieval && &rd[pos..total] != MEMCACHED_END && !MemcachedMessage::is_error(line) {
+            return Err(ProtocolError::InvalidProtocol);
+        }
+
+        let buf = rd.split_to(total);
+        return Ok(Async::Ready((total, MemcachedMessage::Response(buf))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_one_response(buf: &[u8], retrieval: bool) -> Poll<(usize, MemcachedMessage), ProtocolError> {
+        let mut rd = BytesMut::from(buf);
+        read_response(&mut rd, retrieval)
+    }
+
+    #[test]
+    fn parse_get() {
+        let msgs = parse_requests(b"get foo\r\ngets foo  bar baz\r\n").expect("failed to parse");
+        assert_eq!(msgs.len(), 2);
+
+        assert_eq!(msgs[0].get_command(), Some(&b"get"[..]));
+        assert_eq!(msgs[0].key(), b"foo");
+        assert!(msgs[0].is_read());
+
+        assert_eq!(msgs[1].arg_count(), 4);
+        assert_eq!(msgs[1].get_arg(2), Some(&b"bar"[..]));
+        assert_eq!(msgs[1].get_arg(3), Some(&b"baz"[..]));
+        assert_eq!(msgs[1], MemcachedMessage::from_inline("gets foo bar baz"));
+    }
+
+    #[test]
+    fn parse_set() {
+        let msgs = parse_requests(b"set foo 0 0 5\r\nhe\r\no\r\ndelete foo\r\n").expect("failed to parse");
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].key(), b"foo");
+        assert!(!msgs[0].is_read());
+        assert_eq!(msgs[0].get_buf(), &b"set foo 0 0 5\r\nhe\r\no\r\n"[..]);
+        assert_eq!(msgs[1].get_buf(), &b"delete foo\r\n"[..]);
+    }
+
+    #[test]
+    fn parse_partial() {
+        // Nothing should be consumed until the whole request, data block and all, is available.
+        let mut rd = BytesMut::from(&b"set foo 0 0 5\r\nhel"[..]);
+        assert!(!read_request(&mut rd).expect("failed to parse").is_ready());
+        assert_eq!(rd.len(), 18);
+
+        let mut rd = BytesMut::from(&b"get fo"[..]);
+        assert!(!read_request(&mut rd).expect("failed to parse").is_ready());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let invalid: [&[u8]; 4] = [
+            b"set foo 0 0 abc\r\nhello\r\n",
+            b"set foo 0 0 2\r\nhello\r\n",
+            b"\r\n",
+            &[b'a'; MAX_LINE_LEN + 1],
+        ];
+        for buf in &invalid {
+            let mut rd = BytesMut::from(*buf);
+            match read_request(&mut rd) {
+                Err(ProtocolError::InvalidProtocol) => {},
+                _ => panic!("request should have been invalid: {:?}", buf),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_oversized_data_block() {
+        // Data blocks are limited to the same size memcached limits items to.
+        let mut buf = format!("set foo 0 0 {}\r\n", MAX_ITEM_LEN).into_bytes();
+        buf.extend_from_slice(&vec![b'a'; MAX_ITEM_LEN]);
+        buf.extend_from_slice(b"\r\n");
+        assert!(read_request(&mut BytesMut::from(&buf[..]))
+            .expect("failed to parse")
+            .is_ready());
+
+        // Anything bigger is rejected as soon as we see the command line, as is a length so big
+        // that it would overflow.
+        let oversized = format!("set foo 0 0 {}\r\n", MAX_ITEM_LEN + 1);
+        let overflowing = format!("set foo 0 0 {}\r\n", usize::max_value());
+        for buf in &[oversized, overflowing] {
+            match read_request(&mut BytesMut::from(buf.as_bytes())) {
+                Err(ProtocolError::InvalidProtocol) => {},
+                _ => panic!("request should have been invalid: {}", buf),
+            }
+        }
+
+        let overflowing = format!("VALUE foo 0 {}\r\n", usize::max_value());
+        match read_one_response(overflowing.as_bytes(), true) {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("response should have been invalid"),
+        }
+    }
+
+    #[test]
+    fn check_requests() {
+        assert_eq!(check_request(&MemcachedMessage::from_inline("get foo")), None);
+        assert_eq!(check_request(&MemcachedMessage::from_inline("incr foo 1")), None);
+        assert_eq!(check_request(&MemcachedMessage::from_inline("version")), None);
+        assert_eq!(
+            check_request(&MemcachedMessage::from_inline("flush_all")),
+            Some(MemcachedMessage::from_unknown_command())
+        );
+        assert_eq!(
+            check_request(&MemcachedMessage::from_inline("get")),
+            Some(MemcachedMessage::from_client_error_str("bad command line format"))
+        );
+        assert_eq!(
+            check_request(&MemcachedMessage::from_inline("delete foo noreply")),
+            Some(MemcachedMessage::from_client_error_str("noreply is not supported by this proxy"))
+        );
+        assert_eq!(
+            check_request(&MemcachedMessage::from_inline("cas foo 0 0 1 1")),
+            Some(MemcachedMessage::from_client_error_str("cas is not supported by this proxy"))
+        );
+    }
+
+    #[test]
+    fn parse_retrieval_response() {
+        let buf = b"VALUE foo 0 5\r\nhello\r\nVALUE bar 1 3 42\r\nEND\r\n\r\nEND\r\nSTORED\r\n";
+        match read_one_response(buf, true).expect("failed to parse") {
+            Async::Ready((n, msg)) => {
+                assert_eq!(n, buf.len() - 8);
+                assert_eq!(msg.get_buf(), &buf[..n]);
+            },
+            _ => panic!("should have had message"),
+        }
+
+        // A value that happens to look like the end of the response doesn't end it early.
+        assert!(!read_one_response(b"VALUE foo 0 5\r\nEND\r\n", true)
+            .expect("failed to parse")
+            .is_ready());
+        assert!(!read_one_response(b"VALUE foo 0 5\r\nhello\r\n", true)
+            .expect("failed to parse")
+            .is_ready());
+
+        match read_one_response(b"SERVER_ERROR out of memory\r\n", true).expect("failed to parse") {
+            Async::Ready((_, msg)) => assert!(MemcachedMessage::is_error(msg.get_buf())),
+            _ => panic!("should have had message"),
+        }
+
+        match read_one_response(b"STORED\r\n", true) {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("response should have been invalid"),
+        }
+    }
+
+    #[test]
+    fn parse_single_line_response() {
+        for buf in &[&b"STORED\r\n"[..], b"NOT_FOUND\r\n", b"42\r\n", b"VERSION 1.6.9\r\n"] {
+            match read_one_response(buf, false).expect("failed to parse") {
+                Async::Ready((n, msg)) => {
+                    assert_eq!(n, buf.len());
+                    assert_eq!(msg, MemcachedMessage::from_response(buf));
+                },
+                _ => panic!("should have had message"),
+            }
+        }
+
+        assert!(MemcachedMessage::from_response(b"VERSION 1.6.9\r\n").is_version());
+    }
+}
@@ -0,0 +1,422 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::protocol::errors::ProtocolError;
+use futures::prelude::*;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
+use tokio::io::AsyncRead;
+
+const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+const PROXY_V1_MAX_LEN: usize = 107;
+const PROXY_V1_PROTOCOLS: [&str; 3] = ["UNKNOWN", "TCP4", "TCP6"];
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_V2_HEADER_LEN: usize = 16;
+
+/// The state of a PROXY protocol header being decoded from the front of a buffer.
+#[derive(Debug, PartialEq)]
+pub enum ProxyHeader {
+    /// The header isn't complete yet, and needs at least this many more bytes.
+    Incomplete(usize),
+
+    /// The header is complete, with the source address of the client, if the proxy sent one.
+    Complete(Option<SocketAddr>),
+}
+
+/// Decodes a PROXY protocol header, in either the version 1 or version 2 format.
+///
+/// Never asks for more bytes than the header could still need, so that callers can read exactly
+/// the header, and leave whatever the client sends after it for the transport.
+pub fn decode_proxy_header(buf: &[u8]) -> Result<ProxyHeader, ProtocolError> {
+    match buf.first() {
+        None => Ok(ProxyHeader::Incomplete(1)),
+        Some(b'P') => decode_proxy_v1(buf),
+        Some(b'\r') => decode_proxy_v2(buf),
+        Some(_) => Err(ProtocolError::InvalidProtocol),
+    }
+}
+
+fn decode_proxy_v1(buf: &[u8]) -> Result<ProxyHeader, ProtocolError> {
+    let prefix_len = buf.len().min(PROXY_V1_PREFIX.len());
+    if buf[..prefix_len] != PROXY_V1_PREFIX[..prefix_len] {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+    if buf.len() < PROXY_V1_PREFIX.len() {
+        return Ok(ProxyHeader::Incomplete(PROXY_V1_PREFIX.len() - buf.len()));
+    }
+
+    // The header runs up to the first CRLF, so we can't know how long it is until we see one, but
+    // we can tell how much of it there has to be left from the fields we've seen so far.
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < PROXY_V1_MAX_LEN => return proxy_v1_remaining(buf).map(ProxyHeader::Incomplete),
+        None => return Err(ProtocolError::InvalidProtocol),
+    };
+
+    let line = str::from_utf8(&buf[PROXY_V1_PREFIX.len()..end]).map_err(|_| ProtocolError::InvalidProtocol)?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+
+    // Whatever follows `UNKNOWN` is meant to be ignored.
+    if parts[0] == "UNKNOWN" {
+        return Ok(ProxyHeader::Complete(None));
+    }
+    if parts.len() != 5 {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
+    let parse_ip = |s: &str| {
+        let ip = s.parse::<IpAddr>().map_err(|_| ProtocolError::InvalidProtocol)?;
+        match (parts[0], ip) {
+            ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(ip),
+            _ => Err(ProtocolError::InvalidProtocol),
+        }
+    };
+    let parse_port = |s: &str| s.parse::<u16>().map_err(|_| ProtocolError::InvalidProtocol);
+
+    // We only care about the source, but the destination still has to be well-formed.
+    let source_ip = parse_ip(parts[1])?;
+    parse_ip(parts[2])?;
+    let source_port = parse_port(parts[3])?;
+    parse_port(parts[4])?;
+
+    Ok(ProxyHeader::Complete(Some(SocketAddr::new(source_ip, source_port))))
+}
+
+/// Works out the fewest bytes that could still be left of an unfinished version 1 header.
+///
+/// Every field has a shortest possible form, like `0.0.0.0` for an IPv4 address, so anything
+/// shorter than the fields we haven't seen yet, and the CRLF, can't run past the end of the header.
+fn proxy_v1_remaining(buf: &[u8]) -> Result<usize, ProtocolError> {
+    if buf.last() == Some(&b'\r') {
+        return Ok(1);
+    }
+
+    let fields = buf[PROXY_V1_PREFIX.len()..].split(|b| *b == b' ').collect::<Vec<_>>();
+    let protocol = fields[0];
+    if fields.len() == 1 {
+        // Still reading the protocol, so go by the shortest one it could turn out to be.
+        return PROXY_V1_PROTOCOLS
+            .iter()
+            .filter(|candidate| candidate.as_bytes().starts_with(protocol))
+            .map(|candidate| candidate.len() - protocol.len() + proxy_v1_fields_len(candidate, 1))
+            .min()
+            .ok_or(ProtocolError::InvalidProtocol);
+    }
+
+    let protocol = str::from_utf8(protocol).map_err(|_| ProtocolError::InvalidProtocol)?;
+    if protocol == "UNKNOWN" {
+        return Ok(2);
+    }
+    if !PROXY_V1_PROTOCOLS.contains(&protocol) || fields.len() > 5 {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
+    let current = fields.len() - 1;
+    let current_len = fields[current].len();
+    let current_min = proxy_v1_field_min_len(protocol, current);
+    Ok(current_min.saturating_sub(current_len) + proxy_v1_fields_len(protocol, fields.len()))
+}
+
+/// Gets the shortest that the fields of a version 1 header can be, starting from the given field,
+/// including the separating spaces and the CRLF at the end.
+fn proxy_v1_fields_len(protocol: &str, from: usize) -> usize {
+    if protocol == "UNKNOWN" {
+        return 2;
+    }
+    (from..5).map(|field| 1 + proxy_v1_field_min_len(protocol, field)).sum::<usize>() + 2
+}
+
+fn proxy_v1_field_min_len(protocol: &str, field: usize) -> usize {
+    match (protocol, field) {
+        ("TCP4", 1) | ("TCP4", 2) => 7,
+        ("TCP6", 1) | ("TCP6", 2) => 2,
+        _ => 1,
+    }
+}
+
+fn decode_proxy_v2(buf: &[u8]) -> Result<ProxyHeader, ProtocolError> {
+    let signature_len = buf.len().min(PROXY_V2_SIGNATURE.len());
+    if buf[..signature_len] != PROXY_V2_SIGNATURE[..signature_len] {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+    if buf.len() < PROXY_V2_HEADER_LEN {
+        return Ok(ProxyHeader::Incomplete(PROXY_V2_HEADER_LEN - buf.len()));
+    }
+
+    let version_command = buf[12];
+    let family = buf[13];
+    let total_len = PROXY_V2_HEADER_LEN + ((usize::from(buf[14]) << 8) | usize::from(buf[15]));
+    if version_command >> 4 != 2 {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+    if buf.len() < total_len {
+        return Ok(ProxyHeader::Incomplete(total_len - buf.len()));
+    }
+
+    match version_command & 0x0f {
+        // Connections made by the proxy itself, such as for health checks, carry no client.
+        0x0 => return Ok(ProxyHeader::Complete(None)),
+        0x1 => {},
+        _ => return Err(ProtocolError::InvalidProtocol),
+    }
+
+    // Any TLVs after the addresses are counted in the length, but we've no use for them.
+    let addrs = &buf[PROXY_V2_HEADER_LEN..total_len];
+    let port = |offset: usize| (u16::from(addrs[offset]) << 8) | u16::from(addrs[offset + 1]);
+    match family >> 4 {
+        0x1 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(ProxyHeader::Complete(Some(SocketAddr::new(IpAddr::V4(ip), port(8)))))
+        },
+        0x2 if addrs.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Ok(ProxyHeader::Complete(Some(SocketAddr::new(IpAddr::V6(ip), port(32)))))
+        },
+        // Unspecified or Unix domain socket addresses, neither of which tell us anything useful.
+        0x0 | 0x3 => Ok(ProxyHeader::Complete(None)),
+        _ => Err(ProtocolError::InvalidProtocol),
+    }
+}
+
+/// Reads a PROXY protocol header off the front of a stream.
+///
+/// Resolves to the stream, with nothing past the header read from it, and the source address of
+/// the client, if the proxy sent one.  This waits as long as it takes for the header to show up,
+/// so callers should put their own deadline on it.
+pub fn read_proxy_header<T>(stream: T) -> ReadProxyHeader<T>
+where
+    T: AsyncRead,
+{
+    ReadProxyHeader {
+        stream: Some(stream),
+        buf: Vec::new(),
+    }
+}
+
+pub struct ReadProxyHeader<T> {
+    stream: Option<T>,
+    buf: Vec<u8>,
+}
+
+impl<T> Future for ReadProxyHeader<T>
+where
+    T: AsyncRead,
+{
+    type Error = ProtocolError;
+    type Item = (T, Option<SocketAddr>);
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let needed = match decode_proxy_header(&self.buf)? {
+                ProxyHeader::Complete(source_addr) => {
+                    let stream = self.stream.take().expect("polled ReadProxyHeader after completion");
+                    return Ok(Async::Ready((stream, source_addr)));
+                },
+                ProxyHeader::Incomplete(needed) => needed,
+            };
+
+            let start = self.buf.len();
+            self.buf.resize(start + needed, 0);
+            let result = self
+                .stream
+                .as_mut()
+                .expect("polled ReadProxyHeader after completion")
+                .poll_read(&mut self.buf[start..]);
+            let n = match result {
+                Ok(Async::Ready(n)) => n,
+                Ok(Async::NotReady) => {
+                    self.buf.truncate(start);
+                    return Ok(Async::NotReady);
+                },
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                return Err(ProtocolError::IoError(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before the PROXY protocol header was complete",
+                )));
+            }
+            self.buf.truncate(start + n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn addr(s: &str) -> Option<SocketAddr> { Some(s.parse().unwrap()) }
+
+    fn v2_header(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut buf = PROXY_V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family);
+        buf.push((addrs.len() >> 8) as u8);
+        buf.push(addrs.len() as u8);
+        buf.extend_from_slice(addrs);
+        buf
+    }
+
+    fn assert_invalid(buf: &[u8]) {
+        match decode_proxy_header(buf) {
+            Err(ProtocolError::InvalidProtocol) => {},
+            x => panic!("expected invalid header, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_decode_v1() {
+        let header = b"PROXY TCP4 192.168.1.10 10.0.0.1 56324 6379\r\n";
+        assert_eq!(
+            decode_proxy_header(header).unwrap(),
+            ProxyHeader::Complete(addr("192.168.1.10:56324"))
+        );
+
+        let header = b"PROXY TCP6 fd00::10 fd00::1 56324 6379\r\n";
+        assert_eq!(
+            decode_proxy_header(header).unwrap(),
+            ProxyHeader::Complete(addr("[fd00::10]:56324"))
+        );
+
+        assert_eq!(decode_proxy_header(b"PROXY UNKNOWN\r\n").unwrap(), ProxyHeader::Complete(None));
+        assert_eq!(
+            decode_proxy_header(b"PROXY UNKNOWN ignored stuff\r\n").unwrap(),
+            ProxyHeader::Complete(None)
+        );
+
+        // We're asked for as much as the rest of the fields have to take up, at the least.
+        assert_eq!(decode_proxy_header(b"").unwrap(), ProxyHeader::Incomplete(1));
+        assert_eq!(decode_proxy_header(b"PRO").unwrap(), ProxyHeader::Incomplete(3));
+        assert_eq!(decode_proxy_header(b"PROXY ").unwrap(), ProxyHeader::Incomplete(9));
+        assert_eq!(decode_proxy_header(b"PROXY T").unwrap(), ProxyHeader::Incomplete(15));
+        assert_eq!(decode_proxy_header(b"PROXY TCP4 1").unwrap(), ProxyHeader::Incomplete(20));
+        assert_eq!(decode_proxy_header(b"PROXY TCP6 fd").unwrap(), ProxyHeader::Incomplete(9));
+        assert_eq!(decode_proxy_header(b"PROXY TCP4 1.2.3.4 1.2.3.4 1 6379").unwrap(), ProxyHeader::Incomplete(2));
+        assert_eq!(decode_proxy_header(b"PROXY TCP4 1.2.3.4\r").unwrap(), ProxyHeader::Incomplete(1));
+        assert_eq!(decode_proxy_header(b"PROXY UNKNOWN foo").unwrap(), ProxyHeader::Incomplete(2));
+
+        assert_invalid(b"*1\r\n$4\r\nPING\r\n");
+        assert_invalid(b"PROXX");
+        assert_invalid(b"PROXY \r\n");
+        assert_invalid(b"PROXY TCP4 192.168.1.10 10.0.0.1 56324\r\n");
+        assert_invalid(b"PROXY TCP4 fd00::10 10.0.0.1 56324 6379\r\n");
+        assert_invalid(b"PROXY TCP4 192.168.1.10 10.0.0.1 99999 6379\r\n");
+        assert_invalid(b"PROXY UDP4 192.168.1.10 10.0.0.1 56324 6379\r\n");
+        assert_invalid(&[b'P', b'R', b'O', b'X', b'Y', b' ', b'A'].repeat(16));
+        assert_invalid(b"PROXY UDP");
+        assert_invalid(b"PROXY TCP4 1.2.3.4 1.2.3.4 1 6379 ");
+    }
+
+    #[test]
+    fn test_decode_v2() {
+        let v4_addrs = [192, 168, 1, 10, 10, 0, 0, 1, 0xdc, 0x04, 0x18, 0xeb];
+        let header = v2_header(0x1, 0x11, &v4_addrs);
+        assert_eq!(
+            decode_proxy_header(&header).unwrap(),
+            ProxyHeader::Complete(addr("192.168.1.10:56324"))
+        );
+
+        let mut v6_addrs = vec![0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10];
+        v6_addrs.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        v6_addrs.extend_from_slice(&[0xdc, 0x04, 0x18, 0xeb]);
+        let header = v2_header(0x1, 0x21, &v6_addrs);
+        assert_eq!(
+            decode_proxy_header(&header).unwrap(),
+            ProxyHeader::Complete(addr("[fd00::10]:56324"))
+        );
+
+        // TLVs after the addresses are skipped over.
+        let mut with_tlvs = v4_addrs.to_vec();
+        with_tlvs.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let header = v2_header(0x1, 0x11, &with_tlvs);
+        assert_eq!(
+            decode_proxy_header(&header).unwrap(),
+            ProxyHeader::Complete(addr("192.168.1.10:56324"))
+        );
+
+        assert_eq!(decode_proxy_header(&v2_header(0x0, 0x00, &[])).unwrap(), ProxyHeader::Complete(None));
+        assert_eq!(decode_proxy_header(&v2_header(0x1, 0x00, &[])).unwrap(), ProxyHeader::Complete(None));
+
+        // We're asked for the fixed header first, and then exactly the addresses.
+        let header = v2_header(0x1, 0x11, &v4_addrs);
+        assert_eq!(decode_proxy_header(&header[..5]).unwrap(), ProxyHeader::Incomplete(11));
+        assert_eq!(decode_proxy_header(&header[..16]).unwrap(), ProxyHeader::Incomplete(12));
+
+        let mut bad_version = header.clone();
+        bad_version[12] = 0x11;
+        assert_invalid(&bad_version);
+        assert_invalid(&v2_header(0x2, 0x11, &v4_addrs));
+        assert_invalid(&v2_header(0x1, 0x11, &v4_addrs[..8]));
+        assert_invalid(&v2_header(0x1, 0x41, &v4_addrs));
+        assert_invalid(b"\r\n\r\n\0\r\nQUIZ\n");
+    }
+
+    /// Counts how many reads it takes to get through a stream.
+    struct CountingReader {
+        inner: Cursor<Vec<u8>>,
+        reads: usize,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    impl AsyncRead for CountingReader {}
+
+    #[test]
+    fn test_read_proxy_header() {
+        let mut v1 = b"PROXY TCP4 192.168.1.10 10.0.0.1 56324 6379\r\n".to_vec();
+        v1.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+        let mut v2 = v2_header(0x1, 0x11, &[192, 168, 1, 10, 10, 0, 0, 1, 0xdc, 0x04, 0x18, 0xeb]);
+        v2.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+
+        // Whatever the client sent after the header is left for the transport to read.
+        for buf in vec![v1, v2] {
+            let (mut stream, source_addr) = read_proxy_header(Cursor::new(buf)).wait().unwrap();
+            assert_eq!(source_addr, addr("192.168.1.10:56324"));
+
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, b"*1\r\n$4\r\nPING\r\n");
+        }
+
+        // The header is read a field or so at a time, rather than a byte at a time.
+        let reader = CountingReader {
+            inner: Cursor::new(b"PROXY TCP4 192.168.1.10 10.0.0.1 56324 6379\r\n".to_vec()),
+            reads: 0,
+        };
+        let (reader, _) = read_proxy_header(reader).wait().unwrap();
+        assert_eq!(reader.reads, 8);
+
+        // Hanging up partway through the header is an error, too.
+        match read_proxy_header(Cursor::new(b"PROXY TCP4 192.168".to_vec())).wait() {
+            Err(ProtocolError::IoError(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            x => panic!("expected EOF, got {:?}", x.map(|(_, addr)| addr)),
+        }
+    }
+}
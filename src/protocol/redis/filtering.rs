@@ -17,7 +17,9 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use phf::phf_set;
+use super::RedisMessage;
+use btoi::btoi;
+use phf::{phf_map, phf_set};
 
 static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "DEL",
@@ -31,8 +33,10 @@ static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "PTTL",
     "RESTORE",
     "SORT",
+    "TOUCH",
     "TTL",
     "TYPE",
+    "UNLINK",
     "APPEND",
     "BITCOUNT",
     "BITPOS",
@@ -68,9 +72,17 @@ static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "HSETNX",
     "HVALS",
     "HSCAN",
+    "HSTRLEN",
+    "HRANDFIELD",
     "LINDEX",
     "LINSERT",
     "LLEN",
+    "LPOS",
+    "BLPOP",
+    "BRPOP",
+    "BRPOPLPUSH",
+    "BLMOVE",
+    "BLMPOP",
     "LPOP",
     "LPUSH",
     "LPUSHX",
@@ -87,8 +99,10 @@ static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "SDIFF",
     "SDIFFSTORE",
     "SINTER",
+    "SINTERCARD",
     "SINTERSTORE",
     "SISMEMBER",
+    "SMISMEMBER",
     "SMEMBERS",
     "SMOVE",
     "SPOP",
@@ -101,6 +115,7 @@ static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "ZCARD",
     "ZCOUNT",
     "ZINCRBY",
+    "ZINTERCARD",
     "ZINTERSTORE",
     "ZLEXCOUNT",
     "ZRANGE",
@@ -115,18 +130,428 @@ static VALID_COMMANDS: phf::Set<&'static str> = phf_set! {
     "ZREVRANGEBYSCORE",
     "ZREVRANK",
     "ZSCORE",
+    "ZMSCORE",
+    "ZRANDMEMBER",
     "ZUNIONSTORE",
     "ZSCAN",
+    "BZPOPMIN",
+    "BZPOPMAX",
+    "BZMPOP",
     "PFADD",
     "PFCOUNT",
     "PFMERGE",
     "EVAL",
     "EVALSHA",
+    "LCS",
+    "OBJECT",
+    "CLUSTER",
+    "ASKING",
     "PING",
     "QUIT",
+    "RESET",
+    "HELLO",
+    "AUTH",
+    "SELECT",
+    "SCAN",
+    "FLUSHALL",
+    "FLUSHDB",
+    "DBSIZE",
+    "KEYS",
+    "SCRIPT",
+    "TIME",
 };
 
-pub fn check_command_validity(cmd: &[u8]) -> bool {
+/// Commands that operate on exactly two keys, a source followed by a destination.
+static TWO_KEY_COMMANDS: phf::Set<&'static str> = phf_set! {
+    "SMOVE",
+    "RPOPLPUSH",
+    "BRPOPLPUSH",
+    "BLMOVE",
+};
+
+/// Commands that operate on multiple keys, and are split up so that each key goes to the backend
+/// that owns it.
+static MULTI_KEY_COMMANDS: phf::Set<&'static str> = phf_set! {
+    "MGET",
+    "MSET",
+    "DEL",
+    "UNLINK",
+    "EXISTS",
+    "TOUCH",
+};
+
+/// Commands where every argument is a key.
+static ALL_KEY_COMMANDS: phf::Set<&'static str> = phf_set! {
+    "SDIFF",
+    "SDIFFSTORE",
+    "SINTER",
+    "SINTERSTORE",
+    "SUNION",
+    "SUNIONSTORE",
+    "PFCOUNT",
+    "PFMERGE",
+};
+
+static READ_COMMANDS: phf::Set<&'static str> = phf_set! {
+    "DUMP",
+    "EXISTS",
+    "PTTL",
+    "TTL",
+    "TYPE",
+    "BITCOUNT",
+    "BITPOS",
+    "GET",
+    "GETBIT",
+    "GETRANGE",
+    "MGET",
+    "STRLEN",
+    "HEXISTS",
+    "HGET",
+    "HGETALL",
+    "HKEYS",
+    "HLEN",
+    "HMGET",
+    "HVALS",
+    "HSCAN",
+    "HSTRLEN",
+    "HRANDFIELD",
+    "LINDEX",
+    "LLEN",
+    "LPOS",
+    "LRANGE",
+    "SCARD",
+    "SDIFF",
+    "SINTER",
+    "SINTERCARD",
+    "SISMEMBER",
+    "SMISMEMBER",
+    "SMEMBERS",
+    "SRANDMEMBER",
+    "SUNION",
+    "SSCAN",
+    "ZCARD",
+    "ZCOUNT",
+    "ZINTERCARD",
+    "ZLEXCOUNT",
+    "ZRANGE",
+    "ZRANGEBYLEX",
+    "ZRANGEBYSCORE",
+    "ZRANK",
+    "ZREVRANGE",
+    "ZREVRANGEBYSCORE",
+    "ZREVRANK",
+    "ZSCORE",
+    "ZMSCORE",
+    "ZRANDMEMBER",
+    "ZSCAN",
+    "PFCOUNT",
+    "LCS",
+    "OBJECT",
+};
+
+static BLOCKING_COMMANDS: phf::Set<&'static str> = phf_set! {
+    "BLPOP",
+    "BRPOP",
+    "BRPOPLPUSH",
+    "BLMOVE",
+    "BLMPOP",
+    "BZPOPMIN",
+    "BZPOPMAX",
+    "BZMPOP",
+};
+
+static KEYLESS_COMMANDS: phf::Set<&'static str> = phf_set! {
+    "PING",
+    "QUIT",
+    "RESET",
+    "HELLO",
+    "AUTH",
+    "SELECT",
+    "SCAN",
+    "ASKING",
+    "CLUSTER",
+    "TIME",
+};
+
+static BROADCAST_COMMANDS: phf::Set<&'static str> = phf_set! {
+    "FLUSHALL",
+    "FLUSHDB",
+    "DBSIZE",
+    "KEYS",
+    "SCRIPT",
+};
+
+/// Commands that we know of, but can't support, along with why.
+///
+/// Anything not in `VALID_COMMANDS` is rejected either way, but for these, we can tell the client
+/// exactly what it ran into instead of just saying that the command isn't valid.
+static UNSUPPORTED_COMMANDS: phf::Map<&'static str, &'static str> = phf_map! {
+    "WAIT" => "it waits on replication of writes made over the same connection, which a proxy can't promise",
+    "WAITAOF" => "it waits on AOF persistence (new in Redis 7.2), which can't be coordinated across backends",
+    "XREAD" => "streams are not supported",
+    "XREADGROUP" => "streams are not supported",
+    "REPLICAOF" => "replication is managed on the backends directly",
+    "SLAVEOF" => "replication is managed on the backends directly",
+    "SYNC" => "replication is managed on the backends directly",
+    "PSYNC" => "replication is managed on the backends directly",
+    "REPLCONF" => "replication is managed on the backends directly",
+    "FAILOVER" => "replication is managed on the backends directly",
+    "READONLY" => "cluster management is not supported",
+    "READWRITE" => "cluster management is not supported",
+    "MIGRATE" => "cluster management is not supported",
+    "MULTI" => "transactions can't span backends",
+    "EXEC" => "transactions can't span backends",
+    "DISCARD" => "transactions can't span backends",
+    "WATCH" => "transactions can't span backends",
+    "UNWATCH" => "transactions can't span backends",
+    "SUBSCRIBE" => "pub/sub is not supported",
+    "PSUBSCRIBE" => "pub/sub is not supported",
+    "SSUBSCRIBE" => "pub/sub is not supported",
+    "UNSUBSCRIBE" => "pub/sub is not supported",
+    "PUNSUBSCRIBE" => "pub/sub is not supported",
+    "SUNSUBSCRIBE" => "pub/sub is not supported",
+    "PUBLISH" => "pub/sub is not supported",
+    "SPUBLISH" => "pub/sub is not supported",
+    "RANDOMKEY" => "it would have to scan the keyspace of every backend",
+    "RENAME" => "keys may not live on the same backend",
+    "RENAMENX" => "keys may not live on the same backend",
+    "COPY" => "keys may not live on the same backend",
+    "MOVE" => "cross-database commands are not supported",
+    "SWAPDB" => "cross-database commands are not supported",
+    "CONFIG" => "server administration is managed on the backends directly",
+    "SHUTDOWN" => "server administration is managed on the backends directly",
+    "SAVE" => "server administration is managed on the backends directly",
+    "BGSAVE" => "server administration is managed on the backends directly",
+    "BGREWRITEAOF" => "server administration is managed on the backends directly",
+    "MONITOR" => "server administration is managed on the backends directly",
+    "CLIENT" => "server administration is managed on the backends directly",
+};
+
+/// How a command relates to the keys it operates on, which determines how it gets routed.
+#[derive(Debug, PartialEq)]
+pub enum KeyArity {
+    /// The command doesn't operate on any key, so it can be served by any backend.
+    None,
+
+    /// The command operates on a single key, and goes to the backend that owns it.
+    Single,
+
+    /// The command operates on multiple keys, and can be split up so that each key goes to the
+    /// backend that owns it, i.e. `MGET`.
+    Multi,
+
+    /// The command operates on multiple keys which must all be owned by the same backend, i.e.
+    /// `LCS`.
+    Colocated(MultiKeyLayout),
+
+    /// The command operates on the entire keyspace, so it has to go to every backend, i.e.
+    /// `FLUSHALL`.
+    Broadcast,
+}
+
+/// How the keys of a multi-key command are laid out.
+///
+/// Multi-key commands can only be served if all of their keys live on the same backend, so we need
+/// to be able to find all of them, not just the first.
+#[derive(Debug, PartialEq)]
+pub enum MultiKeyLayout {
+    /// The given number of keys directly follow the command, i.e. `LCS key1 key2`.
+    Consecutive(usize),
+
+    /// The number of keys directly follows the command, and the keys follow that, i.e. `SINTERCARD
+    /// numkeys key [key ...]`.
+    NumKeys,
+
+    /// Every argument is a key, i.e. `SUNIONSTORE destination key [key ...]`.
+    All,
+
+    /// A destination key directly follows the command, then the number of source keys, and the
+    /// source keys follow that, i.e. `ZUNIONSTORE destination numkeys key [key ...]`.
+    DestinationNumKeys,
+
+    /// A timeout directly follows the command, then the number of keys, and the keys follow that,
+    /// i.e. `BLMPOP timeout numkeys key [key ...]`.
+    TimeoutNumKeys,
+
+    /// A key directly follows the command, and a destination key optionally follows a `STORE`
+    /// option, i.e. `SORT key [... STORE destination]`.
+    Store,
+}
+
+pub fn check_command_validity(cmd: &[u8]) -> bool { command_in_set(&VALID_COMMANDS, cmd) }
+
+/// Gets the reason the given command isn't supported, if it's one we know of.
+pub fn get_unsupported_reason(cmd: &[u8]) -> Option<&'static str> {
+    // We only get here for commands we've already rejected, so there's no need to be clever.
+    let cmd = String::from_utf8_lossy(cmd).to_ascii_uppercase();
+    UNSUPPORTED_COMMANDS.get(cmd.as_str()).cloned()
+}
+
+/// Whether or not the given command only reads data.
+pub fn is_read_command(cmd: &[u8]) -> bool { command_in_set(&READ_COMMANDS, cmd) }
+
+/// Whether or not the given message can block on the backend.
+///
+/// Stream reads only block when they're given the `BLOCK` option.
+pub fn is_blocking_command(msg: &RedisMessage) -> bool {
+    let cmd = match msg.get_command() {
+        Some(cmd) => cmd,
+        None => return false,
+    };
+
+    if command_in_set(&BLOCKING_COMMANDS, cmd) {
+        return true;
+    }
+
+    if !cmd.eq_ignore_ascii_case(b"XREAD") && !cmd.eq_ignore_ascii_case(b"XREADGROUP") {
+        return false;
+    }
+
+    match msg {
+        RedisMessage::Bulk(_, args) => {
+            args.iter().skip(1).any(|arg| {
+                match get_data(arg) {
+                    Some(buf) => buf.eq_ignore_ascii_case(b"BLOCK"),
+                    None => false,
+                }
+            })
+        },
+        _ => false,
+    }
+}
+
+/// Gets the key arity of the given command.
+pub fn get_key_arity(cmd: &[u8]) -> KeyArity {
+    if command_in_set(&MULTI_KEY_COMMANDS, cmd) {
+        KeyArity::Multi
+    } else if cmd.eq_ignore_ascii_case(b"LCS") {
+        KeyArity::Colocated(MultiKeyLayout::Consecutive(2))
+    } else if command_in_set(&TWO_KEY_COMMANDS, cmd) {
+        KeyArity::Colocated(MultiKeyLayout::Consecutive(2))
+    } else if cmd.eq_ignore_ascii_case(b"SINTERCARD") || cmd.eq_ignore_ascii_case(b"ZINTERCARD") {
+        KeyArity::Colocated(MultiKeyLayout::NumKeys)
+    } else if command_in_set(&ALL_KEY_COMMANDS, cmd) {
+        KeyArity::Colocated(MultiKeyLayout::All)
+    } else if cmd.eq_ignore_ascii_case(b"ZINTERSTORE") || cmd.eq_ignore_ascii_case(b"ZUNIONSTORE") {
+        KeyArity::Colocated(MultiKeyLayout::DestinationNumKeys)
+    } else if cmd.eq_ignore_ascii_case(b"BLMPOP") || cmd.eq_ignore_ascii_case(b"BZMPOP") {
+        KeyArity::Colocated(MultiKeyLayout::TimeoutNumKeys)
+    } else if cmd.eq_ignore_ascii_case(b"SORT") {
+        KeyArity::Colocated(MultiKeyLayout::Store)
+    } else if command_in_set(&KEYLESS_COMMANDS, cmd) {
+        KeyArity::None
+    } else if command_in_set(&BROADCAST_COMMANDS, cmd) {
+        KeyArity::Broadcast
+    } else {
+        KeyArity::Single
+    }
+}
+
+/// Gets the key layout of the given command if it operates on multiple keys.
+pub fn get_multi_key_layout(cmd: &[u8]) -> Option<MultiKeyLayout> {
+    match get_key_arity(cmd) {
+        KeyArity::Colocated(layout) => Some(layout),
+        _ => None,
+    }
+}
+
+/// Gets the position of the argument used as the key for routing the given message.
+///
+/// Commands without a key are routed by the command itself, at position 0.
+pub fn get_key_position(msg: &RedisMessage) -> usize {
+    let arg_count = match msg {
+        RedisMessage::Bulk(_, args) => args.len(),
+        _ => 0,
+    };
+
+    let arity = match msg.get_command() {
+        Some(cmd) if arg_count >= 2 => get_key_arity(cmd),
+        _ => return 0,
+    };
+
+    // Some commands, like OBJECT and DEBUG OBJECT, carry their key after a subcommand, and some
+    // carry it after the number of keys, or a timeout and the number of keys, rather than right
+    // after the command itself.
+    match arity {
+        KeyArity::None | KeyArity::Broadcast => 0,
+        KeyArity::Colocated(MultiKeyLayout::NumKeys) if arg_count > 2 => 2,
+        KeyArity::Colocated(MultiKeyLayout::TimeoutNumKeys) if arg_count > 3 => 3,
+        _ if has_subcommand_key(msg) => 2,
+        _ => 1,
+    }
+}
+
+/// Gets all of the keys for the given message if it's a multi-key command.
+pub fn get_multi_keys(msg: &RedisMessage) -> Option<Vec<&[u8]>> {
+    let args = match msg {
+        RedisMessage::Bulk(_, args) => args,
+        _ => return None,
+    };
+
+    let get_count = |idx: usize| args.get(idx).and_then(get_data).and_then(|buf| btoi::<usize>(buf).ok());
+    let (start, count) = match msg.get_command().and_then(get_multi_key_layout)? {
+        MultiKeyLayout::Consecutive(count) => (1, count),
+        MultiKeyLayout::NumKeys => (2, get_count(1)?),
+        MultiKeyLayout::TimeoutNumKeys => (3, get_count(2)?),
+        MultiKeyLayout::All => (1, args.len()),
+        MultiKeyLayout::DestinationNumKeys => {
+            let count = get_count(2)?;
+            let mut keys = args.iter().skip(1).take(1).filter_map(get_data).collect::<Vec<_>>();
+            keys.extend(args.iter().skip(3).take(count).filter_map(get_data));
+            return Some(keys);
+        },
+        MultiKeyLayout::Store => {
+            let mut keys = args.iter().skip(1).take(1).filter_map(get_data).collect::<Vec<_>>();
+            keys.extend(get_store_destination(msg));
+            return Some(keys);
+        },
+    };
+
+    Some(args.iter().skip(start).take(count).filter_map(get_data).collect())
+}
+
+/// Gets the destination key given to the `STORE` option of the given message, if any.
+///
+/// The options are walked one by one, skipping over the arguments of `BY`, `LIMIT` and `GET`, so
+/// that a pattern or offset that happens to read `STORE` isn't mistaken for the option itself.
+fn get_store_destination(msg: &RedisMessage) -> Option<&[u8]> {
+    let args = match msg {
+        RedisMessage::Bulk(_, args) => args,
+        _ => return None,
+    };
+
+    let mut options = args.iter().skip(2).filter_map(get_data);
+    while let Some(option) = options.next() {
+        if option.eq_ignore_ascii_case(b"STORE") {
+            return options.next();
+        } else if option.eq_ignore_ascii_case(b"BY") || option.eq_ignore_ascii_case(b"GET") {
+            options.next();
+        } else if option.eq_ignore_ascii_case(b"LIMIT") {
+            options.nth(1);
+        }
+    }
+    None
+}
+
+/// Whether or not the given message only reads data.
+///
+/// `SORT` only reads unless it's given a destination to store its result in.
+pub fn is_read_message(msg: &RedisMessage) -> bool {
+    match msg.get_command() {
+        Some(cmd) if cmd.eq_ignore_ascii_case(b"SORT") => get_store_destination(msg).is_none(),
+        Some(cmd) => is_read_command(cmd),
+        None => false,
+    }
+}
+
+fn get_data(msg: &RedisMessage) -> Option<&[u8]> {
+    match msg {
+        RedisMessage::Data(buf, offset) => Some(&buf[*offset..buf.len() - 2]),
+        _ => None,
+    }
+}
+
+fn command_in_set(set: &phf::Set<&'static str>, cmd: &[u8]) -> bool {
     // This is goofy but redis only supports commands with ASCII characters, so we munge
     // these bytes to make sure that, if they were lowercase ASCII, they now become
     // uppercase ASCII... and we do it by hand instead of using str::to_uppercase because
@@ -145,7 +570,57 @@ pub fn check_command_validity(cmd: &[u8]) -> bool {
     }
 
     let as_str = unsafe { std::str::from_utf8_unchecked(m) };
-    VALID_COMMANDS.contains(as_str)
+    set.contains(as_str)
+}
+
+pub fn is_debug_command(cmd: &[u8]) -> bool { cmd.eq_ignore_ascii_case(b"DEBUG") }
+
+/// Whether or not the given message carries its key after a subcommand, such as `OBJECT IDLETIME
+/// <key>`, rather than directly after the command.
+pub fn has_subcommand_key(msg: &RedisMessage) -> bool {
+    match msg {
+        RedisMessage::Bulk(_, args) if args.len() == 3 => {
+            match msg.get_command() {
+                Some(cmd) => cmd.eq_ignore_ascii_case(b"OBJECT") || is_debug_object_command(msg),
+                None => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Whether or not the given message is a write that can be repeated without any further effect.
+///
+/// This is deliberately narrow: only a plain `SET <key> <value>`, without any options, and a
+/// single-key `DEL <key>`, qualify.
+pub fn is_idempotent_write_command(msg: &RedisMessage) -> bool {
+    match msg {
+        RedisMessage::Bulk(_, args) => {
+            match (msg.get_command(), args.len()) {
+                (Some(cmd), 3) => cmd.eq_ignore_ascii_case(b"SET"),
+                (Some(cmd), 2) => cmd.eq_ignore_ascii_case(b"DEL"),
+                _ => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Whether or not the given message is a well-formed `DEBUG OBJECT <key>` command.
+pub fn is_debug_object_command(msg: &RedisMessage) -> bool {
+    match msg {
+        RedisMessage::Bulk(_, args) if args.len() == 3 => {
+            match (&args[0], &args[1]) {
+                (RedisMessage::Data(cmd, cmd_off), RedisMessage::Data(sub, sub_off)) => {
+                    let cmd = &cmd[*cmd_off..cmd.len() - 2];
+                    let sub = &sub[*sub_off..sub.len() - 2];
+                    is_debug_command(cmd) && sub.eq_ignore_ascii_case(b"OBJECT")
+                },
+                _ => false,
+            }
+        },
+        _ => false,
+    }
 }
 
 #[cfg(test)]
@@ -166,6 +641,191 @@ mod tests {
         assert!(!check_command_validity(invalid_cmd_2.as_bytes()));
     }
 
+    #[test]
+    fn ensure_read_vs_write() {
+        assert!(is_read_command(b"GET"));
+        assert!(is_read_command(b"hgetall"));
+        assert!(!is_read_command(b"SET"));
+        assert!(!is_read_command(b"del"));
+    }
+
+    #[test]
+    fn ensure_newer_commands() {
+        assert!(check_command_validity(b"LPOS"));
+        assert!(is_read_command(b"lpos"));
+        assert!(is_read_command(b"LCS"));
+        assert!(is_read_command(b"SINTERCARD"));
+
+        assert_eq!(get_multi_key_layout(b"LPOS"), None);
+        assert_eq!(get_multi_key_layout(b"lcs"), Some(MultiKeyLayout::Consecutive(2)));
+        assert_eq!(get_multi_key_layout(b"SINTERCARD"), Some(MultiKeyLayout::NumKeys));
+    }
+
+    #[test]
+    fn ensure_key_positions() {
+        assert_eq!(get_key_position(&RedisMessage::from_inline("PING")), 0);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("LPOS mylist a")), 1);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("LCS key1 key2")), 1);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("SINTERCARD 2 key1 key2")), 2);
+
+        // Blocking pops that take several keys lead with their timeout, which is never the key.
+        assert_eq!(get_key_position(&RedisMessage::from_inline("BLMPOP 0 2 key1 key2 LEFT")), 3);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("BZMPOP 1.5 1 key1 MIN")), 3);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("OBJECT IDLETIME key1")), 2);
+
+        // Options after the key shouldn't change where we find it.
+        assert_eq!(get_key_position(&RedisMessage::from_inline("SET key1 val KEEPTTL")), 1);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("SET key1 val PXAT 1700000000000")), 1);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("EXPIRE key1 100 GT")), 1);
+    }
+
+    #[test]
+    fn ensure_multi_keys() {
+        let lpos = RedisMessage::from_inline("LPOS mylist a");
+        assert_eq!(get_multi_keys(&lpos), None);
+
+        let lcs = RedisMessage::from_inline("LCS key1 key2");
+        assert_eq!(get_multi_keys(&lcs), Some(vec![&b"key1"[..], &b"key2"[..]]));
+
+        let sintercard = RedisMessage::from_inline("SINTERCARD 2 key1 key2 LIMIT 5");
+        assert_eq!(get_multi_keys(&sintercard), Some(vec![&b"key1"[..], &b"key2"[..]]));
+
+        let bad_sintercard = RedisMessage::from_inline("SINTERCARD two key1 key2");
+        assert_eq!(get_multi_keys(&bad_sintercard), None);
+
+        let set = RedisMessage::from_inline("SET key1 val EXAT 1700000000");
+        assert_eq!(get_multi_keys(&set), None);
+
+        let expire = RedisMessage::from_inline("EXPIRE key1 100 NX");
+        assert_eq!(get_multi_keys(&expire), None);
+
+        let smove = RedisMessage::from_inline("SMOVE src dst member");
+        assert_eq!(get_multi_keys(&smove), Some(vec![&b"src"[..], &b"dst"[..]]));
+
+        let sunionstore = RedisMessage::from_inline("SUNIONSTORE dst key1 key2");
+        assert_eq!(get_multi_keys(&sunionstore), Some(vec![&b"dst"[..], &b"key1"[..], &b"key2"[..]]));
+
+        let zunionstore = RedisMessage::from_inline("ZUNIONSTORE dst 2 key1 key2 WEIGHTS 1 2");
+        assert_eq!(get_multi_keys(&zunionstore), Some(vec![&b"dst"[..], &b"key1"[..], &b"key2"[..]]));
+
+        let bad_zunionstore = RedisMessage::from_inline("ZUNIONSTORE dst two key1 key2");
+        assert_eq!(get_multi_keys(&bad_zunionstore), None);
+
+        let blmpop = RedisMessage::from_inline("BLMPOP 0 2 key1 key2 LEFT COUNT 10");
+        assert_eq!(get_multi_keys(&blmpop), Some(vec![&b"key1"[..], &b"key2"[..]]));
+
+        let bzmpop = RedisMessage::from_inline("BZMPOP 5 1 key1 MAX");
+        assert_eq!(get_multi_keys(&bzmpop), Some(vec![&b"key1"[..]]));
+
+        // SORT only has a second key if it's storing its result.
+        let sort = RedisMessage::from_inline("SORT key1 LIMIT 0 10 ALPHA");
+        assert_eq!(get_multi_keys(&sort), Some(vec![&b"key1"[..]]));
+        assert!(is_read_message(&sort));
+
+        let sort_store = RedisMessage::from_inline("SORT key1 DESC store dst");
+        assert_eq!(get_multi_keys(&sort_store), Some(vec![&b"key1"[..], &b"dst"[..]]));
+        assert!(!is_read_message(&sort_store));
+        assert_eq!(get_key_position(&sort_store), 1);
+
+        // Patterns that read like the STORE option aren't one.
+        let sort_patterns = RedisMessage::from_inline("SORT key1 BY store GET store GET # ALPHA");
+        assert_eq!(get_multi_keys(&sort_patterns), Some(vec![&b"key1"[..]]));
+        assert!(is_read_message(&sort_patterns));
+
+        let sort_by_store = RedisMessage::from_inline("SORT key1 BY store LIMIT 0 5 STORE dst");
+        assert_eq!(get_multi_keys(&sort_by_store), Some(vec![&b"key1"[..], &b"dst"[..]]));
+        assert!(!is_read_message(&sort_by_store));
+    }
+
+    #[test]
+    fn ensure_subcommand_keys() {
+        assert!(has_subcommand_key(&RedisMessage::from_inline("OBJECT IDLETIME foo")));
+        assert!(has_subcommand_key(&RedisMessage::from_inline("object freq foo")));
+        assert!(has_subcommand_key(&RedisMessage::from_inline("DEBUG OBJECT foo")));
+        assert!(!has_subcommand_key(&RedisMessage::from_inline("OBJECT HELP")));
+        assert!(!has_subcommand_key(&RedisMessage::from_inline("SET foo bar")));
+        assert!(is_read_command(b"OBJECT"));
+    }
+
+    #[test]
+    fn ensure_debug_detection() {
+        assert!(is_debug_command(b"debug"));
+        assert!(is_debug_command(b"DEBUG"));
+        assert!(!check_command_validity(b"DEBUG"));
+
+        assert!(is_debug_object_command(&RedisMessage::from_inline("DEBUG OBJECT foo")));
+        assert!(is_debug_object_command(&RedisMessage::from_inline("debug object foo")));
+        assert!(!is_debug_object_command(&RedisMessage::from_inline("DEBUG SLEEP 10")));
+        assert!(!is_debug_object_command(&RedisMessage::from_inline("DEBUG OBJECT")));
+        assert!(!is_debug_object_command(&RedisMessage::from_inline("GET foo bar")));
+    }
+
+    #[test]
+    fn ensure_blocking_detection() {
+        assert!(is_blocking_command(&RedisMessage::from_inline("BLPOP queue 0")));
+        assert!(is_blocking_command(&RedisMessage::from_inline("brpop queue 0")));
+        assert!(is_blocking_command(&RedisMessage::from_inline("BLMPOP 0 1 queue LEFT")));
+        assert!(is_blocking_command(&RedisMessage::from_inline("XREAD BLOCK 0 STREAMS s $")));
+        assert!(!is_blocking_command(&RedisMessage::from_inline("XREAD STREAMS s 0")));
+        assert!(!is_blocking_command(&RedisMessage::from_inline("LPOP queue")));
+        assert!(!is_blocking_command(&RedisMessage::Ping));
+    }
+
+    #[test]
+    fn ensure_key_arity() {
+        assert_eq!(get_key_arity(b"GET"), KeyArity::Single);
+        assert_eq!(get_key_arity(b"mget"), KeyArity::Multi);
+        assert_eq!(get_key_arity(b"DEL"), KeyArity::Multi);
+        assert_eq!(get_key_arity(b"unlink"), KeyArity::Multi);
+        assert_eq!(get_key_arity(b"EXISTS"), KeyArity::Multi);
+        assert_eq!(get_key_arity(b"touch"), KeyArity::Multi);
+        assert_eq!(get_key_arity(b"lcs"), KeyArity::Colocated(MultiKeyLayout::Consecutive(2)));
+        assert_eq!(get_key_arity(b"ZINTERCARD"), KeyArity::Colocated(MultiKeyLayout::NumKeys));
+        assert_eq!(get_key_arity(b"smove"), KeyArity::Colocated(MultiKeyLayout::Consecutive(2)));
+        assert_eq!(get_key_arity(b"SUNIONSTORE"), KeyArity::Colocated(MultiKeyLayout::All));
+        assert_eq!(get_key_arity(b"pfcount"), KeyArity::Colocated(MultiKeyLayout::All));
+        assert_eq!(get_key_arity(b"ZUNIONSTORE"), KeyArity::Colocated(MultiKeyLayout::DestinationNumKeys));
+        assert_eq!(get_key_arity(b"sort"), KeyArity::Colocated(MultiKeyLayout::Store));
+        assert_eq!(get_key_arity(b"ping"), KeyArity::None);
+        assert_eq!(get_key_arity(b"blmpop"), KeyArity::Colocated(MultiKeyLayout::TimeoutNumKeys));
+        assert_eq!(get_key_arity(b"BZMPOP"), KeyArity::Colocated(MultiKeyLayout::TimeoutNumKeys));
+        assert_eq!(get_key_arity(b"flushall"), KeyArity::Broadcast);
+        assert_eq!(get_key_arity(b"DBSIZE"), KeyArity::Broadcast);
+        assert_eq!(get_key_arity(b"SCRIPT"), KeyArity::Broadcast);
+        assert!(check_command_validity(b"FLUSHDB"));
+
+        // Keyless commands never have their arguments mistaken for a key.
+        assert_eq!(get_key_position(&RedisMessage::from_inline("PING hello")), 0);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("SCRIPT LOAD return")), 0);
+        assert_eq!(get_key_position(&RedisMessage::from_inline("FLUSHALL ASYNC")), 0);
+    }
+
+    #[test]
+    fn ensure_unsupported_reasons() {
+        assert!(get_unsupported_reason(b"waitaof").unwrap().contains("Redis 7.2"));
+        assert_eq!(get_unsupported_reason(b"MULTI"), Some("transactions can't span backends"));
+        assert!(get_unsupported_reason(b"wait").unwrap().contains("same connection"));
+        assert_eq!(get_unsupported_reason(b"move"), Some("cross-database commands are not supported"));
+        assert!(!check_command_validity(b"WAIT"));
+        assert_eq!(get_unsupported_reason(b"NOTACOMMAND"), None);
+
+        // Nothing we support should ever be reported as unsupported.
+        for cmd in VALID_COMMANDS.iter() {
+            assert_eq!(get_unsupported_reason(cmd.as_bytes()), None);
+        }
+    }
+
+    #[test]
+    fn ensure_idempotent_writes() {
+        assert!(is_idempotent_write_command(&RedisMessage::from_inline("SET foo bar")));
+        assert!(is_idempotent_write_command(&RedisMessage::from_inline("del foo")));
+        assert!(!is_idempotent_write_command(&RedisMessage::from_inline("SET foo bar NX")));
+        assert!(!is_idempotent_write_command(&RedisMessage::from_inline("SET foo bar EX 10")));
+        assert!(!is_idempotent_write_command(&RedisMessage::from_inline("DEL foo bar")));
+        assert!(!is_idempotent_write_command(&RedisMessage::from_inline("INCR foo")));
+        assert!(!is_idempotent_write_command(&RedisMessage::from_inline("GET foo")));
+    }
+
     #[bench]
     fn bench_valid_lookup(b: &mut Bencher) {
         let valid_cmd = "PFCOUNT".as_bytes();
@@ -24,29 +24,57 @@ use crate::{
 };
 use btoi::btoi;
 use bytes::{BufMut, BytesMut};
-use futures::prelude::*;
+use futures::{future::Either, prelude::*};
 use itoa;
-use tokio::io::{write_all, AsyncRead, AsyncWrite, Error, ErrorKind};
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{write_all, AsyncRead, AsyncWrite, Error, ErrorKind},
+    timer::{Delay, Timeout},
+};
 
 mod filtering;
-use self::filtering::check_command_validity;
+use self::filtering::{
+    check_command_validity, get_key_position, get_multi_keys, get_unsupported_reason, is_blocking_command,
+    is_debug_command, is_debug_object_command, is_idempotent_write_command, is_read_message,
+};
+pub use self::filtering::{get_key_arity, KeyArity};
 
 const MAX_OUTSTANDING_WBUF: usize = 8192;
 
+// Aggregates are parsed recursively, so we cap how deeply they can nest to keep a malicious client
+// from overflowing the stack.  Nothing legitimate comes anywhere close to this.
+const MAX_NESTING_DEPTH: usize = 64;
+
 const REDIS_COMMAND_ERROR: u8 = b'-';
 const REDIS_COMMAND_STATUS: u8 = b'+';
 const REDIS_COMMAND_INTEGER: u8 = b':';
 const REDIS_COMMAND_DATA: u8 = b'$';
 const REDIS_COMMAND_BULK: u8 = b'*';
+const REDIS_COMMAND_MAP: u8 = b'%';
+const REDIS_COMMAND_SET: u8 = b'~';
+const REDIS_COMMAND_DOUBLE: u8 = b',';
+const REDIS_COMMAND_BOOLEAN: u8 = b'#';
+const REDIS_COMMAND_BIG_NUMBER: u8 = b'(';
+const REDIS_COMMAND_VERBATIM: u8 = b'=';
+const REDIS_COMMAND_PUSH: u8 = b'>';
+
+/// The protocol version that every client starts out speaking.
+///
+/// RESP3 frames can be parsed, but replies from backends are never converted to RESP3, so this is
+/// also the only version clients can negotiate with `HELLO`.
+pub const REDIS_RESP2: usize = 2;
 
 const REDIS_NULL_BUF: [u8; 5] = [b'$', b'-', b'1', b'\r', b'\n'];
 const REDIS_OK_BUF: [u8; 5] = [b'+', b'O', b'K', b'\r', b'\n'];
 const REDIS_PING_RESP_BUF: [u8; 7] = [b'+', b'P', b'O', b'N', b'G', b'\r', b'\n'];
+const REDIS_INLINE_COMMANDS: [&[u8]; 4] = [b"ping\r\n", b"PING\r\n", b"quit\r\n", b"QUIT\r\n"];
 const REDIS_STATUS_BUF: [u8; 1] = [REDIS_COMMAND_STATUS];
 const REDIS_ERR_BUF: [u8; 5] = [b'-', b'E', b'R', b'R', b' '];
 const REDIS_INT_BUF: [u8; 1] = [REDIS_COMMAND_INTEGER];
 const REDIS_CRLF: [u8; 2] = [b'\r', b'\n'];
 const REDIS_BACKEND_CLOSED: &str = "backend closed prematurely";
+const REDIS_BACKEND_TIMED_OUT: &str = "backend timed out";
+const REDIS_BACKEND_READ_FAILED: &str = "failed to read from backend";
 
 /// A Redis-specific transport.
 pub struct RedisTransport<T>
@@ -57,6 +85,8 @@ where
     rbuf: BytesMut,
     wbuf: BytesMut,
     closed: bool,
+    allow_debug: bool,
+    max_args: Option<usize>,
 }
 
 pub struct RedisMultipleMessages<T>
@@ -67,6 +97,8 @@ where
     rbuf: BytesMut,
     bytes_read: usize,
     msgs: EnqueuedRequests<RedisMessage>,
+    read_timeout: Option<Duration>,
+    read_deadline: Option<Delay>,
 }
 
 /// A RESP-based client/server message for Redis.
@@ -81,6 +113,10 @@ where
 ///
 /// This means that callers themselves must chop off any remaining data, such as the trailing CRLF
 /// for data values.
+///
+/// The RESP3 types follow the same layout.  Maps hold their keys and values flattened, in order,
+/// so every key is followed by its value, and the offset of a verbatim string points at its
+/// three-letter format, such as "txt", rather than past it.
 #[derive(Clone, Debug, PartialEq)]
 pub enum RedisMessage {
     Null,
@@ -92,42 +128,91 @@ pub enum RedisMessage {
     Integer(BytesMut, i64),
     Data(BytesMut, usize),
     Bulk(BytesMut, Vec<RedisMessage>),
+    Double(BytesMut, usize),
+    Boolean(BytesMut, bool),
+    BigNumber(BytesMut, usize),
+    Verbatim(BytesMut, usize),
+    Map(BytesMut, Vec<RedisMessage>),
+    Set(BytesMut, Vec<RedisMessage>),
+    Push(BytesMut, Vec<RedisMessage>),
 }
 
 impl RedisMessage {
     pub fn from_inline(cmd: &str) -> RedisMessage {
         let args = cmd
             .split_whitespace()
-            .map(|part| {
-                let buf = part.as_bytes();
-                let mut new_buf = BytesMut::new();
-                new_buf.extend_from_slice(&[REDIS_COMMAND_DATA]);
-
-                let mut cnt_buf = [b'\0'; 20];
-                let n = itoa::write(&mut cnt_buf[..], buf.len()).unwrap();
-                new_buf.extend_from_slice(&cnt_buf[..n]);
-                new_buf.extend_from_slice(b"\r\n");
-                new_buf.extend_from_slice(buf);
-                new_buf.extend_from_slice(b"\r\n");
-
-                RedisMessage::Data(new_buf, 1 + n + 2)
-            })
+            .map(|part| RedisMessage::from_data(part.as_bytes()))
             .collect::<Vec<_>>();
 
-        let mut buf = BytesMut::new();
-        buf.extend_from_slice(&[REDIS_COMMAND_BULK]);
+        RedisMessage::from_array(args)
+    }
 
-        let mut cnt_buf = [b'\0'; 20];
-        let n = itoa::write(&mut cnt_buf[..], args.len()).unwrap();
-        buf.extend_from_slice(&cnt_buf[..n]);
-        buf.extend_from_slice(b"\r\n");
+    pub fn from_data(data: &[u8]) -> RedisMessage {
+        let mut buf = new_header_buf(REDIS_COMMAND_DATA, data.len());
+        let offset = buf.len();
+        buf.extend_from_slice(data);
+        buf.extend_from_slice(&REDIS_CRLF[..]);
 
-        for arg in &args {
-            let arg_buf = arg.get_buf();
-            buf.unsplit(arg_buf);
-        }
+        RedisMessage::Data(buf, offset)
+    }
+
+    pub fn from_array(items: Vec<RedisMessage>) -> RedisMessage {
+        let buf = new_aggregate_buf(REDIS_COMMAND_BULK, items.len(), &items);
+        RedisMessage::Bulk(buf, items)
+    }
+
+    /// Creates a map from the given key/value pairs.
+    ///
+    /// Maps only exist in RESP3, so this should only be sent to clients that have negotiated it.
+    pub fn from_map(entries: Vec<(RedisMessage, RedisMessage)>) -> RedisMessage {
+        let count = entries.len();
+        let items = entries
+            .into_iter()
+            .flat_map(|(key, value)| vec![key, value])
+            .collect::<Vec<_>>();
+        let buf = new_aggregate_buf(REDIS_COMMAND_MAP, count, &items);
+
+        RedisMessage::Map(buf, items)
+    }
+
+    pub fn from_set(items: Vec<RedisMessage>) -> RedisMessage {
+        let buf = new_aggregate_buf(REDIS_COMMAND_SET, items.len(), &items);
+        RedisMessage::Set(buf, items)
+    }
 
-        RedisMessage::Bulk(buf, args)
+    pub fn from_push(items: Vec<RedisMessage>) -> RedisMessage {
+        let buf = new_aggregate_buf(REDIS_COMMAND_PUSH, items.len(), &items);
+        RedisMessage::Push(buf, items)
+    }
+
+    pub fn from_double(value: f64) -> RedisMessage {
+        // RESP3 spells out the special values in lowercase, where Rust would capitalize NaN.
+        let value_str = if value.is_nan() { "nan".to_owned() } else { value.to_string() };
+        RedisMessage::Double(new_line_buf(REDIS_COMMAND_DOUBLE, value_str.as_bytes()), 1)
+    }
+
+    pub fn from_boolean(value: bool) -> RedisMessage {
+        let value_buf: &[u8] = if value { b"t" } else { b"f" };
+        RedisMessage::Boolean(new_line_buf(REDIS_COMMAND_BOOLEAN, value_buf), value)
+    }
+
+    /// Creates a big number from its decimal representation, which is passed along as-is.
+    pub fn from_big_number(value: &str) -> RedisMessage {
+        RedisMessage::BigNumber(new_line_buf(REDIS_COMMAND_BIG_NUMBER, value.as_bytes()), 1)
+    }
+
+    /// Creates a verbatim string with the given three-letter format, such as "txt" or "mkd".
+    pub fn from_verbatim(format: &str, data: &[u8]) -> RedisMessage {
+        assert_eq!(format.len(), 3, "verbatim string formats must be three bytes long");
+
+        let mut buf = new_header_buf(REDIS_COMMAND_VERBATIM, format.len() + 1 + data.len());
+        let offset = buf.len();
+        buf.extend_from_slice(format.as_bytes());
+        buf.extend_from_slice(b":");
+        buf.extend_from_slice(data);
+        buf.extend_from_slice(&REDIS_CRLF[..]);
+
+        RedisMessage::Verbatim(buf, offset)
     }
 
     pub fn from_status(status_str: &str) -> RedisMessage {
@@ -163,6 +248,18 @@ impl RedisMessage {
         RedisMessage::Error(rd, 5)
     }
 
+    /// Creates an error with a specific error code, such as `NOPROTO`, rather than the generic `ERR`.
+    pub fn from_error_code(code: &str, error_str: &str) -> RedisMessage {
+        let mut rd = BytesMut::with_capacity(1 + code.len() + 1 + error_str.len() + 2);
+        rd.put_u8(REDIS_COMMAND_ERROR);
+        rd.put_slice(code.as_bytes());
+        rd.put_u8(b' ');
+        rd.put_slice(error_str.as_bytes());
+        rd.put_slice(&REDIS_CRLF[..]);
+
+        RedisMessage::Error(rd, 1 + code.len() + 1)
+    }
+
     pub fn from_integer(value: i64) -> RedisMessage {
         let mut value_buf = [b'\0'; 20];
         let n = itoa::write(&mut value_buf[..], value).unwrap();
@@ -174,6 +271,15 @@ impl RedisMessage {
         RedisMessage::Integer(buf, value)
     }
 
+    /// Whether or not this message is the reply to a `PING`.
+    pub fn is_pong(&self) -> bool {
+        match self {
+            RedisMessage::Ping => true,
+            RedisMessage::Status(buf, _) => buf[..] == REDIS_PING_RESP_BUF[..],
+            _ => false,
+        }
+    }
+
     pub fn get_command(&self) -> Option<&[u8]> {
         match self {
             RedisMessage::Bulk(_, ref args) => {
@@ -200,6 +306,13 @@ impl RedisMessage {
             RedisMessage::Integer(buf, _) => buf,
             RedisMessage::Data(buf, _) => buf,
             RedisMessage::Bulk(buf, _) => buf,
+            RedisMessage::Double(buf, _) => buf,
+            RedisMessage::Boolean(buf, _) => buf,
+            RedisMessage::BigNumber(buf, _) => buf,
+            RedisMessage::Verbatim(buf, _) => buf,
+            RedisMessage::Map(buf, _) => buf,
+            RedisMessage::Set(buf, _) => buf,
+            RedisMessage::Push(buf, _) => buf,
         }
     }
 
@@ -214,6 +327,13 @@ impl RedisMessage {
             RedisMessage::Integer(ref buf, _) => buf.clone(),
             RedisMessage::Data(ref buf, _) => buf.clone(),
             RedisMessage::Bulk(ref buf, _) => buf.clone(),
+            RedisMessage::Double(ref buf, _) => buf.clone(),
+            RedisMessage::Boolean(ref buf, _) => buf.clone(),
+            RedisMessage::BigNumber(ref buf, _) => buf.clone(),
+            RedisMessage::Verbatim(ref buf, _) => buf.clone(),
+            RedisMessage::Map(ref buf, _) => buf.clone(),
+            RedisMessage::Set(ref buf, _) => buf.clone(),
+            RedisMessage::Push(ref buf, _) => buf.clone(),
         }
     }
 }
@@ -230,6 +350,13 @@ impl Sizable for RedisMessage {
             RedisMessage::Integer(ref buf, _) => buf.len(),
             RedisMessage::Data(ref buf, _) => buf.len(),
             RedisMessage::Bulk(ref buf, _) => buf.len(),
+            RedisMessage::Double(ref buf, _) => buf.len(),
+            RedisMessage::Boolean(ref buf, _) => buf.len(),
+            RedisMessage::BigNumber(ref buf, _) => buf.len(),
+            RedisMessage::Verbatim(ref buf, _) => buf.len(),
+            RedisMessage::Map(ref buf, _) => buf.len(),
+            RedisMessage::Set(ref buf, _) => buf.len(),
+            RedisMessage::Push(ref buf, _) => buf.len(),
         }
     }
 }
@@ -238,14 +365,16 @@ impl Message for RedisMessage {
     fn key(&self) -> &[u8] {
         match self {
             RedisMessage::Bulk(_, ref args) => {
-                let arg_pos = if args.len() < 2 { 0 } else { 1 };
+                let arg_pos = get_key_position(self);
 
+                // Malformed commands don't really have a key, but they still have to be routed
+                // somewhere so the backend can reject them, so any backend will do.
                 match args.get(arg_pos) {
                     Some(RedisMessage::Data(buf, offset)) => {
                         let end = buf.len() - 2;
                         &buf[*offset..end]
                     },
-                    _ => panic!("command message does not have expected structure"),
+                    _ => b"",
                 }
             },
             RedisMessage::Data(buf, offset) => {
@@ -254,10 +383,21 @@ impl Message for RedisMessage {
             },
             RedisMessage::Ping => b"ping",
             RedisMessage::Quit => b"quit",
-            _ => panic!("message should be multi-bulk or data!"),
+            // Replies aren't commands, and get rejected before being routed, so they have no key.
+            _ => b"",
+        }
+    }
+
+    fn command(&self) -> Option<&[u8]> {
+        match self {
+            RedisMessage::Ping => Some(b"ping"),
+            RedisMessage::Quit => Some(b"quit"),
+            _ => self.get_command(),
         }
     }
 
+    fn is_error(buf: &[u8]) -> bool { buf.first() == Some(&REDIS_COMMAND_ERROR) }
+
     fn is_inline(&self) -> bool {
         match self {
             RedisMessage::Data(_, _) => false,
@@ -266,6 +406,34 @@ impl Message for RedisMessage {
         }
     }
 
+    fn is_read(&self) -> bool { is_read_message(self) }
+
+    fn is_idempotent_write(&self) -> bool { is_idempotent_write_command(self) }
+
+    fn get_repeated_response(&self, response: &Self) -> Self {
+        // Deleting a key that was just deleted deletes nothing.  Otherwise, a repeated write gets
+        // the same response as the first, including any error.
+        match (self.get_command(), response) {
+            (Some(cmd), RedisMessage::Integer(_, _)) if cmd.eq_ignore_ascii_case(b"DEL") => {
+                RedisMessage::from_integer(0)
+            },
+            _ => response.clone(),
+        }
+    }
+
+    fn is_blocking(&self) -> bool { is_blocking_command(self) }
+
+    fn is_broadcast(&self) -> bool {
+        match self.get_command() {
+            Some(cmd) => get_key_arity(cmd) == KeyArity::Broadcast,
+            None => false,
+        }
+    }
+
+    fn is_key_listing(&self) -> bool { self.get_command().map_or(false, |cmd| cmd.eq_ignore_ascii_case(b"KEYS")) }
+
+    fn colocated_keys(&self) -> Option<Vec<&[u8]>> { get_multi_keys(self) }
+
     fn into_buf(self) -> BytesMut { self.into_resp() }
 }
 
@@ -279,9 +447,21 @@ where
             rbuf: BytesMut::new(),
             wbuf: BytesMut::new(),
             closed: false,
+            allow_debug: false,
+            max_args: None,
         }
     }
 
+    pub fn set_allow_debug(mut self, allow_debug: bool) -> Self {
+        self.allow_debug = allow_debug;
+        self
+    }
+
+    pub fn set_max_args(mut self, max_args: Option<usize>) -> Self {
+        self.max_args = max_args;
+        self
+    }
+
     fn fill_read_buf(&mut self) -> Poll<(), ProtocolError> {
         loop {
             self.rbuf.reserve(8192);
@@ -308,7 +488,7 @@ where
 
         let socket_closed = self.fill_read_buf()?.is_ready();
 
-        match read_message(&mut self.rbuf) {
+        match read_message(&mut self.rbuf, self.max_args) {
             Ok(Async::Ready((bytes_read, cmd))) => {
                 trace!("[protocol] got message from client! ({} bytes)", bytes_read);
 
@@ -320,6 +500,38 @@ where
                     self.closed = true;
                 }
 
+                // DEBUG is dangerous, so it's off unless explicitly enabled, and even then we only
+                // allow DEBUG OBJECT since it's the only subcommand that targets a specific key.
+                // Rejections are sent back inline without closing the transport.
+                if let Some(cmd_key) = cmd.get_command() {
+                    if is_debug_command(cmd_key) {
+                        if !self.allow_debug {
+                            let emsg = RedisMessage::from_error_str("DEBUG is disabled on this proxy");
+                            return Ok(Async::Ready(Some(emsg)));
+                        }
+
+                        if !is_debug_object_command(&cmd) {
+                            let emsg = RedisMessage::from_error_str("only DEBUG OBJECT <key> is supported");
+                            return Ok(Async::Ready(Some(emsg)));
+                        }
+
+                        return Ok(Async::Ready(Some(cmd)));
+                    }
+                }
+
+                // If we know why we can't support this command, say so, but leave the transport open
+                // since the client is likely to just carry on without it.
+                if let Some(cmd_key) = cmd.get_command() {
+                    if let Some(reason) = get_unsupported_reason(cmd_key) {
+                        let emsg = RedisMessage::from_error_str(&format!(
+                            "{} is not supported by this proxy: {}",
+                            String::from_utf8_lossy(cmd_key).to_uppercase(),
+                            reason
+                        ));
+                        return Ok(Async::Ready(Some(emsg)));
+                    }
+                }
+
                 // If this command is invalid, kill the transport.  We also give the transport
                 // owner an error message, which is inlined and so we can kill the transport while
                 // still sending an error back to the client themselves.
@@ -334,6 +546,17 @@ where
 
                 Ok(Async::Ready(Some(cmd)))
             },
+            Err(e @ ProtocolError::InvalidProtocol) | Err(e @ ProtocolError::TooManyArguments) => {
+                // The client sent us something that isn't RESP at all, like an HTTP request sent
+                // to the wrong port, or a command that's too big to bother buffering.  There's no
+                // way to resynchronize, so we hand back a protocol error, inlined like invalid
+                // commands, and close the transport.
+                self.closed = true;
+                self.rbuf.clear();
+
+                let emsg = RedisMessage::from_error_str(&format!("Protocol error: {}", e));
+                Ok(Async::Ready(Some(emsg)))
+            },
             Err(e) => Err(e),
             _ => {
                 if socket_closed {
@@ -386,12 +609,40 @@ impl<T> RedisMultipleMessages<T>
 where
     T: AsyncRead,
 {
-    pub fn new(transport: T, msgs: EnqueuedRequests<RedisMessage>) -> Self {
+    pub fn new(transport: T, msgs: EnqueuedRequests<RedisMessage>, read_timeout: Option<Duration>) -> Self {
         RedisMultipleMessages {
             transport: Some(transport),
             rbuf: BytesMut::new(),
             bytes_read: 0,
             msgs,
+            read_timeout,
+            read_deadline: None,
+        }
+    }
+
+    fn poll_read_deadline(&mut self) -> Result<(), ProtocolError> {
+        if let Some(deadline) = self.read_deadline.as_mut() {
+            let elapsed = deadline
+                .poll()
+                .map_err(|e| Error::new(ErrorKind::Other, e))?
+                .is_ready();
+            if elapsed {
+                return Err(Error::new(ErrorKind::TimedOut, "backend read timed out").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails all of the requests we haven't yet read a response for.
+    ///
+    /// Responses are read in order, so whatever we did read belongs to the requests at the front
+    /// of the batch, and each remaining request gets an error in its own position.  Once this is
+    /// called, the connection is out of sync with the requests sent on it, and can't be reused.
+    fn fail_pending(&mut self, reason: &str) {
+        let err = RedisMessage::from_error_str(reason);
+        for mut qmsg in self.msgs.drain(..) {
+            qmsg.fulfill(err.clone())
         }
     }
 
@@ -417,7 +668,22 @@ where
     type Item = (T, usize);
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let socket_closed = self.fill_read_buf()?.is_ready();
+        let bytes_read = self.bytes_read;
+        let socket_closed = match self.fill_read_buf() {
+            Ok(closed) => closed.is_ready(),
+            Err(e) => {
+                self.fail_pending(REDIS_BACKEND_READ_FAILED);
+                return Err(e);
+            },
+        };
+
+        // The read timeout only covers time spent waiting on the socket, so every time we make
+        // some progress, the clock starts over.
+        if let Some(read_timeout) = self.read_timeout {
+            if self.read_deadline.is_none() || self.bytes_read != bytes_read {
+                self.read_deadline = Some(Delay::new(Instant::now() + read_timeout));
+            }
+        }
 
         loop {
             // We've collected all the messages, time to return.
@@ -425,7 +691,7 @@ where
                 return Ok(Async::Ready((self.transport.take().unwrap(), self.bytes_read)));
             }
 
-            let result = read_message(&mut self.rbuf);
+            let result = read_message(&mut self.rbuf, None);
             match result {
                 Ok(Async::Ready((bytes_read, msg))) => {
                     trace!("[protocol] got message from server! ({} bytes)", bytes_read);
@@ -433,34 +699,66 @@ where
                     let mut qmsg = self.msgs.remove(0);
                     qmsg.fulfill(msg)
                 },
-                Err(e) => return Err(e),
+                Err(e) => {
+                    self.fail_pending(REDIS_BACKEND_READ_FAILED);
+                    return Err(e);
+                },
                 _ => {
-                    return if socket_closed {
-                        // If the socket is closed, let's also close up shop after responding to
-                        // the client with errors.
-                        let err = RedisMessage::from_error_str(REDIS_BACKEND_CLOSED);
-                        while let Some(mut qmsg) = self.msgs.pop() {
-                            qmsg.fulfill(err.clone())
-                        }
+                    // If the socket is closed, or the backend has stopped sending us anything, we
+                    // aren't getting the rest of our responses, so close up shop after responding
+                    // to the client with errors.
+                    if socket_closed {
+                        self.fail_pending(REDIS_BACKEND_CLOSED);
+                        return Err(ProtocolError::BackendClosedPrematurely);
+                    }
+
+                    if let Err(e) = self.poll_read_deadline() {
+                        self.fail_pending(REDIS_BACKEND_TIMED_OUT);
+                        return Err(e);
+                    }
 
-                        Err(ProtocolError::BackendClosedPrematurely)
-                    } else {
-                        Ok(Async::NotReady)
-                    };
+                    return Ok(Async::NotReady);
                 },
             }
         }
     }
 }
 
-pub fn read_messages<T>(rx: T, msgs: EnqueuedRequests<RedisMessage>) -> RedisMultipleMessages<T>
+pub fn read_messages<T>(
+    rx: T, msgs: EnqueuedRequests<RedisMessage>, read_timeout: Option<Duration>,
+) -> RedisMultipleMessages<T>
 where
     T: AsyncRead,
 {
-    RedisMultipleMessages::new(rx, msgs)
+    RedisMultipleMessages::new(rx, msgs, read_timeout)
 }
 
-fn read_message(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+/// Parses as many complete messages as possible from the given buffer.
+///
+/// This is the same parser that client and backend connections use, but without any sockets
+/// involved, so that it can be driven directly with arbitrary input, as the fuzz targets do.  Any
+/// trailing partial message is left unparsed, and the number of bytes consumed by the parsed
+/// messages is returned alongside them.
+#[cfg(any(test, fuzzing))]
+pub fn parse_messages(buf: &[u8], max_args: Option<usize>) -> Result<(Vec<RedisMessage>, usize), ProtocolError> {
+    let mut rd = BytesMut::from(buf);
+    let mut msgs = Vec::new();
+    let mut consumed = 0;
+
+    while !rd.is_empty() {
+        match read_message(&mut rd, max_args)? {
+            Async::Ready((n, msg)) => {
+                consumed += n;
+                msgs.push(msg);
+            },
+            Async::NotReady => break,
+        }
+    }
+
+    Ok((msgs, consumed))
+}
+
+fn read_message(rd: &mut BytesMut, max_args: Option<usize>) -> Poll<(usize, RedisMessage), ProtocolError> {
     // Check to see if we got any inline commands.
     //
     // This is either shortform commands -- like PING or QUIT -- or hard-coded responses like an OK
@@ -470,7 +768,7 @@ fn read_message(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError>
         return Ok(Async::Ready(msg_tuple));
     }
 
-    read_message_internal(rd)
+    read_message_internal(rd, max_args, 0)
 }
 
 fn read_inline_messages(rd: &mut BytesMut) -> Option<(usize, RedisMessage)> {
@@ -512,7 +810,9 @@ fn read_inline_messages(rd: &mut BytesMut) -> Option<(usize, RedisMessage)> {
     None
 }
 
-fn read_message_internal(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_message_internal(
+    rd: &mut BytesMut, max_args: Option<usize>, depth: usize,
+) -> Poll<(usize, RedisMessage), ProtocolError> {
     // Try reading a single byte to see if we have a message.  Match it against known
     // message types, and process accordingly.
     let first = match rd.len() {
@@ -524,14 +824,28 @@ fn read_message_internal(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), Proto
         None => Ok(Async::NotReady),
         Some(t) => {
             match &t {
-                &REDIS_COMMAND_BULK => read_bulk(rd),
+                &REDIS_COMMAND_BULK => read_bulk(rd, max_args, depth),
                 &REDIS_COMMAND_DATA => read_data(rd),
                 &REDIS_COMMAND_STATUS => read_status(rd),
                 &REDIS_COMMAND_ERROR => read_error(rd),
                 &REDIS_COMMAND_INTEGER => read_integer(rd),
-                x => {
-                    debug!("got unknown type sigil: {:?}", x);
-                    Ok(Async::NotReady)
+                &REDIS_COMMAND_MAP | &REDIS_COMMAND_SET | &REDIS_COMMAND_PUSH => {
+                    read_aggregate(rd, max_args, depth)
+                },
+                &REDIS_COMMAND_DOUBLE => read_double(rd),
+                &REDIS_COMMAND_BOOLEAN => read_boolean(rd),
+                &REDIS_COMMAND_BIG_NUMBER => read_big_number(rd),
+                &REDIS_COMMAND_VERBATIM => read_verbatim(rd),
+                _ => {
+                    // The only non-RESP data we accept are the inline commands, so if we might
+                    // still be waiting on the rest of one of those, keep waiting.  Anything else
+                    // can never turn into a valid message, so fail fast instead of buffering.
+                    if REDIS_INLINE_COMMANDS.iter().any(|cmd| cmd.starts_with(&rd[..])) {
+                        return Ok(Async::NotReady);
+                    }
+
+                    debug!("[protocol] got unknown type sigil: {:?}", t);
+                    Err(ProtocolError::InvalidProtocol)
                 },
             }
         },
@@ -551,15 +865,17 @@ fn read_line(rd: &BytesMut) -> Poll<usize, ProtocolError> {
     }
 }
 
-fn read_bulk_count(rd: &mut BytesMut) -> Poll<(usize, usize), ProtocolError> {
+fn read_bulk_count(rd: &mut BytesMut) -> Poll<(usize, Option<usize>), ProtocolError> {
     // Make sure there's at least a CRLF-terminated line in the buffer.
     let pos = try_ready!(read_line(rd));
 
-    // Try to extract the bulk count integer, leaving the rest.
+    // Try to extract the bulk count integer, leaving the rest.  A count of -1 is a null array,
+    // which is how blocking commands like BLPOP report that they timed out.
     let buf = rd.split_to(pos + 2);
-    match btoi::<usize>(&buf[1..pos]) {
-        Ok(count) => Ok(Async::Ready((pos + 2, count))),
-        Err(_) => Err(ProtocolError::InvalidProtocol),
+    match btoi::<i64>(&buf[1..pos]) {
+        Ok(-1) => Ok(Async::Ready((pos + 2, None))),
+        Ok(count) if count >= 0 => Ok(Async::Ready((pos + 2, Some(count as usize)))),
+        _ => Err(ProtocolError::InvalidProtocol),
     }
 }
 
@@ -631,14 +947,31 @@ fn read_data(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
     }
 }
 
-fn read_bulk(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+fn read_bulk(rd: &mut BytesMut, max_args: Option<usize>, depth: usize) -> Poll<(usize, RedisMessage), ProtocolError> {
+    if depth >= MAX_NESTING_DEPTH {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
     let mut total = 0;
     let mut buf = rd.clone();
 
-    // Get the number of items in the command.
+    // Get the number of items in the command.  Backends can send us empty arrays, like the keys
+    // on a page of SCAN results that turned up nothing, so this can legitimately be zero.
     let (n, count) = try_ready!(read_bulk_count(&mut buf));
-    if count < 1 {
-        return Err(ProtocolError::InvalidProtocol);
+    let count = match count {
+        Some(count) => count,
+        None => {
+            let _ = rd.split_to(n);
+            return Ok(Async::Ready((n, RedisMessage::Null)));
+        },
+    };
+
+    // Check the count against our limit before we go and try to read all of the arguments, so
+    // that an oversized command is rejected up front instead of sitting in our buffer.
+    if let Some(max_args) = max_args {
+        if count > max_args {
+            return Err(ProtocolError::TooManyArguments);
+        }
     }
     total += n;
 
@@ -646,7 +979,7 @@ fn read_bulk(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
     // This can legitimately fail because, at this point, buf might not contain the full message.
     let mut args = Vec::new();
     for _ in 0..count {
-        let (n, msg) = try_ready!(read_message_internal(&mut buf));
+        let (n, msg) = try_ready!(read_message_internal(&mut buf, max_args, depth + 1));
         total += n;
 
         args.push(msg);
@@ -657,6 +990,160 @@ fn read_bulk(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
     Ok(Async::Ready((total, RedisMessage::Bulk(buf, args))))
 }
 
+fn read_double(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+    // Make sure there's at least a CRLF-terminated line in the buffer.
+    let crlf_pos = try_ready!(read_line(rd));
+
+    // We pass doubles along as we got them, but we still make sure that they are doubles.
+    let value = std::str::from_utf8(&rd[1..crlf_pos]).map_err(|_| ProtocolError::InvalidProtocol)?;
+    if value != "nan" && value.parse::<f64>().is_err() {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
+    // Slice off the entire message.
+    let total = crlf_pos + 2;
+    let buf = rd.split_to(total);
+
+    Ok(Async::Ready((total, RedisMessage::Double(buf, 1))))
+}
+
+fn read_boolean(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+    // Make sure there's at least a CRLF-terminated line in the buffer.
+    let crlf_pos = try_ready!(read_line(rd));
+
+    let value = match &rd[1..crlf_pos] {
+        b"t" => true,
+        b"f" => false,
+        _ => return Err(ProtocolError::InvalidProtocol),
+    };
+
+    // Slice off the entire message.
+    let total = crlf_pos + 2;
+    let buf = rd.split_to(total);
+
+    Ok(Async::Ready((total, RedisMessage::Boolean(buf, value))))
+}
+
+fn read_big_number(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+    // Make sure there's at least a CRLF-terminated line in the buffer.
+    let crlf_pos = try_ready!(read_line(rd));
+
+    // Big numbers can be any size, so all we can check is that they're made of digits.
+    let digits = match rd[1] {
+        b'-' => &rd[2..crlf_pos],
+        _ => &rd[1..crlf_pos],
+    };
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
+    // Slice off the entire message.
+    let total = crlf_pos + 2;
+    let buf = rd.split_to(total);
+
+    Ok(Async::Ready((total, RedisMessage::BigNumber(buf, 1))))
+}
+
+fn read_verbatim(rd: &mut BytesMut) -> Poll<(usize, RedisMessage), ProtocolError> {
+    // Make sure there's at least a CRLF-terminated line in the buffer.
+    let len_crlf_pos = try_ready!(read_line(rd));
+
+    // Verbatim strings are data prefixed with their format, like "txt:", so they can't be null.
+    let len = btoi::<usize>(&rd[1..len_crlf_pos]).map_err(|_| ProtocolError::InvalidProtocol)?;
+    if len < 4 {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
+    // See if the actual data is available in the buffer.
+    let offset = len_crlf_pos + 2;
+    if rd.len() < offset + len + 2 {
+        return Ok(Async::NotReady);
+    }
+
+    if rd[offset + 3] != b':' {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
+    // Slice off the entire message.
+    let total = offset + len + 2;
+    let buf = rd.split_to(total);
+
+    Ok(Async::Ready((total, RedisMessage::Verbatim(buf, offset))))
+}
+
+fn read_aggregate(
+    rd: &mut BytesMut, max_args: Option<usize>, depth: usize,
+) -> Poll<(usize, RedisMessage), ProtocolError> {
+    if depth >= MAX_NESTING_DEPTH {
+        return Err(ProtocolError::InvalidProtocol);
+    }
+
+    let mut total = 0;
+    let mut buf = rd.clone();
+    let sigil = buf[0];
+
+    // Get the number of entries in the aggregate.  Unlike commands, these can be empty, and maps
+    // count their key/value pairs rather than the items themselves.
+    let (n, count) = try_ready!(read_bulk_count(&mut buf));
+    let count = count.ok_or(ProtocolError::InvalidProtocol)?;
+    let item_count = match sigil {
+        REDIS_COMMAND_MAP => count.checked_mul(2).ok_or(ProtocolError::InvalidProtocol)?,
+        _ => count,
+    };
+
+    if let Some(max_args) = max_args {
+        if item_count > max_args {
+            return Err(ProtocolError::TooManyArguments);
+        }
+    }
+    total += n;
+
+    // Like bulks, the buffer might not contain the full message yet.
+    let mut items = Vec::new();
+    for _ in 0..item_count {
+        let (n, msg) = try_ready!(read_message_internal(&mut buf, max_args, depth + 1));
+        total += n;
+
+        items.push(msg);
+    }
+
+    let buf = rd.split_to(total);
+    let msg = match sigil {
+        REDIS_COMMAND_MAP => RedisMessage::Map(buf, items),
+        REDIS_COMMAND_SET => RedisMessage::Set(buf, items),
+        _ => RedisMessage::Push(buf, items),
+    };
+
+    Ok(Async::Ready((total, msg)))
+}
+
+fn new_header_buf(sigil: u8, len: usize) -> BytesMut {
+    let mut len_buf = [b'\0'; 20];
+    let n = itoa::write(&mut len_buf[..], len).unwrap();
+
+    let mut buf = BytesMut::with_capacity(1 + n + 2);
+    buf.put_u8(sigil);
+    buf.put_slice(&len_buf[..n]);
+    buf.put_slice(&REDIS_CRLF[..]);
+    buf
+}
+
+fn new_line_buf(sigil: u8, value: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(1 + value.len() + 2);
+    buf.put_u8(sigil);
+    buf.put_slice(value);
+    buf.put_slice(&REDIS_CRLF[..]);
+    buf
+}
+
+fn new_aggregate_buf(sigil: u8, count: usize, items: &[RedisMessage]) -> BytesMut {
+    let mut buf = new_header_buf(sigil, count);
+    for item in items {
+        buf.unsplit(item.get_buf());
+    }
+    buf
+}
+
 pub fn write_raw_message<T>(tx: T, msg: RedisMessage) -> impl Future<Item = (T, usize), Error = ProtocolError>
 where
     T: AsyncWrite,
@@ -669,7 +1156,7 @@ where
 }
 
 pub fn write_messages<T>(
-    transport: T, mut msgs: EnqueuedRequests<RedisMessage>,
+    transport: T, mut msgs: EnqueuedRequests<RedisMessage>, write_timeout: Option<Duration>,
 ) -> impl Future<Item = (T, EnqueuedRequests<RedisMessage>, usize), Error = ProtocolError>
 where
     T: AsyncWrite,
@@ -691,15 +1178,26 @@ where
     };
 
     let buf_len = buf.len();
-    write_all(transport, buf)
+    let write = write_all(transport, buf)
         .map(move |(transport, _buf)| (transport, msgs, buf_len))
-        .map_err(|e| e.into())
+        .map_err(|e| e.into());
+
+    match write_timeout {
+        None => Either::A(write),
+        Some(write_timeout) => {
+            Either::B(Timeout::new(write, write_timeout).map_err(|e| {
+                e.into_inner()
+                    .unwrap_or_else(|| Error::new(ErrorKind::TimedOut, "backend write timed out").into())
+            }))
+        },
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use spectral::prelude::*;
+    use std::io::Cursor;
     use test::Bencher;
 
     static DATA_GET_SIMPLE: &[u8] = b"*2\r\n$3\r\nget\r\n$6\r\nfoobar\r\n";
@@ -723,11 +1221,19 @@ mod tests {
     static DATA_QUIT_UPPER: &[u8] = b"QUIT\r\n";
     static DATA_QUIT_FULL_LOWER: &[u8] = b"*1\r\n$4\r\nquit\r\n";
     static DATA_QUIT_FULL_UPPER: &[u8] = b"*1\r\n$4\r\nQUIT\r\n";
+    static DATA_HTTP_REQUEST: &[u8] = b"GET / HTTP/1.1\r\n";
+    static DATA_PARTIAL_INLINE: &[u8] = b"PI";
 
     fn get_message_from_buf(buf: &[u8]) -> Poll<RedisMessage, ProtocolError> {
         let mut rd = BytesMut::with_capacity(buf.len());
         rd.put_slice(&buf[..]);
-        read_message(&mut rd).map(|res| res.map(|(_, msg)| msg))
+        read_message(&mut rd, None).map(|res| res.map(|(_, msg)| msg))
+    }
+
+    fn get_message_from_buf_with_max_args(buf: &[u8], max_args: usize) -> Poll<RedisMessage, ProtocolError> {
+        let mut rd = BytesMut::with_capacity(buf.len());
+        rd.put_slice(&buf[..]);
+        read_message(&mut rd, Some(max_args)).map(|res| res.map(|(_, msg)| msg))
     }
 
     fn check_data_matches(msg: RedisMessage, data: &[u8]) {
@@ -812,6 +1318,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_pong() {
+        let res = get_message_from_buf(b"+PONG\r\n");
+        match res.unwrap() {
+            Async::Ready(msg) => assert!(msg.is_pong()),
+            _ => panic!("should have had message"),
+        }
+
+        match get_message_from_buf(&DATA_STATUS).unwrap() {
+            Async::Ready(msg) => assert!(!msg.is_pong()),
+            _ => panic!("should have had message"),
+        }
+    }
+
     #[test]
     fn parse_error() {
         let res = get_message_from_buf(&DATA_ERROR);
@@ -857,6 +1377,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_null_array() {
+        // Blocking commands like BLPOP reply with a null array when they time out.
+        let mut rd = BytesMut::from(&b"*-1\r\n:1\r\n"[..]);
+        match read_message(&mut rd, None) {
+            Ok(Async::Ready((5, msg))) => assert_eq!(msg, RedisMessage::Null),
+            _ => panic!("should have had null message"),
+        }
+        assert_eq!(&rd[..], &b":1\r\n"[..]);
+
+        // Any other negative count is still invalid, as is a null map, set, or push.
+        for buf in &[&b"*-2\r\n"[..], &b"%-1\r\n"[..], &b"~-1\r\n"[..], &b">-1\r\n"[..]] {
+            match get_message_from_buf(buf) {
+                Err(ProtocolError::InvalidProtocol) => {},
+                _ => panic!("should have been rejected as invalid protocol"),
+            }
+        }
+    }
+
     #[test]
     fn parse_integer() {
         let res = get_message_from_buf(&DATA_INTEGER_1337);
@@ -950,6 +1489,190 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_invalid_first_byte() {
+        match get_message_from_buf(&DATA_HTTP_REQUEST) {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("should have been rejected as invalid protocol"),
+        }
+
+        // A partial inline command could still be valid, so we should keep waiting for more.
+        let res = get_message_from_buf(&DATA_PARTIAL_INLINE);
+        assert_that(&res).is_ok().matches(|val| val.is_not_ready());
+    }
+
+    #[test]
+    fn parse_max_args() {
+        match get_message_from_buf_with_max_args(&DATA_GET_SIMPLE, 2) {
+            Ok(Async::Ready(msg)) => check_bulk_matches(msg, vec![b"get", b"foobar"]),
+            _ => panic!("should have had message"),
+        }
+
+        match get_message_from_buf_with_max_args(&DATA_GET_SIMPLE, 1) {
+            Err(ProtocolError::TooManyArguments) => {},
+            _ => panic!("should have been rejected for too many arguments"),
+        }
+
+        // We should reject based on the count alone, without waiting for the arguments to arrive.
+        match get_message_from_buf_with_max_args(&DATA_SHORT_CIRCUIT_PARTIAL_ARG, 1) {
+            Err(ProtocolError::TooManyArguments) => {},
+            _ => panic!("should have been rejected for too many arguments"),
+        }
+    }
+
+    #[test]
+    fn transport_passes_time_through() {
+        // TIME is answered by the processor, so the transport has to let it through rather than
+        // rejecting it as an invalid command.
+        let transport = RedisTransport::new(Cursor::new(b"*1\r\n$4\r\nTIME\r\n".to_vec()));
+        match transport.into_future().wait() {
+            Ok((Some(msg), transport)) => {
+                check_bulk_matches(msg, vec![b"TIME"]);
+                assert!(!transport.closed);
+            },
+            _ => panic!("should have had message"),
+        }
+    }
+
+    #[test]
+    fn parse_messages_from_slice() {
+        let mut buf = b"ping\r\n".to_vec();
+        buf.extend_from_slice(DATA_GET_SIMPLE);
+        buf.extend_from_slice(b"*1\r\n$4\r\n");
+
+        // Only complete messages are parsed, and the partial one at the end is left alone.
+        let (msgs, consumed) = parse_messages(&buf, None).expect("failed to parse messages");
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0], RedisMessage::Ping);
+        assert_eq!(consumed, 6 + DATA_GET_SIMPLE.len());
+    }
+
+    #[test]
+    fn parse_deeply_nested() {
+        // Nesting right up to the limit is fine...
+        let mut buf = b"*1\r\n".repeat(MAX_NESTING_DEPTH);
+        buf.extend_from_slice(b":1\r\n");
+        let (msgs, _) = parse_messages(&buf, None).expect("failed to parse messages");
+        assert_eq!(msgs.len(), 1);
+
+        // ...but anything deeper is rejected, rather than recursing until we run out of stack, even
+        // if the rest of the message hasn't arrived yet.
+        let buf = b"*1\r\n".repeat(MAX_NESTING_DEPTH + 1);
+        match parse_messages(&buf, None) {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("should have been rejected as invalid protocol"),
+        }
+
+        let buf = b"*1\r\n%1\r\n".repeat(100_000);
+        match parse_messages(&buf, Some(1024)) {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("should have been rejected as invalid protocol"),
+        }
+    }
+
+    fn check_round_trip(msg: RedisMessage, expected: &[u8]) {
+        let buf = msg.clone().into_resp();
+        assert_eq!(&buf[..], expected);
+        assert_eq!(msg.size(), expected.len());
+
+        match get_message_from_buf(expected) {
+            Ok(Async::Ready(parsed)) => assert_eq!(parsed, msg),
+            _ => panic!("should have had message"),
+        }
+    }
+
+    #[test]
+    fn round_trip_map() {
+        let msg = RedisMessage::from_map(vec![
+            (RedisMessage::from_data(b"first"), RedisMessage::from_integer(1)),
+            (RedisMessage::from_data(b"second"), RedisMessage::from_boolean(false)),
+        ]);
+        check_round_trip(msg, b"%2\r\n$5\r\nfirst\r\n:1\r\n$6\r\nsecond\r\n#f\r\n");
+        check_round_trip(RedisMessage::from_map(Vec::new()), b"%0\r\n");
+    }
+
+    #[test]
+    fn round_trip_set() {
+        let msg = RedisMessage::from_set(vec![RedisMessage::from_data(b"a"), RedisMessage::from_data(b"b")]);
+        check_round_trip(msg, b"~2\r\n$1\r\na\r\n$1\r\nb\r\n");
+    }
+
+    #[test]
+    fn round_trip_double() {
+        check_round_trip(RedisMessage::from_double(3.25), b",3.25\r\n");
+        check_round_trip(RedisMessage::from_double(-10.0), b",-10\r\n");
+        check_round_trip(RedisMessage::from_double(std::f64::INFINITY), b",inf\r\n");
+        check_round_trip(RedisMessage::from_double(std::f64::NAN), b",nan\r\n");
+
+        match get_message_from_buf(b",pi\r\n") {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("should have been rejected as invalid protocol"),
+        }
+    }
+
+    #[test]
+    fn round_trip_boolean() {
+        check_round_trip(RedisMessage::from_boolean(true), b"#t\r\n");
+        check_round_trip(RedisMessage::from_boolean(false), b"#f\r\n");
+
+        match get_message_from_buf(b"#x\r\n") {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("should have been rejected as invalid protocol"),
+        }
+    }
+
+    #[test]
+    fn round_trip_big_number() {
+        check_round_trip(
+            RedisMessage::from_big_number("3492890328409238509324850943850943825024385"),
+            b"(3492890328409238509324850943850943825024385\r\n",
+        );
+        check_round_trip(RedisMessage::from_big_number("-12"), b"(-12\r\n");
+
+        match get_message_from_buf(b"(12a\r\n") {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("should have been rejected as invalid protocol"),
+        }
+    }
+
+    #[test]
+    fn round_trip_verbatim() {
+        check_round_trip(
+            RedisMessage::from_verbatim("txt", b"Some string"),
+            b"=15\r\ntxt:Some string\r\n",
+        );
+
+        // A verbatim string split across reads should wait for the rest.
+        let res = get_message_from_buf(b"=15\r\ntxt:Some");
+        assert_that(&res).is_ok().matches(|val| val.is_not_ready());
+
+        match get_message_from_buf(b"=4\r\ntxt!\r\n") {
+            Err(ProtocolError::InvalidProtocol) => {},
+            _ => panic!("should have been rejected as invalid protocol"),
+        }
+    }
+
+    #[test]
+    fn round_trip_push() {
+        let msg = RedisMessage::from_push(vec![
+            RedisMessage::from_data(b"message"),
+            RedisMessage::from_data(b"channel"),
+            RedisMessage::from_set(vec![RedisMessage::from_double(1.5)]),
+        ]);
+        check_round_trip(msg, b">3\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n~1\r\n,1.5\r\n");
+    }
+
+    #[test]
+    fn parse_partial_aggregate() {
+        let res = get_message_from_buf(b"%2\r\n+a\r\n:1\r\n+b\r\n");
+        assert_that(&res).is_ok().matches(|val| val.is_not_ready());
+
+        match get_message_from_buf_with_max_args(b"~3\r\n", 2) {
+            Err(ProtocolError::TooManyArguments) => {},
+            _ => panic!("should have been rejected for too many arguments"),
+        }
+    }
+
     #[bench]
     fn bench_parse_get_simple(b: &mut Bencher) { b.iter(|| get_message_from_buf(&DATA_GET_SIMPLE)); }
 
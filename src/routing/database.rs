@@ -0,0 +1,297 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{RouteLogging, ROUTER_UNAVAILABLE};
+use crate::{
+    backend::processor::Processor,
+    common::{
+        AssignedRequests, AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, MessageResponse, RouteLog,
+    },
+};
+use futures::{
+    future::{join_all, JoinAll},
+    prelude::*,
+};
+use metrics_runtime::Sink as MetricSink;
+use std::collections::{HashMap, HashSet};
+use tower_service::Service;
+
+const ROUTER_DB_OUT_OF_RANGE: &str = "DB index is out of range";
+
+/// The response future for a batch split across databases.
+///
+/// Holds the responses from each pool the batch was sent to, along with the responses we came up
+/// with ourselves, such as for `SELECT`.
+pub struct DatabaseFuture<F, T>
+where
+    F: Future<Item = AssignedResponses<T>>,
+{
+    inner: JoinAll<Vec<F>>,
+    responses: Option<AssignedResponses<T>>,
+}
+
+impl<F, T> Future for DatabaseFuture<F, T>
+where
+    F: Future<Item = AssignedResponses<T>>,
+{
+    type Error = F::Error;
+    type Item = AssignedResponses<T>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner_responses = try_ready!(self.inner.poll());
+
+        let mut responses = self.responses.take().expect("database future polled after completion");
+        responses.extend(inner_responses.into_iter().flatten());
+        Ok(Async::Ready(responses))
+    }
+}
+
+/// Routes each client to the pool for the database it has selected.
+///
+/// Every client starts out on database 0, and switches with `SELECT`, which we answer ourselves.
+/// The router is cloned for each client, so the selected database is tracked per client.  Since
+/// each database can live on an entirely separate set of backends, a batch that switches databases
+/// partway through is split up, and the requests on either side of the switch go to their own
+/// pools.
+#[derive(Clone)]
+pub struct DatabaseRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    processor: P,
+    inners: HashMap<usize, S>,
+    unavailable: HashSet<usize>,
+    selected: usize,
+    route_log: Option<RouteLog>,
+    sink: MetricSink,
+}
+
+impl<P, S> DatabaseRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    pub fn new(processor: P, inners: HashMap<usize, S>, sink: MetricSink) -> DatabaseRouter<P, S> {
+        assert!(inners.contains_key(&0), "database router needs a pool for database 0");
+
+        DatabaseRouter {
+            processor,
+            inners,
+            unavailable: HashSet::new(),
+            selected: 0,
+            route_log: None,
+            sink,
+        }
+    }
+}
+
+impl<P, S> RouteLogging for DatabaseRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    fn set_route_log(&mut self, route_log: RouteLog) { self.route_log = Some(route_log); }
+}
+
+impl<P, S> Service<AssignedRequests<P::Message>> for DatabaseRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>> + Clone,
+{
+    type Error = S::Error;
+    type Future = DatabaseFuture<S::Future, P::Message>;
+    type Response = S::Response;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        // We can't know which databases the next batch will use until we see it, so every pool has
+        // to be ready.  Dead pools don't hold anything up: we answer their requests with errors.
+        self.unavailable.clear();
+
+        let mut ready = true;
+        for (db, inner) in self.inners.iter_mut() {
+            match inner.poll_ready() {
+                Ok(Async::NotReady) => ready = false,
+                Ok(Async::Ready(())) => {},
+                Err(_) => {
+                    self.sink.record_counter("router_unavailable", 1);
+                    self.unavailable.insert(*db);
+                },
+            }
+        }
+
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            self.sink.record_counter("router_backpressure", 1);
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn call(&mut self, req: AssignedRequests<P::Message>) -> Self::Future {
+        let mut batches: HashMap<usize, EnqueuedRequests<P::Message>> = HashMap::new();
+        let mut responses = Vec::new();
+        for (id, msg) in req {
+            if let Some(db) = self.processor.get_selected_database(&msg) {
+                let response = if self.inners.contains_key(&db) {
+                    self.selected = db;
+                    self.processor.get_ok_message()
+                } else {
+                    self.processor.get_error_message_str(ROUTER_DB_OUT_OF_RANGE)
+                };
+                responses.push((id, MessageResponse::Complete(response)));
+                continue;
+            }
+
+            if self.unavailable.contains(&self.selected) {
+                let response = self.processor.get_error_message_str(ROUTER_UNAVAILABLE);
+                responses.push((id, MessageResponse::Complete(response)));
+                continue;
+            }
+
+            batches
+                .entry(self.selected)
+                .or_insert_with(Vec::new)
+                .push(EnqueuedRequest::new(id, msg).set_route_log(self.route_log.clone()));
+        }
+
+        let inners = &mut self.inners;
+        let inner_responses = batches
+            .into_iter()
+            .map(|(db, batch)| inners.get_mut(&db).expect("batch for unknown database").call(batch))
+            .collect::<Vec<_>>();
+
+        DatabaseFuture {
+            inner: join_all(inner_responses),
+            responses: Some(responses),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::redis::RedisProcessor, protocol::redis::RedisMessage, routing::mock::MockService};
+    use metrics_runtime::Receiver;
+
+    fn get_router(pools: Vec<(usize, MockService)>) -> DatabaseRouter<RedisProcessor, MockService> {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let processor = RedisProcessor::new().set_allow_select(true);
+        DatabaseRouter::new(processor, pools.into_iter().collect(), receiver.get_sink())
+    }
+
+    fn get_response_ids(responses: AssignedResponses<RedisMessage>) -> Vec<usize> {
+        let mut ids = responses.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_sets_in_different_databases_use_different_pools() {
+        let default = MockService::new(false);
+        let analytics = MockService::new(false);
+        let mut router = get_router(vec![(0, default.clone()), (2, analytics.clone())]);
+
+        let reqs = vec![
+            (0, RedisMessage::from_inline("SET key0 foo")),
+            (1, RedisMessage::from_inline("SELECT 2")),
+            (2, RedisMessage::from_inline("SET key2 bar")),
+            (3, RedisMessage::from_inline("SELECT 0")),
+            (4, RedisMessage::from_inline("SET key0b baz")),
+        ];
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let responses = router.call(reqs).wait().expect("router should respond");
+        assert_eq!(get_response_ids(responses), vec![0, 1, 2, 3, 4]);
+
+        assert_eq!(default.seen(), vec!["key0", "key0b"]);
+        assert_eq!(analytics.seen(), vec!["key2"]);
+
+        // The selected database sticks around for the rest of the client's batches.
+        let reqs = vec![(0, RedisMessage::from_inline("SELECT 2"))];
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        router.call(reqs).wait().expect("router should respond");
+
+        let reqs = vec![(1, RedisMessage::from_inline("SET key2b qux"))];
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        router.call(reqs).wait().expect("router should respond");
+
+        assert!(default.seen().is_empty());
+        assert_eq!(analytics.seen(), vec!["key2b"]);
+
+        // Each client gets its own copy of the router, starting out on database 0.
+        let mut other = get_router(vec![(0, default.clone()), (2, analytics.clone())]);
+        let reqs = vec![(0, RedisMessage::from_inline("SET other foo"))];
+        assert_eq!(other.poll_ready(), Ok(Async::Ready(())));
+        other.call(reqs).wait().expect("router should respond");
+        assert_eq!(default.seen(), vec!["other"]);
+    }
+
+    #[test]
+    fn test_unmapped_database_is_out_of_range() {
+        let default = MockService::new(false);
+        let mut router = get_router(vec![(0, default.clone())]);
+
+        let reqs = vec![
+            (0, RedisMessage::from_inline("SELECT 5")),
+            (1, RedisMessage::from_inline("SET key0 foo")),
+        ];
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let mut responses = router.call(reqs).wait().expect("router should respond");
+        responses.sort_by_key(|(id, _)| *id);
+
+        match responses.remove(0) {
+            (0, MessageResponse::Complete(msg)) => {
+                assert_eq!(msg, RedisMessage::from_error_str("DB index is out of range"))
+            },
+            _ => panic!("SELECT should have gotten an error response"),
+        }
+
+        // A failed SELECT leaves the client on the database it was already using.
+        assert_eq!(default.seen(), vec!["key0"]);
+    }
+
+    #[test]
+    fn test_dead_database_pool_responds_with_errors() {
+        let default = MockService::new(false);
+        let mut router = get_router(vec![(0, default.clone()), (1, MockService::new(true))]);
+
+        let reqs = vec![
+            (0, RedisMessage::from_inline("SELECT 1")),
+            (1, RedisMessage::from_inline("SET key1 foo")),
+            (2, RedisMessage::from_inline("SELECT 0")),
+            (3, RedisMessage::from_inline("SET key0 foo")),
+        ];
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let mut responses = router.call(reqs).wait().expect("router should respond");
+        responses.sort_by_key(|(id, _)| *id);
+        assert_eq!(responses.len(), 4);
+
+        match responses.remove(1) {
+            (1, MessageResponse::Complete(msg)) => {
+                assert_eq!(msg, RedisMessage::from_error_str("backend pool unavailable"))
+            },
+            _ => panic!("request should have gotten an error response"),
+        }
+        assert_eq!(default.seen(), vec!["key0"]);
+    }
+}
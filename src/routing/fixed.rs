@@ -17,11 +17,13 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+use super::{respond_unavailable, RouteLogging, RouterFuture};
 use crate::{
     backend::processor::Processor,
-    common::{AssignedRequests, EnqueuedRequest, EnqueuedRequests, Message},
+    common::{AssignedRequests, AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, RouteLog},
 };
-use futures::prelude::*;
+use futures::{future::Either, prelude::*};
+use metrics_runtime::Sink as MetricSink;
 use tower_service::Service;
 
 #[derive(Clone)]
@@ -33,6 +35,9 @@ where
 {
     processor: P,
     inner: S,
+    unavailable: bool,
+    route_log: Option<RouteLog>,
+    sink: MetricSink,
 }
 
 impl<P, S> FixedRouter<P, S>
@@ -41,23 +46,113 @@ where
     P::Message: Message + Send,
     S: Service<EnqueuedRequests<P::Message>> + Clone,
 {
-    pub fn new(processor: P, inner: S) -> FixedRouter<P, S> { FixedRouter { processor, inner } }
+    pub fn new(processor: P, inner: S, sink: MetricSink) -> FixedRouter<P, S> {
+        FixedRouter {
+            processor,
+            inner,
+            unavailable: false,
+            route_log: None,
+            sink,
+        }
+    }
 }
 
-impl<P, S> Service<AssignedRequests<P::Message>> for FixedRouter<P, S>
+impl<P, S> RouteLogging for FixedRouter<P, S>
 where
     P: Processor + Clone + Send + 'static,
     P::Message: Message + Send,
     S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    fn set_route_log(&mut self, route_log: RouteLog) { self.route_log = Some(route_log); }
+}
+
+impl<P, S> Service<AssignedRequests<P::Message>> for FixedRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>> + Clone,
 {
     type Error = S::Error;
-    type Future = S::Future;
+    type Future = RouterFuture<S::Future, S::Response, S::Error>;
     type Response = S::Response;
 
-    fn poll_ready(&mut self) -> Poll<(), Self::Error> { self.inner.poll_ready() }
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        // If the pool is dead, we still want to answer the client, so we become ready and respond
+        // to the next batch with errors ourselves.
+        match self.inner.poll_ready() {
+            Ok(Async::NotReady) => {
+                self.sink.record_counter("router_backpressure", 1);
+                Ok(Async::NotReady)
+            },
+            Ok(Async::Ready(())) => Ok(Async::Ready(())),
+            Err(_) => {
+                self.sink.record_counter("router_unavailable", 1);
+                self.unavailable = true;
+                Ok(Async::Ready(()))
+            },
+        }
+    }
 
     fn call(&mut self, req: AssignedRequests<P::Message>) -> Self::Future {
-        let transformed = req.into_iter().map(|(id, msg)| EnqueuedRequest::new(id, msg)).collect();
-        self.inner.call(transformed)
+        if self.unavailable {
+            self.unavailable = false;
+            return Either::B(respond_unavailable(&self.processor, req));
+        }
+
+        let transformed = req
+            .into_iter()
+            .map(|(id, msg)| EnqueuedRequest::new(id, msg).set_route_log(self.route_log.clone()))
+            .collect();
+        Either::A(self.inner.call(transformed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::redis::RedisProcessor, common::MessageResponse, protocol::redis::RedisMessage};
+    use futures::future::{ok, FutureResult};
+    use metrics_runtime::Receiver;
+
+    #[derive(Clone)]
+    struct DeadService;
+
+    impl Service<EnqueuedRequests<RedisMessage>> for DeadService {
+        type Error = ();
+        type Future = FutureResult<AssignedResponses<RedisMessage>, ()>;
+        type Response = AssignedResponses<RedisMessage>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Err(()) }
+
+        fn call(&mut self, _req: EnqueuedRequests<RedisMessage>) -> Self::Future {
+            panic!("dead service should never be called")
+        }
+    }
+
+    #[test]
+    fn test_dead_pool_responds_with_errors() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let mut router = FixedRouter::new(RedisProcessor::new(), DeadService, receiver.get_sink());
+
+        // The router should keep answering, batch after batch, rather than erroring out or
+        // stalling the client.
+        for _ in 0..2 {
+            let reqs = vec![
+                (0, RedisMessage::from_inline("GET foo")),
+                (1, RedisMessage::from_inline("SET foo bar")),
+            ];
+
+            assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+            let responses = router.call(reqs).wait().expect("router should respond");
+            let expected = RedisMessage::from_error_str("backend pool unavailable");
+            assert_eq!(responses.len(), 2);
+            for (i, (id, response)) in responses.into_iter().enumerate() {
+                assert_eq!(id, i);
+                match response {
+                    MessageResponse::Complete(msg) => assert_eq!(msg, expected),
+                    MessageResponse::Failed => panic!("request should have gotten an error response"),
+                }
+            }
+        }
     }
 }
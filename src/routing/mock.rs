@@ -0,0 +1,79 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::{
+    common::{AssignedResponses, EnqueuedRequests},
+    protocol::redis::RedisMessage,
+};
+use futures::{
+    future::{ok, FutureResult},
+    prelude::*,
+};
+use std::sync::{Arc, Mutex};
+use tower_service::Service;
+
+/// Answers every request it's sent with `OK`, under the ID the request was sent with, and keeps
+/// track of the key of every request it's sent.
+///
+/// A dead service never becomes ready, like a pool whose backends have all gone away.
+#[derive(Clone)]
+pub struct MockService {
+    dead: bool,
+    seen: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockService {
+    pub fn new(dead: bool) -> MockService {
+        MockService {
+            dead,
+            seen: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Gets the keys of every request sent since the last time this was called.
+    pub fn seen(&self) -> Vec<String> { self.seen.lock().unwrap().drain(..).collect() }
+}
+
+impl Service<EnqueuedRequests<RedisMessage>> for MockService {
+    type Error = ();
+    type Future = FutureResult<AssignedResponses<RedisMessage>, ()>;
+    type Response = AssignedResponses<RedisMessage>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        if self.dead {
+            Err(())
+        } else {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn call(&mut self, req: EnqueuedRequests<RedisMessage>) -> Self::Future {
+        let mut seen = self.seen.lock().unwrap();
+        let mut responses = Vec::new();
+        for mut msg in req {
+            seen.push(String::from_utf8_lossy(msg.key()).into_owned());
+            if let Some(rx) = msg.get_response_rx() {
+                msg.fulfill(RedisMessage::OK);
+                responses.push(rx.wait().expect("response should have been sent"));
+            }
+        }
+
+        ok(responses)
+    }
+}
@@ -20,6 +20,47 @@
 mod errors;
 pub use self::errors::RouterError;
 
+mod database;
 mod fixed;
+#[cfg(test)]
+mod mock;
+mod readwrite;
 mod shadow;
-pub use self::{fixed::FixedRouter, shadow::ShadowRouter};
+pub use self::{database::DatabaseRouter, fixed::FixedRouter, readwrite::ReadWriteRouter, shadow::ShadowRouter};
+
+use crate::{
+    backend::processor::Processor,
+    common::{AssignedRequests, AssignedResponses, MessageResponse, RouteLog},
+};
+use futures::future::{ok, Either, FutureResult};
+
+/// The response future for a router: either the inner service's future, or an immediate response.
+pub type RouterFuture<F, R, E> = Either<F, FutureResult<R, E>>;
+
+const ROUTER_UNAVAILABLE: &str = "backend pool unavailable";
+
+/// A router that can keep track of which backend each of its requests is sent to.
+pub trait RouteLogging {
+    /// Sets where the requests this router sends to its pools record which backend they went to.
+    ///
+    /// Routers are cloned for each client, so this only applies to the client it's set for.
+    fn set_route_log(&mut self, route_log: RouteLog);
+}
+
+/// Responds to every request in the given batch with an error saying that the pool is unavailable.
+///
+/// Routers use this when their inner service fails to become ready, which usually means the pool
+/// behind it has died.  The client still gets a response for every request, in order, instead of
+/// being disconnected outright.
+fn respond_unavailable<P, E>(
+    processor: &P, req: AssignedRequests<P::Message>,
+) -> FutureResult<AssignedResponses<P::Message>, E>
+where
+    P: Processor,
+{
+    let responses = req
+        .into_iter()
+        .map(|(id, _)| (id, MessageResponse::Complete(processor.get_error_message_str(ROUTER_UNAVAILABLE))))
+        .collect();
+    ok(responses)
+}
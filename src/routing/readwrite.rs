@@ -0,0 +1,297 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use super::{respond_unavailable, RouteLogging, RouterFuture};
+use crate::{
+    backend::processor::Processor,
+    common::{AssignedRequests, AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, RouteLog},
+};
+use futures::{
+    future::{join_all, Either, JoinAll, Map},
+    prelude::*,
+};
+use metrics_runtime::Sink as MetricSink;
+use tower_service::Service;
+
+type ResponseFlattener<R> = fn(Vec<R>) -> R;
+
+/// The response future for a read/write split batch: the responses from each pool it was sent to.
+pub type ReadWriteFuture<F, R> = Map<JoinAll<Vec<F>>, ResponseFlattener<R>>;
+
+fn flatten_responses<T>(responses: Vec<AssignedResponses<T>>) -> AssignedResponses<T> {
+    responses.into_iter().flatten().collect()
+}
+
+/// Splits reads from writes, sending reads to a replica pool and everything else to the primary.
+///
+/// Reads are spread over the replica pools in round-robin order, a batch at a time.  If the next
+/// replica pool is unavailable, its reads go to the primary instead, so that losing a replica
+/// never costs us anything more than extra load on the primary.
+///
+/// Whether a request is a read is up to the protocol.  Anything it doesn't know to be a read,
+/// including commands it's never heard of, goes to the primary, since sending a write to a replica
+/// is far worse than sending a read to the primary.
+#[derive(Clone)]
+pub struct ReadWriteRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    processor: P,
+    primary_inner: S,
+    replica_inners: Vec<S>,
+    next_replica: usize,
+    unavailable: bool,
+    replica_unavailable: bool,
+    route_log: Option<RouteLog>,
+    sink: MetricSink,
+}
+
+impl<P, S> ReadWriteRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    pub fn new(processor: P, primary_inner: S, replica_inners: Vec<S>, sink: MetricSink) -> ReadWriteRouter<P, S> {
+        assert!(!replica_inners.is_empty(), "read/write router needs at least one replica pool");
+
+        ReadWriteRouter {
+            processor,
+            primary_inner,
+            replica_inners,
+            next_replica: 0,
+            unavailable: false,
+            replica_unavailable: false,
+            route_log: None,
+            sink,
+        }
+    }
+
+    fn enqueue(&self, reqs: AssignedRequests<P::Message>) -> EnqueuedRequests<P::Message> {
+        reqs.into_iter()
+            .map(|(id, msg)| EnqueuedRequest::new(id, msg).set_route_log(self.route_log.clone()))
+            .collect()
+    }
+}
+
+impl<P, S> RouteLogging for ReadWriteRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>> + Clone,
+{
+    fn set_route_log(&mut self, route_log: RouteLog) { self.route_log = Some(route_log); }
+}
+
+impl<P, S> Service<AssignedRequests<P::Message>> for ReadWriteRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Send,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>> + Clone,
+{
+    type Error = S::Error;
+    type Future = RouterFuture<ReadWriteFuture<S::Future, S::Response>, S::Response, S::Error>;
+    type Response = S::Response;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        // If the primary pool is dead, we still want to answer the client, so we become ready and
+        // respond to the next batch with errors ourselves.
+        match self.primary_inner.poll_ready() {
+            Ok(Async::NotReady) => {
+                self.sink.record_counter("router_backpressure", 1);
+                return Ok(Async::NotReady);
+            },
+            Ok(Async::Ready(())) => {},
+            Err(_) => {
+                self.sink.record_counter("router_unavailable", 1);
+                self.unavailable = true;
+                return Ok(Async::Ready(()));
+            },
+        }
+
+        // The replica pool whose turn it is has to be ready, too, unless it's dead, in which case
+        // its reads fall back to the primary.
+        match self.replica_inners[self.next_replica].poll_ready() {
+            Ok(Async::NotReady) => {
+                self.sink.record_counter("router_backpressure", 1);
+                Ok(Async::NotReady)
+            },
+            Ok(Async::Ready(())) => {
+                self.replica_unavailable = false;
+                Ok(Async::Ready(()))
+            },
+            Err(_) => {
+                self.sink.record_counter("router_replica_unavailable", 1);
+                self.replica_unavailable = true;
+                Ok(Async::Ready(()))
+            },
+        }
+    }
+
+    fn call(&mut self, req: AssignedRequests<P::Message>) -> Self::Future {
+        if self.unavailable {
+            self.unavailable = false;
+            return Either::B(respond_unavailable(&self.processor, req));
+        }
+
+        let (reads, writes) = if self.replica_unavailable {
+            (Vec::new(), req)
+        } else {
+            req.into_iter().partition::<Vec<_>, _>(|(_, msg)| msg.is_read())
+        };
+
+        let mut responses = Vec::new();
+        if !writes.is_empty() {
+            let writes = self.enqueue(writes);
+            responses.push(self.primary_inner.call(writes));
+        }
+
+        // We only move on to the next replica once we've actually used this one, since it's the
+        // one that was just polled to be ready.
+        if !reads.is_empty() {
+            let reads = self.enqueue(reads);
+            responses.push(self.replica_inners[self.next_replica].call(reads));
+            self.next_replica = (self.next_replica + 1) % self.replica_inners.len();
+        }
+
+        let flatten: ResponseFlattener<S::Response> = flatten_responses;
+        Either::A(join_all(responses).map(flatten))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::redis::RedisProcessor, common::MessageResponse, protocol::redis::RedisMessage,
+        routing::mock::MockService,
+    };
+    use metrics_runtime::Receiver;
+
+    fn get_batch() -> AssignedRequests<RedisMessage> {
+        vec![
+            (0, RedisMessage::from_inline("GET read1")),
+            (1, RedisMessage::from_inline("SET write1 foo")),
+            (2, RedisMessage::from_inline("mget read2 read3")),
+            (3, RedisMessage::from_inline("DEL write2")),
+            (4, RedisMessage::from_inline("SOMENEWCOMMAND unknown1")),
+        ]
+    }
+
+    #[test]
+    fn test_reads_and_writes_are_split() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let primary = MockService::new(false);
+        let replica1 = MockService::new(false);
+        let replica2 = MockService::new(false);
+        let mut router = ReadWriteRouter::new(
+            RedisProcessor::new(),
+            primary.clone(),
+            vec![replica1.clone(), replica2.clone()],
+            receiver.get_sink(),
+        );
+
+        // Reads should alternate between the replicas, batch by batch, while writes and unknown
+        // commands always go to the primary.
+        for i in 0..4 {
+            assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+            let responses = router.call(get_batch()).wait().expect("router should respond");
+            assert_eq!(responses.len(), 5);
+
+            assert_eq!(primary.seen(), vec!["write1", "write2", "unknown1"]);
+            let (active, idle) = if i % 2 == 0 { (&replica1, &replica2) } else { (&replica2, &replica1) };
+            assert_eq!(active.seen(), vec!["read1", "read2"]);
+            assert!(idle.seen().is_empty());
+        }
+
+        // A batch of only writes never touches the replicas, and doesn't use up a replica's turn.
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let writes = vec![(0, RedisMessage::from_inline("SET write1 foo"))];
+        assert_eq!(router.call(writes).wait().map(|responses| responses.len()), Ok(1));
+        assert_eq!(primary.seen(), vec!["write1"]);
+
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let reads = vec![(0, RedisMessage::from_inline("get read1"))];
+        assert_eq!(router.call(reads).wait().map(|responses| responses.len()), Ok(1));
+        assert_eq!(replica1.seen(), vec!["read1"]);
+        assert!(replica2.seen().is_empty());
+    }
+
+    #[test]
+    fn test_reads_are_classified_by_the_protocol() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let primary = MockService::new(false);
+        let replica = MockService::new(false);
+        let mut router =
+            ReadWriteRouter::new(RedisProcessor::new(), primary.clone(), vec![replica.clone()], receiver.get_sink());
+
+        // SORT only reads unless it's storing its result, and newer reads are known as well.
+        let reqs = vec![
+            (0, RedisMessage::from_inline("SORT read1 LIMIT 0 10")),
+            (1, RedisMessage::from_inline("SORT write1 STORE dest")),
+            (2, RedisMessage::from_inline("LPOS read2 foo")),
+        ];
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        assert_eq!(router.call(reqs).wait().map(|responses| responses.len()), Ok(3));
+        assert_eq!(primary.seen(), vec!["write1"]);
+        assert_eq!(replica.seen(), vec!["read1", "read2"]);
+    }
+
+    #[test]
+    fn test_dead_replica_falls_back_to_primary() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let primary = MockService::new(false);
+        let replica = MockService::new(true);
+        let mut router =
+            ReadWriteRouter::new(RedisProcessor::new(), primary.clone(), vec![replica.clone()], receiver.get_sink());
+
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let responses = router.call(get_batch()).wait().expect("router should respond");
+        assert_eq!(responses.len(), 5);
+        assert_eq!(primary.seen(), vec!["read1", "write1", "read2", "write2", "unknown1"]);
+        assert!(replica.seen().is_empty());
+    }
+
+    #[test]
+    fn test_dead_primary_responds_with_errors() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let replica = MockService::new(false);
+        let mut router = ReadWriteRouter::new(
+            RedisProcessor::new(),
+            MockService::new(true),
+            vec![replica.clone()],
+            receiver.get_sink(),
+        );
+
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let responses = router.call(get_batch()).wait().expect("router should respond");
+        let expected = RedisMessage::from_error_str("backend pool unavailable");
+        assert_eq!(responses.len(), 5);
+        for (i, (id, response)) in responses.into_iter().enumerate() {
+            assert_eq!(id, i);
+            match response {
+                MessageResponse::Complete(msg) => assert_eq!(msg, expected),
+                MessageResponse::Failed => panic!("request should have gotten an error response"),
+            }
+        }
+        assert!(replica.seen().is_empty());
+    }
+}
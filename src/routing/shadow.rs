@@ -17,15 +17,39 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+use super::{respond_unavailable, RouteLogging, RouterFuture};
 use crate::{
     backend::processor::Processor,
-    common::{AssignedRequests, EnqueuedRequest, EnqueuedRequests, Message},
+    common::{
+        AssignedRequests, AssignedResponses, EnqueuedRequest, EnqueuedRequests, Message, MessageResponse, RouteLog,
+    },
+};
+use futures::{future::Either, prelude::*, stream::futures_unordered::FuturesUnordered};
+use metrics_runtime::Sink as MetricSink;
+use rand::{thread_rng, Rng};
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    timer::Delay,
 };
-use futures::{prelude::*, stream::futures_unordered::FuturesUnordered};
-use std::marker::PhantomData;
-use tokio::sync::mpsc;
 use tower_service::Service;
 
+// How long the shadow worker waits for in-flight shadow requests to finish after being told to
+// close.  Shadow requests are bounded by their backend timeouts, so this is purely a backstop.
+const SHADOW_DRAIN_TIMEOUT_MS: u64 = 5000;
+
+// The fraction of mismatched shadow responses that get logged, so that a badly diverging shadow
+// pool doesn't flood the logs.
+const SHADOW_MISMATCH_LOG_RATE: f64 = 0.01;
+
+// How many shadow requests can be waiting on each shadow worker.  Past this, shadow requests are
+// dropped rather than queued, so a slow shadow pool can't build up an unbounded backlog in memory.
+const SHADOW_BUFFER: usize = 1024;
+
 #[derive(Derivative)]
 #[derivative(Clone)]
 pub struct ShadowRouter<P, S>
@@ -37,42 +61,218 @@ where
 {
     processor: P,
     default_inner: S,
-    shadow_inner: S,
-    noops: mpsc::UnboundedSender<S::Future>,
+    shadow_inners: Vec<S>,
+    noops: Vec<mpsc::Sender<ShadowRequest<S::Future, P::Message>>>,
+    sample_rate: f64,
+    compare: bool,
+    unavailable: bool,
+    route_log: Option<RouteLog>,
+    sink: MetricSink,
+}
+
+/// How the responses to a shadow request compared to the responses from the primary.
+#[derive(Debug, Default, PartialEq)]
+struct ShadowComparison {
+    matched: usize,
+    mismatched: Vec<String>,
+}
+
+/// A shadow request handed off to the shadow worker.
+///
+/// When comparing, this also carries the command for each request, and a channel that the primary
+/// responses arrive on, so that both sets of responses can be matched up once they're ready.
+struct ShadowRequest<F, M> {
+    inner: F,
+    responses: Option<AssignedResponses<M>>,
+    compare: Option<(HashMap<usize, String>, oneshot::Receiver<AssignedResponses<M>>)>,
+}
+
+impl<F, M> ShadowRequest<F, M> {
+    pub fn new(
+        inner: F, compare: Option<(HashMap<usize, String>, oneshot::Receiver<AssignedResponses<M>>)>,
+    ) -> ShadowRequest<F, M> {
+        ShadowRequest {
+            inner,
+            responses: None,
+            compare,
+        }
+    }
+}
+
+impl<F, M> Future for ShadowRequest<F, M>
+where
+    F: Future<Item = AssignedResponses<M>>,
+    M: Message,
+{
+    type Error = ();
+    type Item = Option<ShadowComparison>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.responses.is_none() {
+            let responses = try_ready!(self.inner.poll().map_err(|_| ()));
+            self.responses = Some(responses);
+        }
+
+        let primary = match self.compare.as_mut() {
+            None => return Ok(Async::Ready(None)),
+            Some((_, primary_rx)) => {
+                match primary_rx.poll() {
+                    Ok(Async::Ready(primary)) => primary,
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    // The primary request never finished, typically because the client went away,
+                    // so there's nothing to compare against.
+                    Err(_) => return Ok(Async::Ready(None)),
+                }
+            },
+        };
+
+        let (commands, _) = self.compare.take().expect("compare state missing");
+        let responses = self.responses.take().expect("shadow responses missing");
+        Ok(Async::Ready(Some(compare_responses(&commands, primary, responses))))
+    }
+}
+
+/// Passes through the responses from the primary pool, handing off a copy of them to be compared
+/// against the responses from each shadow pool, if we're comparing.
+pub struct PrimaryResponse<F, M> {
+    inner: F,
+    txs: Vec<oneshot::Sender<AssignedResponses<M>>>,
+}
+
+impl<F, M> Future for PrimaryResponse<F, M>
+where
+    F: Future<Item = AssignedResponses<M>>,
+    M: Clone,
+{
+    type Error = F::Error;
+    type Item = F::Item;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let responses = try_ready!(self.inner.poll());
+        for tx in self.txs.drain(..) {
+            let _ = tx.send(responses.clone());
+        }
+
+        Ok(Async::Ready(responses))
+    }
+}
+
+/// Compares the responses from the primary and shadow pools, request by request.
+///
+/// Only requests that got an actual response from both pools are compared, since a failed request
+/// says more about the health of a pool than about the data in it.
+fn compare_responses<M: Message>(
+    commands: &HashMap<usize, String>, primary: AssignedResponses<M>, shadow: AssignedResponses<M>,
+) -> ShadowComparison {
+    let mut primary = primary
+        .into_iter()
+        .filter_map(|(id, response)| {
+            match response {
+                MessageResponse::Complete(msg) => Some((id, msg.into_buf())),
+                MessageResponse::Failed => None,
+            }
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut comparison = ShadowComparison::default();
+    for (id, response) in shadow {
+        let shadow_buf = match response {
+            MessageResponse::Complete(msg) => msg.into_buf(),
+            MessageResponse::Failed => continue,
+        };
+
+        if let Some(primary_buf) = primary.remove(&id) {
+            if primary_buf == shadow_buf {
+                comparison.matched += 1;
+            } else {
+                comparison.mismatched.push(commands.get(&id).cloned().unwrap_or_default());
+            }
+        }
+    }
+
+    comparison
 }
 
-struct ShadowWorker<S, Request>
+struct ShadowWorker<S, M, C>
 where
-    S: Service<Request>,
+    S: Service<EnqueuedRequests<M>, Response = AssignedResponses<M>>,
+    M: Message + Clone,
+    C: Future,
 {
-    rx: mpsc::UnboundedReceiver<S::Future>,
+    rx: mpsc::Receiver<ShadowRequest<S::Future, M>>,
+    close: Option<C>,
+    deadline: Option<Delay>,
     should_close: bool,
-    inner: FuturesUnordered<S::Future>,
+    inner: FuturesUnordered<ShadowRequest<S::Future, M>>,
+    sink: MetricSink,
     _service: PhantomData<S>,
 }
 
-impl<S, Request> ShadowWorker<S, Request>
+impl<S, M, C> ShadowWorker<S, M, C>
 where
-    S: Service<Request>,
+    S: Service<EnqueuedRequests<M>, Response = AssignedResponses<M>>,
+    M: Message + Clone,
+    C: Future,
 {
-    pub fn new(rx: mpsc::UnboundedReceiver<S::Future>) -> ShadowWorker<S, Request> {
+    pub fn new(
+        rx: mpsc::Receiver<ShadowRequest<S::Future, M>>, close: C, sink: MetricSink,
+    ) -> ShadowWorker<S, M, C> {
         ShadowWorker {
             rx,
+            close: Some(close),
+            deadline: None,
             should_close: false,
             inner: FuturesUnordered::new(),
+            sink,
             _service: PhantomData,
         }
     }
+
+    fn poll_close(&mut self) {
+        let closed = match self.close.as_mut() {
+            Some(close) => {
+                match close.poll() {
+                    Ok(Async::NotReady) => false,
+                    _ => true,
+                }
+            },
+            None => false,
+        };
+
+        // Once we're told to close, we stop taking new shadow requests, although anything already
+        // handed to us will still be pulled in and driven, and we give ourselves a bounded amount
+        // of time to finish everything in flight.
+        if closed {
+            self.close = None;
+            self.rx.close();
+            self.deadline = Some(Delay::new(Instant::now() + Duration::from_millis(SHADOW_DRAIN_TIMEOUT_MS)));
+        }
+    }
+
+    fn record_comparison(&mut self, comparison: ShadowComparison) {
+        self.sink.record_counter("shadow_match", comparison.matched as u64);
+        self.sink.record_counter("shadow_mismatch", comparison.mismatched.len() as u64);
+
+        for command in comparison.mismatched {
+            if thread_rng().gen_bool(SHADOW_MISMATCH_LOG_RATE) {
+                debug!("[shadow] response for '{}' command differed from primary", command);
+            }
+        }
+    }
 }
 
-impl<S, Request> Future for ShadowWorker<S, Request>
+impl<S, M, C> Future for ShadowWorker<S, M, C>
 where
-    S: Service<Request>,
+    S: Service<EnqueuedRequests<M>, Response = AssignedResponses<M>>,
+    M: Message + Clone,
+    C: Future,
 {
     type Error = ();
     type Item = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.poll_close();
+
         if !self.should_close {
             loop {
                 match self.rx.poll() {
@@ -90,11 +290,14 @@ where
             }
         }
 
-        // Just drive our inner futures; we don't care about their return value.
+        // Drive our inner futures, recording how they compared to the primary if we're comparing.
         loop {
             match self.inner.poll() {
-                // These are successful results, so we just drop the value and keep on moving on.
-                Ok(Async::Ready(Some(_))) => {},
+                Ok(Async::Ready(Some(comparison))) => {
+                    if let Some(comparison) = comparison {
+                        self.record_comparison(comparison);
+                    }
+                },
                 // If we have no more futures to drive, and we've been instructed to close, it's
                 // time to go.
                 Ok(Async::Ready(None)) => {
@@ -110,6 +313,21 @@ where
             }
         }
 
+        // If we've run out of time to drain, whatever is still in flight is abandoned.
+        if let Some(deadline) = self.deadline.as_mut() {
+            match deadline.poll() {
+                Ok(Async::NotReady) => {},
+                _ => {
+                    let abandoned = self.inner.len();
+                    if abandoned > 0 {
+                        warn!("[shadow] abandoning {} in-flight shadow request(s) at shutdown", abandoned);
+                        self.sink.record_counter("shadow_dropped", abandoned as u64);
+                    }
+                    return Ok(Async::Ready(()));
+                },
+            }
+        }
+
         Ok(Async::NotReady)
     }
 }
@@ -117,51 +335,570 @@ where
 impl<P, S> ShadowRouter<P, S>
 where
     P: Processor + Clone + Send + 'static,
-    P::Message: Message + Clone + Send,
-    S: Service<EnqueuedRequests<P::Message>> + Clone + Send + 'static,
+    P::Message: Message + Clone + Send + 'static,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>> + Clone + Send + 'static,
     S::Future: Future + Send + 'static,
 {
-    pub fn new(processor: P, default_inner: S, shadow_inner: S) -> ShadowRouter<P, S> {
-        let (tx, rx) = mpsc::unbounded_channel();
+    /// Creates a new `ShadowRouter`, mirroring requests to the given shadow pools, by name.
+    pub fn new<C>(
+        processor: P, default_inner: S, shadow_pools: Vec<(String, S)>, close: C, sink: MetricSink,
+    ) -> ShadowRouter<P, S>
+    where
+        C: Future + Clone + Send + 'static,
+    {
+        // Spin off a task for each shadow pool that drives all of its shadow responses, and drains
+        // them when the listener is closed.  Its metrics are labeled with the pool they're for.
+        let mut shadow_inners = Vec::new();
+        let mut noops = Vec::new();
+        for (name, shadow_inner) in shadow_pools {
+            let mut worker_sink = sink.clone();
+            worker_sink.add_default_labels(&[("pool", name)]);
+
+            let (tx, rx) = mpsc::channel(SHADOW_BUFFER);
+            let shadow: ShadowWorker<S, P::Message, C> = ShadowWorker::new(rx, close.clone(), worker_sink);
+            tokio::spawn(shadow);
 
-        // Spin off a task that drives all of the shadow responses.
-        let shadow: ShadowWorker<S, EnqueuedRequests<P::Message>> = ShadowWorker::new(rx);
-        tokio::spawn(shadow);
+            shadow_inners.push(shadow_inner);
+            noops.push(tx);
+        }
 
         ShadowRouter {
             processor,
             default_inner,
-            shadow_inner,
-            noops: tx,
+            shadow_inners,
+            noops,
+            sample_rate: 1.0,
+            compare: false,
+            unavailable: false,
+            route_log: None,
+            sink,
         }
     }
+
+    /// Sets the fraction of requests, from 0.0 to 1.0, that are mirrored to the shadow pools.
+    ///
+    /// Requests are picked at random, and those that aren't picked never touch any shadow pool at
+    /// all.  Everything is mirrored by default.
+    pub fn set_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Sets whether or not shadow responses are compared against the primary responses.
+    ///
+    /// Every request that gets a response from both the primary and a shadow pool is counted as
+    /// either a match or a mismatch, and a sample of the mismatched commands are logged.  Off by default.
+    pub fn set_compare(mut self, compare: bool) -> Self {
+        self.compare = compare;
+        self
+    }
+
+    fn should_shadow(&self) -> bool { self.sample_rate >= 1.0 || thread_rng().gen_bool(self.sample_rate) }
 }
 
-impl<P, S> Service<AssignedRequests<P::Message>> for ShadowRouter<P, S>
+impl<P, S> RouteLogging for ShadowRouter<P, S>
 where
     P: Processor + Clone + Send + 'static,
     P::Message: Message + Clone + Send,
     S: Service<EnqueuedRequests<P::Message>> + Clone,
     S::Future: Future + Send + 'static,
+{
+    /// Only requests sent to the default pool are tracked, since that's where responses come from.
+    fn set_route_log(&mut self, route_log: RouteLog) { self.route_log = Some(route_log); }
+}
+
+impl<P, S> Service<AssignedRequests<P::Message>> for ShadowRouter<P, S>
+where
+    P: Processor + Clone + Send + 'static,
+    P::Message: Message + Clone + Send,
+    S: Service<EnqueuedRequests<P::Message>, Response = AssignedResponses<P::Message>> + Clone,
+    S::Future: Future + Send + 'static,
 {
     type Error = S::Error;
-    type Future = S::Future;
+    type Future = RouterFuture<PrimaryResponse<S::Future, P::Message>, S::Response, S::Error>;
     type Response = S::Response;
 
-    fn poll_ready(&mut self) -> Poll<(), Self::Error> { self.default_inner.poll_ready() }
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        // If the default pool is dead, we still want to answer the client, so we become ready and
+        // respond to the next batch with errors ourselves.  The shadow pools are never consulted,
+        // since they must never affect the primary response.
+        match self.default_inner.poll_ready() {
+            Ok(Async::NotReady) => {
+                self.sink.record_counter("router_backpressure", 1);
+                Ok(Async::NotReady)
+            },
+            Ok(Async::Ready(())) => Ok(Async::Ready(())),
+            Err(_) => {
+                self.sink.record_counter("router_unavailable", 1);
+                self.unavailable = true;
+                Ok(Async::Ready(()))
+            },
+        }
+    }
 
     fn call(&mut self, req: AssignedRequests<P::Message>) -> Self::Future {
-        let shadow_reqs = req
-            .clone()
+        if self.unavailable {
+            self.unavailable = false;
+            return Either::B(respond_unavailable(&self.processor, req));
+        }
+
+        let mut primary_txs = Vec::new();
+        if self.should_shadow() {
+            // To compare, we need the shadow responses, under the same IDs as the primary ones.
+            let commands = if self.compare {
+                Some(
+                    req.iter()
+                        .map(|(id, msg)| (*id, String::from_utf8_lossy(msg.command().unwrap_or(&b""[..])).into_owned()))
+                        .collect::<HashMap<_, _>>(),
+                )
+            } else {
+                None
+            };
+
+            // Every shadow pool gets its own copy of the requests.
+            for (shadow_inner, noops) in self.shadow_inners.iter_mut().zip(self.noops.iter_mut()) {
+                let (shadow_reqs, compare) = match commands.as_ref() {
+                    Some(commands) => {
+                        let shadow_reqs = req
+                            .clone()
+                            .into_iter()
+                            .map(|(id, msg)| EnqueuedRequest::new(id, msg))
+                            .collect();
+
+                        let (tx, rx) = oneshot::channel();
+                        primary_txs.push(tx);
+                        (shadow_reqs, Some((commands.clone(), rx)))
+                    },
+                    None => {
+                        let shadow_reqs = req
+                            .clone()
+                            .into_iter()
+                            .map(|(_, msg)| EnqueuedRequest::without_response(msg))
+                            .collect();
+                        (shadow_reqs, None)
+                    },
+                };
+
+                // The shadow pools are purely observational, so if we can't hand off the shadow
+                // request to the worker, whether it's gone or just too far behind, we just note it
+                // and move on: it must never affect the primary response.
+                let shadow = ShadowRequest::new(shadow_inner.call(shadow_reqs), compare);
+                if noops.try_send(shadow).is_err() {
+                    self.sink.record_counter("shadow_dropped", 1);
+                }
+            }
+        }
+
+        let default_reqs = req
             .into_iter()
-            .map(|(_, msg)| EnqueuedRequest::without_response(msg))
+            .map(|(id, msg)| EnqueuedRequest::new(id, msg).set_route_log(self.route_log.clone()))
             .collect();
+        Either::A(PrimaryResponse {
+            inner: self.default_inner.call(default_reqs),
+            txs: primary_txs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::redis::RedisProcessor, protocol::redis::RedisMessage, routing::mock::MockService,
+        util::metrics::{get_counter, get_labeled_counter},
+    };
+    use futures::future::{lazy, ok, FutureResult};
+    use metrics_runtime::Receiver;
+    use std::sync::{Arc, Mutex};
 
-        let default_reqs = req.into_iter().map(|(id, msg)| EnqueuedRequest::new(id, msg)).collect();
+    /// Answers every `GET` with its own value, and everything else with `OK`, under the IDs the
+    /// requests were sent with.
+    #[derive(Clone)]
+    struct ValueService {
+        value: &'static str,
+    }
+
+    impl Service<EnqueuedRequests<RedisMessage>> for ValueService {
+        type Error = ();
+        type Future = FutureResult<AssignedResponses<RedisMessage>, ()>;
+        type Response = AssignedResponses<RedisMessage>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
+
+        fn call(&mut self, req: EnqueuedRequests<RedisMessage>) -> Self::Future {
+            let mut responses = Vec::new();
+            for mut msg in req {
+                if let Some(rx) = msg.get_response_rx() {
+                    let is_get = msg.request().command().map_or(false, |cmd| cmd.eq_ignore_ascii_case(b"GET"));
+                    let response = if is_get {
+                        RedisMessage::from_data(self.value.as_bytes())
+                    } else {
+                        RedisMessage::OK
+                    };
+                    msg.fulfill(response);
+                    responses.push(rx.wait().expect("failed to get response"));
+                }
+            }
+
+            ok(responses)
+        }
+    }
+
+    /// Keeps track of every request it's sent, and answers them all with `OK`.
+    #[derive(Clone)]
+    struct RecordingService {
+        seen: Arc<Mutex<Vec<RedisMessage>>>,
+    }
+
+    impl Service<EnqueuedRequests<RedisMessage>> for RecordingService {
+        type Error = ();
+        type Future = FutureResult<AssignedResponses<RedisMessage>, ()>;
+        type Response = AssignedResponses<RedisMessage>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
+
+        fn call(&mut self, req: EnqueuedRequests<RedisMessage>) -> Self::Future {
+            let mut responses = Vec::new();
+            for mut msg in req {
+                self.seen.lock().unwrap().push(msg.request().clone());
+                if let Some(rx) = msg.get_response_rx() {
+                    msg.fulfill(RedisMessage::OK);
+                    responses.push(rx.wait().expect("failed to get response"));
+                }
+            }
 
-        let noop = self.shadow_inner.call(shadow_reqs);
-        let _ = self.noops.try_send(noop);
+            ok(responses)
+        }
+    }
+
+    #[test]
+    fn test_failed_shadow_send_does_not_affect_primary() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+
+        // Drop the worker side of the channel so that every shadow handoff fails.
+        let (tx, rx) = mpsc::channel(SHADOW_BUFFER);
+        drop(rx);
+
+        let mut router = ShadowRouter {
+            processor: RedisProcessor::new(),
+            default_inner: MockService::new(false),
+            shadow_inners: vec![MockService::new(false)],
+            noops: vec![tx],
+            sample_rate: 1.0,
+            compare: false,
+            unavailable: false,
+            route_log: None,
+            sink: receiver.get_sink(),
+        };
+
+        let reqs = vec![
+            (0, RedisMessage::from_inline("GET foo")),
+            (1, RedisMessage::from_inline("GET bar")),
+        ];
+
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        assert_eq!(router.call(reqs).wait().map(|responses| responses.len()), Ok(2));
+
+        // The batch went to a single shadow pool, so there's one dropped handoff to show for it.
+        assert_eq!(get_counter(&receiver, "shadow_dropped"), 1);
+    }
+
+    #[test]
+    fn test_full_shadow_queue_does_not_affect_primary() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+
+        // Keep the worker side of the channel around, but never drain it, so that it fills up.
+        let (tx, rx) = mpsc::channel(1);
+
+        let mut router = ShadowRouter {
+            processor: RedisProcessor::new(),
+            default_inner: MockService::new(false),
+            shadow_inners: vec![MockService::new(false)],
+            noops: vec![tx],
+            sample_rate: 1.0,
+            compare: false,
+            unavailable: false,
+            route_log: None,
+            sink: receiver.get_sink(),
+        };
+
+        // Every batch still gets its primary response, but only the first one fits in the queue.
+        for _ in 0..3 {
+            let reqs = vec![(0, RedisMessage::from_inline("SET foo bar"))];
+            assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+            assert_eq!(router.call(reqs).wait().map(|responses| responses.len()), Ok(1));
+        }
+        assert_eq!(get_counter(&receiver, "shadow_dropped"), 2);
+
+        drop(router);
+        assert_eq!(rx.collect().wait().expect("failed to collect shadow requests").len(), 1);
+    }
+
+    #[test]
+    fn test_dead_default_pool_responds_with_errors() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let (tx, _rx) = mpsc::channel(SHADOW_BUFFER);
+
+        let mut router = ShadowRouter {
+            processor: RedisProcessor::new(),
+            default_inner: MockService::new(true),
+            shadow_inners: vec![MockService::new(false)],
+            noops: vec![tx],
+            sample_rate: 1.0,
+            compare: false,
+            unavailable: false,
+            route_log: None,
+            sink: receiver.get_sink(),
+        };
+
+        let reqs = vec![
+            (3, RedisMessage::from_inline("GET foo")),
+            (4, RedisMessage::from_inline("GET bar")),
+        ];
+
+        // Every request gets its own error, in the same slot it was sent in.
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let responses = router.call(reqs).wait().expect("router should respond");
+        let expected = RedisMessage::from_error_str("backend pool unavailable");
+        assert_eq!(responses.len(), 2);
+        for ((id, response), expected_id) in responses.into_iter().zip(vec![3, 4]) {
+            assert_eq!(id, expected_id);
+            match response {
+                MessageResponse::Complete(msg) => assert_eq!(msg, expected),
+                MessageResponse::Failed => panic!("request should have gotten an error response"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_shadow_worker_drains_on_close() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+
+        // Hand the worker a shadow request and then close it right away: it should still drive
+        // the request it was given, and then exit even though the sender is still alive.
+        let (mut tx, rx) = mpsc::channel(SHADOW_BUFFER);
+        let shadow = ShadowRequest::new(MockService::new(false).call(Vec::new()), None);
+        tx.try_send(shadow).expect("failed to send shadow request");
+
+        let worker: ShadowWorker<MockService, RedisMessage, _> =
+            ShadowWorker::new(rx, ok::<(), ()>(()), receiver.get_sink());
+        assert_eq!(worker.wait(), Ok(()));
+
+        // Once closed, new shadow requests are refused.
+        let shadow = ShadowRequest::new(MockService::new(false).call(Vec::new()), None);
+        assert!(tx.try_send(shadow).is_err());
+    }
+
+    #[test]
+    fn test_compare_divergent_backends() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let (tx, rx) = mpsc::channel(SHADOW_BUFFER);
+
+        let mut router = ShadowRouter {
+            processor: RedisProcessor::new(),
+            default_inner: ValueService { value: "old" },
+            shadow_inners: vec![ValueService { value: "new" }],
+            noops: vec![tx],
+            sample_rate: 1.0,
+            compare: true,
+            unavailable: false,
+            route_log: None,
+            sink: receiver.get_sink(),
+        };
+
+        let reqs = vec![
+            (5, RedisMessage::from_inline("SET foo bar")),
+            (6, RedisMessage::from_inline("GET foo")),
+            (7, RedisMessage::from_inline("SET bar baz")),
+        ];
+
+        // The client still only ever sees what the primary said.
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        let responses = router.call(reqs).wait().expect("router should respond");
+        let values = responses
+            .into_iter()
+            .map(|(id, response)| {
+                match response {
+                    MessageResponse::Complete(msg) => (id, msg),
+                    MessageResponse::Failed => panic!("request should have gotten a response"),
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![
+                (5, RedisMessage::OK),
+                (6, RedisMessage::from_data(b"old")),
+                (7, RedisMessage::OK),
+            ]
+        );
+
+        // Both writes agree, but the read diverged.
+        drop(router);
+        let shadows = rx.collect().wait().expect("failed to collect shadow requests");
+        assert_eq!(shadows.len(), 1);
+        let comparisons = shadows
+            .into_iter()
+            .map(|shadow| shadow.wait().expect("shadow request failed"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            comparisons,
+            vec![Some(ShadowComparison {
+                matched: 2,
+                mismatched: vec!["GET".to_string()],
+            })]
+        );
+    }
+
+    #[test]
+    fn test_comparisons_are_labeled_by_pool() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let sink = receiver.get_sink();
+
+        // The runtime only returns once every shadow worker has drained and exited, which they do
+        // once the listener closes.
+        tokio_io_pool::run(lazy(move || {
+            let (close_tx, close_rx) = futures::sync::oneshot::channel::<()>();
+            let shadow_pools = vec![
+                ("shadow".to_owned(), ValueService { value: "old" }),
+                ("shadow2".to_owned(), ValueService { value: "new" }),
+            ];
+            let mut router = ShadowRouter::new(
+                RedisProcessor::new(),
+                ValueService { value: "old" },
+                shadow_pools,
+                close_rx.shared(),
+                sink,
+            )
+            .set_compare(true);
+
+            assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+            router.call(vec![(0, RedisMessage::from_inline("GET foo"))]).then(move |_| {
+                let _ = close_tx.send(());
+                ok::<(), ()>(())
+            })
+        }));
+
+        // Each shadow pool is compared against the primary on its own.
+        assert_eq!(get_labeled_counter(&receiver, "shadow_match", ("pool", "shadow")), 1);
+        assert_eq!(get_labeled_counter(&receiver, "shadow_mismatch", ("pool", "shadow")), 0);
+        assert_eq!(get_labeled_counter(&receiver, "shadow_match", ("pool", "shadow2")), 0);
+        assert_eq!(get_labeled_counter(&receiver, "shadow_mismatch", ("pool", "shadow2")), 1);
+    }
+
+    #[test]
+    fn test_compare_skips_failed_responses() {
+        let commands = vec![(0, "GET".to_string()), (1, "GET".to_string()), (2, "GET".to_string())]
+            .into_iter()
+            .collect();
+        let primary = vec![
+            (0, MessageResponse::Complete(RedisMessage::from_data(b"a"))),
+            (1, MessageResponse::Failed),
+            (2, MessageResponse::Complete(RedisMessage::from_data(b"c"))),
+        ];
+        let shadow = vec![
+            (0, MessageResponse::Failed),
+            (1, MessageResponse::Complete(RedisMessage::from_data(b"b"))),
+            (2, MessageResponse::Complete(RedisMessage::from_data(b"c"))),
+        ];
+
+        let comparison = compare_responses(&commands, primary, shadow);
+        assert_eq!(
+            comparison,
+            ShadowComparison {
+                matched: 1,
+                mismatched: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_mirror_to_multiple_shadow_pools() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let (tx1, rx1) = mpsc::channel(SHADOW_BUFFER);
+        let (tx2, rx2) = mpsc::channel(SHADOW_BUFFER);
+
+        let primary_seen = Arc::new(Mutex::new(Vec::new()));
+        let shadow1_seen = Arc::new(Mutex::new(Vec::new()));
+        let shadow2_seen = Arc::new(Mutex::new(Vec::new()));
+
+        let mut router = ShadowRouter {
+            processor: RedisProcessor::new(),
+            default_inner: RecordingService {
+                seen: primary_seen.clone(),
+            },
+            shadow_inners: vec![
+                RecordingService {
+                    seen: shadow1_seen.clone(),
+                },
+                RecordingService {
+                    seen: shadow2_seen.clone(),
+                },
+            ],
+            noops: vec![tx1, tx2],
+            sample_rate: 1.0,
+            compare: false,
+            unavailable: false,
+            route_log: None,
+            sink: receiver.get_sink(),
+        };
+
+        let write = RedisMessage::from_inline("SET foo bar");
+        assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+        assert_eq!(
+            router.call(vec![(0, write.clone())]).wait().map(|responses| responses.len()),
+            Ok(1)
+        );
+
+        // Every pool saw the write, and each shadow pool's worker got its own shadow request.
+        drop(router);
+        for seen in &[primary_seen, shadow1_seen, shadow2_seen] {
+            assert_eq!(*seen.lock().unwrap(), vec![write.clone()]);
+        }
+        for rx in vec![rx1, rx2] {
+            assert_eq!(rx.collect().wait().expect("failed to collect shadow requests").len(), 1);
+        }
+    }
+
+    fn count_shadowed(sample_rate: f64, batches: usize) -> usize {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let (tx, rx) = mpsc::channel(SHADOW_BUFFER);
+
+        let mut router = ShadowRouter {
+            processor: RedisProcessor::new(),
+            default_inner: MockService::new(false),
+            shadow_inners: vec![MockService::new(false)],
+            noops: vec![tx],
+            sample_rate,
+            compare: false,
+            unavailable: false,
+            route_log: None,
+            sink: receiver.get_sink(),
+        };
+
+        // Every batch still gets its primary response, whether or not it was mirrored.
+        for _ in 0..batches {
+            let reqs = vec![(0, RedisMessage::from_inline("SET foo bar"))];
+            assert_eq!(router.poll_ready(), Ok(Async::Ready(())));
+            assert_eq!(router.call(reqs).wait().map(|responses| responses.len()), Ok(1));
+        }
+
+        // Once the router is gone, the worker side sees exactly the shadow work it was handed.
+        drop(router);
+        rx.collect().wait().expect("failed to collect shadow requests").len()
+    }
+
+    #[test]
+    fn test_no_shadow_work_when_not_sampled() {
+        assert_eq!(count_shadowed(0.0, 1000), 0);
+        assert_eq!(count_shadowed(1.0, 1000), 1000);
+    }
 
-        self.default_inner.call(default_reqs)
+    #[test]
+    fn test_shadow_sampling() {
+        // With 10,000 batches, the standard deviation is 50, so this is far outside of any
+        // realistic chance of flaking.
+        let shadowed = count_shadowed(0.5, 10_000);
+        assert!(shadowed > 4500 && shadowed < 5500, "shadowed {} of 10000 batches", shadowed);
     }
 }
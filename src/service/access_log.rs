@@ -0,0 +1,322 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::{common::Message, util::ClientAddr};
+use bytes::BytesMut;
+use metrics_runtime::{data::Counter, Sink as MetricSink};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Arc,
+    },
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How many writes can be waiting on the access log thread before new ones are dropped.
+const ACCESS_LOG_BUFFER: usize = 1024;
+
+struct AccessEntry {
+    timestamp: SystemTime,
+    command: String,
+    key: String,
+    backends: Vec<Arc<String>>,
+    start: Instant,
+    bytes: usize,
+    status: Option<&'static str>,
+}
+
+struct AccessLogFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+}
+
+impl AccessLogFile {
+    fn append(&mut self, lines: &[u8]) -> io::Result<()> {
+        // Once the file is full, the previous one is replaced by it and we start over, so there are
+        // at most two files' worth of access logs on disk at any given time.
+        if self.written > 0 && self.written + lines.len() as u64 > self.max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, &rotated)?;
+
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.written = 0;
+        }
+
+        self.file.write_all(lines)?;
+        self.written += lines.len() as u64;
+        Ok(())
+    }
+}
+
+/// Where access log entries are written to.
+///
+/// Entries go to the regular log by default, or to a dedicated file, shared by every client of a
+/// listener, which is rotated once it reaches a maximum size.
+///
+/// Either way, the actual writing happens on a dedicated thread, so that a slow disk or logger
+/// never stalls the clients being logged.  If that thread falls too far behind, entries are dropped
+/// and counted in `access_log_dropped`.  The thread exits once every clone of the writer is gone.
+#[derive(Clone)]
+pub struct AccessLogWriter {
+    tx: SyncSender<String>,
+    dropped: Counter,
+}
+
+impl AccessLogWriter {
+    /// Creates a writer that writes entries to the regular log.
+    pub fn logger(sink: &mut MetricSink) -> io::Result<AccessLogWriter> { AccessLogWriter::spawn(None, sink) }
+
+    /// Creates a writer that appends entries to the given file.
+    ///
+    /// When writing an entry would take the file over `max_bytes`, it's first moved aside to the
+    /// same path with `.1` appended, replacing any file that was there, and a new file is started.
+    pub fn file(path: &Path, max_bytes: u64, sink: &mut MetricSink) -> io::Result<AccessLogWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+
+        let file = AccessLogFile {
+            path: path.to_path_buf(),
+            file,
+            written,
+            max_bytes,
+        };
+        AccessLogWriter::spawn(Some(file), sink)
+    }
+
+    fn spawn(mut file: Option<AccessLogFile>, sink: &mut MetricSink) -> io::Result<AccessLogWriter> {
+        let (tx, rx) = sync_channel::<String>(ACCESS_LOG_BUFFER);
+        thread::Builder::new().name("access-log".to_owned()).spawn(move || {
+            for lines in rx {
+                match file.as_mut() {
+                    Some(file) => {
+                        if let Err(e) = file.append(lines.as_bytes()) {
+                            warn!("[access] failed to write to access log: {}", e);
+                        }
+                    },
+                    None => {
+                        for line in lines.lines() {
+                            info!("[access] {}", line);
+                        }
+                    },
+                }
+            }
+        })?;
+
+        Ok(AccessLogWriter {
+            tx,
+            dropped: sink.counter("access_log_dropped"),
+        })
+    }
+
+    fn write(&self, lines: String, count: u64) {
+        if self.tx.try_send(lines).is_err() {
+            self.dropped.record(count);
+        }
+    }
+}
+
+/// Tracks client requests as they flow through a pipeline and logs them once they're responded to.
+///
+/// Each request is logged on its own line, in the order the client sent them:
+///
+/// `<timestamp> <client address> "<command>" "<key>" <backends> <status> <response bytes> <duration>us`
+///
+/// The timestamp is when the request was read, in seconds since the Unix epoch, with microsecond
+/// precision.  The key is the first key of the request, escaped, or empty if it has none.  The
+/// backends are every backend that served part of the request, separated by commas, `*` for a
+/// request sent to every backend of a pool, or `-` if it never reached one, like a request that was
+/// answered by the proxy itself.  The status is either `ok` or `error`, depending on whether or not
+/// the response was an error.
+pub struct AccessLog {
+    client: ClientAddr,
+    writer: AccessLogWriter,
+    pending: VecDeque<AccessEntry>,
+}
+
+impl AccessLog {
+    pub fn new(client: ClientAddr, writer: AccessLogWriter) -> AccessLog {
+        AccessLog {
+            client,
+            writer,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Starts tracking the given request.
+    pub fn start<M: Message>(&mut self, msg: &M) {
+        let command = match msg.command() {
+            Some(cmd) => String::from_utf8_lossy(cmd).to_lowercase(),
+            None => "-".to_owned(),
+        };
+        let key = msg
+            .key()
+            .iter()
+            .flat_map(|b| std::ascii::escape_default(*b))
+            .map(char::from)
+            .collect();
+
+        self.pending.push_back(AccessEntry {
+            timestamp: SystemTime::now(),
+            command,
+            key,
+            backends: Vec::new(),
+            start: Instant::now(),
+            bytes: 0,
+            status: None,
+        });
+    }
+
+    /// Records a response buffer, and the backends that served it, logging the oldest pending
+    /// request if the buffer completes it.
+    pub fn record(&mut self, buf: &BytesMut, count: u64, is_error: bool, backends: Vec<Arc<String>>) {
+        if let Some(entry) = self.pending.front_mut() {
+            entry.bytes += buf.len();
+            if entry.status.is_none() {
+                entry.status = Some(if is_error { "error" } else { "ok" });
+            }
+            for backend in backends {
+                if !entry.backends.contains(&backend) {
+                    entry.backends.push(backend);
+                }
+            }
+        }
+
+        // Everything completed by this buffer goes out in a single write.
+        let mut lines = String::new();
+        for _ in 0..count {
+            if let Some(entry) = self.pending.pop_front() {
+                let timestamp = entry.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let elapsed = entry.start.elapsed();
+                let elapsed_us = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+                let backends = if entry.backends.is_empty() {
+                    "-".to_owned()
+                } else {
+                    entry.backends.iter().map(|b| b.as_str()).collect::<Vec<_>>().join(",")
+                };
+                lines.push_str(&format!(
+                    "{}.{:06} {} \"{}\" \"{}\" {} {} {} {}us\n",
+                    timestamp.as_secs(),
+                    timestamp.subsec_micros(),
+                    self.client,
+                    entry.command,
+                    entry.key,
+                    backends,
+                    entry.status.unwrap_or("ok"),
+                    entry.bytes,
+                    elapsed_us
+                ));
+            }
+        }
+
+        if !lines.is_empty() {
+            self.writer.write(lines, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{protocol::redis::RedisMessage, util::metrics::get_counter};
+    use metrics_runtime::Receiver;
+    use std::{env, process, time::Duration};
+
+    /// Reads the given file once it has the given number of lines, since it's written to by
+    /// another thread.
+    fn read_lines(path: &Path, count: usize) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let contents = fs::read_to_string(path).unwrap_or_default();
+            let lines = contents.lines().map(|l| l.to_owned()).collect::<Vec<_>>();
+            if lines.len() >= count || Instant::now() > deadline {
+                return lines;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_access_log_file_rotation() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let path = env::temp_dir().join(format!("synchrotron-access-{}.log", process::id()));
+        let rotated = env::temp_dir().join(format!("synchrotron-access-{}.log.1", process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+
+        let writer = AccessLogWriter::file(&path, 180, &mut receiver.get_sink()).expect("failed to open access log");
+        let mut log = AccessLog::new(ClientAddr::Tcp("127.0.0.1:5000".parse().unwrap()), writer);
+
+        log.start(&RedisMessage::from_inline("GET foo"));
+        log.start(&RedisMessage::from_array(vec![
+            RedisMessage::from_data(b"SET"),
+            RedisMessage::from_data(b"a \"b\"\r\n"),
+            RedisMessage::from_data(b"bar"),
+        ]));
+        let backend = Arc::new("127.0.0.1:6379".to_owned());
+        log.record(&BytesMut::from(&b"$3\r\nbar\r\n"[..]), 1, false, vec![backend]);
+        log.record(&BytesMut::from(&b"-ERR no\r\n"[..]), 1, true, Vec::new());
+
+        // Each request gets its own line, with its first key quoted and escaped, and where it went.
+        let lines = read_lines(&path, 2);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#" 127.0.0.1:5000 "get" "foo" 127.0.0.1:6379 ok 9 "#));
+        assert!(lines[1].contains(r#" 127.0.0.1:5000 "set" "a \"b\"\r\n" - error 9 "#));
+
+        // Going over the maximum size moves the old file aside and starts a new one.
+        log.start(&RedisMessage::from_inline("GET foo"));
+        log.record(&BytesMut::from(&b"$-1\r\n"[..]), 1, false, Vec::new());
+
+        assert_eq!(read_lines(&rotated, 2).len(), 2);
+        assert_eq!(read_lines(&path, 1).len(), 1);
+        assert_eq!(get_counter(&receiver, "access_log_dropped"), 0);
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+    }
+
+    #[test]
+    fn test_access_log_drops_entries_when_behind() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+
+        // Nothing ever reads from this channel, so it's full after the first write, just like when
+        // the access log thread can't keep up.
+        let (tx, _rx) = sync_channel(1);
+        let writer = AccessLogWriter {
+            tx,
+            dropped: receiver.get_sink().counter("access_log_dropped"),
+        };
+        let mut log = AccessLog::new(ClientAddr::Tcp("127.0.0.1:5000".parse().unwrap()), writer);
+
+        for _ in 0..3 {
+            log.start(&RedisMessage::from_inline("GET foo"));
+            log.record(&BytesMut::from(&b"$-1\r\n"[..]), 1, false, Vec::new());
+        }
+
+        // The client never waits on the log: the entries that didn't fit are simply counted.
+        assert_eq!(get_counter(&receiver, "access_log_dropped"), 2);
+    }
+}
@@ -17,7 +17,14 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
+mod access_log;
 mod errors;
 mod pipeline;
+mod response_sizes;
 
-pub use self::{errors::PipelineError, pipeline::Pipeline};
+pub use self::{
+    access_log::{AccessLog, AccessLogWriter},
+    errors::PipelineError,
+    pipeline::Pipeline,
+    response_sizes::ResponseSizes,
+};
@@ -18,10 +18,13 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 use crate::{
-    backend::{message_queue::MessageQueue, processor::Processor},
-    common::{AssignedRequests, AssignedResponse, Message},
-    service::PipelineError,
-    util::{Batch, FutureExt, Timed},
+    backend::{
+        message_queue::MessageQueue,
+        processor::{ClientState, Processor},
+    },
+    common::{AssignedRequests, AssignedResponse, Message, RouteLog},
+    service::{AccessLog, AccessLogWriter, PipelineError, ResponseSizes},
+    util::{Batch, ClientAddr, FutureExt, MemoryBudget, Timed},
 };
 use bytes::BytesMut;
 use futures::prelude::*;
@@ -29,9 +32,30 @@ use metrics_runtime::{
     data::{Counter, Histogram},
     Sink as MetricSink,
 };
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
 use tower_service::Service;
 
+/// A batch of requests that we're waiting on the service to respond to.
+struct PendingBatch<F: Future> {
+    slot_ids: Vec<usize>,
+    response: Timed<F>,
+    deadline: Option<Delay>,
+}
+
+impl<F: Future> PendingBatch<F> {
+    /// Whether or not the batch has run past its deadline, if it has one.
+    fn is_expired(&mut self) -> bool {
+        match self.deadline.as_mut().map(|deadline| deadline.poll()) {
+            Some(Ok(Async::Ready(()))) => true,
+            _ => false,
+        }
+    }
+}
+
 /// Pipeline-capable service base.
 ///
 /// `Pipeline` can simultaenously drive a `Transport` and an underlying `Service`,
@@ -45,19 +69,26 @@ where
     P: Processor,
     P::Message: Message + Clone,
 {
-    responses: VecDeque<Timed<S::Future>>,
+    responses: VecDeque<PendingBatch<S::Future>>,
+    request_timeout: Option<Duration>,
     transport: Batch<T>,
     service: S,
     queue: MessageQueue<P>,
+    client_state: ClientState,
 
     send_buf: Option<(BytesMut, u64)>,
     finish: bool,
+    memory_paused: bool,
+    access_log: Option<AccessLog>,
+    response_sizes: ResponseSizes,
 
     sink: MetricSink,
     bytes_sent: Counter,
     bytes_received: Counter,
     messages_sent: Counter,
     messages_received: Counter,
+    memory_pressure: Counter,
+    request_timeouts: Counter,
     client_e2e: Histogram,
 }
 
@@ -75,23 +106,76 @@ where
         let bytes_received = sink.counter("bytes_received");
         let messages_sent = sink.counter("messages_sent");
         let messages_received = sink.counter("messages_received");
+        let memory_pressure = sink.counter("memory_pressure");
+        let request_timeouts = sink.counter("request_timeouts");
+        let orphaned_responses = sink.counter("orphaned_responses");
         let client_e2e = sink.histogram("client_e2e");
+        let response_sizes = ResponseSizes::new(sink.clone());
 
         Pipeline {
             responses: VecDeque::new(),
+            request_timeout: None,
             transport: Batch::new(transport, 128),
             service,
-            queue: MessageQueue::new(processor),
+            queue: MessageQueue::new(processor).set_orphaned_responses(orphaned_responses),
+            client_state: ClientState::default(),
             send_buf: None,
             finish: false,
+            memory_paused: false,
+            access_log: None,
+            response_sizes,
             sink,
             bytes_sent,
             bytes_received,
             messages_sent,
             messages_received,
+            memory_pressure,
+            request_timeouts,
             client_e2e,
         }
     }
+
+    /// Sets whether or not identical reads within a batch are deduplicated.
+    pub fn set_dedupe_reads(mut self, dedupe_reads: bool) -> Self {
+        self.queue = self.queue.set_dedupe_reads(dedupe_reads);
+        self
+    }
+
+    /// Sets whether or not identical, consecutive writes within a batch are coalesced.
+    pub fn set_coalesce_writes(mut self, coalesce_writes: bool) -> Self {
+        self.queue = self.queue.set_coalesce_writes(coalesce_writes);
+        self
+    }
+
+    /// Sets the maximum number of responses that can be pending before we stop reading requests.
+    pub fn set_max_pending_responses(mut self, max_pending: Option<usize>) -> Self {
+        self.queue = self.queue.set_max_pending(max_pending);
+        self
+    }
+
+    /// Sets the budget for bytes buffered across all clients, past which we stop reading requests.
+    pub fn set_memory_budget(mut self, budget: Option<MemoryBudget>) -> Self {
+        self.queue = self.queue.set_memory_budget(budget);
+        self
+    }
+
+    /// Sets how long to wait on the responses to each batch of requests before failing them.
+    ///
+    /// Every batch is timed from when it was sent, regardless of any batches ahead of it.
+    pub fn set_request_timeout(mut self, request_timeout: Option<Duration>) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Enables access logging for all requests from the given client, written to the given writer.
+    ///
+    /// The backend each request was served by is taken from the given route log, which must be the
+    /// same one the client's router records its requests in.
+    pub fn set_access_log(mut self, client: ClientAddr, writer: AccessLogWriter, route_log: RouteLog) -> Self {
+        self.queue = self.queue.set_route_log(Some(route_log));
+        self.access_log = Some(AccessLog::new(client, writer));
+        self
+    }
 }
 
 impl<T, S, P> Future for Pipeline<T, S, P>
@@ -107,19 +191,30 @@ where
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
-            // In order, drive the response futures we're waiting on.  Keep pulling from the
-            // front to keep things in order, and as soon as we hit something that isn't ready or
-            // isn't ready to flush to the message queue.
-            while let Some(mut f) = self.responses.pop_front() {
-                match f.poll() {
+            // Drive every response future we're waiting on.  The queue puts responses back in the
+            // order the client sent them in, so batches can complete in any order, and each one is
+            // checked against its own deadline: a batch stuck behind a slow one, or one that's
+            // blocked on the backend, still times out on schedule.  Batches with blocking commands
+            // have no deadline at all.  Expired batches are dropped, and whatever the service
+            // eventually sends back for them is ignored.
+            let mut i = 0;
+            while i < self.responses.len() {
+                match self.responses[i].response.poll() {
                     Ok(Async::Ready((start, rsp))) => {
+                        self.responses.remove(i);
                         self.queue.fulfill(rsp);
                         let end = self.sink.now();
                         self.client_e2e.record_timing(start, end);
                     },
                     Ok(Async::NotReady) => {
-                        self.responses.push_front(f);
-                        break;
+                        if !self.responses[i].is_expired() {
+                            i += 1;
+                            continue;
+                        }
+
+                        let batch = self.responses.remove(i).expect("expired batch missing");
+                        self.request_timeouts.record(batch.slot_ids.len() as u64);
+                        self.queue.fail(batch.slot_ids, "request timed out");
                     },
                     Err(e) => {
                         return Err(PipelineError::from_service_error(e));
@@ -148,6 +243,12 @@ where
             let mut bytes_sent = 0;
 
             while let Some((buf, count)) = self.queue.get_sendable_buf() {
+                let is_error = P::Message::is_error(&buf);
+                self.response_sizes.record(buf.len(), count, is_error);
+                if let Some(access_log) = self.access_log.as_mut() {
+                    access_log.record(&buf, count, is_error, self.queue.take_routes());
+                }
+
                 let buf_len = buf.len();
                 if let AsyncSink::NotReady(buf) =
                     self.transport.start_send(buf).map_err(PipelineError::from_sink_error)?
@@ -179,6 +280,26 @@ where
                 return Ok(Async::NotReady);
             }
 
+            // If we're holding on to too many responses, stop reading requests until we've sent some
+            // back.  We'll get woken up as our outstanding responses complete.
+            if self.queue.is_full() {
+                return Ok(Async::NotReady);
+            }
+
+            // Likewise if all clients together are holding on to too much.  Only clients that are
+            // waiting on responses back off, though: they'll get woken up when those complete, but
+            // a client with nothing outstanding has nothing to wake it up, and nothing to free.  We
+            // get polled again every time a response comes in, so we only count the pause once, when
+            // it starts, rather than once for every poll while it lasts.
+            if self.queue.is_over_budget() && !self.queue.is_empty() {
+                if !self.memory_paused {
+                    self.memory_paused = true;
+                    self.memory_pressure.record(1);
+                }
+                return Ok(Async::NotReady);
+            }
+            self.memory_paused = false;
+
             // Make sure the underlying service is ready to be called.
             try_ready!(self.service.poll_ready().map_err(PipelineError::from_service_error));
 
@@ -187,11 +308,30 @@ where
                 Some((batch, batch_size)) => {
                     self.messages_received.record(batch.len() as u64);
                     self.bytes_received.record(batch_size as u64);
-                    let batch = self.queue.enqueue(batch)?;
+                    for msg in &batch {
+                        self.response_sizes.start(msg);
+                        if let Some(access_log) = self.access_log.as_mut() {
+                            access_log.start(msg);
+                        }
+                    }
+
+                    let batch = self.queue.enqueue_authenticated(batch, &mut self.client_state)?;
                     if !batch.is_empty() {
+                        // Blocking commands are expected to take as long as they take, so a batch
+                        // with one of them in it is never timed out.
+                        let slot_ids = batch.iter().map(|(slot_id, _)| *slot_id).collect();
+                        let blocking = batch.iter().any(|(_, msg)| msg.is_blocking());
                         let fut = self.service.call(batch);
                         let start = self.sink.now();
-                        self.responses.push_back(fut.timed(start));
+                        let deadline = match self.request_timeout {
+                            Some(timeout) if !blocking => Some(Delay::new(Instant::now() + timeout)),
+                            _ => None,
+                        };
+                        self.responses.push_back(PendingBatch {
+                            slot_ids,
+                            response: fut.timed(start),
+                            deadline,
+                        });
                     }
                 },
                 None => {
@@ -204,3 +344,442 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::redis::{redis_new_data_buffer, RedisProcessor},
+        common::MessageResponse,
+        protocol::redis::RedisMessage,
+        util::metrics::get_counter,
+    };
+    use futures::future::{self, empty, lazy, Empty, MapErr};
+    use metrics_runtime::Receiver;
+    use std::sync::{atomic::AtomicUsize, Arc, Mutex};
+    use tokio::sync::oneshot;
+
+    /// A transport that hands out one message at a time, as if each arrived on its own, and closes
+    /// once it runs out.
+    struct MockTransport {
+        incoming: Arc<Mutex<VecDeque<RedisMessage>>>,
+        sent: Arc<Mutex<BytesMut>>,
+        ready: bool,
+    }
+
+    impl Stream for MockTransport {
+        type Error = ();
+        type Item = RedisMessage;
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Ok(Async::NotReady);
+            }
+
+            Ok(Async::Ready(self.incoming.lock().unwrap().pop_front()))
+        }
+    }
+
+    impl Sink for MockTransport {
+        type SinkError = ();
+        type SinkItem = BytesMut;
+
+        fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+            self.sent.lock().unwrap().extend_from_slice(&item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), Self::SinkError> { Ok(Async::Ready(())) }
+    }
+
+    /// A service whose responses never complete.
+    struct StalledService;
+
+    impl Service<AssignedRequests<RedisMessage>> for StalledService {
+        type Error = ();
+        type Future = Empty<Self::Response, Self::Error>;
+        type Response = Vec<AssignedResponse<RedisMessage>>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
+
+        fn call(&mut self, _req: AssignedRequests<RedisMessage>) -> Self::Future { empty() }
+    }
+
+    type PendingCall = (oneshot::Sender<Vec<AssignedResponse<RedisMessage>>>, AssignedRequests<RedisMessage>);
+
+    /// A service whose responses complete only when the test says so, in whatever order it likes.
+    #[derive(Clone)]
+    struct ControlledService {
+        calls: Arc<Mutex<Vec<PendingCall>>>,
+    }
+
+    impl ControlledService {
+        /// Completes the given call, answering each request with its own key, in reverse order.
+        fn complete(&self, idx: usize) {
+            let (tx, req) = self.calls.lock().unwrap().remove(idx);
+            let responses = req
+                .into_iter()
+                .rev()
+                .map(|(slot, msg)| (slot, MessageResponse::Complete(redis_new_data_buffer(msg.key()))))
+                .collect();
+            tx.send(responses).expect("pipeline dropped the response");
+        }
+    }
+
+    impl Service<AssignedRequests<RedisMessage>> for ControlledService {
+        type Error = ();
+        type Future = MapErr<oneshot::Receiver<Self::Response>, fn(oneshot::error::RecvError)>;
+        type Response = Vec<AssignedResponse<RedisMessage>>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> { Ok(Async::Ready(())) }
+
+        fn call(&mut self, req: AssignedRequests<RedisMessage>) -> Self::Future {
+            let (tx, rx) = oneshot::channel();
+            self.calls.lock().unwrap().push((tx, req));
+
+            let map_err: fn(oneshot::error::RecvError) = |_| ();
+            rx.map_err(map_err)
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_completion_responds_in_order() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let incoming = vec!["get a", "mget b c", "get d"]
+            .into_iter()
+            .map(RedisMessage::from_inline)
+            .collect();
+        let sent = Arc::new(Mutex::new(BytesMut::new()));
+        let transport = MockTransport {
+            incoming: Arc::new(Mutex::new(incoming)),
+            sent: sent.clone(),
+            ready: false,
+        };
+        let service = ControlledService {
+            calls: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let mut pipeline = Pipeline::new(transport, service.clone(), RedisProcessor::new(), receiver.get_sink());
+
+        lazy(|| {
+            // Each request arrives on its own, so it becomes its own batch.
+            for _ in 0..10 {
+                assert_eq!(pipeline.poll().ok(), Some(Async::NotReady));
+            }
+            assert_eq!(service.calls.lock().unwrap().len(), 3);
+
+            // The later batches finish first, but nothing can be sent until the first one does.
+            service.complete(2);
+            service.complete(1);
+            assert_eq!(pipeline.poll().ok(), Some(Async::NotReady));
+            assert!(sent.lock().unwrap().is_empty());
+
+            // Once it does, everything goes out, in the order the client sent it, including the
+            // fragments of the MGET, whose responses came back reversed.
+            service.complete(0);
+            assert_eq!(pipeline.poll().ok(), Some(Async::Ready(())));
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(
+            &sent.lock().unwrap()[..],
+            &b"$1\r\na\r\n*2\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_pipelining_past_max_pending_responses() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let incoming = (0..5)
+            .map(|i| RedisMessage::from_inline(&format!("get key{}", i)))
+            .collect::<VecDeque<_>>();
+        let incoming = Arc::new(Mutex::new(incoming));
+        let transport = MockTransport {
+            incoming: incoming.clone(),
+            sent: Arc::new(Mutex::new(BytesMut::new())),
+            ready: false,
+        };
+
+        let mut pipeline = Pipeline::new(transport, StalledService, RedisProcessor::new(), receiver.get_sink())
+            .set_max_pending_responses(Some(2));
+
+        // Once two responses are pending, the pipeline should stop reading requests, even though
+        // the client has more to send.
+        lazy(|| {
+            match pipeline.poll() {
+                Ok(Async::NotReady) => {},
+                _ => panic!("pipeline should be waiting on responses"),
+            }
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(incoming.lock().unwrap().len(), 3);
+        assert_eq!(pipeline.queue.in_flight(), 2);
+    }
+
+    #[test]
+    fn test_dropped_pipeline_orphans_responses() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let incoming = vec!["get a", "get b", "get c"]
+            .into_iter()
+            .map(RedisMessage::from_inline)
+            .collect();
+        let transport = MockTransport {
+            incoming: Arc::new(Mutex::new(incoming)),
+            sent: Arc::new(Mutex::new(BytesMut::new())),
+            ready: false,
+        };
+
+        let mut pipeline = Pipeline::new(transport, StalledService, RedisProcessor::new(), receiver.get_sink());
+
+        lazy(|| {
+            for _ in 0..10 {
+                assert_eq!(pipeline.poll().ok(), Some(Async::NotReady));
+            }
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(pipeline.queue.in_flight(), 3);
+        assert_eq!(get_counter(&receiver, "orphaned_responses"), 0);
+
+        // If the client goes away while its responses are still outstanding, they have nowhere to go.
+        drop(pipeline);
+        assert_eq!(get_counter(&receiver, "orphaned_responses"), 3);
+    }
+
+    #[test]
+    fn test_memory_pressure_counted_once_per_pause() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let incoming = vec!["get a", "get b"]
+            .into_iter()
+            .map(RedisMessage::from_inline)
+            .collect();
+        let transport = MockTransport {
+            incoming: Arc::new(Mutex::new(incoming)),
+            sent: Arc::new(Mutex::new(BytesMut::new())),
+            ready: false,
+        };
+        let service = ControlledService {
+            calls: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        // A budget this small is exhausted by any single request.
+        let budget = MemoryBudget::new(1, Arc::new(AtomicUsize::new(0)));
+        let mut pipeline = Pipeline::new(transport, service.clone(), RedisProcessor::new(), receiver.get_sink())
+            .set_memory_budget(Some(budget));
+
+        lazy(|| {
+            // However many times we get polled while paused, it's still the one pause.
+            for _ in 0..10 {
+                assert_eq!(pipeline.poll().ok(), Some(Async::NotReady));
+            }
+            assert_eq!(service.calls.lock().unwrap().len(), 1);
+            assert_eq!(get_counter(&receiver, "memory_pressure"), 1);
+
+            // Once the first response goes out, the pipeline reads the next request, which pauses
+            // it all over again.
+            service.complete(0);
+            for _ in 0..10 {
+                assert_eq!(pipeline.poll().ok(), Some(Async::NotReady));
+            }
+            assert_eq!(service.calls.lock().unwrap().len(), 1);
+            assert_eq!(get_counter(&receiver, "memory_pressure"), 2);
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reading_resumes_below_max_pending_responses() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let incoming = (0..4)
+            .map(|i| RedisMessage::from_inline(&format!("get key{}", i)))
+            .collect::<VecDeque<_>>();
+        let incoming = Arc::new(Mutex::new(incoming));
+        let sent = Arc::new(Mutex::new(BytesMut::new()));
+        let transport = MockTransport {
+            incoming: incoming.clone(),
+            sent: sent.clone(),
+            ready: false,
+        };
+        let service = ControlledService {
+            calls: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let mut pipeline = Pipeline::new(transport, service.clone(), RedisProcessor::new(), receiver.get_sink())
+            .set_max_pending_responses(Some(2));
+
+        lazy(|| {
+            // Reading stops once two responses are pending...
+            for _ in 0..10 {
+                assert_eq!(pipeline.poll().ok(), Some(Async::NotReady));
+            }
+            assert_eq!(service.calls.lock().unwrap().len(), 2);
+            assert_eq!(incoming.lock().unwrap().len(), 2);
+
+            // ...and picks back up as soon as one of them has been sent, but only for as long as
+            // there's room for another.
+            service.complete(0);
+            for _ in 0..10 {
+                assert_eq!(pipeline.poll().ok(), Some(Async::NotReady));
+            }
+            assert_eq!(&sent.lock().unwrap()[..], &b"$4\r\nkey0\r\n"[..]);
+            assert_eq!(service.calls.lock().unwrap().len(), 2);
+            assert_eq!(incoming.lock().unwrap().len(), 1);
+
+            // The same goes for the next one, which leaves the client with nothing more to send.
+            service.complete(0);
+            for _ in 0..10 {
+                assert_eq!(pipeline.poll().ok(), Some(Async::NotReady));
+            }
+            assert_eq!(service.calls.lock().unwrap().len(), 2);
+            assert!(incoming.lock().unwrap().is_empty());
+
+            // Once the rest are answered, everything has gone out in order, and we're done.
+            service.complete(0);
+            service.complete(0);
+            assert_eq!(pipeline.poll().ok(), Some(Async::Ready(())));
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        assert_eq!(
+            &sent.lock().unwrap()[..],
+            &b"$4\r\nkey0\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let incoming = vec![RedisMessage::from_inline("get key")].into_iter().collect();
+        let sent = Arc::new(Mutex::new(BytesMut::new()));
+        let transport = MockTransport {
+            incoming: Arc::new(Mutex::new(incoming)),
+            sent: sent.clone(),
+            ready: false,
+        };
+
+        let pipeline = Pipeline::new(transport, StalledService, RedisProcessor::new(), receiver.get_sink())
+            .set_request_timeout(Some(Duration::from_millis(50)));
+
+        // The service never responds, so the only way the pipeline finishes is by timing out the
+        // request.  We give up waiting well after that should have happened.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        tokio_io_pool::run(lazy(move || {
+            let give_up = Delay::new(Instant::now() + Duration::from_secs(5));
+            pipeline.select2(give_up).then(move |result| {
+                let _ = tx.send(match result {
+                    Ok(future::Either::A(_)) => Some(start.elapsed()),
+                    _ => None,
+                });
+                Ok(())
+            })
+        }));
+
+        let elapsed = rx
+            .recv()
+            .expect("pipeline never finished")
+            .expect("request never timed out");
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_secs(1));
+        assert_eq!(&sent.lock().unwrap()[..], &b"-ERR request timed out\r\n"[..]);
+    }
+
+    #[test]
+    fn test_request_timeout_skips_blocking_commands() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let incoming = vec![RedisMessage::from_inline("blpop queue 0")].into_iter().collect();
+        let sent = Arc::new(Mutex::new(BytesMut::new()));
+        let transport = MockTransport {
+            incoming: Arc::new(Mutex::new(incoming)),
+            sent: sent.clone(),
+            ready: false,
+        };
+
+        let processor = RedisProcessor::new().set_allow_blocking(true);
+        let pipeline = Pipeline::new(transport, StalledService, processor, receiver.get_sink())
+            .set_request_timeout(Some(Duration::from_millis(50)));
+
+        // BLPOP with no timeout waits for as long as it takes, so the pipeline should still be
+        // waiting on it well after the request timeout has passed.
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            let give_up = Delay::new(Instant::now() + Duration::from_millis(250));
+            pipeline.select2(give_up).then(move |result| {
+                let _ = tx.send(match result {
+                    Ok(future::Either::B(_)) => true,
+                    _ => false,
+                });
+                Ok(())
+            })
+        }));
+
+        assert_eq!(rx.recv(), Ok(true));
+        assert!(sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_request_timeout_behind_blocking_command() {
+        let receiver = Receiver::builder().build().expect("failed to build metrics receiver");
+        let incoming = vec!["blpop queue 0", "get key"]
+            .into_iter()
+            .map(RedisMessage::from_inline)
+            .collect();
+        let sent = Arc::new(Mutex::new(BytesMut::new()));
+        let transport = MockTransport {
+            incoming: Arc::new(Mutex::new(incoming)),
+            sent: sent.clone(),
+            ready: false,
+        };
+
+        let processor = RedisProcessor::new().set_allow_blocking(true);
+        let mut pipeline = Pipeline::new(transport, StalledService, processor, receiver.get_sink())
+            .set_request_timeout(Some(Duration::from_millis(50)));
+
+        // The BLPOP never returns, but the GET queued up behind it should still time out, leaving
+        // only the BLPOP in flight.
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokio_io_pool::run(lazy(move || {
+            let mut give_up = Delay::new(Instant::now() + Duration::from_secs(5));
+            let mut both_sent = false;
+            future::poll_fn(move || {
+                let _ = pipeline.poll();
+                match pipeline.queue.in_flight() {
+                    2 => both_sent = true,
+                    1 if both_sent => {
+                        let _ = tx.send(true);
+                        return Ok(Async::Ready(()));
+                    },
+                    _ => {},
+                }
+
+                if let Ok(Async::NotReady) = give_up.poll() {
+                    return Ok(Async::NotReady);
+                }
+
+                let _ = tx.send(false);
+                Ok(Async::Ready(()))
+            })
+        }));
+
+        assert_eq!(rx.recv(), Ok(true));
+
+        // The GET's error can't go out ahead of the BLPOP's response.
+        assert!(sent.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::common::Message;
+use metrics_runtime::{data::Histogram, Sink as MetricSink};
+use std::collections::{HashMap, VecDeque};
+
+/// Where the size of a pending request's response gets recorded.
+enum Target {
+    /// The histogram for a command we've already seen.
+    Known(Histogram),
+
+    /// A command we haven't recorded a response for yet.
+    New(Vec<u8>),
+}
+
+/// Tracks client requests as they flow through a pipeline and records the size of their responses.
+///
+/// Sizes are recorded, in bytes, to the `response_bytes` histogram, labeled by the lowercased
+/// command.  Error responses are all labeled as `error`, regardless of their command, which keeps
+/// unknown commands sent by clients from creating new labels.  We hold on to the histogram for each
+/// command once we've seen it, so tracking a request doesn't have to build its label all over again.
+pub struct ResponseSizes {
+    sink: MetricSink,
+    histograms: HashMap<Vec<u8>, Histogram>,
+    unknown: Histogram,
+    errors: Histogram,
+    pending: VecDeque<(Target, usize, bool)>,
+}
+
+impl ResponseSizes {
+    pub fn new(mut sink: MetricSink) -> ResponseSizes {
+        let unknown = sink.histogram_with_labels("response_bytes", &[("command", "unknown")]);
+        let errors = sink.histogram_with_labels("response_bytes", &[("command", "error")]);
+
+        ResponseSizes {
+            sink,
+            histograms: HashMap::new(),
+            unknown,
+            errors,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Starts tracking the given request.
+    pub fn start<M: Message>(&mut self, msg: &M) {
+        let target = match msg.command() {
+            Some(cmd) => match self.histograms.get(cmd) {
+                Some(histogram) => Target::Known(histogram.clone()),
+                None => Target::New(cmd.to_vec()),
+            },
+            None => Target::Known(self.unknown.clone()),
+        };
+
+        self.pending.push_back((target, 0, false));
+    }
+
+    /// Records a response buffer, recording the size of the oldest pending request's response if
+    /// the buffer completes it.
+    pub fn record(&mut self, buf_len: usize, count: u64, is_error: bool) {
+        if let Some((_, bytes, failed)) = self.pending.front_mut() {
+            // Only the first buffer of a response tells us whether or not it's an error.
+            if *bytes == 0 {
+                *failed = is_error;
+            }
+            *bytes += buf_len;
+        }
+
+        for _ in 0..count {
+            if let Some((target, bytes, failed)) = self.pending.pop_front() {
+                // Only commands that actually succeed get a histogram of their own.
+                match (target, failed) {
+                    (_, true) => self.errors.record_value(bytes as u64),
+                    (Target::Known(histogram), false) => histogram.record_value(bytes as u64),
+                    (Target::New(cmd), false) => self.get_histogram(cmd).record_value(bytes as u64),
+                }
+            }
+        }
+    }
+
+    /// Gets the histogram for the given command, creating it if this is the first we've seen of it.
+    fn get_histogram(&mut self, cmd: Vec<u8>) -> Histogram {
+        if let Some(histogram) = self.histograms.get(&cmd) {
+            return histogram.clone();
+        }
+
+        let command = String::from_utf8_lossy(&cmd).to_lowercase();
+        let histogram = self
+            .sink
+            .histogram_with_labels("response_bytes", &[("command", command)]);
+        self.histograms.insert(cmd, histogram.clone());
+        histogram
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A limit on the total number of bytes buffered across every client connection.
+///
+/// Budgets built on the same usage counter share it, so a budget with a different limit can be
+/// swapped in on reload while the connections from before the reload keep counting against it.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize, used: Arc<AtomicUsize>) -> MemoryBudget { MemoryBudget { limit, used } }
+
+    /// Counts the given number of bytes as buffered.
+    pub fn acquire(&self, n: usize) { self.used.fetch_add(n, Ordering::Relaxed); }
+
+    /// Counts the given number of bytes as no longer buffered.
+    pub fn release(&self, n: usize) { self.used.fetch_sub(n, Ordering::Relaxed); }
+
+    /// Whether or not buffered bytes have reached the limit.
+    pub fn is_exhausted(&self) -> bool { self.used.load(Ordering::Relaxed) >= self.limit }
+}
@@ -17,19 +17,18 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
-use crate::protocol::errors::ProtocolError;
+use crate::{protocol::errors::ProtocolError, util::MaybeTlsStream};
 use futures::prelude::*;
-use tokio::net::tcp::TcpStream;
 
-/// Wraps any future that does protocol operations and hands back a TCP stream.
+/// Wraps any future that does protocol operations and hands back a backend stream.
 pub struct ProcessFuture {
-    inner: Box<Future<Item = TcpStream, Error = ProtocolError> + Send + 'static>,
+    inner: Box<Future<Item = MaybeTlsStream, Error = ProtocolError> + Send + 'static>,
 }
 
 impl ProcessFuture {
     pub fn new<F>(inner: F) -> ProcessFuture
     where
-        F: Future<Item = TcpStream, Error = ProtocolError> + Send + 'static,
+        F: Future<Item = MaybeTlsStream, Error = ProtocolError> + Send + 'static,
     {
         ProcessFuture { inner: Box::new(inner) }
     }
@@ -37,7 +36,7 @@ impl ProcessFuture {
 
 impl Future for ProcessFuture {
     type Error = ProtocolError;
-    type Item = TcpStream;
+    type Item = MaybeTlsStream;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> { self.inner.poll() }
 }
@@ -0,0 +1,63 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use metrics_core::{Key, Recorder, Snapshot, SnapshotProvider};
+use metrics_runtime::Receiver;
+
+/// Sums up the values of a single counter out of a metrics snapshot, optionally only where it has
+/// a specific label.
+struct CounterRecorder<'a> {
+    name: &'a str,
+    label: Option<(&'a str, &'a str)>,
+    value: u64,
+}
+
+impl<'a> Recorder for CounterRecorder<'a> {
+    fn record_counter(&mut self, key: Key, value: u64) {
+        let labeled = match self.label {
+            Some((label_key, label_value)) => {
+                key.labels().any(|label| label.key() == label_key && label.value() == label_value)
+            },
+            None => true,
+        };
+
+        if key.name() == self.name && labeled {
+            self.value += value;
+        }
+    }
+
+    fn record_gauge(&mut self, _key: Key, _value: i64) {}
+
+    fn record_histogram(&mut self, _key: Key, _values: &[u64]) {}
+}
+
+/// Gets the total of the given counter, across all of its labels, recorded by the given receiver.
+pub fn get_counter(receiver: &Receiver, name: &str) -> u64 { record_counter(receiver, name, None) }
+
+/// Gets the total of the given counter, where it has the given label, recorded by the given receiver.
+pub fn get_labeled_counter(receiver: &Receiver, name: &str, label: (&str, &str)) -> u64 {
+    record_counter(receiver, name, Some(label))
+}
+
+fn record_counter(receiver: &Receiver, name: &str, label: Option<(&str, &str)>) -> u64 {
+    let snapshot = receiver.get_controller().get_snapshot().expect("failed to get metrics snapshot");
+    let mut recorder = CounterRecorder { name, label, value: 0 };
+    snapshot.record(&mut recorder);
+    recorder.value
+}
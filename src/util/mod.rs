@@ -24,11 +24,33 @@ mod timed;
 mod untyped;
 pub use self::{batch::Batch, timed::Timed, untyped::Untyped};
 
+mod container;
+pub use self::container::IntegerMappedVec;
+
+#[cfg(test)]
+pub mod metrics;
+
+// The fuzz targets build the parser on its own, out of the same source files, and none of it needs
+// sockets, TLS or buffer budgets, so those are left out rather than dragging in their dependencies.
+#[cfg(not(fuzzing))]
 mod helpers;
+#[cfg(not(fuzzing))]
 pub use self::helpers::ProcessFuture;
 
-mod container;
-pub use self::container::IntegerMappedVec;
+#[cfg(not(fuzzing))]
+mod budget;
+#[cfg(not(fuzzing))]
+pub use self::budget::MemoryBudget;
+
+#[cfg(not(fuzzing))]
+mod network;
+#[cfg(not(fuzzing))]
+pub use self::network::{ClientAddr, IpNetwork, SocketOptions};
+
+#[cfg(not(fuzzing))]
+mod tls;
+#[cfg(not(fuzzing))]
+pub use self::tls::{get_tls_acceptor, Connector, MaybeTlsStream};
 
 impl<T: ?Sized> StreamExt for T where T: Stream {}
 
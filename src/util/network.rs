@@ -0,0 +1,211 @@
+// Copyright (c) 2018 Nuclear Furnace
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::net::TcpStream;
+
+/// Tuning applied to TCP sockets, to clients and backends alike.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SocketOptions {
+    /// Whether or not Nagle's algorithm is disabled, so that small writes go out right away.
+    pub nodelay: bool,
+
+    /// How long the connection can sit idle before TCP keepalive probes are sent, if at all.
+    pub keepalive: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Creates a set of socket options, where a `keepalive_secs` of 0 disables keepalives.
+    pub fn new(nodelay: bool, keepalive_secs: u64) -> SocketOptions {
+        SocketOptions {
+            nodelay,
+            keepalive: if keepalive_secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(keepalive_secs))
+            },
+        }
+    }
+
+    /// Applies these options to the given socket.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        stream.set_keepalive(self.keepalive)
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions { SocketOptions::new(true, 0) }
+}
+
+/// The address a client connected to us from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientAddr {
+    /// A client connected over TCP, from the given address.
+    Tcp(SocketAddr),
+
+    /// A client connected over the Unix domain socket at the given path.
+    Unix(PathBuf),
+}
+
+impl ClientAddr {
+    /// Gets the IP address of the client, if it connected over TCP.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientAddr::Tcp(addr) => Some(addr.ip()),
+            ClientAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => write!(f, "{}", addr),
+            ClientAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A block of IP addresses, such as `10.0.0.0/8` or `fd00::/8`.
+///
+/// A plain address, without a prefix length, is a block of just that address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Whether or not the given address is within this block.
+    ///
+    /// IPv4-mapped IPv6 addresses, such as `::ffff:10.0.0.1`, are treated as their IPv4 address.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, normalize(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = mask_u32(self.prefix_len);
+                u32::from(net) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = mask_u128(self.prefix_len);
+                u128::from(net) & mask == u128::from(addr) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<IpNetwork, ()> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+
+        let addr = normalize(addr.parse::<IpAddr>().map_err(|_| ())?);
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse::<u8>().map_err(|_| ())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(());
+        }
+
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => {
+            match v6.segments() {
+                [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                    IpAddr::V4(Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8))
+                },
+                _ => IpAddr::V6(v6),
+            }
+        },
+        addr => addr,
+    }
+}
+
+fn mask_u32(prefix_len: u8) -> u32 { u32::max_value().checked_shl(32 - u32::from(prefix_len)).unwrap_or(0) }
+
+fn mask_u128(prefix_len: u8) -> u128 { u128::max_value().checked_shl(128 - u32::from(prefix_len)).unwrap_or(0) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr { s.parse().unwrap() }
+
+    #[test]
+    fn test_parse() {
+        assert!("10.0.0.0/8".parse::<IpNetwork>().is_ok());
+        assert!("10.0.0.1".parse::<IpNetwork>().is_ok());
+        assert!("fd00::/8".parse::<IpNetwork>().is_ok());
+        assert!("0.0.0.0/0".parse::<IpNetwork>().is_ok());
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("fd00::/129".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let net = "10.1.0.0/16".parse::<IpNetwork>().unwrap();
+        assert!(net.contains(ip("10.1.0.0")));
+        assert!(net.contains(ip("10.1.255.255")));
+        assert!(!net.contains(ip("10.2.0.0")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("fd00::1")));
+
+        let single = "192.168.1.1".parse::<IpNetwork>().unwrap();
+        assert!(single.contains(ip("192.168.1.1")));
+        assert!(!single.contains(ip("192.168.1.2")));
+
+        let any = "0.0.0.0/0".parse::<IpNetwork>().unwrap();
+        assert!(any.contains(ip("1.2.3.4")));
+        assert!(!any.contains(ip("fd00::1")));
+
+        let v6 = "fd00::/8".parse::<IpNetwork>().unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let tcp = ClientAddr::Tcp("10.0.0.1:6379".parse().unwrap());
+        assert_eq!(tcp.ip(), Some(ip("10.0.0.1")));
+        assert_eq!(tcp.to_string(), "10.0.0.1:6379");
+
+        let unix = ClientAddr::Unix(PathBuf::from("/var/run/synchrotron.sock"));
+        assert_eq!(unix.ip(), None);
+        assert_eq!(unix.to_string(), "unix:/var/run/synchrotron.sock");
+    }
+}
@@ -1,7 +1,9 @@
 use std::str;
 use std::env;
 use std::fs::File;
-use std::io::{Error, Write};
+use std::io::{Error, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Child, Stdio};
 use tempfile::{Builder, TempDir};
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -51,34 +53,123 @@ fn get_redis_config(stats_port: u16, listen1_port: u16, listen2_port: u16, redis
     "#, stats_port = stats_port, listen1_port = listen1_port, listen2_port = listen2_port, redis1_port = redis1_port, redis2_port = redis2_port)
 }
 
+fn get_redis_tls_config(stats_port: u16, listen_port: u16, redis_port: u16, cert_path: &Path, key_path: &Path) -> String {
+    format!(r#"
+        {{
+            "stats_addr": "127.0.0.1:{stats_port}",
+            "listeners": {{
+                "tls": {{
+                    "protocol": "redis",
+                    "address": "127.0.0.1:{listen_port}",
+                    "tls": {{
+                        "cert_path": "{cert_path}",
+                        "key_path": "{key_path}"
+                    }},
+                    "pools": {{
+                        "default": {{
+                            "addresses": ["127.0.0.1:{redis_port}"]
+                        }}
+                    }},
+                    "routing": {{
+                        "type": "fixed"
+                    }}
+                }}
+            }}
+        }}
+    "#, stats_port = stats_port, listen_port = listen_port, redis_port = redis_port, cert_path = cert_path.display(), key_path = key_path.display())
+}
+
+fn get_redis_backend_tls_config(stats_port: u16, listen_port: u16, redis_port: u16, ca_path: &Path) -> String {
+    format!(r#"
+        {{
+            "stats_addr": "127.0.0.1:{stats_port}",
+            "listeners": {{
+                "backend_tls": {{
+                    "protocol": "redis",
+                    "address": "127.0.0.1:{listen_port}",
+                    "pools": {{
+                        "default": {{
+                            "addresses": ["127.0.0.1:{redis_port}"],
+                            "options": {{
+                                "backend_tls": "true",
+                                "backend_tls_sni": "localhost",
+                                "backend_ca_path": "{ca_path}"
+                            }}
+                        }}
+                    }},
+                    "routing": {{
+                        "type": "fixed"
+                    }}
+                }}
+            }}
+        }}
+    "#, stats_port = stats_port, listen_port = listen_port, redis_port = redis_port, ca_path = ca_path.display())
+}
+
+fn get_redis_unix_config(stats_port: u16, socket_path: &Path, redis_port: u16) -> String {
+    format!(r#"
+        {{
+            "stats_addr": "127.0.0.1:{stats_port}",
+            "listeners": {{
+                "unix": {{
+                    "protocol": "redis",
+                    "address": "unix:{socket_path}",
+                    "socket_mode": "600",
+                    "pools": {{
+                        "default": {{
+                            "addresses": ["127.0.0.1:{redis_port}"]
+                        }}
+                    }},
+                    "routing": {{
+                        "type": "fixed"
+                    }}
+                }}
+            }}
+        }}
+    "#, stats_port = stats_port, socket_path = socket_path.display(), redis_port = redis_port)
+}
+
+fn get_memcached_config(stats_port: u16, listen_port: u16, memcached1_port: u16, memcached2_port: u16) -> String {
+    format!(r#"
+        {{
+            "stats_addr": "127.0.0.1:{stats_port}",
+            "listeners": {{
+                "fixed": {{
+                    "protocol": "memcached",
+                    "address": "127.0.0.1:{listen_port}",
+                    "pools": {{
+                        "default": {{
+                            "addresses": ["127.0.0.1:{memcached1_port}", "127.0.0.1:{memcached2_port}"],
+                            "options": {{
+                                "cooloff_timeout_ms": "2000",
+                                "timeout_ms": "100"
+                            }}
+                        }}
+                    }},
+                    "routing": {{
+                        "type": "fixed"
+                    }}
+                }}
+            }}
+        }}
+    "#, stats_port = stats_port, listen_port = listen_port, memcached1_port = memcached1_port, memcached2_port = memcached2_port)
+}
+
 pub struct SynchrotronRunner {
     handle: Child,
     port: u16,
+    stats_port: u16,
     fixed_conn_str: String,
     shadow_conn_str: String,
     conf_dir: Option<TempDir>,
+    tls_dir: Option<TempDir>,
+    socket_dir: Option<TempDir>,
 }
 
 impl SynchrotronRunner {
     pub fn new_redis(stats_port: u16, listen1_port: u16, listen2_port: u16, redis1_port: u16, redis2_port: u16) -> Result<SynchrotronRunner, Error> {
         let full_config = get_redis_config(stats_port, listen1_port, listen2_port, redis1_port, redis2_port);
-
-        // Create our configuration file from the data we got.
-        let conf_dir = Builder::new()
-            .prefix("synchrotron-test-")
-            .tempdir()?;
-
-        let file_path = conf_dir.path().join("synchrotron");
-        let file_path_w_ext = conf_dir.path().join("synchrotron.json");
-        let mut conf_file = File::create(file_path_w_ext)?;
-        conf_file.write(full_config.as_bytes())?;
-
-        // Now try and launch Synchrotron.
-        let handle = Command::new("../target/debug/synchrotron")
-            .env("SYNC_CONFIG", file_path)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()?;
+        let (handle, conf_dir) = launch_synchrotron(full_config)?;
 
         wait_until(|| check_synchrotron(listen1_port));
         wait_until(|| check_synchrotron(listen2_port));
@@ -86,9 +177,104 @@ impl SynchrotronRunner {
         Ok(SynchrotronRunner {
             handle: handle,
             port: listen1_port,
+            stats_port: stats_port,
             fixed_conn_str: format!("redis://127.0.0.1:{}", listen1_port),
             shadow_conn_str: format!("redis://127.0.0.1:{}", listen2_port),
             conf_dir: Some(conf_dir),
+            tls_dir: None,
+            socket_dir: None,
+        })
+    }
+
+    pub fn new_redis_tls(stats_port: u16, listen_port: u16, redis_port: u16) -> Result<SynchrotronRunner, Error> {
+        let tls_dir = generate_test_certs()?;
+        let cert_path = tls_dir.path().join("cert.pem");
+        let key_path = tls_dir.path().join("key.pem");
+
+        let full_config = get_redis_tls_config(stats_port, listen_port, redis_port, &cert_path, &key_path);
+        let (handle, conf_dir) = launch_synchrotron(full_config)?;
+
+        let ca_path = tls_dir.path().join("ca.pem");
+        wait_until(|| check_synchrotron_tls(listen_port, &ca_path));
+
+        // The redis crate we use can't speak TLS, so clients for this listener go through
+        // redis-cli instead, and there's only the one listener.
+        Ok(SynchrotronRunner {
+            handle: handle,
+            port: listen_port,
+            stats_port: stats_port,
+            fixed_conn_str: format!("127.0.0.1:{}", listen_port),
+            shadow_conn_str: format!("127.0.0.1:{}", listen_port),
+            conf_dir: Some(conf_dir),
+            tls_dir: Some(tls_dir),
+            socket_dir: None,
+        })
+    }
+
+    pub fn new_redis_backend_tls(stats_port: u16, listen_port: u16, redis_port: u16, ca_path: &Path) -> Result<SynchrotronRunner, Error> {
+        let full_config = get_redis_backend_tls_config(stats_port, listen_port, redis_port, ca_path);
+        let (handle, conf_dir) = launch_synchrotron(full_config)?;
+
+        wait_until(|| check_synchrotron(listen_port));
+
+        // Only the connections to Redis are encrypted, so clients talk to us in plaintext as usual.
+        Ok(SynchrotronRunner {
+            handle: handle,
+            port: listen_port,
+            stats_port: stats_port,
+            fixed_conn_str: format!("redis://127.0.0.1:{}", listen_port),
+            shadow_conn_str: format!("redis://127.0.0.1:{}", listen_port),
+            conf_dir: Some(conf_dir),
+            tls_dir: None,
+            socket_dir: None,
+        })
+    }
+
+    pub fn new_redis_unix(stats_port: u16, redis_port: u16) -> Result<SynchrotronRunner, Error> {
+        let socket_dir = Builder::new()
+            .prefix("synchrotron-test-socket-")
+            .tempdir()?;
+        let socket_path = socket_dir.path().join("synchrotron.sock");
+
+        let full_config = get_redis_unix_config(stats_port, &socket_path, redis_port);
+        let (handle, conf_dir) = launch_synchrotron(full_config)?;
+
+        wait_until(|| check_synchrotron_unix(&socket_path));
+
+        // There's no port to speak of, so we borrow the stats port to tell instances apart in logs.
+        Ok(SynchrotronRunner {
+            handle: handle,
+            port: stats_port,
+            stats_port: stats_port,
+            fixed_conn_str: format!("unix://{}", socket_path.display()),
+            shadow_conn_str: format!("unix://{}", socket_path.display()),
+            conf_dir: Some(conf_dir),
+            tls_dir: None,
+            socket_dir: Some(socket_dir),
+        })
+    }
+
+    /// Path to the socket file of the listener, if it's listening on a Unix domain socket.
+    pub fn get_socket_path(&self) -> Option<PathBuf> {
+        self.socket_dir.as_ref().map(|dir| dir.path().join("synchrotron.sock"))
+    }
+
+    pub fn new_memcached(stats_port: u16, listen_port: u16, memcached1_port: u16, memcached2_port: u16) -> Result<SynchrotronRunner, Error> {
+        let full_config = get_memcached_config(stats_port, listen_port, memcached1_port, memcached2_port);
+        let (handle, conf_dir) = launch_synchrotron(full_config)?;
+
+        wait_until(|| check_memcached_version(listen_port, "Synchrotron"));
+
+        // There's no shadow listener for memcached, so both connection strings are the same.
+        Ok(SynchrotronRunner {
+            handle: handle,
+            port: listen_port,
+            stats_port: stats_port,
+            fixed_conn_str: format!("127.0.0.1:{}", listen_port),
+            shadow_conn_str: format!("127.0.0.1:{}", listen_port),
+            conf_dir: Some(conf_dir),
+            tls_dir: None,
+            socket_dir: None,
         })
     }
 
@@ -99,6 +285,39 @@ impl SynchrotronRunner {
     pub fn get_shadow_conn_str(&self) -> &str {
         self.shadow_conn_str.as_str()
     }
+
+    /// Tells Synchrotron to reload its configuration, closing down the listeners it has now.
+    pub fn reload(&self) {
+        let status = Command::new("kill")
+            .arg("-USR1")
+            .arg(self.handle.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success(), "failed to signal Synchrotron to reload");
+    }
+
+    /// Gets the current value of a metric for the given listener from the stats endpoint.
+    pub fn get_metric(&self, name: &str, listener: &str) -> Option<f64> {
+        let mut conn = TcpStream::connect(("127.0.0.1", self.stats_port)).unwrap();
+        conn.write_all(b"GET / HTTP/1.0\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+
+        let label = format!("listener=\"{}\"", listener);
+        response.lines()
+            .filter(|line| line.starts_with(&format!("{}{{", name)) && line.contains(&label))
+            .filter_map(|line| line.rsplit(' ').next())
+            .filter_map(|value| value.parse().ok())
+            .next()
+    }
+
+    /// Runs a command through redis-cli, over TLS if the listener has it, and returns its output.
+    ///
+    /// `tls` can be turned off to see what happens to a client that doesn't speak TLS.
+    pub fn run_redis_cli(&self, tls: bool, args: &[&str]) -> String {
+        let ca_path = self.tls_dir.as_ref().map(|dir| dir.path().join("ca.pem"));
+        run_redis_cli(self.port, ca_path.as_ref().filter(|_| tls).map(|path| path.as_path()), args)
+    }
 }
 
 impl Drop for SynchrotronRunner {
@@ -111,21 +330,38 @@ impl Drop for SynchrotronRunner {
     }
 }
 
+fn launch_synchrotron(full_config: String) -> Result<(Child, TempDir), Error> {
+    // Create our configuration file from the data we got.
+    let conf_dir = Builder::new()
+        .prefix("synchrotron-test-")
+        .tempdir()?;
+
+    let file_path = conf_dir.path().join("synchrotron");
+    let file_path_w_ext = conf_dir.path().join("synchrotron.json");
+    let mut conf_file = File::create(file_path_w_ext)?;
+    conf_file.write(full_config.as_bytes())?;
+
+    // Now try and launch Synchrotron.
+    let handle = Command::new("../target/debug/synchrotron")
+        .env("SYNC_CONFIG", file_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    Ok((handle, conf_dir))
+}
+
 pub struct RedisRunner {
     handle: Child,
     port: u16,
     conn_str: String,
+    tls_dir: Option<TempDir>,
 }
 
 impl RedisRunner {
     pub fn new(port: u16) -> Result<RedisRunner, Error> {
-        let redis_bin = match env::var("REDIS_BIN") {
-            Ok(s) => s,
-            Err(_) => "/usr/local/bin/redis-server".to_owned(),
-        };
-
         // Launch Redis on the specified port.
-        let handle = Command::new(redis_bin)
+        let handle = Command::new(get_redis_bin())
             .arg("--port")
             .arg(port.to_string())
             .stdout(Stdio::null())
@@ -139,12 +375,51 @@ impl RedisRunner {
             handle: handle,
             port: port,
             conn_str: format!("redis://127.0.0.1:{}", port),
+            tls_dir: None,
+        })
+    }
+
+    pub fn new_tls(port: u16) -> Result<RedisRunner, Error> {
+        let tls_dir = generate_test_certs()?;
+        let ca_path = tls_dir.path().join("ca.pem");
+
+        // Launch Redis with only a TLS port, so that anything talking plaintext to it fails.
+        let handle = Command::new(get_redis_bin())
+            .arg("--port")
+            .arg("0")
+            .arg("--tls-port")
+            .arg(port.to_string())
+            .arg("--tls-cert-file")
+            .arg(tls_dir.path().join("cert.pem"))
+            .arg("--tls-key-file")
+            .arg(tls_dir.path().join("key.pem"))
+            .arg("--tls-ca-cert-file")
+            .arg(&ca_path)
+            .arg("--tls-auth-clients")
+            .arg("no")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // Wait for the instance to be ready.
+        wait_until(|| check_redis_tls(port, &ca_path));
+
+        Ok(RedisRunner {
+            handle: handle,
+            port: port,
+            conn_str: format!("127.0.0.1:{}", port),
+            tls_dir: Some(tls_dir),
         })
     }
 
     pub fn get_conn_str(&self) -> &str {
         self.conn_str.as_str()
     }
+
+    /// Path to the CA certificate that signed this instance's certificate, if it's using TLS.
+    pub fn get_ca_path(&self) -> Option<PathBuf> {
+        self.tls_dir.as_ref().map(|dir| dir.path().join("ca.pem"))
+    }
 }
 
 impl Drop for RedisRunner {
@@ -156,6 +431,60 @@ impl Drop for RedisRunner {
     }
 }
 
+fn get_redis_bin() -> String {
+    match env::var("REDIS_BIN") {
+        Ok(s) => s,
+        Err(_) => "/usr/local/bin/redis-server".to_owned(),
+    }
+}
+
+pub struct MemcachedRunner {
+    handle: Child,
+    port: u16,
+    conn_str: String,
+}
+
+impl MemcachedRunner {
+    pub fn new(port: u16) -> Result<MemcachedRunner, Error> {
+        let memcached_bin = match env::var("MEMCACHED_BIN") {
+            Ok(s) => s,
+            Err(_) => "/usr/local/bin/memcached".to_owned(),
+        };
+
+        // Launch memcached on the specified port.
+        let handle = Command::new(memcached_bin)
+            .arg("-p")
+            .arg(port.to_string())
+            .arg("-U")
+            .arg("0")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // Wait for the instance to be ready.
+        wait_until(|| check_memcached_version(port, "memcached"));
+
+        Ok(MemcachedRunner {
+            handle: handle,
+            port: port,
+            conn_str: format!("127.0.0.1:{}", port),
+        })
+    }
+
+    pub fn get_conn_str(&self) -> &str {
+        self.conn_str.as_str()
+    }
+}
+
+impl Drop for MemcachedRunner {
+    fn drop(&mut self) {
+        // If it panics, it panics. ¯\_(ツ)_/¯
+        self.handle.kill().unwrap();
+
+        println!("memcached ({}) killed!", self.port);
+    }
+}
+
 fn wait_until<F>(f: F)
     where F: Fn() -> bool
 {
@@ -199,6 +528,115 @@ fn check_redis(port: u16) -> bool {
     }
 }
 
+/// Generates a throwaway CA, plus a certificate and key for `localhost` signed by it.
+///
+/// rustls won't accept a self-signed certificate as a server's own, so we need both halves for
+/// anything that verifies the server it's talking to.
+fn generate_test_certs() -> Result<TempDir, Error> {
+    let tls_dir = Builder::new()
+        .prefix("synchrotron-test-tls-")
+        .tempdir()?;
+
+    let ca_path: PathBuf = tls_dir.path().join("ca.pem");
+    let ca_key_path: PathBuf = tls_dir.path().join("ca-key.pem");
+    let cert_path: PathBuf = tls_dir.path().join("cert.pem");
+    let key_path: PathBuf = tls_dir.path().join("key.pem");
+    let csr_path: PathBuf = tls_dir.path().join("cert.csr");
+    let ext_path: PathBuf = tls_dir.path().join("cert.ext");
+
+    let mut ext_file = File::create(&ext_path)?;
+    ext_file.write_all(b"subjectAltName=DNS:localhost\nbasicConstraints=CA:FALSE\n")?;
+
+    run_openssl(Command::new("openssl")
+        .args(&["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=synchrotron-test-ca"])
+        .arg("-keyout")
+        .arg(&ca_key_path)
+        .arg("-out")
+        .arg(&ca_path))?;
+    run_openssl(Command::new("openssl")
+        .args(&["req", "-newkey", "rsa:2048", "-nodes", "-subj", "/CN=localhost"])
+        .arg("-keyout")
+        .arg(&key_path)
+        .arg("-out")
+        .arg(&csr_path))?;
+    run_openssl(Command::new("openssl")
+        .args(&["x509", "-req", "-days", "1", "-CAcreateserial"])
+        .arg("-in")
+        .arg(&csr_path)
+        .arg("-CA")
+        .arg(&ca_path)
+        .arg("-CAkey")
+        .arg(&ca_key_path)
+        .arg("-extfile")
+        .arg(&ext_path)
+        .arg("-out")
+        .arg(&cert_path))?;
+
+    Ok(tls_dir)
+}
+
+fn run_openssl(command: &mut Command) -> Result<(), Error> {
+    let status = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    assert!(status.success(), "failed to generate test certificates");
+
+    Ok(())
+}
+
+fn run_redis_cli(port: u16, ca_path: Option<&Path>, args: &[&str]) -> String {
+    let mut command = Command::new("redis-cli");
+    command.args(&["-h", "localhost", "-p", port.to_string().as_str()]);
+    if let Some(ca_path) = ca_path {
+        command.arg("--tls").arg("--cacert").arg(ca_path);
+    }
+
+    let result = command
+        .args(args)
+        .output()
+        .expect("failed to run redis-cli");
+
+    String::from_utf8_lossy(&result.stdout).into_owned()
+}
+
+fn check_synchrotron_tls(port: u16, ca_path: &Path) -> bool {
+    if run_redis_cli(port, Some(ca_path), &["ping"]) == "PONG\n" {
+        println!("Synchrotron ({}) is running!", port);
+        true
+    } else {
+        println!("Synchrotron ({}) not running yet.", port);
+        false
+    }
+}
+
+fn check_redis_tls(port: u16, ca_path: &Path) -> bool {
+    if run_redis_cli(port, Some(ca_path), &["ping"]) == "PONG\n" {
+        println!("redis-server ({}) is running!", port);
+        true
+    } else {
+        println!("redis-server ({}) not running yet.", port);
+        false
+    }
+}
+
+fn check_synchrotron_unix(socket_path: &Path) -> bool {
+    let result = Command::new("redis-cli")
+        .arg("-s")
+        .arg(socket_path)
+        .arg("ping")
+        .output()
+        .expect("failed to run redis-cli");
+
+    if result.stdout == b"PONG\n" {
+        println!("Synchrotron ({}) is running!", socket_path.display());
+        true
+    } else {
+        println!("Synchrotron ({}) not running yet.", socket_path.display());
+        false
+    }
+}
+
 fn check_synchrotron(port: u16) -> bool {
     let result = Command::new("redis-cli")
         .args(&["-h", "localhost", "-p", port.to_string().as_str(), "ping"])
@@ -223,6 +661,28 @@ fn check_synchrotron(port: u16) -> bool {
     }
 }
 
+fn check_memcached_version(port: u16, name: &str) -> bool {
+    let result = TcpStream::connect(("127.0.0.1", port)).and_then(|mut conn| {
+        conn.set_read_timeout(Some(Duration::from_millis(500)))?;
+        conn.write_all(b"version\r\n")?;
+
+        let mut buf = [0; 64];
+        let n = conn.read(&mut buf)?;
+        Ok(buf[..n].starts_with(b"VERSION "))
+    });
+
+    match result {
+        Ok(true) => {
+            println!("{} ({}) is running!", name, port);
+            true
+        },
+        _ => {
+            println!("{} ({}) not running yet.", name, port);
+            false
+        },
+    }
+}
+
 pub fn get_redis_daemons() -> (SynchrotronRunner, RedisRunner, RedisRunner) {
     let offset = PORT_OFFSET.fetch_add(1, Ordering::SeqCst) as u16;
 
@@ -238,3 +698,57 @@ pub fn get_redis_daemons() -> (SynchrotronRunner, RedisRunner, RedisRunner) {
 
     (synchrotron, redis1, redis2)
 }
+
+pub fn get_redis_tls_daemons() -> (SynchrotronRunner, RedisRunner) {
+    let offset = PORT_OFFSET.fetch_add(1, Ordering::SeqCst) as u16;
+
+    let synchrotron_stats_port = 43000 + offset;
+    let synchrotron_listen_port = 44000 + offset;
+    let redis_port = 46000 + offset;
+
+    let redis = RedisRunner::new(redis_port).unwrap();
+    let synchrotron = SynchrotronRunner::new_redis_tls(synchrotron_stats_port, synchrotron_listen_port, redis_port).unwrap();
+
+    (synchrotron, redis)
+}
+
+pub fn get_redis_backend_tls_daemons() -> (SynchrotronRunner, RedisRunner) {
+    let offset = PORT_OFFSET.fetch_add(1, Ordering::SeqCst) as u16;
+
+    let synchrotron_stats_port = 43000 + offset;
+    let synchrotron_listen_port = 44000 + offset;
+    let redis_port = 46000 + offset;
+
+    let redis = RedisRunner::new_tls(redis_port).unwrap();
+    let ca_path = redis.get_ca_path().unwrap();
+    let synchrotron = SynchrotronRunner::new_redis_backend_tls(synchrotron_stats_port, synchrotron_listen_port, redis_port, &ca_path).unwrap();
+
+    (synchrotron, redis)
+}
+
+pub fn get_redis_unix_daemons() -> (SynchrotronRunner, RedisRunner) {
+    let offset = PORT_OFFSET.fetch_add(1, Ordering::SeqCst) as u16;
+
+    let synchrotron_stats_port = 43000 + offset;
+    let redis_port = 46000 + offset;
+
+    let redis = RedisRunner::new(redis_port).unwrap();
+    let synchrotron = SynchrotronRunner::new_redis_unix(synchrotron_stats_port, redis_port).unwrap();
+
+    (synchrotron, redis)
+}
+
+pub fn get_memcached_daemons() -> (SynchrotronRunner, MemcachedRunner, MemcachedRunner) {
+    let offset = PORT_OFFSET.fetch_add(1, Ordering::SeqCst) as u16;
+
+    let synchrotron_stats_port = 43000 + offset;
+    let synchrotron_listen_port = 44000 + offset;
+    let memcached1_port = 46000 + offset;
+    let memcached2_port = 47000 + offset;
+
+    let memcached1 = MemcachedRunner::new(memcached1_port).unwrap();
+    let memcached2 = MemcachedRunner::new(memcached2_port).unwrap();
+    let synchrotron = SynchrotronRunner::new_memcached(synchrotron_stats_port, synchrotron_listen_port, memcached1_port, memcached2_port).unwrap();
+
+    (synchrotron, memcached1, memcached2)
+}
@@ -14,7 +14,9 @@ mod redis_tests {
     use redis::cmd as redis_cmd;
     use redis::Client as RedisClient;
     use redis::{Commands, RedisResult, ErrorKind as RedisErrorKind};
-    use daemons::get_redis_daemons;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use daemons::{get_redis_backend_tls_daemons, get_redis_daemons, get_redis_tls_daemons, get_redis_unix_daemons};
 
     #[test]
     fn test_set_get() {
@@ -28,6 +30,29 @@ mod redis_tests {
         assert_eq!(value, 42);
     }
 
+    #[test]
+    fn test_set_expire_options() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // Options that come after the key should pass through to the backend untouched.
+        let _: () = conn.set_ex("options_key", 42, 100).unwrap();
+        let set_cmd = redis_cmd("SET").arg("options_key").arg(43).arg("KEEPTTL").clone();
+        let _: () = set_cmd.query(&conn).unwrap();
+        let value: isize = conn.get("options_key").unwrap();
+        assert_eq!(value, 43);
+        let ttl: isize = conn.ttl("options_key").unwrap();
+        assert!(ttl > 0);
+
+        let expire_cmd = redis_cmd("EXPIRE").arg("options_key").arg(1000).arg("GT").clone();
+        let updated: isize = expire_cmd.query(&conn).unwrap();
+        assert_eq!(updated, 1);
+        let ttl: isize = conn.ttl("options_key").unwrap();
+        assert!(ttl > 100);
+    }
+
     #[test]
     fn test_mget() {
         let (sd, _rd1, _rd2) = get_redis_daemons();
@@ -112,6 +137,93 @@ mod redis_tests {
         assert_eq!(value, ["Hello", "There", "World"]);
     }
 
+    #[test]
+    fn test_object_routes_by_key() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+
+        // Spread a handful of keys over both backends.  If OBJECT was routed by its subcommand
+        // rather than its key, some of these would land on the wrong backend and come back nil.
+        for i in 0..10 {
+            let key = format!("object-key-{}", i);
+            let _: () = conn.set(&key, i).unwrap();
+
+            let idle_cmd = redis_cmd("OBJECT").arg("IDLETIME").arg(&key).clone();
+            let idle_result: RedisResult<isize> = idle_cmd.query(&conn);
+            assert!(idle_result.is_ok());
+
+            let encoding_cmd = redis_cmd("OBJECT").arg("ENCODING").arg(&key).clone();
+            let encoding_result: RedisResult<String> = encoding_cmd.query(&conn);
+            assert!(encoding_result.is_ok());
+        }
+    }
+
+    #[test]
+    fn test_tls_listener() {
+        let (sd, _rd) = get_redis_tls_daemons();
+
+        // Commands round-trip as usual once the client has done the TLS handshake.
+        assert_eq!(sd.run_redis_cli(true, &["set", "tls_key", "42"]), "OK\n");
+        assert_eq!(sd.run_redis_cli(true, &["get", "tls_key"]), "42\n");
+
+        // A client that doesn't speak TLS just gets disconnected, without a reply.
+        assert_ne!(sd.run_redis_cli(false, &["ping"]), "PONG\n");
+
+        // ...and that doesn't get in the way of anyone else.
+        assert_eq!(sd.run_redis_cli(true, &["ping"]), "PONG\n");
+    }
+
+    #[test]
+    fn test_tls_backend() {
+        let (sd, _rd) = get_redis_backend_tls_daemons();
+
+        // Redis only listens for TLS here, so this only works if we're speaking it to the backend.
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("tls_backend_key", 42).unwrap();
+        let value: isize = conn.get("tls_backend_key").unwrap();
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn test_unix_listener() {
+        let (sd, _rd) = get_redis_unix_daemons();
+
+        // The socket file should only be accessible to us, as configured.
+        let socket_path = sd.get_socket_path().unwrap();
+        let mode = fs::metadata(&socket_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("unix_key", 42).unwrap();
+        let value: isize = conn.get("unix_key").unwrap();
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn test_drain_metrics() {
+        let (sd, _rd1, _rd2) = get_redis_daemons();
+
+        // Hold a connection open across a reload, so that the old listener has a client to drain.
+        let client = RedisClient::open(sd.get_fixed_conn_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let _: () = conn.set("drain_key", 42).unwrap();
+
+        sd.reload();
+
+        // While it waits for us to go away, the old listener reports us as draining.
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(sd.get_metric("clients_draining", "fixed"), Some(1.0));
+
+        // Once the reload timeout runs out, it stops waiting and disconnects us.
+        thread::sleep(Duration::from_millis(5000));
+        assert_eq!(sd.get_metric("drain_timeout_forced", "fixed"), Some(1.0));
+        assert_eq!(sd.get_metric("clients_draining", "fixed"), Some(0.0));
+    }
+
     #[test]
     fn test_large_insert_times_out() {
         let (sd, _rd1, _rd2) = get_redis_daemons();
@@ -274,3 +386,111 @@ mod redis_tests {
         assert!(result.is_err());
     }
 }
+
+#[cfg(test)]
+mod memcached_tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use daemons::get_memcached_daemons;
+
+    fn connect(conn_str: &str) -> TcpStream {
+        let conn = TcpStream::connect(conn_str).unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(1000))).unwrap();
+        conn
+    }
+
+    // Sends a request and reads until the response ends with the given terminator.
+    fn request(conn: &mut TcpStream, req: &[u8], terminator: &[u8]) -> String {
+        conn.write_all(req).unwrap();
+
+        let mut resp = Vec::new();
+        let mut buf = [0; 1024];
+        while !resp.ends_with(terminator) {
+            let n = conn.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed before full response");
+            resp.extend_from_slice(&buf[..n]);
+        }
+
+        String::from_utf8(resp).unwrap()
+    }
+
+    #[test]
+    fn test_set_get() {
+        let (sd, _md1, _md2) = get_memcached_daemons();
+
+        // A simple set and then get.
+        let mut conn = connect(sd.get_fixed_conn_str());
+        let stored = request(&mut conn, b"set my_key 0 0 2\r\n42\r\n", b"\r\n");
+        assert_eq!(stored, "STORED\r\n");
+        let value = request(&mut conn, b"get my_key\r\n", b"END\r\n");
+        assert_eq!(value, "VALUE my_key 0 2\r\n42\r\nEND\r\n");
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let (sd, _md1, _md2) = get_memcached_daemons();
+
+        let mut conn = connect(sd.get_fixed_conn_str());
+        for (key, value) in &[("key_one", "42"), ("key_two", "43"), ("key_three", "44")] {
+            let req = format!("set {} 0 0 {}\r\n{}\r\n", key, value.len(), value);
+            let stored = request(&mut conn, req.as_bytes(), b"\r\n");
+            assert_eq!(stored, "STORED\r\n");
+        }
+
+        // Keys come back in the order they were asked for, skipping misses, with a single END.
+        let values = request(&mut conn, b"get key_one key_missing key_two key_three\r\n", b"END\r\n");
+        assert_eq!(
+            values,
+            "VALUE key_one 0 2\r\n42\r\nVALUE key_two 0 2\r\n43\r\nVALUE key_three 0 2\r\n44\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn test_delete_incr() {
+        let (sd, _md1, _md2) = get_memcached_daemons();
+
+        let mut conn = connect(sd.get_fixed_conn_str());
+        assert_eq!(request(&mut conn, b"add counter 0 0 1\r\n1\r\n", b"\r\n"), "STORED\r\n");
+        assert_eq!(request(&mut conn, b"add counter 0 0 1\r\n1\r\n", b"\r\n"), "NOT_STORED\r\n");
+        assert_eq!(request(&mut conn, b"incr counter 41\r\n", b"\r\n"), "42\r\n");
+        assert_eq!(request(&mut conn, b"decr counter 2\r\n", b"\r\n"), "40\r\n");
+        assert_eq!(request(&mut conn, b"delete counter\r\n", b"\r\n"), "DELETED\r\n");
+        assert_eq!(request(&mut conn, b"delete counter\r\n", b"\r\n"), "NOT_FOUND\r\n");
+        assert_eq!(request(&mut conn, b"get counter\r\n", b"END\r\n"), "END\r\n");
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let (sd, _md1, _md2) = get_memcached_daemons();
+
+        let mut conn = connect(sd.get_fixed_conn_str());
+        assert!(request(&mut conn, b"version\r\n", b"\r\n").starts_with("VERSION "));
+
+        // Unsupported commands are rejected without closing the connection.
+        assert_eq!(request(&mut conn, b"flush_all\r\n", b"\r\n"), "ERROR\r\n");
+        assert_eq!(
+            request(&mut conn, b"delete my_key noreply\r\n", b"\r\n"),
+            "CLIENT_ERROR noreply is not supported by this proxy\r\n"
+        );
+        assert_eq!(
+            request(&mut conn, b"append my_key 0 0 1\r\nx\r\n", b"\r\n"),
+            "CLIENT_ERROR append is not supported by this proxy\r\n"
+        );
+        assert_eq!(request(&mut conn, b"get my_key\r\n", b"END\r\n"), "END\r\n");
+    }
+
+    #[test]
+    fn test_quit_drops_conn() {
+        let (sd, _md1, _md2) = get_memcached_daemons();
+
+        let mut conn = connect(sd.get_fixed_conn_str());
+        assert_eq!(request(&mut conn, b"set my_key 0 0 2\r\n19\r\n", b"\r\n"), "STORED\r\n");
+
+        // Quitting gets no response: the connection is just closed.
+        conn.write_all(b"quit\r\n").unwrap();
+        let mut buf = [0; 64];
+        let n = conn.read(&mut buf).unwrap();
+        assert_eq!(n, 0);
+    }
+}